@@ -0,0 +1,86 @@
+//! Dispatch policies, choosing the downstreams each datagram is sent to.
+//!
+//! By default every datagram is copied to every downstream of a route. To
+//! spread load over a group of collectors, or to keep a backup collector on
+//! standby, a route may instead send each datagram to one downstream:
+//!
+//! - [`Policy::RoundRobin`] takes each downstream in turn;
+//! - [`Policy::Hash`] picks a downstream by hashing the source address of
+//!   the datagram, so that each sender sticks to one downstream;
+//! - [`Policy::Failover`] sends to the first downstream listed, moving down
+//!   the list while it is unavailable.
+//!
+//! When sending to one downstream fails, or the downstream is unavailable,
+//! the datagram is sent to the next downstream in the list instead. A
+//! downstream is unavailable while disabled after repeated send failures, or
+//! with a heartbeat timeout, when it has not sent a datagram back within the
+//! timeout, see [`Downstream::is_alive`](crate::Downstream::is_alive).
+//! Heartbeats require downstreams running mproxy-server with heartbeats
+//! enabled, as other collectors do not answer the probes.
+
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::time::Duration;
+
+/// How the downstreams of a datagram are chosen
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Policy {
+    /// Copy each datagram to every downstream
+    #[default]
+    All,
+    /// Send to each downstream in turn
+    RoundRobin,
+    /// Send to a downstream chosen by the source address
+    Hash,
+    /// Send to the first available downstream
+    Failover,
+}
+
+impl FromStr for Policy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "all" => Ok(Policy::All),
+            "round-robin" => Ok(Policy::RoundRobin),
+            "hash" => Ok(Policy::Hash),
+            "failover" => Ok(Policy::Failover),
+            _ => Err(format!("unknown dispatch policy '{}'", s)),
+        }
+    }
+}
+
+/// Dispatch settings of a route
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Dispatch {
+    /// How downstreams are chosen
+    pub policy: Policy,
+    /// If set, downstreams are expected to send a datagram back at least
+    /// this often, and are passed over while they do not
+    pub heartbeat: Option<Duration>,
+}
+
+impl Dispatch {
+    /// Dispatch with `policy`, without heartbeats
+    pub fn new(policy: Policy) -> Self {
+        Dispatch {
+            policy,
+            heartbeat: None,
+        }
+    }
+
+    /// Expect heartbeats from downstreams within `timeout`
+    pub fn with_heartbeat(mut self, timeout: Duration) -> Self {
+        self.heartbeat = Some(timeout);
+        self
+    }
+}
+
+/// Index of the downstream for datagrams from `source`, out of `count`
+pub(crate) fn hash_index(source: IpAddr, count: usize) -> usize {
+    let mut hasher = DefaultHasher::new();
+    source.hash(&mut hasher);
+    (hasher.finish() % count as u64) as usize
+}
@@ -0,0 +1,586 @@
+//! Downstream UDP targets, with send failures isolated per target.
+//!
+//! Errors sending to one target (e.g. network unreachable, or connection
+//! refused on a connected socket) are counted for that target only. After
+//! repeated failures the target is disabled, and retried after a backoff
+//! interval, while delivery to other targets continues.
+//!
+//! Datagrams sent, send errors, and datagrams dropped while a target is
+//! disabled are counted in its [`DownstreamMetrics`]. Targets are rate
+//! limited as set with
+//! [`set_downstream_limit`](mproxy_common::ratelimit::set_downstream_limit).
+//!
+//! The targets of a route are kept in [`Downstreams`], so that they can be
+//! added and removed while the route is running, and datagrams are sent to
+//! them according to the [`Dispatch`] of the route.
+//!
+//! Host names are re-resolved periodically, and a socket is kept for each
+//! resolved address, see [`ResolveMode`](crate::ResolveMode).
+
+use std::io::{Error, ErrorKind, Result as ioResult};
+use std::net::{IpAddr, SocketAddr, UdpSocket};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use log::{debug, info, warn};
+use mproxy_common::control::{register_control, unregister_control, RouteControl};
+use mproxy_common::metrics::{DownstreamMetrics, RouteMetrics};
+use mproxy_common::ratelimit::{downstream_limit, Limiter, Shaped};
+
+use crate::dispatch::{hash_index, Dispatch, Policy};
+use crate::resolve::{resolve, resolve_mode, ResolveMode, Resolved};
+use crate::target_socket;
+
+/// Consecutive send failures before a target is disabled
+const FAILURE_THRESHOLD: u32 = 5;
+
+/// Initial delay before retrying a disabled target
+const RETRY_MIN: Duration = Duration::from_secs(1);
+
+/// Maximum delay before retrying a disabled target
+const RETRY_MAX: Duration = Duration::from_secs(60);
+
+/// Minimum interval between checks for heartbeats from a target
+const HEARTBEAT_POLL: Duration = Duration::from_millis(100);
+
+/// Sockets bound for each resolved address of a downstream
+type Sockets = Vec<(SocketAddr, UdpSocket)>;
+
+/// UDP socket sending to a downstream address
+#[derive(Debug)]
+pub struct Downstream {
+    name: String,
+    resolved: Arc<Resolved>,
+    version: u64,
+    sockets: Sockets,
+    active: usize,
+    metrics: Arc<DownstreamMetrics>,
+    failures: u32,
+    retry_interval: Duration,
+    disabled_until: Option<Instant>,
+    heartbeat: Option<Duration>,
+    last_heard: Instant,
+    last_poll: Instant,
+    last_probe: Option<Instant>,
+    alive: bool,
+    limiter: Option<Limiter>,
+}
+
+impl Downstream {
+    /// Resolve `server_addr`, and bind a socket for sending to each of its
+    /// addresses
+    pub fn new(server_addr: &String) -> ioResult<Self> {
+        let resolved = resolve(server_addr)?;
+        let sockets = bind_sockets(resolved.addrs(), vec![]).map_err(|(e, _)| e)?;
+        Ok(Downstream {
+            name: server_addr.to_string(),
+            version: resolved.version(),
+            resolved,
+            sockets,
+            active: 0,
+            metrics: Arc::new(DownstreamMetrics::new(server_addr)),
+            failures: 0,
+            retry_interval: RETRY_MIN,
+            disabled_until: None,
+            heartbeat: None,
+            last_heard: Instant::now(),
+            last_poll: Instant::now(),
+            last_probe: None,
+            alive: true,
+            limiter: downstream_limit().map(Limiter::new),
+        })
+    }
+
+    /// Count sends in `metrics`, e.g. as registered with a route by
+    /// [`RouteMetrics::downstream`](mproxy_common::metrics::RouteMetrics::downstream)
+    pub fn with_metrics(mut self, metrics: Arc<DownstreamMetrics>) -> Self {
+        self.metrics = metrics;
+        self
+    }
+
+    /// Expect the target to send a datagram back to the sending socket at
+    /// least every `timeout`, see [`Downstream::is_alive`]
+    pub fn with_heartbeat(mut self, timeout: Option<Duration>) -> Self {
+        self.heartbeat = timeout;
+        self
+    }
+
+    /// Downstream address, as given
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Resolved downstream socket address currently sent to. With
+    /// [`ResolveMode::All`], the first of the addresses sent to
+    pub fn addr(&self) -> SocketAddr {
+        self.sockets[self.active].0
+    }
+
+    /// All resolved downstream socket addresses with a bound socket
+    pub fn addrs(&self) -> Vec<SocketAddr> {
+        self.sockets.iter().map(|(addr, _)| *addr).collect()
+    }
+
+    /// Total number of failed sends
+    pub fn errors(&self) -> u64 {
+        self.metrics.send_errors()
+    }
+
+    /// Returns true if the target is disabled after repeated failures,
+    /// and not yet due for a retry
+    pub fn is_disabled(&self) -> bool {
+        self.disabled_until
+            .is_some_and(|until| Instant::now() < until)
+    }
+
+    /// Returns true unless the target is expected to send heartbeats, and has
+    /// not sent a datagram back within the heartbeat timeout. A new target
+    /// is given one timeout to send its first heartbeat.
+    ///
+    /// Zero-length datagrams are sent to the target every third of the
+    /// timeout as probes, so that targets on standby learn where to send
+    /// heartbeats. Only the UDP server answers these probes, when enabled with
+    /// `mproxy_server::set_answer_heartbeats`, so heartbeats should only be
+    /// expected from such targets
+    pub fn is_alive(&mut self) -> bool {
+        let Some(timeout) = self.heartbeat else {
+            return true;
+        };
+        if self
+            .last_probe
+            .is_none_or(|probed| probed.elapsed() >= timeout / 3)
+        {
+            self.last_probe = Some(Instant::now());
+            for (addr, socket) in &self.sockets {
+                let _ = send_to(socket, *addr, &[]);
+            }
+        }
+        if self.last_poll.elapsed() >= HEARTBEAT_POLL {
+            self.last_poll = Instant::now();
+            if self.poll_heartbeats() {
+                self.last_heard = self.last_poll;
+            }
+        }
+        let alive = self.last_heard.elapsed() < timeout;
+        if alive != self.alive {
+            self.alive = alive;
+            match alive {
+                true => info!("downstream {}: heartbeat received", self.name),
+                false => warn!(
+                    "downstream {}: no heartbeat for {}s",
+                    self.name,
+                    timeout.as_secs_f32()
+                ),
+            }
+        }
+        alive
+    }
+
+    /// Receive any datagrams sent back by the target, returning true if
+    /// there were any
+    fn poll_heartbeats(&self) -> bool {
+        let mut buf = [0u8; 64];
+        let mut heard = false;
+        for (_, socket) in &self.sockets {
+            if socket.set_nonblocking(true).is_err() {
+                continue;
+            }
+            while socket.recv(&mut buf).is_ok() {
+                heard = true;
+            }
+            let _ = socket.set_nonblocking(false);
+        }
+        heard
+    }
+
+    /// Time to wait before sending a datagram of `bytes`, if the target has a
+    /// rate limit delaying datagrams over it, see [`Limiter::delay`]
+    pub fn delay(&mut self, bytes: usize) -> Duration {
+        if self.is_disabled() {
+            return Duration::ZERO;
+        }
+        self.limiter
+            .as_mut()
+            .map_or(Duration::ZERO, |limiter| limiter.delay(bytes))
+    }
+
+    /// Send a datagram downstream. Returns true if the datagram was sent,
+    /// or false if the send failed, the target is disabled, or the datagram
+    /// was dropped over the rate limit of the target. Sleeps if a rate limit
+    /// delays the datagram, unless the caller waited for [`Downstream::delay`]
+    pub fn send(&mut self, buf: &[u8]) -> bool {
+        self.send_limited(buf, true)
+    }
+
+    /// As [`Downstream::send`], dropping the datagram if over the rate limit
+    /// of the target, instead of sleeping
+    pub fn send_now(&mut self, buf: &[u8]) -> bool {
+        self.send_limited(buf, false)
+    }
+
+    fn send_limited(&mut self, buf: &[u8], delay: bool) -> bool {
+        if self.is_disabled() {
+            self.metrics.dropped();
+            return false;
+        }
+        if let Some(limiter) = &mut self.limiter {
+            let shaped = match delay {
+                true => limiter.admit(buf.len()),
+                false => limiter.admit_now(buf.len()),
+            };
+            self.metrics.limited(shaped);
+            if shaped == Shaped::Dropped {
+                return false;
+            }
+        }
+        self.update_sockets();
+        let sent = match resolve_mode() {
+            ResolveMode::Failover => {
+                let (addr, socket) = &self.sockets[self.active];
+                let sent = send_to(socket, *addr, buf);
+                match &sent {
+                    Ok(c) => self.metrics.sent(*c),
+                    Err(e) if self.sockets.len() > 1 => {
+                        self.active = (self.active + 1) % self.sockets.len();
+                        info!(
+                            "downstream {}: sending to {} failed: {}, failing over to {}",
+                            self.name,
+                            addr,
+                            e,
+                            self.addr()
+                        );
+                    }
+                    Err(_) => (),
+                }
+                sent.map(|_| ())
+            }
+            ResolveMode::All => {
+                // succeeds if any address was sent to
+                let mut sent = Err(Error::new(ErrorKind::NotFound, "no addresses"));
+                for (addr, socket) in &self.sockets {
+                    match send_to(socket, *addr, buf) {
+                        Ok(c) => {
+                            self.metrics.sent(c);
+                            sent = Ok(());
+                        }
+                        Err(e) => {
+                            if sent.is_err() {
+                                sent = Err(e);
+                            }
+                        }
+                    }
+                }
+                sent
+            }
+        };
+        match sent {
+            Ok(()) => {
+                if self.disabled_until.take().is_some() {
+                    self.metrics.set_disabled(false);
+                    info!("downstream {}: recovered", self.name);
+                }
+                self.failures = 0;
+                self.retry_interval = RETRY_MIN;
+                true
+            }
+            Err(e) => {
+                self.metrics.send_error();
+                self.failures += 1;
+                if self.failures >= FAILURE_THRESHOLD {
+                    warn!(
+                        "downstream {}: disabled after {} failures, retrying in {}s: {}",
+                        self.name,
+                        self.failures,
+                        self.retry_interval.as_secs(),
+                        e
+                    );
+                    self.disabled_until = Some(Instant::now() + self.retry_interval);
+                    self.metrics.set_disabled(true);
+                    self.retry_interval = (self.retry_interval * 2).min(RETRY_MAX);
+                }
+                false
+            }
+        }
+    }
+
+    /// Rebind sockets if the resolved addresses have changed, keeping the
+    /// sockets of unchanged addresses, and the active address if still resolved
+    fn update_sockets(&mut self) {
+        let version = self.resolved.version();
+        if version == self.version {
+            return;
+        }
+        self.version = version;
+        let active = self.addr();
+        let previous = std::mem::take(&mut self.sockets);
+        match bind_sockets(self.resolved.addrs(), previous) {
+            Ok(sockets) => self.sockets = sockets,
+            Err((e, previous)) => {
+                warn!(
+                    "downstream {}: binding sockets for new addresses: {}, keeping previous addresses",
+                    self.name, e
+                );
+                self.sockets = previous;
+            }
+        }
+        self.active = self
+            .sockets
+            .iter()
+            .position(|(addr, _)| *addr == active)
+            .unwrap_or(0);
+    }
+}
+
+/// Send `buf` to `addr`. Sockets for IPv6 multicast groups are connected
+fn send_to(socket: &UdpSocket, addr: SocketAddr, buf: &[u8]) -> ioResult<usize> {
+    if !(addr.is_ipv6() && addr.ip().is_multicast()) {
+        socket.send_to(buf, addr)
+    } else {
+        socket.send(buf)
+    }
+}
+
+/// Bind a socket for each of `addrs`, reusing sockets in `previous`. Addresses
+/// for which no socket can be bound are skipped. If no socket can be bound,
+/// returns the last error along with `previous`
+fn bind_sockets(
+    addrs: Vec<SocketAddr>,
+    mut previous: Sockets,
+) -> Result<Sockets, (Error, Sockets)> {
+    let mut sockets = vec![];
+    let mut last_error = None;
+    for addr in addrs {
+        if let Some(i) = previous.iter().position(|(a, _)| *a == addr) {
+            sockets.push(previous.remove(i));
+            continue;
+        }
+        match target_socket(addr) {
+            Ok(socket) => sockets.push((addr, socket)),
+            Err(e) => {
+                debug!("skipping {}: {}", addr, e);
+                last_error = Some(e);
+            }
+        }
+    }
+    if sockets.is_empty() {
+        let e = last_error.unwrap_or_else(|| Error::new(ErrorKind::NotFound, "no addresses"));
+        return Err((e, previous));
+    }
+    Ok(sockets)
+}
+
+/// Downstream targets of a route, which may be changed while the route is
+/// running. Registered as the [`RouteControl`] for the route, so that targets
+/// can be added and removed with the admin API
+#[derive(Debug)]
+pub struct Downstreams {
+    metrics: Arc<RouteMetrics>,
+    targets: Mutex<Vec<Downstream>>,
+    dispatch: Dispatch,
+    next: AtomicUsize,
+    active: Mutex<Option<String>>,
+    stopped: AtomicBool,
+    exited: AtomicBool,
+}
+
+impl Downstreams {
+    /// Bind a socket for sending to each of `addrs`, counting sends in the
+    /// outputs of route `metrics`, and register for control under the route name
+    pub fn register(metrics: Arc<RouteMetrics>, addrs: &[String]) -> ioResult<Arc<Self>> {
+        Self::register_dispatch(metrics, addrs, Dispatch::default())
+    }
+
+    /// As [`Downstreams::register`], sending datagrams according to `dispatch`
+    pub fn register_dispatch(
+        metrics: Arc<RouteMetrics>,
+        addrs: &[String],
+        dispatch: Dispatch,
+    ) -> ioResult<Arc<Self>> {
+        let targets = addrs
+            .iter()
+            .map(|addr| {
+                Ok(Downstream::new(addr)?
+                    .with_metrics(metrics.downstream(addr))
+                    .with_heartbeat(dispatch.heartbeat))
+            })
+            .collect::<ioResult<Vec<Downstream>>>()?;
+        let downstreams = Arc::new(Downstreams {
+            metrics,
+            targets: Mutex::new(targets),
+            dispatch,
+            next: AtomicUsize::new(0),
+            active: Mutex::new(None),
+            stopped: AtomicBool::new(false),
+            exited: AtomicBool::new(false),
+        });
+        register_control(downstreams.metrics.name(), downstreams.clone());
+        Ok(downstreams)
+    }
+
+    /// Probe targets and check for heartbeats, if the dispatch expects them.
+    /// Called periodically by routes, so that targets on standby are checked
+    /// while not sent to, see [`Downstream::is_alive`]
+    pub fn check_heartbeats(&self) {
+        if self.dispatch.heartbeat.is_some() {
+            for target in self.targets.lock().unwrap().iter_mut() {
+                target.is_alive();
+            }
+        }
+    }
+
+    /// Send a datagram to the targets chosen by the dispatch policy, see
+    /// [`Downstream::send`]. With [`Policy::Hash`], datagrams without a
+    /// source address are sent round-robin
+    pub fn send(&self, buf: &[u8]) {
+        self.send_from(buf, None)
+    }
+
+    /// As [`Downstreams::send`], for a datagram received from `source`.
+    /// Waits for the chosen target if its rate limit delays the datagram,
+    /// without holding the lock on the targets. With [`Policy::All`] and
+    /// several targets, waiting for one would slow the others, so datagrams
+    /// over a delaying limit are dropped for that target only
+    pub fn send_from(&self, buf: &[u8], source: Option<IpAddr>) {
+        let turn = match (self.dispatch.policy, source) {
+            (Policy::Hash, None) | (Policy::RoundRobin, _) => {
+                self.next.fetch_add(1, Ordering::Relaxed)
+            }
+            _ => 0,
+        };
+        while let Some(wait) = self.try_send_from(buf, source, turn) {
+            sleep(wait);
+        }
+    }
+
+    /// Send a datagram as [`Downstreams::send_from`], unless a chosen target
+    /// delays it, returning the time to wait before trying again
+    fn try_send_from(&self, buf: &[u8], source: Option<IpAddr>, turn: usize) -> Option<Duration> {
+        let mut targets = self.targets.lock().unwrap();
+        if targets.is_empty() {
+            return None;
+        }
+        let count = targets.len();
+        let first = match (self.dispatch.policy, source) {
+            (Policy::All, _) => {
+                if let [target] = targets.as_mut_slice() {
+                    let wait = target.delay(buf.len());
+                    if !wait.is_zero() {
+                        return Some(wait);
+                    }
+                    target.send(buf);
+                    return None;
+                }
+                for target in targets.iter_mut() {
+                    target.send_now(buf);
+                }
+                return None;
+            }
+            (Policy::Failover, _) => 0,
+            (Policy::Hash, Some(source)) => hash_index(source, count),
+            (Policy::Hash, None) | (Policy::RoundRobin, _) => turn % count,
+        };
+        // the first available target, in list order from `first`
+        let mut skipped_first = false;
+        for i in (0..count).map(|i| (first + i) % count) {
+            let target = &mut targets[i];
+            if target.is_disabled() || !target.is_alive() {
+                skipped_first |= i == first;
+                continue;
+            }
+            let wait = target.delay(buf.len());
+            if !wait.is_zero() {
+                return Some(wait);
+            }
+            if target.send(buf) {
+                if self.dispatch.policy == Policy::Failover {
+                    self.set_active(target.name());
+                }
+                return None;
+            }
+        }
+        if skipped_first {
+            // no target is available, so try the chosen target regardless,
+            // unless it was already tried and failed
+            targets[first].send(buf);
+        }
+        None
+    }
+
+    /// Log changes of the target sent to with [`Policy::Failover`]
+    fn set_active(&self, name: &str) {
+        let mut active = self.active.lock().unwrap();
+        if active.as_deref() != Some(name) {
+            if active.is_some() {
+                warn!("{}: now sending to {}", self.metrics.name(), name);
+            }
+            *active = Some(name.to_string());
+        }
+    }
+
+    /// Returns true once the route has been asked to stop
+    pub fn is_stopped(&self) -> bool {
+        self.stopped.load(Ordering::Relaxed)
+    }
+
+    /// Remove the route from the admin API and metrics, once its thread has stopped
+    pub fn unregister(&self) {
+        unregister_control(self.metrics.name());
+        self.metrics.unregister();
+        self.exited.store(true, Ordering::Relaxed);
+    }
+
+    /// Wait up to `timeout` for a stopped route to exit, releasing its
+    /// listening socket so that the address can be bound again.
+    /// Returns false if the route is still running
+    pub fn wait_stopped(&self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        while !self.exited.load(Ordering::Relaxed) {
+            if Instant::now() >= deadline {
+                return false;
+            }
+            sleep(Duration::from_millis(10));
+        }
+        true
+    }
+}
+
+impl RouteControl for Downstreams {
+    fn downstreams(&self) -> Vec<String> {
+        let targets = self.targets.lock().unwrap();
+        targets.iter().map(|t| t.name().to_string()).collect()
+    }
+
+    fn add_downstream(&self, addr: &str) -> Result<(), String> {
+        let mut targets = self.targets.lock().unwrap();
+        if targets.iter().any(|t| t.name() == addr) {
+            return Err(format!("downstream {} already exists", addr));
+        }
+        let target = Downstream::new(&addr.to_string())
+            .map_err(|e| format!("downstream {}: {}", addr, e))?;
+        targets.push(
+            target
+                .with_metrics(self.metrics.downstream(addr))
+                .with_heartbeat(self.dispatch.heartbeat),
+        );
+        info!("{}: added downstream {}", self.metrics.name(), addr);
+        Ok(())
+    }
+
+    fn remove_downstream(&self, addr: &str) -> Result<(), String> {
+        let mut targets = self.targets.lock().unwrap();
+        let count = targets.len();
+        targets.retain(|t| t.name() != addr);
+        if targets.len() == count {
+            return Err(format!("no downstream {}", addr));
+        }
+        self.metrics.remove_downstream(addr);
+        info!("{}: removed downstream {}", self.metrics.name(), addr);
+        Ok(())
+    }
+
+    fn stop(&self) {
+        self.stopped.store(true, Ordering::Relaxed);
+    }
+}
@@ -0,0 +1,216 @@
+//! Following growing files, as with `tail -F`.
+//!
+//! Without following, the client stops at the end of its input. A
+//! [`Follower`] instead waits for the file to grow, and detects truncation,
+//! when it reads again from the start, and rotation, when the path is
+//! replaced by a new file. The rest of a rotated file is sent before the new
+//! file is opened from its start.
+//!
+//! The read offset can be kept in a state file, written after each datagram
+//! is sent, so that a restarted client resumes where it stopped. The offset
+//! is only used if the file at the path is the same as when it was written.
+
+use std::fs::{metadata, File, Metadata, OpenOptions};
+use std::io::{stdout, BufWriter, Read, Result as ioResult, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::thread::sleep;
+use std::time::Duration;
+
+use log::{info, warn};
+
+use crate::{bind_targets, read_size, with_tag, Pacer, Pacing};
+
+/// Follow settings
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Follow {
+    /// File keeping the read offset across restarts
+    pub state: Option<PathBuf>,
+    /// Time between checks for growth, truncation, and rotation at the end
+    /// of the file
+    pub poll: Duration,
+}
+
+impl Default for Follow {
+    fn default() -> Self {
+        Follow {
+            state: None,
+            poll: Duration::from_millis(250),
+        }
+    }
+}
+
+/// Identity of a file, to detect rotation
+#[cfg(unix)]
+fn file_id(meta: &Metadata) -> u64 {
+    use std::os::unix::fs::MetadataExt;
+    meta.ino()
+}
+
+#[cfg(not(unix))]
+fn file_id(_meta: &Metadata) -> u64 {
+    0
+}
+
+/// A file followed as it grows, truncated, or rotated
+#[derive(Debug)]
+pub struct Follower {
+    path: PathBuf,
+    file: File,
+    id: u64,
+    offset: u64,
+    state: Option<File>,
+    poll: Duration,
+}
+
+impl Follower {
+    /// Open `path` for following, resuming from the offset in the state file
+    /// of `follow` if it has one for the same file
+    pub fn open(path: &Path, follow: &Follow) -> ioResult<Self> {
+        let mut file = File::open(path)?;
+        let meta = file.metadata()?;
+        let id = file_id(&meta);
+        let mut offset = 0;
+        let state = match &follow.state {
+            Some(state_path) => {
+                let mut state = OpenOptions::new()
+                    .read(true)
+                    .write(true)
+                    .create(true)
+                    .truncate(false)
+                    .open(state_path)?;
+                let mut saved = String::new();
+                state.read_to_string(&mut saved)?;
+                let mut fields = saved.split_whitespace().map(str::parse::<u64>);
+                match (fields.next(), fields.next()) {
+                    (Some(Ok(saved_id)), Some(Ok(saved_offset)))
+                        if saved_id == id && saved_offset <= meta.len() =>
+                    {
+                        info!("resuming {} at offset {}", path.display(), saved_offset);
+                        offset = file.seek(SeekFrom::Start(saved_offset))?;
+                    }
+                    (None, _) => (),
+                    _ => warn!(
+                        "{} is not the file in {}, reading from the start",
+                        path.display(),
+                        state_path.display()
+                    ),
+                }
+                Some(state)
+            }
+            None => None,
+        };
+        Ok(Follower {
+            path: path.to_path_buf(),
+            file,
+            id,
+            offset,
+            state,
+            poll: follow.poll,
+        })
+    }
+
+    /// Read from the current file, returning 0 at its end
+    fn read(&mut self, buf: &mut [u8]) -> ioResult<usize> {
+        let c = self.file.read(buf)?;
+        self.offset += c as u64;
+        Ok(c)
+    }
+
+    /// Save the offset of the data read so far to the state file
+    fn save(&mut self) -> ioResult<()> {
+        if let Some(state) = &mut self.state {
+            // fixed width, so that a shorter offset does not leave stale digits
+            let line = format!("{:20} {:20}\n", self.id, self.offset);
+            state.seek(SeekFrom::Start(0))?;
+            state.write_all(line.as_bytes())?;
+        }
+        Ok(())
+    }
+
+    /// At the end of the file, check for truncation and rotation. Returns
+    /// true if there may be more to read
+    fn check(&mut self) -> ioResult<bool> {
+        let len = self.file.metadata()?.len();
+        if len < self.offset {
+            info!(
+                "{} was truncated, reading from the start",
+                self.path.display()
+            );
+            self.offset = self.file.seek(SeekFrom::Start(0))?;
+            self.save()?;
+            return Ok(true);
+        } else if len > self.offset {
+            return Ok(true);
+        }
+        match metadata(&self.path) {
+            Ok(meta) if file_id(&meta) != self.id => match File::open(&self.path) {
+                Ok(file) => {
+                    info!("{} was rotated, reopening", self.path.display());
+                    self.id = file_id(&file.metadata()?);
+                    self.file = file;
+                    self.offset = 0;
+                    self.save()?;
+                    Ok(true)
+                }
+                Err(e) => {
+                    warn!("reopening {}: {}", self.path.display(), e);
+                    Ok(false)
+                }
+            },
+            _ => Ok(false),
+        }
+    }
+}
+
+/// Send data appended to the file of `follower` to each of `server_addrs`,
+/// as with [`crate::client_reader_stream_paced`], without stopping at the
+/// end of the file. Optionally copy output to stdout
+pub fn client_follow_stream(
+    follower: Follower,
+    server_addrs: Vec<String>,
+    tee: bool,
+    pacing: Pacing,
+) -> ioResult<()> {
+    follow_stream(follower, server_addrs, tee, pacing, None)
+}
+
+/// As [`client_follow_stream`], prefixing each datagram with `tag`
+pub(crate) fn follow_stream(
+    mut follower: Follower,
+    server_addrs: Vec<String>,
+    tee: bool,
+    pacing: Pacing,
+    tag: Option<&[u8]>,
+) -> ioResult<()> {
+    let mut targets = bind_targets(&follower.path, server_addrs)?;
+    let mut pacer = Pacer::new(pacing);
+
+    let mut buf = vec![0u8; read_size(tag)?];
+    let mut tagged = vec![];
+    let mut output_buffer = BufWriter::new(stdout());
+
+    loop {
+        let c = follower.read(&mut buf)?;
+        if c == 0 {
+            if !follower.check()? {
+                sleep(follower.poll);
+            }
+            continue;
+        } else if c == 1 && buf[0] == b'\n' {
+            // skip empty lines
+            follower.save()?;
+            continue;
+        }
+
+        let data = with_tag(tag, &buf[0..c], &mut tagged);
+        pacer.wait(data.len());
+        for target in &mut targets {
+            target.send(data);
+        }
+        follower.save()?;
+        if tee {
+            output_buffer.write_all(data)?;
+            output_buffer.flush()?;
+        }
+    }
+}
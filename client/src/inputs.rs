@@ -0,0 +1,379 @@
+//! Several inputs streamed concurrently, from paths, glob patterns, and
+//! watched directories.
+//!
+//! Each input file is sent from its own thread. Glob patterns may use `*`,
+//! `?`, and `[...]` in any component, and are expanded to the files they
+//! match when the client starts. A directory is read as all of its files.
+//!
+//! When watching, the directory of each pattern is also watched for new files
+//! matching it, see [`DirWatcher`]. New files are sent once they are
+//! complete, or when following, as soon as they appear. Each file is only
+//! sent once. Wildcards in watched patterns are only allowed in the file name.
+//!
+//! With tagging, each datagram starts with the file name of its input and
+//! `": "`, so that receivers can tell inputs apart.
+
+use std::collections::HashSet;
+use std::fs::{read_dir, File};
+use std::io::{stdin, BufRead, BufReader, Error, ErrorKind, Result as ioResult};
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread::{spawn, JoinHandle};
+use std::time::Duration;
+
+use log::{error, info};
+use mproxy_common::watch::{Change, DirWatcher};
+
+use crate::follow::follow_stream;
+use crate::{reader_stream, Follow, Follower, Pacing};
+
+/// Options applying to every input
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct InputOptions {
+    /// Watch the directories of inputs for new files
+    pub watch: bool,
+    /// Prefix datagrams with the file name of their input
+    pub tag: bool,
+    /// Follow inputs as they grow. The state file, if any, is suffixed with
+    /// the absolute path of each input, escaped
+    pub follow: Option<Follow>,
+}
+
+/// An input opened before streaming, e.g. so that privileges may be dropped
+enum Source {
+    Stream(PathBuf, Box<dyn BufRead + Send>),
+    Follow(PathBuf, Follower),
+}
+
+/// A watched directory, and the pattern of file names to send from it
+struct Watch {
+    watcher: DirWatcher,
+    pattern: String,
+}
+
+/// Inputs opened from paths and glob patterns, see [`Inputs::open`]
+pub struct Inputs {
+    sources: Vec<Source>,
+    watches: Vec<Watch>,
+    options: InputOptions,
+    seen: Arc<Mutex<HashSet<PathBuf>>>,
+}
+
+/// Returns true if `s` contains glob wildcards
+pub fn is_glob(s: &str) -> bool {
+    s.contains(['*', '?', '['])
+}
+
+/// Match a file name against a glob pattern of `*`, `?`, and `[...]`
+/// character classes, which may be negated with `!` and contain ranges
+pub fn glob_match(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+    // hidden files are only matched by patterns starting with a dot
+    if name.first() == Some(&'.') && pattern.first() != Some(&'.') {
+        return false;
+    }
+    let (mut p, mut n) = (0, 0);
+    let mut backtrack: Option<(usize, usize)> = None;
+    while n < name.len() {
+        let step = match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, n));
+                p += 1;
+                continue;
+            }
+            Some('?') => Some(p + 1),
+            Some('[') => match_class(&pattern, p, name[n]),
+            Some(c) if *c == name[n] => Some(p + 1),
+            _ => None,
+        };
+        match (step, backtrack) {
+            (Some(next), _) => {
+                p = next;
+                n += 1;
+            }
+            // let the last star match one more character
+            (None, Some((star, matched))) => {
+                p = star + 1;
+                n = matched + 1;
+                backtrack = Some((star, matched + 1));
+            }
+            (None, None) => return false,
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+/// Match `c` against the character class starting at `pattern[start]`,
+/// returning the index after the class if it matches
+fn match_class(pattern: &[char], start: usize, c: char) -> Option<usize> {
+    let mut i = start + 1;
+    let negated = matches!(pattern.get(i), Some('!') | Some('^'));
+    if negated {
+        i += 1;
+    }
+    let mut matched = false;
+    let mut first = true;
+    while let Some(&class) = pattern.get(i) {
+        if class == ']' && !first {
+            return (matched != negated).then_some(i + 1);
+        }
+        if pattern.get(i + 1) == Some(&'-') && pattern.get(i + 2).is_some_and(|end| *end != ']') {
+            matched |= (class..=pattern[i + 2]).contains(&c);
+            i += 3;
+        } else {
+            matched |= class == c;
+            i += 1;
+        }
+        first = false;
+    }
+    // an unclosed bracket matches itself
+    (c == '[').then_some(start + 1)
+}
+
+/// Expand `pattern` to the files it matches, in order. Paths without
+/// wildcards are returned as they are, and directories as their files
+pub fn expand_pattern(pattern: &Path) -> ioResult<Vec<PathBuf>> {
+    if pattern.is_dir() {
+        return expand_pattern(&pattern.join("*"));
+    } else if !pattern.to_str().is_some_and(is_glob) {
+        return Ok(vec![pattern.to_path_buf()]);
+    }
+    let mut paths = vec![PathBuf::new()];
+    for component in pattern.components() {
+        match component {
+            Component::Normal(part) if part.to_str().is_some_and(is_glob) => {
+                let part = part.to_str().unwrap();
+                let mut matches = vec![];
+                for dir in &paths {
+                    let entries = match dir.as_os_str().is_empty() {
+                        true => read_dir("."),
+                        false => read_dir(dir),
+                    };
+                    for entry in entries.into_iter().flatten().flatten() {
+                        let name = entry.file_name();
+                        if name.to_str().is_some_and(|name| glob_match(part, name)) {
+                            matches.push(dir.join(name));
+                        }
+                    }
+                }
+                matches.sort();
+                paths = matches;
+            }
+            component => paths.iter_mut().for_each(|path| path.push(component)),
+        }
+    }
+    paths.retain(|path| path.is_file());
+    Ok(paths)
+}
+
+impl Inputs {
+    /// Open the files of `patterns`, and start watching their directories if
+    /// required by `options`
+    pub fn open(patterns: &[PathBuf], options: InputOptions) -> ioResult<Self> {
+        let mut inputs = Inputs {
+            sources: vec![],
+            watches: vec![],
+            options,
+            seen: Arc::new(Mutex::new(HashSet::new())),
+        };
+        for pattern in patterns {
+            if inputs.options.watch && pattern.as_os_str() != "-" {
+                inputs.watch(pattern)?;
+            }
+            let paths = expand_pattern(pattern)?;
+            if paths.is_empty() && !inputs.options.watch {
+                return Err(Error::new(
+                    ErrorKind::NotFound,
+                    format!("no files match {}", pattern.display()),
+                ));
+            }
+            for path in paths {
+                if inputs.seen.lock().unwrap().insert(seen_key(&path)) {
+                    let source = open_source(&path, &inputs.options)?;
+                    inputs.sources.push(source);
+                }
+            }
+        }
+        Ok(inputs)
+    }
+
+    /// Watch the directory of `pattern` for new files matching it
+    fn watch(&mut self, pattern: &Path) -> ioResult<()> {
+        let (dir, name) = match pattern.is_dir() {
+            true => (pattern, "*"),
+            false => (
+                pattern.parent().unwrap_or(Path::new("")),
+                pattern.file_name().and_then(|n| n.to_str()).unwrap_or(""),
+            ),
+        };
+        if dir.to_str().is_none_or(is_glob) {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "only the file name of watched pattern {} may contain wildcards",
+                    pattern.display()
+                ),
+            ));
+        }
+        let dir = match dir.as_os_str().is_empty() {
+            true => Path::new("."),
+            false => dir,
+        };
+        self.watches.push(Watch {
+            watcher: DirWatcher::new(dir)?,
+            pattern: name.to_string(),
+        });
+        Ok(())
+    }
+}
+
+/// Open `path` for sending, following it if required by `options`
+fn open_source(path: &Path, options: &InputOptions) -> ioResult<Source> {
+    if path.as_os_str() == "-" {
+        return Ok(Source::Stream(
+            path.into(),
+            Box::new(BufReader::new(stdin())),
+        ));
+    }
+    let source = match &options.follow {
+        Some(follow) => {
+            let follow = Follow {
+                state: follow.state.as_ref().map(|state| state_path(state, path)),
+                ..follow.clone()
+            };
+            Source::Follow(path.into(), Follower::open(path, &follow)?)
+        }
+        None => Source::Stream(path.into(), Box::new(BufReader::new(File::open(path)?))),
+    };
+    Ok(source)
+}
+
+/// The key of `path` among the inputs already seen, so that e.g. `a.log`
+/// from a pattern and `./a.log` from a watched directory are the same input
+fn seen_key(path: &Path) -> PathBuf {
+    std::path::absolute(path).unwrap_or_else(|_| path.to_path_buf())
+}
+
+/// The state file of the input `path`: `state`, suffixed with a dot and the
+/// absolute path of the input, with path separators and `%` escaped as in
+/// URLs, so that inputs of the same name in different directories are kept
+/// apart
+fn state_path(state: &Path, path: &Path) -> PathBuf {
+    let path = std::path::absolute(path).unwrap_or_else(|_| path.to_path_buf());
+    let mut suffix = String::new();
+    for c in path.to_string_lossy().trim_start_matches('/').chars() {
+        match c {
+            '%' | '/' | '\\' | ':' => suffix.push_str(&format!("%{:02X}", c as u32)),
+            c => suffix.push(c),
+        }
+    }
+    let mut state = state.as_os_str().to_owned();
+    state.push(".");
+    state.push(suffix);
+    PathBuf::from(state)
+}
+
+/// Send `source` from a new thread
+fn spawn_source(
+    source: Source,
+    tag: bool,
+    server_addrs: Vec<String>,
+    tee: bool,
+    pacing: Pacing,
+) -> JoinHandle<()> {
+    spawn(move || {
+        let (path, result) = match source {
+            Source::Stream(path, reader) => {
+                let tag = tag_of(&path, tag);
+                let result =
+                    reader_stream(reader, &path, server_addrs, tee, pacing, tag.as_deref());
+                (path, result)
+            }
+            Source::Follow(path, follower) => {
+                let tag = tag_of(&path, tag);
+                let result = follow_stream(follower, server_addrs, tee, pacing, tag.as_deref());
+                (path, result)
+            }
+        };
+        if let Err(e) = result {
+            error!("sending {}: {}", path.display(), e);
+        }
+    })
+}
+
+/// The tag of datagrams read from `path`, if tagged
+fn tag_of(path: &Path, tag: bool) -> Option<Vec<u8>> {
+    tag.then(|| {
+        let mut tag = path
+            .file_name()
+            .unwrap_or(path.as_os_str())
+            .as_encoded_bytes()
+            .to_vec();
+        tag.extend_from_slice(b": ");
+        tag
+    })
+}
+
+/// Send each of `inputs` to `server_addrs` concurrently, returning once all
+/// inputs have ended. When watching, new files are sent as they appear, and
+/// this does not return. Optionally copy output to stdout
+pub fn client_inputs_stream(
+    inputs: Inputs,
+    server_addrs: Vec<String>,
+    tee: bool,
+    pacing: Pacing,
+) -> ioResult<()> {
+    let Inputs {
+        sources,
+        watches,
+        options,
+        seen,
+    } = inputs;
+    let mut threads = vec![];
+    for source in sources {
+        threads.push(spawn_source(
+            source,
+            options.tag,
+            server_addrs.clone(),
+            tee,
+            pacing,
+        ));
+    }
+    for mut watch in watches {
+        let (options, seen, server_addrs) = (options.clone(), seen.clone(), server_addrs.clone());
+        threads.push(spawn(move || loop {
+            let changes = match watch.watcher.wait(Duration::from_secs(1)) {
+                Ok(changes) => changes,
+                Err(e) => {
+                    error!("watching for {}: {}", watch.pattern, e);
+                    return;
+                }
+            };
+            for change in changes {
+                let path = match (change, options.follow.is_some()) {
+                    (Change::Created(path), true) | (Change::Written(path), _) => path,
+                    (Change::Created(_), false) => continue,
+                };
+                let name = path.file_name().and_then(|n| n.to_str()).unwrap_or("");
+                if !glob_match(&watch.pattern, name)
+                    || !seen.lock().unwrap().insert(seen_key(&path))
+                {
+                    continue;
+                }
+                info!("new input {}", path.display());
+                match open_source(&path, &options) {
+                    Ok(source) => {
+                        // detached: watching threads run until the process exits
+                        spawn_source(source, options.tag, server_addrs.clone(), tee, pacing);
+                    }
+                    Err(e) => error!("opening {}: {}", path.display(), e),
+                }
+            }
+        }));
+    }
+    for thread in threads {
+        let _ = thread.join();
+    }
+    Ok(())
+}
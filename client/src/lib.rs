@@ -50,16 +50,74 @@
 //!   mproxy-client [FLAGS] [OPTIONS] ...
 //!
 //! OPTIONS:
-//!   --path        [FILE_DESCRIPTOR]   Filepath, descriptor, or handle. Use "-" for stdin
-//!   --server-addr [HOSTNAME:PORT]     Downstream UDP server address. May be repeated
+//!   --path         [FILE_DESCRIPTOR]   Filepath, descriptor, handle, or glob pattern. Use "-" for stdin. May be repeated
+//!   --server-addr  [HOSTNAME:PORT]     Downstream UDP server address. May be repeated
+//!   --rate         [RATE]              Send at most RATE bytes per second, or datagrams with packets=RATE
+//!   --interval     [MILLISECONDS]      Send at most one datagram per interval, e.g. 0.5
+//!   --speed        [FACTOR]            With --replay, replay FACTOR times faster. Defaults to 1
+//!   --state-file   [FILE]              With --follow, keep the read offset in FILE to resume after restarts
+//!   --pcap-filter  [ADDR:PORT]         With --pcap, send packets to ADDR:PORT, ADDR, or :PORT only
+//!   --resolve-secs [SECONDS]           Interval between lookups of downstream host names. Defaults to 30
+//!   --resolve-mode [MODE]              Send to names with several addresses: failover or all. Defaults to failover
+//!   --log-level    [LEVEL]             Log level: error, warn, info, debug, or trace, or per target, e.g. mproxy_client=debug,warn. Defaults to info
+//!   --log-format   [FORMAT]            Log format: text or json. Defaults to text
+//!   --log-output   [OUTPUT]            Write logs to stderr, syslog, or journald. Defaults to stderr
+//!   --user         [USER]              Switch to USER, a name or ID, once sockets and files are opened
+//!   --group        [GROUP]             Switch to GROUP. Defaults to the primary group of --user
+//!   --pidfile      [FILE]              Write the process ID to FILE
 //!
 //! FLAGS:
 //!   -h, --help    Prints help information
 //!   -t, --tee     Copy input to stdout
+//!   -f, --follow  Keep sending data appended to --path, following truncation and rotation
+//!   -w, --watch   Also send new files appearing in the directories of --path
+//!   --tag         Prefix each datagram with the file name of its input and ": "
+//!   --replay      Replay --path, a capture file written by mproxy-server --format capture
+//!   --loop        With --replay, start again at the end of the capture
+//!   --pcap        Send the UDP payloads of --path, a pcap or pcapng file. With --replay, keep their timing
+//!
+//! PACING:
+//!   By default input is sent as fast as it is read. With --rate or --interval, sends are spread
+//!   evenly over time, so that receivers of large files are not overrun. Input is sent in
+//!   datagrams of up to 8096 bytes. --pcap and --replay input keeps its own timing instead.
+//!
+//! FOLLOW:
+//!   With --follow, the client waits for --path to grow instead of exiting at its end, as with
+//!   tail -F. A truncated file is read again from the start. When the path is replaced, e.g.
+//!   by log rotation, the rest of the old file is sent before the new file is opened. With
+//!   --state-file, a restarted client resumes at the saved offset if the file is unchanged.
+//!
+//! INPUTS:
+//!   Several inputs, from repeated --path values, glob patterns such as '/var/log/app/*.log', or
+//!   directories, are sent concurrently. With --watch, new files matching a pattern are sent
+//!   once they are complete, or with --follow, as soon as they appear. In these modes, the
+//!   follow state of each input is kept in --state-file suffixed with a dot and its absolute path,
+//!   with '/' and '%' escaped as %2F and %25.
+//!
+//! REPLAY:
+//!   With --replay, each datagram of the capture is sent with its original size, after the
+//!   same delay since the first datagram as when it was received, divided by --speed.
+//!
+//! PCAP:
+//!   With --pcap, the payloads of UDP packets in a pcap or pcapng capture are sent as fast as
+//!   they are read, or with --replay, with their original timing. Ethernet, Linux cooked,
+//!   loopback, and raw IP captures are supported. Fragmented packets are skipped.
+//!
+//! RESOLUTION:
+//!   Downstream host names are looked up again every --resolve-secs, keeping the previous
+//!   addresses if a lookup fails. Names with several addresses are sent to one at a time,
+//!   failing over to the next address on errors, or with --resolve-mode all, to every address.
+//!
+//! PRIVILEGES:
+//!   When started as root, e.g. to read log files only readable by root, use --user and --group
+//!   to switch to an unprivileged user once the input files are opened. Sockets to servers are
+//!   bound to ephemeral ports, so root is not needed to send. Files matched later with --watch or
+//!   reopened with --follow are opened as that user. The pid file is written before.
 //!
 //! EXAMPLE:
 //!   mproxy-client --path /dev/random --server-addr '127.0.0.1:9920' --server-addr '[::1]:9921'
 //!   mproxy-client --path - --server-addr '224.0.0.1:9922' --server-addr '[ff02::1]:9923' --tee >> logfile.log
+//!   mproxy-client --path '/var/log/app/*.log' --watch --follow --tag --server-addr '127.0.0.1:9920'
 //! ```
 //!
 //! ### See Also
@@ -70,20 +128,66 @@
 //!
 
 use std::fs::OpenOptions;
-use std::io::{stdin, stdout, BufRead, BufReader, BufWriter, Read, Result as ioResult, Write};
+use std::io::{
+    stdin, stdout, BufRead, BufReader, BufWriter, Error, ErrorKind, Read, Result as ioResult, Write,
+};
 use std::net::{IpAddr, Ipv6Addr, SocketAddr, ToSocketAddrs, UdpSocket};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
 
+use log::{debug, info};
+
+mod dispatch;
+pub use dispatch::{Dispatch, Policy};
+
+mod downstream;
+pub use downstream::{Downstream, Downstreams};
+
+mod follow;
+pub use follow::{client_follow_stream, Follow, Follower};
+
+mod inputs;
+pub use inputs::{client_inputs_stream, expand_pattern, glob_match, is_glob, InputOptions, Inputs};
+
+mod pace;
+use pace::Pacer;
+pub use pace::{Pacing, Rate};
+
+mod pcap;
+pub use pcap::{client_pcap_stream, PcapFilter};
+
+mod replay;
+pub use replay::{client_replay_stream, Replay};
+
+mod resolve;
+pub use resolve::{set_refresh_interval, set_resolve_mode, ResolveMode};
+
 const BUFSIZE: usize = 8096;
 
+/// Resolve `server_addr`, and bind a socket for sending to it. If the name
+/// resolves to several addresses, the first address for which a socket can
+/// be bound is used, e.g. skipping IPv6 addresses on hosts without IPv6.
+/// The name is resolved once; routes send through [`Downstream`] instead,
+/// which follows changes to its addresses
 pub fn target_socket_interface(server_addr: &String) -> ioResult<(SocketAddr, UdpSocket)> {
-    let target_addr = server_addr
-        .to_socket_addrs()
-        .unwrap()
-        .next()
-        .expect("parsing socket address");
+    let mut last_error = None;
+    for target_addr in server_addr.to_socket_addrs()? {
+        match target_socket(target_addr) {
+            Ok(target_socket) => return Ok((target_addr, target_socket)),
+            Err(e) => last_error = Some(e),
+        }
+    }
+    Err(last_error.unwrap_or_else(|| {
+        Error::new(
+            ErrorKind::NotFound,
+            format!("no addresses found for {}", server_addr),
+        )
+    }))
+}
 
+/// Bind a socket for sending to `target_addr`, joining its group if it is a
+/// multicast address
+pub fn target_socket(target_addr: SocketAddr) -> ioResult<UdpSocket> {
     // Binds to a random UDP port for sending to downstream.
     let unspec: SocketAddr = if target_addr.is_ipv4() {
         SocketAddr::new(std::net::Ipv4Addr::UNSPECIFIED.into(), 0)
@@ -91,7 +195,7 @@ pub fn target_socket_interface(server_addr: &String) -> ioResult<(SocketAddr, Ud
         SocketAddr::new(std::net::Ipv6Addr::UNSPECIFIED.into(), 0)
     };
 
-    let target_socket = UdpSocket::bind(unspec).expect("binding client socket");
+    let target_socket = UdpSocket::bind(unspec)?;
     //target_socket.connect(target_addr).unwrap_or_else(|e| panic!("{}", e));
 
     if target_addr.ip().is_multicast() {
@@ -138,76 +242,135 @@ pub fn target_socket_interface(server_addr: &String) -> ioResult<(SocketAddr, Ud
         };
     }
 
-    Ok((target_addr, target_socket))
+    Ok(target_socket)
 }
 
 /// Read bytes from `path` info a buffer, and forward to downstream UDP server addresses.
-/// Optionally copy output to stdout
+/// Optionally copy output to stdout.
+/// Send failures are isolated per downstream, see [`Downstream`]
 pub fn client_socket_stream(path: &PathBuf, server_addrs: Vec<String>, tee: bool) -> ioResult<()> {
-    let mut targets = vec![];
+    let reader = open_input(path)
+        .unwrap_or_else(|e| panic!("opening {}, {}", path.as_os_str().to_str().unwrap(), e));
+    client_reader_stream(reader, path, server_addrs, tee)
+}
 
+/// Bind a socket for sending input from `path` to each of `server_addrs`
+fn bind_targets(path: &Path, server_addrs: Vec<String>) -> ioResult<Vec<Downstream>> {
+    let mut targets = vec![];
     for server_addr in server_addrs {
-        let (target_addr, target_socket) = target_socket_interface(&server_addr)?;
-
-        targets.push((target_addr, target_socket));
-        println!(
+        targets.push(Downstream::new(&server_addr)?);
+        info!(
             "logging from {}: sending to {}",
             &path.as_os_str().to_str().unwrap(),
             server_addr,
         );
     }
+    Ok(targets)
+}
 
-    // if path is "-" set read buffer to stdin
-    // otherwise, create buffered reader from given file descriptor
-    let mut reader: Box<dyn BufRead> = if path == &PathBuf::from_str("-").unwrap() {
-        Box::new(BufReader::new(stdin()))
+/// Open `path` for reading. If path is "-", read from stdin
+pub fn open_input(path: &PathBuf) -> ioResult<Box<dyn BufRead>> {
+    if path == &PathBuf::from_str("-").unwrap() {
+        Ok(Box::new(BufReader::new(stdin())))
     } else {
-        Box::new(BufReader::new(
+        Ok(Box::new(BufReader::new(
             OpenOptions::new()
                 .create(false)
                 .write(false)
                 .read(true)
-                .open(path)
-                .unwrap_or_else(|e| {
-                    panic!("opening {}, {}", path.as_os_str().to_str().unwrap(), e)
-                }),
-        ))
-    };
+                .open(path)?,
+        )))
+    }
+}
 
-    let mut buf = vec![0u8; BUFSIZE];
+/// As [`client_socket_stream`], reading from `reader` opened from `path` with
+/// [`open_input`], e.g. so that privileges may be dropped after opening `path`
+pub fn client_reader_stream(
+    reader: Box<dyn BufRead>,
+    path: &Path,
+    server_addrs: Vec<String>,
+    tee: bool,
+) -> ioResult<()> {
+    client_reader_stream_paced(reader, path, server_addrs, tee, Pacing::default())
+}
+
+/// As [`client_reader_stream`], spacing datagrams as required by `pacing`,
+/// e.g. so that receivers of a large file are not overrun
+pub fn client_reader_stream_paced(
+    reader: Box<dyn BufRead>,
+    path: &Path,
+    server_addrs: Vec<String>,
+    tee: bool,
+    pacing: Pacing,
+) -> ioResult<()> {
+    reader_stream(reader, path, server_addrs, tee, pacing, None)
+}
+
+/// Bytes of input to read for each datagram, so that datagrams prefixed
+/// with `tag` are at most [`BUFSIZE`] bytes
+pub(crate) fn read_size(tag: Option<&[u8]>) -> ioResult<usize> {
+    match tag {
+        Some(tag) if tag.len() >= BUFSIZE => Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("tag of {} bytes exceeds the datagram size", tag.len()),
+        )),
+        Some(tag) => Ok(BUFSIZE - tag.len()),
+        None => Ok(BUFSIZE),
+    }
+}
+
+/// Prefix `data` with `tag`, if any, reusing `tagged` for the result
+pub(crate) fn with_tag<'a>(
+    tag: Option<&[u8]>,
+    data: &'a [u8],
+    tagged: &'a mut Vec<u8>,
+) -> &'a [u8] {
+    match tag {
+        Some(tag) => {
+            tagged.clear();
+            tagged.extend_from_slice(tag);
+            tagged.extend_from_slice(data);
+            tagged
+        }
+        None => data,
+    }
+}
+
+/// As [`client_reader_stream_paced`], prefixing each datagram with `tag`
+pub(crate) fn reader_stream(
+    mut reader: Box<dyn BufRead>,
+    path: &Path,
+    server_addrs: Vec<String>,
+    tee: bool,
+    pacing: Pacing,
+    tag: Option<&[u8]>,
+) -> ioResult<()> {
+    let mut targets = bind_targets(path, server_addrs)?;
+    let mut pacer = Pacer::new(pacing);
+
+    let mut buf = vec![0u8; read_size(tag)?];
+    let mut tagged = vec![];
     let mut output_buffer = BufWriter::new(stdout());
 
     while let Ok(c) = reader.read(&mut buf) {
         if c == 0 {
-            #[cfg(debug_assertions)]
-            println!(
-                "\nclient: encountered EOF in {}, exiting...",
-                &path.display(),
-            );
+            debug!("encountered EOF in {}, exiting...", &path.display());
             break;
         } else if c == 1 && String::from_utf8(buf[0..c].to_vec()).unwrap() == *"\n" {
             // skip empty lines
             continue;
         }
 
-        for (target_addr, target_socket) in &targets {
-            if !(target_addr.is_ipv6() && target_addr.ip().is_multicast()) {
-                target_socket
-                    .send_to(&buf[0..c], target_addr)
-                    .unwrap_or_else(|e| panic!("sending to server socket: {}", e));
-            } else {
-                target_socket
-                    .send(&buf[0..c])
-                    .unwrap_or_else(|e| panic!("sending to server socket: {}", e));
-            }
+        let data = with_tag(tag, &buf[0..c], &mut tagged);
+        pacer.wait(data.len());
+        for target in &mut targets {
+            target.send(data);
         }
         if tee {
-            let _o = output_buffer
-                .write(&buf[0..c])
-                .expect("writing to output buffer");
+            let _o = output_buffer.write(data).expect("writing to output buffer");
             output_buffer.flush().unwrap();
             #[cfg(debug_assertions)]
-            assert!(c == _o);
+            assert!(data.len() == _o);
         }
     }
     Ok(())
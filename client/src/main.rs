@@ -1,9 +1,20 @@
 use std::ffi::OsStr;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
 use std::path::PathBuf;
 use std::process::exit;
+use std::time::Duration;
 
-use mproxy_client::client_socket_stream;
+use mproxy_client::{
+    client_follow_stream, client_inputs_stream, client_pcap_stream, client_reader_stream_paced,
+    client_replay_stream, is_glob, open_input, set_refresh_interval, set_resolve_mode, Follow,
+    Follower, InputOptions, Inputs, Pacing, PcapFilter, Replay, ResolveMode,
+};
+use mproxy_common::daemon::{drop_privileges, write_pidfile};
+use mproxy_common::logging::{init_logging, LogFormat, LogLevels, LogOutput};
+use mproxy_common::systemd::{ready, watchdog};
 
+use log::error;
 use pico_args::Arguments;
 
 pub const HELP: &str = r#"
@@ -15,26 +26,107 @@ USAGE:
   mproxy-client [FLAGS] [OPTIONS] ...
 
 OPTIONS:
-  --path        [FILE_DESCRIPTOR]   Filepath, descriptor, or handle. Use "-" for stdin
-  --server-addr [HOSTNAME:PORT]     Downstream UDP server address. May be repeated
+  --path         [FILE_DESCRIPTOR]   Filepath, descriptor, handle, or glob pattern. Use "-" for stdin. May be repeated
+  --server-addr  [HOSTNAME:PORT]     Downstream UDP server address. May be repeated
+  --rate         [RATE]              Send at most RATE bytes per second, or datagrams with packets=RATE
+  --interval     [MILLISECONDS]      Send at most one datagram per interval, e.g. 0.5
+  --speed        [FACTOR]            With --replay, replay FACTOR times faster. Defaults to 1
+  --state-file   [FILE]              With --follow, keep the read offset in FILE to resume after restarts
+  --pcap-filter  [ADDR:PORT]         With --pcap, send packets to ADDR:PORT, ADDR, or :PORT only
+  --resolve-secs [SECONDS]           Interval between lookups of downstream host names. Defaults to 30
+  --resolve-mode [MODE]              Send to names with several addresses: failover or all. Defaults to failover
+  --log-level    [LEVEL]             Log level: error, warn, info, debug, or trace, or per target, e.g. mproxy_client=debug,warn. Defaults to info
+  --log-format   [FORMAT]            Log format: text or json. Defaults to text
+  --log-output   [OUTPUT]            Write logs to stderr, syslog, or journald. Defaults to stderr
+  --user         [USER]              Switch to USER, a name or ID, once sockets and files are opened
+  --group        [GROUP]             Switch to GROUP. Defaults to the primary group of --user
+  --pidfile      [FILE]              Write the process ID to FILE
 
 FLAGS:
   -h, --help    Prints help information
   -t, --tee     Copy input to stdout
+  -f, --follow  Keep sending data appended to --path, following truncation and rotation
+  -w, --watch   Also send new files appearing in the directories of --path
+  --tag         Prefix each datagram with the file name of its input and ": "
+  --replay      Replay --path, a capture file written by mproxy-server --format capture
+  --loop        With --replay, start again at the end of the capture
+  --pcap        Send the UDP payloads of --path, a pcap or pcapng file. With --replay, keep their timing
+
+PACING:
+  By default input is sent as fast as it is read. With --rate or --interval, sends are spread
+  evenly over time, so that receivers of large files are not overrun. Input is sent in
+  datagrams of up to 8096 bytes. --pcap and --replay input keeps its own timing instead.
+
+FOLLOW:
+  With --follow, the client waits for --path to grow instead of exiting at its end, as with
+  tail -F. A truncated file is read again from the start. When the path is replaced, e.g.
+  by log rotation, the rest of the old file is sent before the new file is opened. With
+  --state-file, a restarted client resumes at the saved offset if the file is unchanged.
+
+INPUTS:
+  Several inputs, from repeated --path values, glob patterns such as '/var/log/app/*.log', or
+  directories, are sent concurrently. With --watch, new files matching a pattern are sent
+  once they are complete, or with --follow, as soon as they appear. In these modes, the
+  follow state of each input is kept in --state-file suffixed with a dot and its absolute path,
+  with '/' and '%' escaped as %2F and %25.
+
+REPLAY:
+  With --replay, each datagram of the capture is sent with its original size, after the
+  same delay since the first datagram as when it was received, divided by --speed.
+
+PCAP:
+  With --pcap, the payloads of UDP packets in a pcap or pcapng capture are sent as fast as
+  they are read, or with --replay, with their original timing. Ethernet, Linux cooked,
+  loopback, and raw IP captures are supported. Fragmented packets are skipped.
+
+RESOLUTION:
+  Downstream host names are looked up again every --resolve-secs, keeping the previous
+  addresses if a lookup fails. Names with several addresses are sent to one at a time,
+  failing over to the next address on errors, or with --resolve-mode all, to every address.
+
+PRIVILEGES:
+  When started as root, e.g. to read log files only readable by root, use --user and --group
+  to switch to an unprivileged user once the input files are opened. Sockets to servers are
+  bound to ephemeral ports, so root is not needed to send. Files matched later with --watch or
+  reopened with --follow are opened as that user. The pid file is written before.
 
 EXAMPLE:
   mproxy-client --path /dev/random --server-addr '127.0.0.1:9920' --server-addr '[::1]:9921'
   mproxy-client --path - --server-addr '224.0.0.1:9922' --server-addr '[ff02::1]:9923' --tee >> logfile.log
+  mproxy-client --path '/var/log/app/*.log' --watch --follow --tag --server-addr '127.0.0.1:9920'
 
 "#;
 
 /// command line arguments
 pub struct ClientArgs {
-    path: PathBuf,
+    paths: Vec<PathBuf>,
     server_addrs: Vec<String>,
+    pacing: Pacing,
+    replay: Option<Replay>,
+    pcap: Option<PcapFilter>,
+    follow: Option<Follow>,
+    watch: bool,
+    tag: bool,
+    resolve_secs: u64,
+    resolve_mode: ResolveMode,
+    user: Option<String>,
+    group: Option<String>,
+    pidfile: Option<PathBuf>,
+    log_level: LogLevels,
+    log_format: LogFormat,
+    log_output: LogOutput,
     tee: bool,
 }
 
+/// Input opened before dropping privileges
+enum Input {
+    Stream(Box<dyn BufRead>),
+    Replay(BufReader<File>, Replay),
+    Pcap(BufReader<File>, PcapFilter, Option<Replay>),
+    Follow(Follower),
+    Many(Inputs),
+}
+
 /// retrieve command line arguments as ClientArgs struct
 fn parse_args() -> Result<ClientArgs, pico_args::Error> {
     let mut pargs = Arguments::from_env();
@@ -43,23 +135,101 @@ fn parse_args() -> Result<ClientArgs, pico_args::Error> {
         exit(0);
     }
     let tee = pargs.contains(["-t", "--tee"]);
+    let replay = pargs.contains("--replay");
+    let repeat = pargs.contains("--loop");
+    let pcap = pargs.contains("--pcap");
+    let follow = pargs.contains(["-f", "--follow"]);
+    let watch = pargs.contains(["-w", "--watch"]);
+    let tag = pargs.contains("--tag");
 
     fn parse_path(s: &OsStr) -> Result<PathBuf, &'static str> {
         Ok(s.into())
     }
 
+    /// Parse milliseconds as seconds
+    fn parse_millis(s: &str) -> Result<f64, String> {
+        s.parse::<f64>()
+            .ok()
+            .filter(|ms| ms.is_finite() && *ms >= 0.0)
+            .map(|ms| ms / 1000.0)
+            .ok_or_else(|| format!("invalid interval '{}'", s))
+    }
+
+    fn parse_speed(s: &str) -> Result<f64, String> {
+        s.parse::<f64>()
+            .ok()
+            .filter(|speed| speed.is_finite() && *speed > 0.0)
+            .ok_or_else(|| format!("invalid speed '{}'", s))
+    }
+
     let args = ClientArgs {
-        path: pargs.value_from_os_str("--path", parse_path)?,
+        paths: pargs.values_from_os_str("--path", parse_path)?,
         server_addrs: pargs.values_from_str("--server-addr")?,
+        pacing: Pacing {
+            rate: pargs.opt_value_from_str("--rate")?,
+            interval: pargs
+                .opt_value_from_fn("--interval", parse_millis)?
+                .map(Duration::from_secs_f64),
+        },
+        replay: match pargs.opt_value_from_fn("--speed", parse_speed)? {
+            speed if replay => Some(Replay {
+                speed: speed.unwrap_or(1.0),
+                repeat,
+            }),
+            _ => None,
+        },
+        pcap: match pargs.opt_value_from_str("--pcap-filter")? {
+            filter if pcap => Some(filter.unwrap_or_default()),
+            _ => None,
+        },
+        follow: match pargs.opt_value_from_os_str("--state-file", parse_path)? {
+            state if follow => Some(Follow {
+                state,
+                ..Follow::default()
+            }),
+            _ => None,
+        },
+        watch,
+        tag,
+        resolve_secs: pargs.opt_value_from_str("--resolve-secs")?.unwrap_or(30),
+        resolve_mode: pargs
+            .opt_value_from_str("--resolve-mode")?
+            .unwrap_or_default(),
+        user: pargs.opt_value_from_str("--user")?,
+        group: pargs.opt_value_from_str("--group")?,
+        pidfile: pargs
+            .opt_value_from_str::<_, String>("--pidfile")?
+            .map(PathBuf::from),
+        log_level: pargs.opt_value_from_str("--log-level")?.unwrap_or_default(),
+        log_format: pargs
+            .opt_value_from_str("--log-format")?
+            .unwrap_or_default(),
+        log_output: pargs
+            .opt_value_from_str("--log-output")?
+            .unwrap_or_default(),
         tee,
     };
+    if args.paths.is_empty() {
+        return Err(pico_args::Error::MissingOption("--path".into()));
+    }
+    let capture = args.pcap.is_some() || args.replay.is_some();
+    if capture && args.follow.is_some() {
+        eprintln!("Error: --follow cannot be combined with --pcap or --replay.");
+        exit(1);
+    }
+    if capture && (args.pacing.rate.is_some() || args.pacing.interval.is_some()) {
+        eprintln!(
+            "Error: --rate and --interval cannot be combined with --pcap or --replay, see --speed."
+        );
+        exit(1);
+    }
     let remaining = pargs.finish();
     if !remaining.is_empty() {
-        println!("Warning: unused arguments {:?}", remaining)
+        eprintln!("Warning: unused arguments {:?}", remaining)
     }
 
     if args.server_addrs.is_empty() && !args.tee {
-        println!(
+        eprintln!(
             "At least one server address (or the --tee flag) is required. See --help for more info"
         );
         exit(0);
@@ -68,6 +238,15 @@ fn parse_args() -> Result<ClientArgs, pico_args::Error> {
     Ok(args)
 }
 
+/// Paths joined for messages
+fn display_paths(paths: &[PathBuf]) -> String {
+    let paths: Vec<_> = paths
+        .iter()
+        .map(|path| path.display().to_string())
+        .collect();
+    paths.join(", ")
+}
+
 pub fn main() {
     let args = match parse_args() {
         Ok(a) => a,
@@ -76,5 +255,86 @@ pub fn main() {
             exit(1);
         }
     };
-    let _ = client_socket_stream(&args.path, args.server_addrs, args.tee);
+    if let Err(e) = init_logging(args.log_level, args.log_format, args.log_output) {
+        eprintln!("Error: initializing logging: {}.", e);
+        exit(1);
+    }
+    set_refresh_interval(Duration::from_secs(args.resolve_secs));
+    set_resolve_mode(args.resolve_mode);
+    let path = &args.paths[0];
+    let many = args.paths.len() > 1
+        || args.watch
+        || args.tag
+        || path.is_dir()
+        || path.to_str().is_some_and(is_glob);
+    let input = match (&args.follow, args.pcap, args.replay) {
+        (_, Some(_), _) | (_, _, Some(_)) if many => {
+            eprintln!("Error: --pcap and --replay take a single file --path.");
+            exit(1);
+        }
+        (follow, _, _) if many => {
+            let options = InputOptions {
+                watch: args.watch,
+                tag: args.tag,
+                follow: follow.clone(),
+            };
+            Inputs::open(&args.paths, options).map(Input::Many)
+        }
+        (Some(_), _, _) if path.as_os_str() == "-" => {
+            eprintln!("Error: --follow requires a file --path.");
+            exit(1);
+        }
+        (Some(follow), _, _) => Follower::open(path, follow).map(Input::Follow),
+        (None, Some(filter), replay) => {
+            File::open(path).map(|f| Input::Pcap(BufReader::new(f), filter, replay))
+        }
+        (None, None, Some(replay)) => {
+            File::open(path).map(|f| Input::Replay(BufReader::new(f), replay))
+        }
+        (None, None, None) => open_input(path).map(Input::Stream),
+    };
+    let input = match input {
+        Ok(input) => input,
+        Err(e) => {
+            eprintln!("Error: opening {}: {}.", display_paths(&args.paths), e);
+            exit(1);
+        }
+    };
+    if let Some(path) = &args.pidfile {
+        if let Err(e) = write_pidfile(path) {
+            eprintln!("Error: writing {}: {}.", path.display(), e);
+            exit(1);
+        }
+    }
+    if let Err(e) = drop_privileges(args.user.as_deref(), args.group.as_deref(), None) {
+        eprintln!("Error: {}.", e);
+        exit(1);
+    }
+    let _watchdog = watchdog();
+    ready(&format!(
+        "sending {} to {}",
+        display_paths(&args.paths),
+        args.server_addrs.join(", ")
+    ));
+    let result = match input {
+        Input::Stream(reader) => {
+            client_reader_stream_paced(reader, path, args.server_addrs, args.tee, args.pacing)
+        }
+        Input::Replay(reader, replay) => {
+            client_replay_stream(reader, path, args.server_addrs, args.tee, replay)
+        }
+        Input::Pcap(reader, filter, replay) => {
+            client_pcap_stream(reader, path, args.server_addrs, args.tee, filter, replay)
+        }
+        Input::Many(inputs) => {
+            client_inputs_stream(inputs, args.server_addrs, args.tee, args.pacing)
+        }
+        Input::Follow(follower) => {
+            client_follow_stream(follower, args.server_addrs, args.tee, args.pacing)
+        }
+    };
+    if let Err(e) = result {
+        error!("sending {}: {}", display_paths(&args.paths), e);
+        exit(1);
+    }
 }
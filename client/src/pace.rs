@@ -0,0 +1,101 @@
+//! Sender-side pacing, spreading datagrams evenly over time.
+//!
+//! Without pacing, input is sent as fast as it can be read, so that large
+//! files can overrun the socket buffers of receivers. A [`Pacing`] limits
+//! sending to a rate in bytes or datagrams per second, and/or to one datagram
+//! per interval.
+//!
+//! Send times are scheduled from the start of the stream rather than from the
+//! previous send, so that sleep overshoot does not accumulate. If sending
+//! falls behind schedule, e.g. while waiting for input, the schedule restarts
+//! instead of bursting to catch up.
+
+use std::str::FromStr;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+/// Falling behind schedule by more than this restarts the schedule
+const MAX_LAG: Duration = Duration::from_millis(100);
+
+/// A sending rate
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Rate {
+    /// Bytes per second
+    Bytes(f64),
+    /// Datagrams per second
+    Datagrams(f64),
+}
+
+impl FromStr for Rate {
+    type Err = String;
+
+    /// Parse `bytes=N`, `packets=N`, or `N` bytes per second
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (unit, value) = s.split_once('=').unwrap_or(("bytes", s));
+        let value = value
+            .parse::<f64>()
+            .ok()
+            .filter(|v| v.is_finite() && *v > 0.0)
+            .ok_or_else(|| format!("invalid rate '{}'", s))?;
+        match unit {
+            "bytes" => Ok(Rate::Bytes(value)),
+            "packets" => Ok(Rate::Datagrams(value)),
+            _ => Err(format!("unknown rate unit '{}'", unit)),
+        }
+    }
+}
+
+/// Limits on the sending rate of a stream
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Pacing {
+    pub rate: Option<Rate>,
+    /// Minimum time between datagrams
+    pub interval: Option<Duration>,
+}
+
+impl Pacing {
+    /// Returns true if sending is paced at all
+    pub fn is_paced(&self) -> bool {
+        self.rate.is_some() || self.interval.is_some()
+    }
+
+    /// Time taken by sending a datagram of `bytes`
+    fn cost(&self, bytes: usize) -> Duration {
+        let rate = match self.rate {
+            Some(Rate::Bytes(rate)) => Duration::from_secs_f64(bytes as f64 / rate),
+            Some(Rate::Datagrams(rate)) => Duration::from_secs_f64(1.0 / rate),
+            None => Duration::ZERO,
+        };
+        rate.max(self.interval.unwrap_or_default())
+    }
+}
+
+/// Waits between datagrams as required by a [`Pacing`]
+#[derive(Debug)]
+pub(crate) struct Pacer {
+    pacing: Pacing,
+    next: Option<Instant>,
+}
+
+impl Pacer {
+    pub(crate) fn new(pacing: Pacing) -> Self {
+        Pacer { pacing, next: None }
+    }
+
+    /// Sleep until a datagram of `bytes` may be sent, and schedule the next
+    pub(crate) fn wait(&mut self, bytes: usize) {
+        if !self.pacing.is_paced() {
+            return;
+        }
+        let now = Instant::now();
+        let due = match self.next {
+            Some(next) if next > now => {
+                sleep(next - now);
+                next
+            }
+            Some(next) if now - next <= MAX_LAG => next,
+            _ => now,
+        };
+        self.next = Some(due + self.pacing.cost(bytes));
+    }
+}
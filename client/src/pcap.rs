@@ -0,0 +1,105 @@
+//! Input from pcap and pcapng files of UDP traffic.
+//!
+//! Packet captures of a feed, e.g. from tcpdump or Wireshark, are read with
+//! [`PcapReader`], keeping the UDP payloads sent to the addresses matching a
+//! [`PcapFilter`]. Payloads are sent as fast as they are read, or with their
+//! original timing as for capture files, see [`Replay`].
+
+use std::io::{Read, Result as ioResult, Seek, SeekFrom};
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
+use std::str::FromStr;
+use std::time::SystemTime;
+
+use mproxy_common::pcap::PcapReader;
+
+use crate::replay::{send_datagrams, Datagrams, Replay};
+
+/// Destination address and port of the UDP packets to send. Unset fields
+/// match any address or port
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct PcapFilter {
+    pub addr: Option<IpAddr>,
+    pub port: Option<u16>,
+}
+
+impl PcapFilter {
+    /// Returns true if datagrams sent to `dest` match the filter
+    pub fn matches(&self, dest: &SocketAddr) -> bool {
+        self.addr.is_none_or(|addr| addr == dest.ip())
+            && self.port.is_none_or(|port| port == dest.port())
+    }
+}
+
+impl FromStr for PcapFilter {
+    type Err = String;
+
+    /// Parse `ADDR:PORT`, `[IPV6]:PORT`, `ADDR`, or `:PORT`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid filter '{}'", s);
+        if let Ok(addr) = s.parse::<SocketAddr>() {
+            return Ok(PcapFilter {
+                addr: Some(addr.ip()),
+                port: Some(addr.port()),
+            });
+        }
+        if let Ok(addr) = s.trim_matches(['[', ']']).parse::<IpAddr>() {
+            return Ok(PcapFilter {
+                addr: Some(addr),
+                port: None,
+            });
+        }
+        match s.strip_prefix(':') {
+            Some(port) => Ok(PcapFilter {
+                addr: None,
+                port: Some(port.parse().map_err(|_| invalid())?),
+            }),
+            None => Err(invalid()),
+        }
+    }
+}
+
+/// UDP payloads of a packet capture matching a filter
+struct Filtered<R: Read> {
+    pcap: PcapReader<R>,
+    filter: PcapFilter,
+}
+
+impl<R: Read + Seek> Datagrams for Filtered<R> {
+    fn next_datagram(&mut self) -> ioResult<Option<(SystemTime, Vec<u8>)>> {
+        while let Some(datagram) = self.pcap.next_datagram()? {
+            if self.filter.matches(&datagram.dest) {
+                return Ok(Some((datagram.time, datagram.data)));
+            }
+        }
+        Ok(None)
+    }
+
+    fn rewind(self) -> ioResult<Self> {
+        let mut reader = self.pcap.into_inner();
+        reader.seek(SeekFrom::Start(0))?;
+        Ok(Filtered {
+            pcap: PcapReader::new(reader)?,
+            filter: self.filter,
+        })
+    }
+}
+
+/// Send the payloads of UDP packets matching `filter` in pcap or pcapng file
+/// `reader`, opened from `path`, to each of `server_addrs`. Packets are sent
+/// with their original timing if `replay` is given, or else as fast as they
+/// are read. Optionally copy output to stdout
+pub fn client_pcap_stream<R: Read + Seek>(
+    reader: R,
+    path: &Path,
+    server_addrs: Vec<String>,
+    tee: bool,
+    filter: PcapFilter,
+    replay: Option<Replay>,
+) -> ioResult<()> {
+    let datagrams = Filtered {
+        pcap: PcapReader::new(reader)?,
+        filter,
+    };
+    send_datagrams(datagrams, path, server_addrs, tee, replay)
+}
@@ -0,0 +1,119 @@
+//! Replay of capture files with their original timing.
+//!
+//! Capture files written by the server with `--format capture` keep each
+//! datagram with its arrival time, see [`mproxy_common::capture`]. Replaying
+//! a capture sends each datagram as it was received, after the same delay
+//! since the first datagram, divided by the replay speed.
+
+use std::io::{stdout, BufWriter, Read, Result as ioResult, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::thread::sleep;
+use std::time::{Instant, SystemTime};
+
+use log::{debug, info};
+use mproxy_common::capture::CaptureReader;
+
+use crate::bind_targets;
+
+/// Replay settings
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Replay {
+    /// Speed multiplier, e.g. 2.0 to replay twice as fast
+    pub speed: f64,
+    /// Start again from the beginning at the end of the capture
+    pub repeat: bool,
+}
+
+impl Default for Replay {
+    fn default() -> Self {
+        Replay {
+            speed: 1.0,
+            repeat: false,
+        }
+    }
+}
+
+/// Timestamped datagrams that can be read again from the start
+pub(crate) trait Datagrams: Sized {
+    /// The next datagram and its arrival time, or None at the end
+    fn next_datagram(&mut self) -> ioResult<Option<(SystemTime, Vec<u8>)>>;
+
+    /// Start again from the first datagram
+    fn rewind(self) -> ioResult<Self>;
+}
+
+impl<R: Read + Seek> Datagrams for CaptureReader<R> {
+    fn next_datagram(&mut self) -> ioResult<Option<(SystemTime, Vec<u8>)>> {
+        Ok(self.next_record()?.map(|record| (record.time, record.data)))
+    }
+
+    fn rewind(self) -> ioResult<Self> {
+        let mut reader = self.into_inner();
+        reader.seek(SeekFrom::Start(0))?;
+        CaptureReader::new(reader)
+    }
+}
+
+/// Send the datagrams of capture file `reader`, opened from `path`, to each
+/// of `server_addrs`, keeping their sizes and the gaps between them.
+/// Optionally copy output to stdout
+pub fn client_replay_stream<R: Read + Seek>(
+    reader: R,
+    path: &Path,
+    server_addrs: Vec<String>,
+    tee: bool,
+    replay: Replay,
+) -> ioResult<()> {
+    send_datagrams(
+        CaptureReader::new(reader)?,
+        path,
+        server_addrs,
+        tee,
+        Some(replay),
+    )
+}
+
+/// Send `datagrams` read from `path` to each of `server_addrs`, with their
+/// original timing if `replay` is given, or else as fast as they are read
+pub(crate) fn send_datagrams<D: Datagrams>(
+    mut datagrams: D,
+    path: &Path,
+    server_addrs: Vec<String>,
+    tee: bool,
+    replay: Option<Replay>,
+) -> ioResult<()> {
+    let mut targets = bind_targets(path, server_addrs)?;
+    let mut output_buffer = BufWriter::new(stdout());
+
+    loop {
+        let start = Instant::now();
+        let mut first: Option<SystemTime> = None;
+        let mut count = 0;
+        while let Some((time, data)) = datagrams.next_datagram()? {
+            if let Some(replay) = replay {
+                let offset = time
+                    .duration_since(*first.get_or_insert(time))
+                    .unwrap_or_default();
+                let due = start + offset.div_f64(replay.speed);
+                let now = Instant::now();
+                if due > now {
+                    sleep(due - now);
+                }
+            }
+            for target in &mut targets {
+                target.send(&data);
+            }
+            if tee {
+                output_buffer.write_all(&data)?;
+                output_buffer.flush()?;
+            }
+            count += 1;
+        }
+        debug!("sent {} datagrams from {}", count, path.display());
+        if !replay.is_some_and(|replay| replay.repeat) || count == 0 {
+            return Ok(());
+        }
+        info!("replaying {} again", path.display());
+        datagrams = datagrams.rewind()?;
+    }
+}
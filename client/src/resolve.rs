@@ -0,0 +1,174 @@
+//! Periodic re-resolution of downstream host names.
+//!
+//! Downstream addresses given as host names are resolved when the target is
+//! created, and again by a background thread every refresh interval (30s by
+//! default), so that targets follow DNS records as they change. The system
+//! resolver does not expose record TTLs, so the interval stands in for them.
+//! If a lookup fails, the previously resolved addresses are kept.
+//!
+//! A name may resolve to several addresses. With [`ResolveMode::Failover`],
+//! datagrams are sent to one address at a time, moving to the next address
+//! when sending fails. With [`ResolveMode::All`], datagrams are sent to every
+//! address. Addresses for which no socket can be bound, e.g. IPv6 addresses
+//! on hosts without IPv6, are skipped.
+
+use std::io::{Error, ErrorKind, Result as ioResult};
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, Once, Weak};
+use std::thread::{sleep, Builder};
+use std::time::Duration;
+
+use log::{info, warn};
+
+/// Default interval between lookups of downstream host names
+const DEFAULT_REFRESH_SECS: u64 = 30;
+
+/// Interval between lookups of downstream host names, in seconds
+static REFRESH_SECS: AtomicU64 = AtomicU64::new(DEFAULT_REFRESH_SECS);
+
+/// Set if datagrams are sent to all resolved addresses
+static SEND_TO_ALL: AtomicBool = AtomicBool::new(false);
+
+/// Names refreshed by the resolver thread
+static NAMES: Mutex<Vec<Weak<Resolved>>> = Mutex::new(Vec::new());
+
+/// Starts the resolver thread once
+static RESOLVER: Once = Once::new();
+
+/// How datagrams are sent to a name resolving to several addresses
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ResolveMode {
+    /// Send to one address, moving to the next when sending fails
+    #[default]
+    Failover,
+    /// Send to every address
+    All,
+}
+
+impl FromStr for ResolveMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "failover" => Ok(ResolveMode::Failover),
+            "all" => Ok(ResolveMode::All),
+            _ => Err(format!("unknown resolve mode '{}'", s)),
+        }
+    }
+}
+
+/// Set how datagrams are sent to names resolving to several addresses, for
+/// all downstreams in this process. Defaults to [`ResolveMode::Failover`]
+pub fn set_resolve_mode(mode: ResolveMode) {
+    SEND_TO_ALL.store(mode == ResolveMode::All, Ordering::Relaxed);
+}
+
+/// Set the interval between lookups of downstream host names, for all
+/// downstreams in this process. Defaults to 30s. Zero disables re-resolution
+pub fn set_refresh_interval(interval: Duration) {
+    REFRESH_SECS.store(interval.as_secs(), Ordering::Relaxed);
+}
+
+/// The current [`ResolveMode`]
+pub(crate) fn resolve_mode() -> ResolveMode {
+    if SEND_TO_ALL.load(Ordering::Relaxed) {
+        ResolveMode::All
+    } else {
+        ResolveMode::Failover
+    }
+}
+
+/// Addresses of a downstream, refreshed while the downstream exists
+#[derive(Debug)]
+pub(crate) struct Resolved {
+    name: String,
+    addrs: Mutex<Vec<SocketAddr>>,
+    version: AtomicU64,
+}
+
+impl Resolved {
+    /// Resolved addresses, in the order returned by the resolver
+    pub(crate) fn addrs(&self) -> Vec<SocketAddr> {
+        self.addrs.lock().unwrap().clone()
+    }
+
+    /// Incremented each time the addresses change
+    pub(crate) fn version(&self) -> u64 {
+        self.version.load(Ordering::Acquire)
+    }
+
+    /// Look up the name again, keeping the current addresses on failure
+    fn refresh(&self) {
+        let addrs = match lookup(&self.name) {
+            Ok(addrs) => addrs,
+            Err(e) => {
+                warn!("resolving {}: {}, keeping previous addresses", self.name, e);
+                return;
+            }
+        };
+        let mut current = self.addrs.lock().unwrap();
+        if *current != addrs {
+            info!("{}: resolved to {:?}", self.name, addrs);
+            *current = addrs;
+            self.version.fetch_add(1, Ordering::Release);
+        }
+    }
+}
+
+/// Resolve `name`. Host names are looked up again periodically by the
+/// resolver thread, for as long as the result is kept
+pub(crate) fn resolve(name: &str) -> ioResult<Arc<Resolved>> {
+    let resolved = Arc::new(Resolved {
+        name: name.to_string(),
+        addrs: Mutex::new(lookup(name)?),
+        version: AtomicU64::new(0),
+    });
+    if name.parse::<SocketAddr>().is_err() {
+        NAMES.lock().unwrap().push(Arc::downgrade(&resolved));
+        RESOLVER.call_once(|| {
+            Builder::new()
+                .name("resolver".to_string())
+                .spawn(refresh_names)
+                .unwrap();
+        });
+    }
+    Ok(resolved)
+}
+
+/// Look up the addresses of `name`, removing duplicates
+fn lookup(name: &str) -> ioResult<Vec<SocketAddr>> {
+    let mut addrs: Vec<SocketAddr> = vec![];
+    for addr in name.to_socket_addrs()? {
+        if !addrs.contains(&addr) {
+            addrs.push(addr);
+        }
+    }
+    if addrs.is_empty() {
+        return Err(Error::new(
+            ErrorKind::NotFound,
+            format!("no addresses found for {}", name),
+        ));
+    }
+    Ok(addrs)
+}
+
+/// Refresh registered names every interval, forgetting dropped names
+fn refresh_names() {
+    loop {
+        let secs = REFRESH_SECS.load(Ordering::Relaxed);
+        sleep(Duration::from_secs(secs.max(1)));
+        if secs == 0 {
+            continue;
+        }
+        let names: Vec<Arc<Resolved>> = {
+            let mut names = NAMES.lock().unwrap();
+            names.retain(|name| name.strong_count() > 0);
+            names.iter().filter_map(Weak::upgrade).collect()
+        };
+        for name in names {
+            name.refresh();
+        }
+    }
+}
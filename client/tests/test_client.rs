@@ -1,12 +1,22 @@
+use std::fs::{read, File};
+use std::io::Cursor;
+use std::net::{SocketAddr, UdpSocket};
 use std::path::PathBuf;
+use std::process::Command;
 use std::str::FromStr;
 use std::thread::sleep;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use testconfig::{truncate, TESTDATA, TESTINGDIR};
 
-use mproxy_client::client_socket_stream;
-use mproxy_server::listener;
+use mproxy_client::{
+    client_follow_stream, client_inputs_stream, client_pcap_stream, client_reader_stream_paced,
+    client_replay_stream, client_socket_stream, glob_match, set_refresh_interval, Downstream,
+    Follow, Follower, InputOptions, Inputs, Pacing, PcapFilter, Rate, Replay,
+};
+use mproxy_common::capture::CaptureReader;
+use mproxy_common::pcap::{encode_packet, write_header, PcapFormat};
+use mproxy_server::{listener, listener_format, OutputFormat};
 
 fn test_client(pathstr: &str, listen_addr: String, target_addr: String, tee: bool) {
     let _l = listener(listen_addr, PathBuf::from_str(pathstr).unwrap(), false);
@@ -18,7 +28,7 @@ fn test_client(pathstr: &str, listen_addr: String, target_addr: String, tee: boo
 
 #[test]
 fn test_client_socket_stream_unicast_ipv4() {
-    let pathstr = &[TESTINGDIR, "streamoutput_client_ipv4_unicast.log"].join(&"");
+    let pathstr = &[TESTINGDIR, "streamoutput_client_ipv4_unicast.log"].join("");
     let listen_addr = "0.0.0.0:9910".to_string();
     let target_addr = "127.0.0.1:9910".to_string();
     test_client(pathstr, listen_addr, target_addr, false)
@@ -26,7 +36,7 @@ fn test_client_socket_stream_unicast_ipv4() {
 
 #[test]
 fn test_client_socket_stream_multicast_ipv4() {
-    let pathstr = &[TESTINGDIR, "streamoutput_client_ipv4_multicast.log"].join(&"");
+    let pathstr = &[TESTINGDIR, "streamoutput_client_ipv4_multicast.log"].join("");
     let target_addr = "224.0.0.110:9911".to_string();
     let listen_addr = target_addr.clone();
     test_client(pathstr, listen_addr, target_addr, false)
@@ -34,7 +44,7 @@ fn test_client_socket_stream_multicast_ipv4() {
 
 #[test]
 fn test_client_socket_stream_unicast_ipv6() {
-    let pathstr = &[TESTINGDIR, "streamoutput_client_ipv6_unicast.log"].join(&"");
+    let pathstr = &[TESTINGDIR, "streamoutput_client_ipv6_unicast.log"].join("");
     let listen_addr = "[::1]:9912".to_string();
     let target_addr = "[::1]:9912".to_string();
     test_client(pathstr, listen_addr, target_addr, false)
@@ -42,7 +52,7 @@ fn test_client_socket_stream_unicast_ipv6() {
 
 #[test]
 fn test_client_socket_stream_multicast_ipv6() {
-    let pathstr = &[TESTINGDIR, "streamoutput_client_ipv6_multicast.log"].join(&"");
+    let pathstr = &[TESTINGDIR, "streamoutput_client_ipv6_multicast.log"].join("");
     let listen_addr = "[ff02::0]:9913".to_string();
     let target_addr = "[ff02::1]:9913".to_string();
     test_client(pathstr, listen_addr, target_addr, false)
@@ -50,7 +60,7 @@ fn test_client_socket_stream_multicast_ipv6() {
 
 #[test]
 fn test_client_socket_tee() {
-    let pathstr = &[TESTINGDIR, "streamoutput_client_tee.log"].join(&"");
+    let pathstr = &[TESTINGDIR, "streamoutput_client_tee.log"].join("");
     let target_addr = "127.0.0.1:9914".to_string();
     let listen_addr = "0.0.0.0:9914".to_string();
     test_client(pathstr, listen_addr, target_addr, true)
@@ -58,8 +68,8 @@ fn test_client_socket_tee() {
 
 #[test]
 fn test_client_multiple_servers() {
-    let pathstr_1 = &[TESTINGDIR, "streamoutput_client_ipv6_multiplex_1.log"].join(&"");
-    let pathstr_2 = &[TESTINGDIR, "streamoutput_client_ipv6_multiplex_2.log"].join(&"");
+    let pathstr_1 = &[TESTINGDIR, "streamoutput_client_ipv6_multiplex_1.log"].join("");
+    let pathstr_2 = &[TESTINGDIR, "streamoutput_client_ipv6_multiplex_2.log"].join("");
     let listen_addr_1 = "[::]:9915".to_string();
     let listen_addr_2 = "[::]:9916".to_string();
     let target_addr_1 = "[::1]:9915".to_string();
@@ -81,3 +91,485 @@ fn test_client_multiple_servers() {
     assert!(bytesize_2 > 0);
     assert!(bytesize_1 == bytesize_2);
 }
+
+#[test]
+fn test_client_failing_downstream() {
+    let pathstr = &[TESTINGDIR, "streamoutput_client_failing_downstream.log"].join("");
+    let listen_addr = "0.0.0.0:9918".to_string();
+    // sending to the broadcast address fails without SO_BROADCAST
+    let failing_addr = "255.255.255.255:9918".to_string();
+    let target_addr = "127.0.0.1:9918".to_string();
+
+    let _l = listener(listen_addr, PathBuf::from_str(pathstr).unwrap(), false);
+    let c = client_socket_stream(
+        &PathBuf::from(TESTDATA),
+        vec![failing_addr, target_addr],
+        false,
+    );
+    assert!(c.is_ok());
+    let bytesize = truncate(PathBuf::from_str(pathstr).unwrap());
+    assert!(bytesize > 0);
+}
+
+#[test]
+fn test_client_resolves_host_name() {
+    set_refresh_interval(Duration::from_secs(1));
+    let server = UdpSocket::bind("127.0.0.1:9927").unwrap();
+    server
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    let mut target = Downstream::new(&"localhost:9927".to_string()).unwrap();
+    let expected: SocketAddr = "127.0.0.1:9927".parse().unwrap();
+    assert!(target.addrs().contains(&expected));
+
+    // sending continues after the name is looked up again
+    sleep(Duration::from_millis(1500));
+    let mut buf = [0u8; 16];
+    for message in [&b"first"[..], &b"second"[..]] {
+        assert!(target.send(message));
+        let c = server.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..c], message);
+    }
+}
+
+#[test]
+fn test_client_log_format_json() {
+    let output = Command::new(env!("CARGO_BIN_EXE_mproxy-client"))
+        .args(["--path", TESTDATA, "--server-addr", "127.0.0.1:9919"])
+        .args(["--tee", "--log-format", "json"])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+
+    // stdout contains only data copied with --tee
+    assert_eq!(output.stdout, read(TESTDATA).unwrap());
+
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    let line = stderr
+        .lines()
+        .find(|line| line.contains("logging from"))
+        .expect("missing log record");
+    assert!(line.starts_with('{') && line.ends_with('}'));
+    assert!(line.contains("\"level\":\"INFO\""));
+    assert!(line.contains("\"target\":\"mproxy_client\""));
+}
+
+#[test]
+fn test_client_paced_stream() {
+    let server = UdpSocket::bind("127.0.0.1:9937").unwrap();
+    server
+        .set_read_timeout(Some(Duration::from_secs(1)))
+        .unwrap();
+    // five datagrams of 8096 bytes, at 20 datagrams per second
+    let input = Box::new(Cursor::new(vec![b'x'; 5 * 8096]));
+    let pacing = Pacing {
+        rate: Some(Rate::Datagrams(20.0)),
+        interval: None,
+    };
+    let start = Instant::now();
+    client_reader_stream_paced(
+        input,
+        &PathBuf::from("-"),
+        vec!["127.0.0.1:9937".to_string()],
+        false,
+        pacing,
+    )
+    .unwrap();
+    let elapsed = start.elapsed();
+
+    let mut buf = vec![0u8; 8096];
+    for _ in 0..5 {
+        assert_eq!(server.recv(&mut buf).unwrap(), 8096);
+    }
+    assert!(elapsed >= Duration::from_millis(200), "{:?}", elapsed);
+    assert!(elapsed < Duration::from_millis(500), "{:?}", elapsed);
+}
+
+#[test]
+fn test_client_capture_replay() {
+    let pathstr = &[TESTINGDIR, "streamoutput_client_capture.cap"].join("");
+    let _ = std::fs::remove_file(pathstr);
+    let _l = listener_format(
+        "127.0.0.1:9938".to_string(),
+        PathBuf::from(pathstr),
+        false,
+        OutputFormat::Capture,
+    );
+    let sender = UdpSocket::bind("127.0.0.1:0").unwrap();
+    for message in [&b"a"[..], b"bb", b"ccc"] {
+        sender.send_to(message, "127.0.0.1:9938").unwrap();
+        sleep(Duration::from_millis(100));
+    }
+
+    let mut capture = CaptureReader::new(File::open(pathstr).unwrap()).unwrap();
+    let mut records = vec![];
+    while let Some(record) = capture.next_record().unwrap() {
+        records.push(record);
+    }
+    assert_eq!(records.len(), 3);
+    assert_eq!(records[1].data, b"bb");
+    assert_eq!(records[1].source, sender.local_addr().unwrap());
+    let gap = records[2].time.duration_since(records[0].time).unwrap();
+    assert!(gap >= Duration::from_millis(190), "{:?}", gap);
+
+    // replay at twice the original speed
+    let server = UdpSocket::bind("127.0.0.1:9939").unwrap();
+    server
+        .set_read_timeout(Some(Duration::from_secs(1)))
+        .unwrap();
+    let replay = Replay {
+        speed: 2.0,
+        repeat: false,
+    };
+    let start = Instant::now();
+    client_replay_stream(
+        File::open(pathstr).unwrap(),
+        &PathBuf::from(pathstr),
+        vec!["127.0.0.1:9939".to_string()],
+        false,
+        replay,
+    )
+    .unwrap();
+    let elapsed = start.elapsed();
+    let mut buf = [0u8; 16];
+    for message in [&b"a"[..], b"bb", b"ccc"] {
+        let c = server.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..c], message);
+    }
+    assert!(elapsed >= gap.div_f64(2.0), "{:?}", elapsed);
+    assert!(elapsed < gap, "{:?}", elapsed);
+    std::fs::remove_file(pathstr).unwrap();
+}
+
+#[test]
+fn test_client_pcap_input() {
+    let source: SocketAddr = "192.0.2.1:5000".parse().unwrap();
+    let start = std::time::SystemTime::now();
+    let mut pcapng = vec![];
+    write_header(&mut pcapng, PcapFormat::PcapNg).unwrap();
+    for (millis, port, message) in [(0, 9941, &b"a"[..]), (100, 9942, b"x"), (200, 9941, b"bb")] {
+        let dest = SocketAddr::new("224.0.0.1".parse().unwrap(), port);
+        let time = start + Duration::from_millis(millis);
+        pcapng.extend(encode_packet(
+            PcapFormat::PcapNg,
+            time,
+            source,
+            dest,
+            message,
+        ));
+    }
+
+    let server = UdpSocket::bind("127.0.0.1:9941").unwrap();
+    server
+        .set_read_timeout(Some(Duration::from_millis(200)))
+        .unwrap();
+    let filter = PcapFilter::from_str("224.0.0.1:9941").unwrap();
+    let started = Instant::now();
+    client_pcap_stream(
+        Cursor::new(pcapng),
+        &PathBuf::from("test.pcapng"),
+        vec!["127.0.0.1:9941".to_string()],
+        false,
+        filter,
+        Some(Replay::default()),
+    )
+    .unwrap();
+    let elapsed = started.elapsed();
+    let mut buf = [0u8; 16];
+    for message in [&b"a"[..], b"bb"] {
+        let c = server.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..c], message);
+    }
+    assert!(server.recv(&mut buf).is_err());
+    assert!(elapsed >= Duration::from_millis(190), "{:?}", elapsed);
+}
+
+#[test]
+fn test_client_follow_rotation_and_state() {
+    use std::fs::{remove_file, rename, OpenOptions};
+    use std::io::Write;
+
+    let dir = std::env::temp_dir();
+    let path = dir.join("mproxy_client_follow.log");
+    let rotated = dir.join("mproxy_client_follow.log.1");
+    let state = dir.join("mproxy_client_follow.state");
+    for file in [&path, &rotated, &state] {
+        let _ = remove_file(file);
+    }
+    let append = |data: &[u8]| {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .unwrap();
+        file.write_all(data).unwrap();
+    };
+    let follow = Follow {
+        state: Some(state.clone()),
+        poll: Duration::from_millis(50),
+    };
+    let receiver = |addr: &str| {
+        let server = UdpSocket::bind(addr).unwrap();
+        server
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        server
+    };
+    let recv = |server: &UdpSocket| {
+        let mut buf = [0u8; 16];
+        let c = server.recv(&mut buf).unwrap();
+        buf[..c].to_vec()
+    };
+
+    let server = receiver("127.0.0.1:9942");
+    append(b"one");
+    let follower = Follower::open(&path, &follow).unwrap();
+    std::thread::spawn(move || {
+        client_follow_stream(
+            follower,
+            vec!["127.0.0.1:9942".to_string()],
+            false,
+            Pacing::default(),
+        )
+    });
+    assert_eq!(recv(&server), b"one");
+    append(b"two");
+    assert_eq!(recv(&server), b"two");
+
+    // the rest of the rotated file is sent before the new file
+    rename(&path, &rotated).unwrap();
+    OpenOptions::new()
+        .append(true)
+        .open(&rotated)
+        .unwrap()
+        .write_all(b"2")
+        .unwrap();
+    append(b"three");
+    assert_eq!(recv(&server), b"2");
+    assert_eq!(recv(&server), b"three");
+
+    // a restarted client resumes after the data already sent
+    sleep(Duration::from_millis(100));
+    let follower = Follower::open(&path, &follow).unwrap();
+    let server = receiver("127.0.0.1:9943");
+    std::thread::spawn(move || {
+        client_follow_stream(
+            follower,
+            vec!["127.0.0.1:9943".to_string()],
+            false,
+            Pacing::default(),
+        )
+    });
+    append(b"four");
+    assert_eq!(recv(&server), b"four");
+    for file in [&path, &rotated, &state] {
+        let _ = remove_file(file);
+    }
+}
+
+#[test]
+fn test_client_glob_inputs_watch_and_tag() {
+    use std::fs::{create_dir_all, remove_dir_all, write};
+
+    assert!(glob_match("*.log", "app.log"));
+    assert!(glob_match("app-[0-9]?.log", "app-12.log"));
+    assert!(!glob_match("app-[!0-9]*", "app-1.log"));
+    assert!(!glob_match("*.log", ".hidden.log"));
+
+    let dir = std::env::temp_dir().join("mproxy_client_inputs");
+    let _ = remove_dir_all(&dir);
+    create_dir_all(&dir).unwrap();
+    write(dir.join("a.log"), b"A").unwrap();
+    write(dir.join("b.log"), b"B").unwrap();
+    write(dir.join("c.txt"), b"C").unwrap();
+
+    let server = UdpSocket::bind("127.0.0.1:9944").unwrap();
+    server
+        .set_read_timeout(Some(Duration::from_secs(1)))
+        .unwrap();
+    let options = InputOptions {
+        watch: true,
+        tag: true,
+        follow: None,
+    };
+    let inputs = Inputs::open(&[dir.join("*.log")], options).unwrap();
+    std::thread::spawn(move || {
+        client_inputs_stream(
+            inputs,
+            vec!["127.0.0.1:9944".to_string()],
+            false,
+            Pacing::default(),
+        )
+    });
+    let mut buf = [0u8; 32];
+    let mut recv = || {
+        let c = server.recv(&mut buf).unwrap();
+        String::from_utf8(buf[..c].to_vec()).unwrap()
+    };
+    let mut received = vec![recv(), recv()];
+    received.sort();
+    assert_eq!(received, ["a.log: A", "b.log: B"]);
+
+    // new files matching the pattern are sent once written
+    write(dir.join("d.txt"), b"D").unwrap();
+    write(dir.join("e.log"), b"E").unwrap();
+    assert_eq!(recv(), "e.log: E");
+    let _ = remove_dir_all(&dir);
+}
+
+#[test]
+fn test_client_inputs_state_files_per_path() {
+    use std::fs::{create_dir_all, read_dir, remove_dir_all, write};
+
+    let dir = std::env::temp_dir().join("mproxy_client_inputs_state");
+    let _ = remove_dir_all(&dir);
+    for sub in ["a", "b"] {
+        create_dir_all(dir.join(sub)).unwrap();
+        write(dir.join(sub).join("app.log"), b"log").unwrap();
+    }
+    let options = InputOptions {
+        follow: Some(Follow {
+            state: Some(dir.join("state")),
+            poll: Duration::from_millis(50),
+        }),
+        ..Default::default()
+    };
+    let _inputs = Inputs::open(&[dir.join("a/app.log"), dir.join("b/app.log")], options).unwrap();
+    // inputs of the same name in different directories have their own state
+    let states: Vec<_> = read_dir(&dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+        .filter(|name| name.starts_with("state."))
+        .collect();
+    let _ = remove_dir_all(&dir);
+    assert_eq!(states.len(), 2, "{:?}", states);
+    assert!(states.iter().all(|name| !name.contains('/')));
+    assert!(states.iter().any(|name| name.ends_with("%2Fa%2Fapp.log")));
+}
+
+#[test]
+fn test_client_inputs_tagged_datagram_size() {
+    use std::fs::{create_dir_all, remove_dir_all, write};
+
+    let dir = std::env::temp_dir().join("mproxy_client_inputs_tagged");
+    let _ = remove_dir_all(&dir);
+    create_dir_all(&dir).unwrap();
+    let data = vec![b'x'; 10000];
+    write(dir.join("big.log"), &data).unwrap();
+
+    let server = UdpSocket::bind("127.0.0.1:9949").unwrap();
+    server
+        .set_read_timeout(Some(Duration::from_secs(1)))
+        .unwrap();
+    let options = InputOptions {
+        tag: true,
+        ..Default::default()
+    };
+    let inputs = Inputs::open(&[dir.join("big.log")], options).unwrap();
+    client_inputs_stream(
+        inputs,
+        vec!["127.0.0.1:9949".to_string()],
+        false,
+        Pacing::default(),
+    )
+    .unwrap();
+    let _ = remove_dir_all(&dir);
+
+    // full-size reads leave room for the tag within the 8096 byte buffers
+    // of the receivers
+    let mut received = vec![];
+    let mut buf = [0u8; 65536];
+    while received.len() < data.len() {
+        let c = server.recv(&mut buf).unwrap();
+        assert!(c <= 8096, "datagram of {} bytes", c);
+        let payload = buf[..c].strip_prefix(b"big.log: ").unwrap();
+        received.extend_from_slice(payload);
+    }
+    assert_eq!(received, data);
+}
+
+#[test]
+fn test_client_exit_status_on_stream_error() {
+    let run = |args: &[&str]| {
+        Command::new(env!("CARGO_BIN_EXE_mproxy-client"))
+            .args(args)
+            .output()
+            .unwrap()
+    };
+    // a text file is neither a capture nor a pcap file
+    for flag in ["--replay", "--pcap"] {
+        let output = run(&["--path", TESTDATA, "--server-addr", "127.0.0.1:9950", flag]);
+        assert_eq!(output.status.code(), Some(1), "{}", flag);
+    }
+    let output = run(&[
+        "--path",
+        TESTDATA,
+        "--server-addr",
+        "unresolvable.invalid:9950",
+    ]);
+    assert_eq!(output.status.code(), Some(1));
+}
+
+#[test]
+fn test_client_watch_relative_pattern() {
+    use std::fs::{create_dir_all, remove_dir_all, write, OpenOptions};
+    use std::io::Write;
+    use std::process::Stdio;
+
+    let dir = std::env::temp_dir().join("mproxy_client_watch_relative");
+    let _ = remove_dir_all(&dir);
+    create_dir_all(&dir).unwrap();
+    write(dir.join("a.log"), b"A").unwrap();
+
+    let server = UdpSocket::bind("127.0.0.1:9951").unwrap();
+    server
+        .set_read_timeout(Some(Duration::from_secs(2)))
+        .unwrap();
+    let mut client = Command::new(env!("CARGO_BIN_EXE_mproxy-client"))
+        .current_dir(&dir)
+        .args(["--path", "*.log", "--watch", "--tag"])
+        .args(["--server-addr", "127.0.0.1:9951"])
+        .stdout(Stdio::null())
+        .spawn()
+        .unwrap();
+    let mut buf = [0u8; 32];
+    let mut recv = || {
+        let c = server.recv(&mut buf).unwrap();
+        String::from_utf8(buf[..c].to_vec()).unwrap()
+    };
+    assert_eq!(recv(), "a.log: A");
+    sleep(Duration::from_millis(100));
+
+    // a file already sent is not sent again when written in the watched
+    // directory, which is reported relative to "."
+    OpenOptions::new()
+        .append(true)
+        .open(dir.join("a.log"))
+        .unwrap()
+        .write_all(b"2")
+        .unwrap();
+    write(dir.join("b.log"), b"B").unwrap();
+    let received = recv();
+    client.kill().unwrap();
+    client.wait().unwrap();
+    let _ = remove_dir_all(&dir);
+    assert_eq!(received, "b.log: B");
+}
+
+#[test]
+fn test_client_conflicting_flags() {
+    for flags in [
+        &["--follow", "--replay"][..],
+        &["--follow", "--pcap"],
+        &["--rate", "1000", "--replay"],
+        &["--interval", "10", "--pcap"],
+    ] {
+        let output = Command::new(env!("CARGO_BIN_EXE_mproxy-client"))
+            .args(["--path", TESTDATA, "--server-addr", "127.0.0.1:9952"])
+            .args(flags)
+            .output()
+            .unwrap();
+        assert_eq!(output.status.code(), Some(1), "{:?}", flags);
+        assert!(String::from_utf8_lossy(&output.stderr).contains("cannot be combined"));
+    }
+}
@@ -0,0 +1,159 @@
+//! Admin HTTP API for inspecting running routes.
+//!
+//! `GET /routes` returns a JSON document listing every route registered in
+//! this process, with its source, run state, and counters, and each of its
+//! downstream outputs with their connected clients:
+//!
+//! ```text
+//! {"routes":[{"name":"0.0.0.0:9920:forward","source":"0.0.0.0:9920","kind":"forward",
+//!   "state":"running","received_datagrams":10,"received_bytes":820,"reconnects":0,
+//!   "last_receive":"2024-01-01T00:00:00.000Z","idle_secs":0.5,
+//!   "downstreams":[{"name":"127.0.0.1:9921","state":"running","sent_datagrams":10,
+//!     "sent_bytes":820,"send_errors":0,"dropped_datagrams":0,
+//!     "clients":[{"addr":"127.0.0.1:50000","connected":"...","queue_depth":0}]}]}]}
+//! ```
+//!
+//! Route states are `running`, `stopped` (the thread handling the route has
+//! exited), or `panicked`. Outputs may also be `disabled` after repeated send
+//! failures.
+//!
+//! Downstreams of routes that send to a list of UDP addresses, such as
+//! `forward` routes, may be changed while the route is running. The route
+//! and address are given as query string parameters, and each request
+//! returns the resulting list of downstreams:
+//!
+//! ```text
+//! GET    /downstreams?route=0.0.0.0:9920:forward
+//! POST   /downstreams?route=0.0.0.0:9920:forward&addr=127.0.0.1:9923
+//! DELETE /downstreams?route=0.0.0.0:9920:forward&addr=127.0.0.1:9923
+//!
+//! {"route":"0.0.0.0:9920:forward","downstreams":["127.0.0.1:9921","127.0.0.1:9923"]}
+//! ```
+
+use std::fmt::Write as _;
+use std::thread::JoinHandle;
+use std::time::SystemTime;
+
+use crate::control::control;
+use crate::http::{serve, Request, Response};
+use crate::logging::{json_string, rfc3339};
+use crate::metrics::routes;
+
+/// Render registered routes as a JSON document
+pub fn routes_json() -> String {
+    let now = SystemTime::now();
+    let mut out = String::from("{\"routes\":[");
+    for (i, route) in routes().iter().enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+        let (last_receive, idle_secs) = match route.last_receive() {
+            Some(t) => (
+                json_string(&rfc3339(t)),
+                format!(
+                    "{:.3}",
+                    now.duration_since(t).unwrap_or_default().as_secs_f64()
+                ),
+            ),
+            None => ("null".to_string(), "null".to_string()),
+        };
+        let _ = write!(
+            out,
+            "{{\"name\":{},\"source\":{},\"kind\":{},\"state\":\"{}\",\
+             \"received_datagrams\":{},\"received_bytes\":{},\"reconnects\":{},\
+             \"last_receive\":{},\"idle_secs\":{},\"downstreams\":[",
+            json_string(route.name()),
+            json_string(route.source()),
+            json_string(route.kind()),
+            route.state().as_str(),
+            route.datagrams_in(),
+            route.bytes_in(),
+            route.reconnects(),
+            last_receive,
+            idle_secs,
+        );
+        for (j, downstream) in route.downstreams().iter().enumerate() {
+            if j > 0 {
+                out.push(',');
+            }
+            let _ = write!(
+                out,
+                "{{\"name\":{},\"state\":\"{}\",\"sent_datagrams\":{},\"sent_bytes\":{},\
+                 \"send_errors\":{},\"dropped_datagrams\":{},\"clients\":[",
+                json_string(downstream.name()),
+                downstream.state().as_str(),
+                downstream.datagrams_out(),
+                downstream.bytes_out(),
+                downstream.send_errors(),
+                downstream.drops(),
+            );
+            for (k, client) in downstream.clients().iter().enumerate() {
+                if k > 0 {
+                    out.push(',');
+                }
+                let queue_depth = client
+                    .queue_depth
+                    .map_or("null".to_string(), |d| d.to_string());
+                let _ = write!(
+                    out,
+                    "{{\"addr\":{},\"connected\":{},\"queue_depth\":{}}}",
+                    json_string(&client.addr),
+                    json_string(&rfc3339(client.connected)),
+                    queue_depth
+                );
+            }
+            out.push_str("]}");
+        }
+        out.push_str("]}");
+    }
+    out.push_str("]}");
+    out
+}
+
+/// List, add, or remove downstreams of the route given by the `route`
+/// query string parameter
+fn downstreams(request: &Request) -> Response {
+    let route = match request.param("route") {
+        Some(route) => route,
+        None => return Response::error("400 Bad Request", "missing route"),
+    };
+    let control = match control(route) {
+        Some(control) => control,
+        None => return Response::error("404 Not Found", "no such route"),
+    };
+    let changed = match (request.method.as_str(), request.param("addr")) {
+        ("GET", _) => Ok(()),
+        ("POST" | "DELETE", None) => {
+            return Response::error("400 Bad Request", "missing addr");
+        }
+        ("POST", Some(addr)) => control.add_downstream(addr),
+        ("DELETE", Some(addr)) => control.remove_downstream(addr),
+        _ => return Response::method_not_allowed(),
+    };
+    if let Err(e) = changed {
+        return Response::error("409 Conflict", &e);
+    }
+    let addrs: Vec<String> = control
+        .downstreams()
+        .iter()
+        .map(|addr| json_string(addr))
+        .collect();
+    Response::json(format!(
+        "{{\"route\":{},\"downstreams\":[{}]}}",
+        json_string(route),
+        addrs.join(",")
+    ))
+}
+
+/// Serve the admin API on `listen_addr`.
+/// Binds a TCP listener, and spawns a thread answering requests in turn
+pub fn serve_admin(listen_addr: String) -> JoinHandle<()> {
+    serve(listen_addr, "admin", |request, _body| {
+        match (request.method.as_str(), request.path.as_str()) {
+            ("GET", "/routes") => Response::json(routes_json()),
+            (_, "/routes") => Response::method_not_allowed(),
+            (_, "/downstreams") => downstreams(request),
+            _ => Response::not_found(),
+        }
+    })
+}
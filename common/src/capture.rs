@@ -0,0 +1,187 @@
+//! Timestamped capture files, keeping datagram boundaries and arrival times.
+//!
+//! Raw server logs concatenate datagrams, so their sizes and timing are lost.
+//! A capture file instead stores each datagram as a record, so that a feed
+//! can be replayed as it was received. Files start with the magic bytes
+//! `MPROXYCAP1\n`, followed by records of, in network byte order:
+//!
+//! ```text
+//! u64     arrival time, in microseconds since the UNIX epoch
+//! u8      source address family, 4 or 6
+//! [u8]    source IP address, 4 or 16 bytes
+//! u16     source port
+//! u32     datagram length
+//! [u8]    datagram
+//! ```
+//!
+//! Captures may be appended to; the magic bytes are only written to empty files.
+//! Datagrams are at most 65535 bytes, as in UDP.
+
+use std::io::{Error, ErrorKind, Read, Result as ioResult, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Magic bytes at the start of capture files
+pub const MAGIC: &[u8] = b"MPROXYCAP1\n";
+
+/// Largest datagram read, to reject corrupt lengths before allocating
+const MAX_DATAGRAM: usize = 65535;
+
+/// A captured datagram
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Record {
+    pub time: SystemTime,
+    pub source: SocketAddr,
+    pub data: Vec<u8>,
+}
+
+/// Encode a datagram received from `source` at `time` as a capture record
+pub fn encode_record(time: SystemTime, source: SocketAddr, data: &[u8]) -> Vec<u8> {
+    let micros = time
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_micros() as u64;
+    let mut record = Vec::with_capacity(data.len() + 31);
+    record.extend_from_slice(&micros.to_be_bytes());
+    match source.ip() {
+        IpAddr::V4(ip) => {
+            record.push(4);
+            record.extend_from_slice(&ip.octets());
+        }
+        IpAddr::V6(ip) => {
+            record.push(6);
+            record.extend_from_slice(&ip.octets());
+        }
+    }
+    record.extend_from_slice(&source.port().to_be_bytes());
+    record.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    record.extend_from_slice(data);
+    record
+}
+
+/// Write the magic bytes starting a capture file
+pub fn write_header<W: Write>(writer: &mut W) -> ioResult<()> {
+    writer.write_all(MAGIC)
+}
+
+/// Reads records from a capture file
+#[derive(Debug)]
+pub struct CaptureReader<R: Read> {
+    reader: R,
+}
+
+impl<R: Read> CaptureReader<R> {
+    /// Read and check the magic bytes of a capture file
+    pub fn new(mut reader: R) -> ioResult<Self> {
+        let mut magic = [0u8; MAGIC.len()];
+        reader.read_exact(&mut magic)?;
+        if magic != MAGIC {
+            return Err(Error::new(ErrorKind::InvalidData, "not a capture file"));
+        }
+        Ok(CaptureReader { reader })
+    }
+
+    /// Read the next record, or None at the end of the file
+    pub fn next_record(&mut self) -> ioResult<Option<Record>> {
+        let mut micros = [0u8; 8];
+        match self.reader.read_exact(&mut micros) {
+            Ok(()) => (),
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e),
+        }
+        let time = UNIX_EPOCH + Duration::from_micros(u64::from_be_bytes(micros));
+        let ip = match self.read_array::<1>()?[0] {
+            4 => IpAddr::V4(Ipv4Addr::from(self.read_array::<4>()?)),
+            6 => IpAddr::V6(Ipv6Addr::from(self.read_array::<16>()?)),
+            family => {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("unknown address family {}", family),
+                ))
+            }
+        };
+        let port = u16::from_be_bytes(self.read_array()?);
+        let len = u32::from_be_bytes(self.read_array()?) as usize;
+        if len > MAX_DATAGRAM {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("datagram length {} too large", len),
+            ));
+        }
+        let mut data = vec![0u8; len];
+        self.reader.read_exact(&mut data)?;
+        Ok(Some(Record {
+            time,
+            source: SocketAddr::new(ip, port),
+            data,
+        }))
+    }
+
+    /// Unwrap the underlying reader
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
+
+    fn read_array<const N: usize>(&mut self) -> ioResult<[u8; N]> {
+        let mut bytes = [0u8; N];
+        self.reader.read_exact(&mut bytes)?;
+        Ok(bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_capture_round_trip() {
+        let records = [
+            Record {
+                time: UNIX_EPOCH + Duration::from_micros(1_700_000_000_123_456),
+                source: "10.0.0.1:5000".parse().unwrap(),
+                data: b"hello".to_vec(),
+            },
+            Record {
+                time: UNIX_EPOCH + Duration::from_micros(1_700_000_001_000_000),
+                source: "[2001:db8::1]:5001".parse().unwrap(),
+                data: vec![],
+            },
+        ];
+        let mut file = vec![];
+        write_header(&mut file).unwrap();
+        for record in &records {
+            file.extend(encode_record(record.time, record.source, &record.data));
+        }
+        let mut reader = CaptureReader::new(&file[..]).unwrap();
+        for record in &records {
+            assert_eq!(reader.next_record().unwrap().as_ref(), Some(record));
+        }
+        assert_eq!(reader.next_record().unwrap(), None);
+    }
+
+    #[test]
+    fn test_capture_invalid() {
+        assert!(CaptureReader::new(&b"MPROXYCAP0\n"[..]).is_err());
+
+        let source = "10.0.0.1:5000".parse().unwrap();
+        let mut record = encode_record(UNIX_EPOCH, source, b"data");
+        record[8] = 5;
+        let file = [MAGIC, &record].concat();
+        let mut reader = CaptureReader::new(&file[..]).unwrap();
+        assert_eq!(
+            reader.next_record().unwrap_err().kind(),
+            ErrorKind::InvalidData
+        );
+
+        // lengths above the largest UDP datagram are corrupt
+        let mut record = encode_record(UNIX_EPOCH, source, b"");
+        let len = record.len();
+        record[len - 4..].copy_from_slice(&(MAX_DATAGRAM as u32 + 1).to_be_bytes());
+        let file = [MAGIC, &record].concat();
+        let mut reader = CaptureReader::new(&file[..]).unwrap();
+        assert_eq!(
+            reader.next_record().unwrap_err().kind(),
+            ErrorKind::InvalidData
+        );
+    }
+}
@@ -0,0 +1,81 @@
+//! Configuration files, and reloading them on `SIGHUP`.
+//!
+//! A configuration file lists command line options, one per line, without
+//! the leading dashes. Options that may be repeated on the command line may
+//! be repeated in the file. Blank lines and lines starting with `#` are
+//! ignored:
+//!
+//! ```text
+//! # mproxy-forward configuration
+//! udp-listen-addr 0.0.0.0:9920
+//! udp-downstream-addr [::1]:9921
+//! udp-downstream-addr localhost:9922
+//! tee
+//! ```
+
+use std::ffi::OsString;
+use std::fs::read_to_string;
+use std::io::Result as ioResult;
+use std::path::Path;
+#[cfg(unix)]
+use std::thread::{Builder, JoinHandle};
+
+/// Read the options in configuration file `path` as command line arguments,
+/// e.g. `["--udp-listen-addr", "0.0.0.0:9920", "--tee"]`
+pub fn read_config(path: &Path) -> ioResult<Vec<OsString>> {
+    let mut args = vec![];
+    for line in read_to_string(path)?.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        match line.split_once(char::is_whitespace) {
+            Some((option, value)) => {
+                args.push(format!("--{}", option).into());
+                args.push(value.trim_start().into());
+            }
+            None => args.push(format!("--{}", line).into()),
+        }
+    }
+    Ok(args)
+}
+
+/// Call `reload` each time the process receives `SIGHUP`.
+///
+/// `SIGHUP` is blocked in the calling thread, and waited for in a new thread.
+/// Call this from the main thread before spawning any other threads, so that
+/// they inherit the blocked signal mask
+#[cfg(unix)]
+pub fn on_sighup<F>(reload: F) -> JoinHandle<()>
+where
+    F: FnMut() + Send + 'static,
+{
+    on_signal(libc::SIGHUP, "sighup", reload)
+}
+
+/// Call `handler` each time the process receives `signal`, as [`on_sighup`]
+#[cfg(unix)]
+pub(crate) fn on_signal<F>(signal: libc::c_int, name: &str, mut handler: F) -> JoinHandle<()>
+where
+    F: FnMut() + Send + 'static,
+{
+    // SAFETY: the signal set is initialized by sigemptyset before use
+    let set = unsafe {
+        let mut set: libc::sigset_t = std::mem::zeroed();
+        libc::sigemptyset(&mut set);
+        libc::sigaddset(&mut set, signal);
+        let res = libc::pthread_sigmask(libc::SIG_BLOCK, &set, std::ptr::null_mut());
+        assert_eq!(res, 0, "blocking {}", name);
+        set
+    };
+    Builder::new()
+        .name(name.to_string())
+        .spawn(move || loop {
+            let mut received = 0;
+            // SAFETY: `set` was initialized above, and `received` is a valid pointer
+            if unsafe { libc::sigwait(&set, &mut received) } == 0 && received == signal {
+                handler();
+            }
+        })
+        .unwrap()
+}
@@ -0,0 +1,76 @@
+//! Runtime control of running routes.
+//!
+//! Routes with a list of downstream addresses register a [`RouteControl`]
+//! under their route name (e.g. `0.0.0.0:9920:forward`), so that downstreams
+//! can be listed, added, and removed while the route is running, either with
+//! the admin API or when the configuration file is reloaded.
+
+use std::sync::{Arc, Mutex};
+
+use log::warn;
+
+/// Controls registered in this process, by route name
+static CONTROLS: Mutex<Vec<(String, Arc<dyn RouteControl>)>> = Mutex::new(vec![]);
+
+/// Downstreams of a running route
+pub trait RouteControl: Send + Sync {
+    /// Downstream addresses, as given when added
+    fn downstreams(&self) -> Vec<String>;
+
+    /// Start sending to downstream `addr`
+    fn add_downstream(&self, addr: &str) -> Result<(), String>;
+
+    /// Stop sending to downstream `addr`
+    fn remove_downstream(&self, addr: &str) -> Result<(), String>;
+
+    /// Stop the route. The thread handling it exits shortly after
+    fn stop(&self);
+}
+
+/// Register `control` for the route named `route`, replacing any previous
+/// control for the same route
+pub fn register_control(route: &str, control: Arc<dyn RouteControl>) {
+    let mut controls = CONTROLS.lock().unwrap();
+    controls.retain(|(name, _)| name != route);
+    controls.push((route.to_string(), control));
+}
+
+/// Remove the control for the route named `route`
+pub fn unregister_control(route: &str) {
+    CONTROLS.lock().unwrap().retain(|(name, _)| name != route);
+}
+
+/// Returns the control for the route named `route`, if registered
+pub fn control(route: &str) -> Option<Arc<dyn RouteControl>> {
+    CONTROLS
+        .lock()
+        .unwrap()
+        .iter()
+        .find(|(name, _)| name == route)
+        .map(|(_, control)| control.clone())
+}
+
+/// Stop every route with a registered control, e.g. when handing over to a
+/// new process
+pub fn stop_all() {
+    let controls = CONTROLS.lock().unwrap().clone();
+    for (_, control) in controls {
+        control.stop();
+    }
+}
+
+/// Update the downstreams of a route from `old` to `new` addresses, e.g. when
+/// a configuration file is reloaded. Downstreams added by other means, such
+/// as the admin API, are left in place
+pub fn update_downstreams(control: &dyn RouteControl, old: &[String], new: &[String]) {
+    for addr in old.iter().filter(|addr| !new.contains(addr)) {
+        if let Err(e) = control.remove_downstream(addr) {
+            warn!("{}", e);
+        }
+    }
+    for addr in new.iter().filter(|addr| !old.contains(addr)) {
+        if let Err(e) = control.add_downstream(addr) {
+            warn!("{}", e);
+        }
+    }
+}
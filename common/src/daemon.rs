@@ -0,0 +1,201 @@
+//! Process setup for daemons: pid files, and dropping root privileges once
+//! sockets and files are opened.
+//!
+//! Daemons started as root, e.g. to bind ports below 1024 or join multicast
+//! groups on restricted interfaces, switch to the user and group given by
+//! `--user` and `--group` after binding their listeners, so that no route
+//! runs as root. The pid file, if any, is written just before.
+//!
+//! Sockets bound later, e.g. for new routes when a configuration file is
+//! reloaded, are bound with the reduced privileges, so binding ports below
+//! 1024 then fails until the daemon is restarted, see [`bind_error`].
+
+use std::fs::write;
+use std::io::{Error, ErrorKind, Result as ioResult};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+#[cfg(unix)]
+use log::info;
+
+/// Set once the process has switched away from the root user
+static DROPPED: AtomicBool = AtomicBool::new(false);
+
+/// Describe a failure to bind `addr`, noting when permission was denied
+/// because root privileges were dropped, e.g. on reload
+pub fn bind_error(addr: &str, e: &Error) -> String {
+    if e.kind() == ErrorKind::PermissionDenied && DROPPED.load(Ordering::Relaxed) {
+        format!(
+            "binding {}: {} (privileges were dropped with --user, restart to bind privileged ports)",
+            addr, e
+        )
+    } else {
+        format!("binding {}: {}", addr, e)
+    }
+}
+
+/// Write the ID of this process to `path`
+pub fn write_pidfile(path: &Path) -> ioResult<()> {
+    write(path, format!("{}\n", std::process::id()))
+}
+
+/// Switch to `user` and `group`, given as names or numeric IDs, after
+/// confining the process to directory `chroot`, if given. If only `user` is
+/// given, the primary group of the user is used. Does nothing if the process
+/// already runs as the given user and group, e.g. after an upgrade
+#[cfg(unix)]
+pub fn drop_privileges(
+    user: Option<&str>,
+    group: Option<&str>,
+    chroot: Option<&Path>,
+) -> Result<(), String> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    // resolve names before /etc is out of reach
+    let (uid, user_gid) = match user {
+        Some(user) => {
+            let (uid, gid) = lookup_user(user)?;
+            (Some(uid), Some(gid))
+        }
+        None => (None, None),
+    };
+    let gid = match group {
+        Some(group) => Some(lookup_group(group)?),
+        None => user_gid,
+    };
+
+    if let Some(dir) = chroot {
+        // log sockets are already connected, and systemd notifications need
+        // a connected socket before the path is out of reach
+        crate::systemd::notify_socket();
+        let c_dir = CString::new(dir.as_os_str().as_bytes())
+            .map_err(|_| format!("invalid directory {}", dir.display()))?;
+        // SAFETY: `c_dir` is a valid NUL-terminated string
+        if unsafe { libc::chroot(c_dir.as_ptr()) } != 0 {
+            return Err(format!(
+                "chroot to {}: {}",
+                dir.display(),
+                std::io::Error::last_os_error()
+            ));
+        }
+        std::env::set_current_dir("/").map_err(|e| format!("entering chroot: {}", e))?;
+        info!("confined to {}", dir.display());
+    }
+
+    // SAFETY: the ID functions have no preconditions
+    let (euid, egid) = unsafe { (libc::geteuid(), libc::getegid()) };
+    if let Some(gid) = gid.filter(|gid| *gid != egid) {
+        // SAFETY: setgroups reads one group ID from a valid pointer
+        unsafe {
+            if euid == 0 && libc::setgroups(1, &gid) != 0 {
+                return Err(format!(
+                    "setting groups: {}",
+                    std::io::Error::last_os_error()
+                ));
+            }
+            if libc::setgid(gid) != 0 {
+                return Err(format!(
+                    "setting group {}: {}",
+                    gid,
+                    std::io::Error::last_os_error()
+                ));
+            }
+        }
+        info!("switched to group {}", gid);
+    }
+    if let Some(uid) = uid.filter(|uid| *uid != euid) {
+        // SAFETY: setuid has no memory safety preconditions
+        unsafe {
+            if libc::setuid(uid) != 0 {
+                return Err(format!(
+                    "setting user {}: {}",
+                    uid,
+                    std::io::Error::last_os_error()
+                ));
+            }
+            if uid != 0 && libc::setuid(0) == 0 {
+                return Err("root privileges could be regained".to_string());
+            }
+        }
+        DROPPED.store(euid == 0, Ordering::Relaxed);
+        info!("switched to user {}", uid);
+    }
+    Ok(())
+}
+
+/// Dropping privileges is only supported on unix
+#[cfg(not(unix))]
+pub fn drop_privileges(
+    user: Option<&str>,
+    group: Option<&str>,
+    chroot: Option<&Path>,
+) -> Result<(), String> {
+    match (user, group, chroot) {
+        (None, None, None) => Ok(()),
+        _ => Err("--user, --group and --chroot are not supported on this platform".to_string()),
+    }
+}
+
+/// Size of the buffer for user and group entries
+#[cfg(unix)]
+const ENTRY_BUFSIZE: usize = 16384;
+
+/// Returns the user ID and primary group ID of `user`, a name or numeric ID
+#[cfg(unix)]
+fn lookup_user(user: &str) -> Result<(libc::uid_t, libc::gid_t), String> {
+    let c_user = std::ffi::CString::new(user).map_err(|_| format!("invalid user '{}'", user))?;
+    let mut buf = vec![0 as libc::c_char; ENTRY_BUFSIZE];
+    // SAFETY: `passwd` and `buf` are valid for writes, and `result` is set to
+    // null or to `passwd` by getpwnam_r and getpwuid_r
+    unsafe {
+        let mut passwd: libc::passwd = std::mem::zeroed();
+        let mut result = std::ptr::null_mut();
+        match user.parse::<libc::uid_t>() {
+            Ok(uid) => libc::getpwuid_r(uid, &mut passwd, buf.as_mut_ptr(), buf.len(), &mut result),
+            Err(_) => libc::getpwnam_r(
+                c_user.as_ptr(),
+                &mut passwd,
+                buf.as_mut_ptr(),
+                buf.len(),
+                &mut result,
+            ),
+        };
+        if result.is_null() {
+            // numeric IDs without an entry have no primary group
+            return match user.parse::<libc::uid_t>() {
+                Ok(uid) => Ok((uid, libc::getegid())),
+                Err(_) => Err(format!("unknown user '{}'", user)),
+            };
+        }
+        Ok((passwd.pw_uid, passwd.pw_gid))
+    }
+}
+
+/// Returns the group ID of `group`, a name or numeric ID
+#[cfg(unix)]
+fn lookup_group(group: &str) -> Result<libc::gid_t, String> {
+    if let Ok(gid) = group.parse() {
+        return Ok(gid);
+    }
+    let c_group =
+        std::ffi::CString::new(group).map_err(|_| format!("invalid group '{}'", group))?;
+    let mut buf = vec![0 as libc::c_char; ENTRY_BUFSIZE];
+    // SAFETY: `entry` and `buf` are valid for writes, and `result` is set to
+    // null or to `entry` by getgrnam_r
+    unsafe {
+        let mut entry: libc::group = std::mem::zeroed();
+        let mut result = std::ptr::null_mut();
+        libc::getgrnam_r(
+            c_group.as_ptr(),
+            &mut entry,
+            buf.as_mut_ptr(),
+            buf.len(),
+            &mut result,
+        );
+        if result.is_null() {
+            return Err(format!("unknown group '{}'", group));
+        }
+        Ok(entry.gr_gid)
+    }
+}
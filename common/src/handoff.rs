@@ -0,0 +1,536 @@
+//! Zero-downtime upgrades, handing listening sockets over to a new process.
+//!
+//! On `SIGUSR2` (see [`on_upgrade`]), the running process starts a new process
+//! with the same command line, and passes it the UDP sockets and TCP listeners
+//! bound with [`udp_socket`] and [`tcp_listener`] over a Unix socket with
+//! `SCM_RIGHTS`. The Unix socket is created in a new directory accessible only
+//! to the user of the process, and the sockets are only sent to a peer with the
+//! same user ID and the process ID of the new process, so that no other
+//! process can take them. The new process uses these in place of binding the same
+//! addresses, so that no datagram or connection is refused during the upgrade:
+//!
+//! ```text
+//! kill -USR2 $(pidof mproxy-reverse)
+//! ```
+//!
+//! Once the new process is ready, the previous process stops accepting
+//! connections and stops its UDP routes, then exits when its connected clients
+//! have disconnected, or after the drain timeout. Multicast sockets are not
+//! handed over: both processes join the group, so that clients of the
+//! previous process keep receiving messages while it drains. If the new
+//! process fails to start, the previous process continues serving.
+//!
+//! [`udp_socket`]: crate::systemd::udp_socket
+//! [`tcp_listener`]: crate::systemd::tcp_listener
+
+use std::io::Result as ioResult;
+#[cfg(unix)]
+use std::net::SocketAddr;
+use std::net::{TcpListener, TcpStream};
+#[cfg(unix)]
+use std::os::unix::io::{AsRawFd, RawFd};
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+#[cfg(unix)]
+use std::sync::Mutex;
+#[cfg(unix)]
+use std::thread::{sleep, JoinHandle};
+use std::time::Duration;
+#[cfg(unix)]
+use std::time::Instant;
+
+#[cfg(unix)]
+use log::{error, info, warn};
+
+#[cfg(unix)]
+use crate::config::on_signal;
+#[cfg(unix)]
+use crate::control::stop_all;
+#[cfg(unix)]
+use crate::metrics::routes;
+#[cfg(unix)]
+use crate::systemd::{inherit, notify, take_inherited};
+
+/// Environment variable naming the Unix socket of the previous process
+#[cfg(unix)]
+const HANDOFF_ENV: &str = "MPROXY_HANDOFF";
+
+/// Time allowed for the new process to connect and become ready
+#[cfg(unix)]
+const HANDOFF_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Interval at which listeners notice the process is draining
+const STOP_POLL: Duration = Duration::from_millis(500);
+
+/// Maximum number of sockets handed over, as limited by `SCM_MAX_FD`
+#[cfg(unix)]
+const MAX_FDS: usize = 253;
+
+/// Set once a new process is ready and this process is draining
+static DRAINING: AtomicBool = AtomicBool::new(false);
+
+/// Sockets bound by this process, which are handed over on upgrade
+#[cfg(unix)]
+static BOUND: Mutex<Vec<(RawFd, SocketAddr)>> = Mutex::new(vec![]);
+
+/// Connection to the previous process, until this process is ready
+#[cfg(unix)]
+static PREVIOUS: Mutex<Option<UnixStream>> = Mutex::new(None);
+
+/// Returns true once this process has handed its sockets over to a new
+/// process, and is draining
+pub fn draining() -> bool {
+    DRAINING.load(Ordering::Relaxed)
+}
+
+/// Iterate over connections accepted by `listener`, as
+/// [`TcpListener::incoming`], until this process is draining. Listeners handed
+/// over to a new process are shared with it until then
+pub fn incoming(listener: &TcpListener) -> impl Iterator<Item = ioResult<TcpStream>> + '_ {
+    // a shared listener may have been readable for the other process only
+    #[cfg(unix)]
+    let _ = listener.set_nonblocking(true);
+    std::iter::from_fn(move || loop {
+        if draining() {
+            return None;
+        }
+        #[cfg(unix)]
+        if !readable(listener.as_raw_fd(), STOP_POLL) {
+            continue;
+        }
+        match listener.accept() {
+            Ok((stream, _)) => {
+                let _ = stream.set_nonblocking(false);
+                return Some(Ok(stream));
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {}
+            Err(e) => return Some(Err(e)),
+        }
+    })
+}
+
+/// Record socket `fd` bound to `addr`, to be handed over on upgrade
+#[cfg(unix)]
+pub(crate) fn record(fd: RawFd, addr: SocketAddr) {
+    let mut bound = BOUND.lock().unwrap();
+    bound.retain(|(bound_fd, _)| *bound_fd != fd);
+    bound.push((fd, addr));
+}
+
+/// Receive the sockets of the previous process, if this process was started
+/// by [`on_upgrade`]
+#[cfg(unix)]
+pub(crate) fn receive() -> Vec<RawFd> {
+    let path = match std::env::var_os(HANDOFF_ENV) {
+        Some(path) => path,
+        None => return vec![],
+    };
+    let received = UnixStream::connect(&path).and_then(|stream| {
+        let fds = recv_fds(&stream)?;
+        Ok((stream, fds))
+    });
+    match received {
+        Ok((stream, fds)) => {
+            info!("received {} sockets from previous process", fds.len());
+            *PREVIOUS.lock().unwrap() = Some(stream);
+            fds
+        }
+        Err(e) => {
+            error!("receiving sockets from {:?}: {}", path, e);
+            vec![]
+        }
+    }
+}
+
+/// Notify the previous process, if any, that this process is ready
+#[cfg(unix)]
+pub(crate) fn complete() {
+    // receive sockets from the previous process, if not already received
+    take_inherited(|_| false);
+    if let Some(mut stream) = PREVIOUS.lock().unwrap().take() {
+        use std::io::Write;
+
+        if let Err(e) = stream.write_all(b"READY\n") {
+            warn!("notifying previous process: {}", e);
+        }
+    }
+}
+
+/// On each `SIGUSR2`, start a new process with the same command line and hand
+/// over the sockets of this process. Once the new process is ready, stop
+/// accepting connections and stop UDP routes, then exit when connected clients
+/// have disconnected, or after `drain`.
+///
+/// As with [`on_sighup`](crate::config::on_sighup), call this from the main
+/// thread before spawning any other threads
+#[cfg(unix)]
+pub fn on_upgrade(drain: Duration) -> JoinHandle<()> {
+    on_signal(libc::SIGUSR2, "sigusr2", move || {
+        if draining() {
+            return;
+        }
+        info!("upgrading: starting new process");
+        let pid = match upgrade() {
+            Ok(pid) => pid,
+            Err(e) => {
+                error!("upgrading: {}", e);
+                return;
+            }
+        };
+        info!("upgrading: handed over to process {}, draining", pid);
+        notify(&format!("MAINPID={}", pid));
+        DRAINING.store(true, Ordering::Relaxed);
+        stop_all();
+        drain_clients(drain);
+        info!("upgrading: drained, exiting");
+        std::process::exit(0);
+    })
+}
+
+/// Start a new process, and hand over the sockets of this process.
+/// Returns the process ID of the new process once it is ready
+#[cfg(unix)]
+fn upgrade() -> Result<u32, String> {
+    use std::io::{BufRead, BufReader};
+    use std::os::unix::fs::DirBuilderExt;
+    use std::process::Command;
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    // a new directory, which fails if the name is taken, keeps other users
+    // from connecting to the socket or replacing it
+    let nonce = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .subsec_nanos();
+    let dir = std::env::temp_dir().join(format!(
+        "mproxy-handoff-{}-{:08x}",
+        std::process::id(),
+        nonce
+    ));
+    std::fs::DirBuilder::new()
+        .mode(0o700)
+        .create(&dir)
+        .map_err(|e| format!("creating {}: {}", dir.display(), e))?;
+    let path = dir.join("handoff.sock");
+    let listener = match UnixListener::bind(&path) {
+        Ok(listener) => listener,
+        Err(e) => {
+            let _ = std::fs::remove_dir(&dir);
+            return Err(format!("binding {}: {}", path.display(), e));
+        }
+    };
+
+    // the executable may have been replaced, so start it by its original path
+    let mut args = std::env::args_os();
+    let program = args.next().ok_or("missing program name")?;
+    let mut child = Command::new(&program)
+        .args(args)
+        .env(HANDOFF_ENV, &path)
+        .spawn()
+        .map_err(|e| format!("starting {:?}: {}", program, e))?;
+
+    let handed_over = (|| {
+        let started = Instant::now();
+        let stream = loop {
+            if !readable(listener.as_raw_fd(), STOP_POLL) {
+                if let Ok(Some(status)) = child.try_wait() {
+                    return Err(format!("new process exited with {}", status));
+                }
+                if started.elapsed() > HANDOFF_TIMEOUT {
+                    return Err("timed out waiting for new process".to_string());
+                }
+                continue;
+            }
+            let (stream, _) = listener.accept().map_err(|e| e.to_string())?;
+            // SAFETY: geteuid has no preconditions
+            let uid = unsafe { libc::geteuid() };
+            match peer_credentials(&stream) {
+                Ok((peer_uid, peer_pid))
+                    if peer_uid == uid && peer_pid.is_none_or(|p| p == child.id()) =>
+                {
+                    break stream;
+                }
+                Ok((peer_uid, peer_pid)) => warn!(
+                    "upgrading: refusing connection from user {} process {:?}",
+                    peer_uid, peer_pid
+                ),
+                Err(e) => warn!("upgrading: checking connection: {}", e),
+            }
+        };
+        send_fds(&stream, &bound()).map_err(|e| format!("sending sockets: {}", e))?;
+
+        let mut line = String::new();
+        stream
+            .set_read_timeout(Some(HANDOFF_TIMEOUT))
+            .and_then(|()| BufReader::new(&stream).read_line(&mut line))
+            .map_err(|e| format!("waiting for new process: {}", e))?;
+        match line.trim() {
+            "READY" => Ok(()),
+            _ => Err("new process exited before it was ready".to_string()),
+        }
+    })();
+    let _ = std::fs::remove_file(&path);
+    let _ = std::fs::remove_dir(&dir);
+
+    match handed_over {
+        Ok(()) => Ok(child.id()),
+        Err(e) => {
+            let _ = child.kill();
+            let _ = child.wait();
+            Err(e)
+        }
+    }
+}
+
+/// Sockets bound by this process which are still open
+#[cfg(unix)]
+fn bound() -> Vec<RawFd> {
+    let mut bound = BOUND.lock().unwrap();
+    bound.retain(|(fd, addr)| match inherit(*fd) {
+        Some(socket) => {
+            let open = socket.local_addr() == Some(*addr);
+            let _ = socket.into_raw_fd();
+            open
+        }
+        None => false,
+    });
+    bound.iter().map(|(fd, _)| *fd).collect()
+}
+
+/// Wait until no clients are connected to any route, or until `timeout`
+#[cfg(unix)]
+fn drain_clients(timeout: Duration) {
+    let started = Instant::now();
+    // allow stopped routes and listeners to finish handling received messages
+    sleep(STOP_POLL);
+    loop {
+        let clients: u64 = routes()
+            .iter()
+            .flat_map(|route| route.downstreams())
+            .map(|downstream| downstream.client_count())
+            .sum();
+        if clients == 0 {
+            break;
+        }
+        if started.elapsed() >= timeout {
+            warn!(
+                "upgrading: closing {} clients after draining for {:?}",
+                clients, timeout
+            );
+            break;
+        }
+        sleep(STOP_POLL);
+    }
+}
+
+/// Wait up to `timeout` for `fd` to become readable
+#[cfg(unix)]
+fn readable(fd: RawFd, timeout: Duration) -> bool {
+    let mut pollfd = libc::pollfd {
+        fd,
+        events: libc::POLLIN,
+        revents: 0,
+    };
+    // SAFETY: `pollfd` is a single valid entry
+    unsafe { libc::poll(&mut pollfd, 1, timeout.as_millis() as libc::c_int) > 0 }
+}
+
+/// User ID and, where available, process ID of the peer of `stream`
+#[cfg(unix)]
+fn peer_credentials(stream: &UnixStream) -> ioResult<(libc::uid_t, Option<u32>)> {
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    {
+        // SAFETY: `cred` is a ucred valid for writes of the given length
+        unsafe {
+            let mut cred: libc::ucred = std::mem::zeroed();
+            let mut len = std::mem::size_of::<libc::ucred>() as libc::socklen_t;
+            if libc::getsockopt(
+                stream.as_raw_fd(),
+                libc::SOL_SOCKET,
+                libc::SO_PEERCRED,
+                &mut cred as *mut libc::ucred as *mut libc::c_void,
+                &mut len,
+            ) != 0
+            {
+                return Err(std::io::Error::last_os_error());
+            }
+            Ok((cred.uid, Some(cred.pid as u32)))
+        }
+    }
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    {
+        // SAFETY: `uid` and `gid` are valid for writes
+        unsafe {
+            let (mut uid, mut gid) = (0, 0);
+            if libc::getpeereid(stream.as_raw_fd(), &mut uid, &mut gid) != 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            Ok((uid, None))
+        }
+    }
+}
+
+/// Send file descriptors `fds` over `stream`, with their count as a line
+#[cfg(unix)]
+fn send_fds(stream: &UnixStream, fds: &[RawFd]) -> ioResult<()> {
+    if fds.len() > MAX_FDS {
+        return Err(std::io::Error::other(format!(
+            "{} sockets exceeds the limit of {}",
+            fds.len(),
+            MAX_FDS
+        )));
+    }
+    let payload = format!("{}\n", fds.len());
+    let data_len = std::mem::size_of_val(fds);
+    // SAFETY: CMSG_SPACE only computes a length
+    let space = unsafe { libc::CMSG_SPACE(data_len as u32) } as usize;
+    // u64 elements align the control buffer for cmsghdr
+    let mut control = vec![0u64; space.div_ceil(8)];
+    let mut iov = libc::iovec {
+        iov_base: payload.as_ptr() as *mut libc::c_void,
+        iov_len: payload.len(),
+    };
+    // SAFETY: the message points to `iov` and `control`, which outlive the
+    // call, and the control message header and data fit in `control`
+    let sent = unsafe {
+        let mut msg: libc::msghdr = std::mem::zeroed();
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        if !fds.is_empty() {
+            msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+            msg.msg_controllen = space as _;
+            let cmsg = libc::CMSG_FIRSTHDR(&msg);
+            (*cmsg).cmsg_level = libc::SOL_SOCKET;
+            (*cmsg).cmsg_type = libc::SCM_RIGHTS;
+            (*cmsg).cmsg_len = libc::CMSG_LEN(data_len as u32) as _;
+            std::ptr::copy_nonoverlapping(
+                fds.as_ptr(),
+                libc::CMSG_DATA(cmsg) as *mut RawFd,
+                fds.len(),
+            );
+        }
+        libc::sendmsg(stream.as_raw_fd(), &msg, 0)
+    };
+    if sent < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Receive file descriptors sent with [`send_fds`] over `stream`
+#[cfg(unix)]
+fn recv_fds(stream: &UnixStream) -> ioResult<Vec<RawFd>> {
+    let mut payload = [0u8; 16];
+    // SAFETY: CMSG_SPACE only computes a length
+    let space =
+        unsafe { libc::CMSG_SPACE((MAX_FDS * std::mem::size_of::<RawFd>()) as u32) } as usize;
+    let mut control = vec![0u64; space.div_ceil(8)];
+    let mut iov = libc::iovec {
+        iov_base: payload.as_mut_ptr() as *mut libc::c_void,
+        iov_len: payload.len(),
+    };
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    let flags = libc::MSG_CMSG_CLOEXEC;
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    let flags = 0;
+
+    let mut fds = vec![];
+    // SAFETY: the message points to `iov` and `control`, which outlive the
+    // call. Control messages are read within the length set by recvmsg
+    let (received, truncated) = unsafe {
+        let mut msg: libc::msghdr = std::mem::zeroed();
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+        msg.msg_controllen = space as _;
+        let received = libc::recvmsg(stream.as_raw_fd(), &mut msg, flags);
+        if received < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+        while !cmsg.is_null() {
+            if (*cmsg).cmsg_level == libc::SOL_SOCKET && (*cmsg).cmsg_type == libc::SCM_RIGHTS {
+                let data = libc::CMSG_DATA(cmsg) as *const RawFd;
+                let len = (*cmsg).cmsg_len as usize - libc::CMSG_LEN(0) as usize;
+                for i in 0..len / std::mem::size_of::<RawFd>() {
+                    fds.push(std::ptr::read_unaligned(data.add(i)));
+                }
+            }
+            cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+        }
+        (received as usize, msg.msg_flags & libc::MSG_CTRUNC != 0)
+    };
+    let expected = std::str::from_utf8(&payload[0..received])
+        .ok()
+        .and_then(|count| count.trim().parse::<usize>().ok());
+    if truncated || expected != Some(fds.len()) {
+        return Err(std::io::Error::other(format!(
+            "expected {:?} sockets, received {}",
+            expected,
+            fds.len()
+        )));
+    }
+    Ok(fds)
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::net::UdpSocket;
+    use std::os::unix::io::FromRawFd;
+
+    use super::*;
+
+    #[test]
+    fn test_send_recv_fds() {
+        let (sender, receiver) = UnixStream::pair().unwrap();
+        let sockets: Vec<UdpSocket> = (0..3)
+            .map(|_| UdpSocket::bind("127.0.0.1:0").unwrap())
+            .collect();
+        let fds: Vec<RawFd> = sockets.iter().map(|s| s.as_raw_fd()).collect();
+        send_fds(&sender, &fds).unwrap();
+
+        let received = recv_fds(&receiver).unwrap();
+        assert_eq!(received.len(), sockets.len());
+        for (fd, socket) in received.into_iter().zip(&sockets) {
+            assert!(!fds.contains(&fd));
+            // SAFETY: `fd` was just received, and is owned by nothing else
+            let copy = unsafe { UdpSocket::from_raw_fd(fd) };
+            assert_eq!(copy.local_addr().unwrap(), socket.local_addr().unwrap());
+        }
+    }
+
+    #[test]
+    fn test_send_recv_no_fds() {
+        let (sender, receiver) = UnixStream::pair().unwrap();
+        send_fds(&sender, &[]).unwrap();
+        assert!(recv_fds(&receiver).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_send_too_many_fds() {
+        let (sender, _receiver) = UnixStream::pair().unwrap();
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let fds = vec![socket.as_raw_fd(); MAX_FDS + 1];
+        assert!(send_fds(&sender, &fds).is_err());
+    }
+
+    #[test]
+    fn test_recv_fds_count_mismatch() {
+        use std::io::Write;
+
+        // a count without the sockets is refused
+        let (mut sender, receiver) = UnixStream::pair().unwrap();
+        sender.write_all(b"2\n").unwrap();
+        assert!(recv_fds(&receiver).is_err());
+    }
+
+    #[test]
+    fn test_peer_credentials() {
+        let (first, _second) = UnixStream::pair().unwrap();
+        let (uid, pid) = peer_credentials(&first).unwrap();
+        // SAFETY: geteuid has no preconditions
+        assert_eq!(uid, unsafe { libc::geteuid() });
+        assert!(pid.is_none_or(|pid| pid == std::process::id()));
+    }
+}
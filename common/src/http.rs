@@ -0,0 +1,230 @@
+//! Minimal HTTP/1.1 request parsing and responses, for the HTTP endpoints
+//! served by mproxy daemons.
+
+use std::io::{BufRead, BufReader, BufWriter, Error, ErrorKind, Read, Result as ioResult, Write};
+use std::thread::{Builder, JoinHandle};
+use std::time::Duration;
+
+use crate::handoff::incoming;
+use crate::systemd::tcp_listener;
+
+/// Maximum size of a request line plus headers
+const MAX_HEAD: usize = 8192;
+
+/// Maximum size of a request body accepted by [`serve`]
+const MAX_BODY: usize = 65536;
+
+/// Time allowed for a client of [`serve`] to send a complete request
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// HTTP request line and headers
+#[derive(Debug)]
+pub struct Request {
+    pub method: String,
+    pub path: String,
+    query: Vec<(String, String)>,
+    headers: Vec<(String, String)>,
+}
+
+impl Request {
+    /// Returns the value of header `name`, ignoring case
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// Returns the value of query string parameter `name`
+    pub fn param(&self, name: &str) -> Option<&str> {
+        self.query
+            .iter()
+            .find(|(k, _)| k == name)
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+/// Read a request line and headers from `reader`.
+/// The request body, if any, is left unread
+pub fn read_request(reader: &mut impl BufRead) -> ioResult<Request> {
+    let mut head_size = 0;
+    let mut lines = vec![];
+    loop {
+        let mut line = String::new();
+        let c = reader.read_line(&mut line)?;
+        head_size += c;
+        if c == 0 || head_size > MAX_HEAD {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "incomplete request head",
+            ));
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        lines.push(line.to_string());
+    }
+
+    let mut request_line = lines.first().map_or("", |l| l.as_str()).split_whitespace();
+    let (method, target) = match (request_line.next(), request_line.next()) {
+        (Some(method), Some(target)) => (method.to_string(), target),
+        _ => return Err(Error::new(ErrorKind::InvalidData, "invalid request line")),
+    };
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    let query = query
+        .split('&')
+        .filter(|p| !p.is_empty())
+        .map(|p| {
+            let (k, v) = p.split_once('=').unwrap_or((p, ""));
+            (percent_decode(k), percent_decode(v))
+        })
+        .collect();
+    let headers = lines
+        .iter()
+        .skip(1)
+        .filter_map(|h| h.split_once(':'))
+        .map(|(k, v)| (k.trim().to_string(), v.trim().to_string()))
+        .collect();
+
+    Ok(Request {
+        method,
+        path: percent_decode(path),
+        query,
+        headers,
+    })
+}
+
+/// Decode `%XX` escapes and `+` in a URL component
+fn percent_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'%' if i + 2 < bytes.len() => {
+                let hex = |b: u8| (b as char).to_digit(16);
+                match (hex(bytes[i + 1]), hex(bytes[i + 2])) {
+                    (Some(high), Some(low)) => {
+                        decoded.push((high * 16 + low) as u8);
+                        i += 3;
+                        continue;
+                    }
+                    _ => decoded.push(b'%'),
+                }
+            }
+            b'+' => decoded.push(b' '),
+            b => decoded.push(b),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+/// Write a complete response with a body, and close the connection
+pub fn respond(
+    stream: &mut impl Write,
+    status: &str,
+    content_type: &str,
+    body: &[u8],
+) -> ioResult<()> {
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        status,
+        content_type,
+        body.len()
+    )?;
+    stream.write_all(body)?;
+    stream.flush()
+}
+
+/// Response returned by a request handler passed to [`serve`]
+#[derive(Debug)]
+pub struct Response {
+    pub status: &'static str,
+    pub content_type: &'static str,
+    pub body: Vec<u8>,
+}
+
+impl Response {
+    pub fn new(status: &'static str, content_type: &'static str, body: Vec<u8>) -> Self {
+        Response {
+            status,
+            content_type,
+            body,
+        }
+    }
+
+    /// `200 OK` with a JSON body
+    pub fn json(body: String) -> Self {
+        Response::new("200 OK", "application/json", body.into_bytes())
+    }
+
+    /// Plain text error response
+    pub fn error(status: &'static str, message: &str) -> Self {
+        Response::new(status, "text/plain", format!("{}\n", message).into_bytes())
+    }
+
+    pub fn not_found() -> Self {
+        Response::error("404 Not Found", "not found")
+    }
+
+    pub fn method_not_allowed() -> Self {
+        Response::error("405 Method Not Allowed", "method not allowed")
+    }
+}
+
+/// Serve HTTP requests on `listen_addr`, answering each with the response
+/// returned by `handler` for the request and its body. Binds a TCP listener,
+/// and spawns a thread named `{listen_addr}:{name}` handling one request per
+/// connection in turn
+pub fn serve<F>(listen_addr: String, name: &str, handler: F) -> JoinHandle<()>
+where
+    F: Fn(&Request, &[u8]) -> Response + Send + 'static,
+{
+    let listener =
+        tcp_listener(&listen_addr).unwrap_or_else(|e| panic!("binding {} listener: {}", name, e));
+    Builder::new()
+        .name(format!("{}:{}", listen_addr, name))
+        .spawn(move || {
+            for stream in incoming(&listener).flatten() {
+                let _ = stream.set_read_timeout(Some(REQUEST_TIMEOUT));
+                let mut writer = match stream.try_clone() {
+                    Ok(w) => BufWriter::new(w),
+                    Err(_) => continue,
+                };
+                let mut reader = BufReader::new(stream);
+                let response = match read_request(&mut reader) {
+                    Ok(request) => match read_body(&request, &mut reader) {
+                        Ok(body) => handler(&request, &body),
+                        Err(e) => Response::error("400 Bad Request", &e.to_string()),
+                    },
+                    Err(_) => Response::error("400 Bad Request", "bad request"),
+                };
+                let _ = respond(
+                    &mut writer,
+                    response.status,
+                    response.content_type,
+                    &response.body,
+                );
+            }
+        })
+        .unwrap()
+}
+
+/// Read the request body, as given by the Content-Length header
+fn read_body(request: &Request, reader: &mut impl Read) -> ioResult<Vec<u8>> {
+    let length: usize = match request.header("Content-Length") {
+        Some(length) => length
+            .parse()
+            .map_err(|_| Error::new(ErrorKind::InvalidData, "invalid content length"))?,
+        None => return Ok(vec![]),
+    };
+    if length > MAX_BODY {
+        return Err(Error::new(ErrorKind::InvalidData, "request body too large"));
+    }
+    let mut body = vec![0u8; length];
+    reader.read_exact(&mut body)?;
+    Ok(body)
+}
@@ -0,0 +1,36 @@
+//! Multicast Network Dispatcher and Proxy
+//!
+//! # MPROXY: Common
+//! Utilities shared by the mproxy client, server, and proxies: a minimal
+//! HTTP/1.1 server, leveled diagnostic logging, process-wide traffic metrics
+//! exported in Prometheus text format, an admin API listing routes as JSON and
+//! changing their downstreams at runtime, configuration files reloaded on
+//! `SIGHUP`, live taps copying the traffic of a route to a socket, systemd
+//! socket activation and readiness notifications, zero-downtime upgrades
+//! handing listening sockets over to a new process, supervision restarting
+//! failed routes, pid files and dropping root privileges, token-bucket rate
+//! limits, timestamped capture and pcap files, and watching directories for new
+//! files.
+//!
+//! ### See Also
+//! - [mproxy-client](https://docs.rs/mproxy-client/)
+//! - [mproxy-server](https://docs.rs/mproxy-server/)
+//! - [mproxy-forward](https://docs.rs/mproxy-forward/)
+//! - [mproxy-reverse](https://docs.rs/mproxy-reverse/)
+//!
+
+pub mod admin;
+pub mod capture;
+pub mod config;
+pub mod control;
+pub mod daemon;
+pub mod handoff;
+pub mod http;
+pub mod logging;
+pub mod metrics;
+pub mod pcap;
+pub mod ratelimit;
+pub mod supervisor;
+pub mod systemd;
+pub mod tap;
+pub mod watch;
@@ -0,0 +1,420 @@
+//! Leveled diagnostic logging for the `log` facade.
+//!
+//! Diagnostics are written to stderr, or to the local syslog or journald
+//! socket on unix, so that stdout is left for data copied with `--tee`.
+//! Records are formatted as text, or as one JSON object per line:
+//!
+//! ```text
+//! 2024-01-01T00:00:00.000Z WARN  mproxy_client::downstream: downstream 127.0.0.1:9920: recovered
+//! {"timestamp":"2024-01-01T00:00:00.000Z","level":"WARN","target":"mproxy_client::downstream","thread":"main","message":"..."}
+//! ```
+//!
+//! Records are tagged with the module path of the call site as the target,
+//! e.g. `mproxy_reverse::bus`, and filtered by level, optionally per target
+//! with directives such as `mproxy_client=debug,warn` (see [`LogLevels`]).
+
+use std::fmt::Write as _;
+use std::io::{stderr, Error, ErrorKind, Result as ioResult, Write};
+#[cfg(unix)]
+use std::os::unix::net::UnixDatagram;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub use log::LevelFilter;
+use log::{Level, Log, Metadata, Record};
+
+/// Path of the local syslog socket
+#[cfg(unix)]
+const SYSLOG_SOCKET: &str = "/dev/log";
+
+/// Path of the journald native protocol socket
+#[cfg(unix)]
+const JOURNALD_SOCKET: &str = "/run/systemd/journal/socket";
+
+/// Syslog facility code for system daemons
+#[cfg(unix)]
+const FACILITY_DAEMON: u8 = 3;
+
+/// Format of each log record
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum LogFormat {
+    /// Timestamp, level, target, and message separated by spaces
+    #[default]
+    Text,
+    /// One JSON object per record
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format!("unknown log format '{}'", s)),
+        }
+    }
+}
+
+/// Destination of log records
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum LogOutput {
+    #[default]
+    Stderr,
+    /// Local syslog daemon, at `/dev/log`
+    #[cfg(unix)]
+    Syslog,
+    /// systemd journal, using the native protocol
+    #[cfg(unix)]
+    Journald,
+}
+
+impl FromStr for LogOutput {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "stderr" => Ok(LogOutput::Stderr),
+            #[cfg(unix)]
+            "syslog" => Ok(LogOutput::Syslog),
+            #[cfg(unix)]
+            "journald" => Ok(LogOutput::Journald),
+            _ => Err(format!("unknown log output '{}'", s)),
+        }
+    }
+}
+
+/// Log level directives: a default level, and levels for targets.
+///
+/// Parsed from a comma-separated list of `LEVEL` or `TARGET=LEVEL` entries,
+/// e.g. `mproxy_client=debug,warn`. A target matches records whose target
+/// is the same module path or a submodule of it, and the longest matching
+/// target takes precedence. Records of other targets use the default level,
+/// which is `info` unless given
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LogLevels {
+    default: LevelFilter,
+    targets: Vec<(String, LevelFilter)>,
+}
+
+impl LogLevels {
+    /// Level of records with target `target`
+    pub fn level(&self, target: &str) -> LevelFilter {
+        self.targets
+            .iter()
+            .filter(|(prefix, _)| {
+                target
+                    .strip_prefix(prefix.as_str())
+                    .is_some_and(|rest| rest.is_empty() || rest.starts_with("::"))
+            })
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map_or(self.default, |(_, level)| *level)
+    }
+
+    /// Most verbose level of any target
+    pub fn max_level(&self) -> LevelFilter {
+        self.targets
+            .iter()
+            .map(|(_, level)| *level)
+            .fold(self.default, Ord::max)
+    }
+}
+
+impl Default for LogLevels {
+    fn default() -> Self {
+        LevelFilter::Info.into()
+    }
+}
+
+impl From<LevelFilter> for LogLevels {
+    fn from(level: LevelFilter) -> Self {
+        LogLevels {
+            default: level,
+            targets: vec![],
+        }
+    }
+}
+
+impl FromStr for LogLevels {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut levels = LogLevels::default();
+        for directive in s.split(',').map(str::trim).filter(|d| !d.is_empty()) {
+            let parse = |level: &str| {
+                level
+                    .parse::<LevelFilter>()
+                    .map_err(|_| format!("unknown log level '{}'", level))
+            };
+            match directive.split_once('=') {
+                Some((target, level)) if !target.is_empty() => {
+                    let target = target.replace('-', "_");
+                    levels.targets.retain(|(t, _)| *t != target);
+                    levels.targets.push((target, parse(level)?));
+                }
+                Some(_) => return Err(format!("missing target in '{}'", directive)),
+                None => levels.default = parse(directive)?,
+            }
+        }
+        Ok(levels)
+    }
+}
+
+enum Sink {
+    Stderr,
+    #[cfg(unix)]
+    Syslog(UnixDatagram),
+    #[cfg(unix)]
+    Journald(UnixDatagram),
+}
+
+struct Logger {
+    levels: LogLevels,
+    format: LogFormat,
+    ident: String,
+    sink: Mutex<Sink>,
+}
+
+/// Install the process-wide logger, writing records at or above the level of
+/// their target in `levels` to `output`. Fails if the syslog or journald
+/// socket cannot be reached, or if a logger was already installed
+pub fn init_logging(
+    levels: impl Into<LogLevels>,
+    format: LogFormat,
+    output: LogOutput,
+) -> ioResult<()> {
+    let levels = levels.into();
+    let sink = match output {
+        LogOutput::Stderr => Sink::Stderr,
+        #[cfg(unix)]
+        LogOutput::Syslog => {
+            let socket = UnixDatagram::unbound()?;
+            socket.connect(SYSLOG_SOCKET)?;
+            Sink::Syslog(socket)
+        }
+        #[cfg(unix)]
+        LogOutput::Journald => {
+            let socket = UnixDatagram::unbound()?;
+            socket.connect(JOURNALD_SOCKET)?;
+            Sink::Journald(socket)
+        }
+    };
+    let ident = std::env::args()
+        .next()
+        .and_then(|arg0| arg0.rsplit(['/', '\\']).next().map(str::to_string))
+        .unwrap_or_else(|| "mproxy".to_string());
+    let max_level = levels.max_level();
+    let logger = Logger {
+        levels,
+        format,
+        ident,
+        sink: Mutex::new(sink),
+    };
+    log::set_boxed_logger(Box::new(logger))
+        .map_err(|e| Error::new(ErrorKind::AlreadyExists, e.to_string()))?;
+    log::set_max_level(max_level);
+    Ok(())
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.levels.level(metadata.target())
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let thread = std::thread::current();
+        let thread = thread.name().unwrap_or("unnamed");
+        let message = record.args().to_string();
+        let mut sink = self.sink.lock().unwrap();
+        let _ = match &mut *sink {
+            Sink::Stderr => {
+                let line = match self.format {
+                    LogFormat::Text => format!(
+                        "{} {:<5} {}: {}\n",
+                        timestamp(),
+                        record.level(),
+                        record.target(),
+                        message
+                    ),
+                    LogFormat::Json => json_record(record, thread, &message) + "\n",
+                };
+                stderr().lock().write_all(line.as_bytes())
+            }
+            #[cfg(unix)]
+            Sink::Syslog(socket) => {
+                let body = match self.format {
+                    LogFormat::Text => format!("{}: {}", record.target(), message),
+                    LogFormat::Json => json_record(record, thread, &message),
+                };
+                let line = format!(
+                    "<{}>{}[{}]: {}",
+                    FACILITY_DAEMON * 8 + severity(record.level()),
+                    self.ident,
+                    std::process::id(),
+                    body
+                );
+                socket.send(line.as_bytes()).map(|_| ())
+            }
+            #[cfg(unix)]
+            Sink::Journald(socket) => {
+                let message = match self.format {
+                    LogFormat::Text => message,
+                    LogFormat::Json => json_record(record, thread, &message),
+                };
+                let mut datagram = vec![];
+                for (field, value) in [
+                    ("PRIORITY", severity(record.level()).to_string().as_str()),
+                    ("SYSLOG_IDENTIFIER", self.ident.as_str()),
+                    ("TARGET", record.target()),
+                    ("THREAD", thread),
+                    ("MESSAGE", message.as_str()),
+                ] {
+                    journal_field(&mut datagram, field, value);
+                }
+                socket.send(&datagram).map(|_| ())
+            }
+        };
+    }
+
+    fn flush(&self) {
+        let _ = stderr().flush();
+    }
+}
+
+/// Syslog severity of a log level
+#[cfg(unix)]
+fn severity(level: Level) -> u8 {
+    match level {
+        Level::Error => 3,
+        Level::Warn => 4,
+        Level::Info => 6,
+        Level::Debug | Level::Trace => 7,
+    }
+}
+
+/// Append a field to a journald native protocol datagram. Values containing
+/// newlines are length-prefixed
+#[cfg(unix)]
+fn journal_field(datagram: &mut Vec<u8>, field: &str, value: &str) {
+    datagram.extend_from_slice(field.as_bytes());
+    if value.contains('\n') {
+        datagram.push(b'\n');
+        datagram.extend_from_slice(&(value.len() as u64).to_le_bytes());
+    } else {
+        datagram.push(b'=');
+    }
+    datagram.extend_from_slice(value.as_bytes());
+    datagram.push(b'\n');
+}
+
+/// Format a record as a single-line JSON object
+fn json_record(record: &Record, thread: &str, message: &str) -> String {
+    format!(
+        "{{\"timestamp\":\"{}\",\"level\":\"{}\",\"target\":{},\"thread\":{},\"message\":{}}}",
+        timestamp(),
+        record.level(),
+        json_string(record.target()),
+        json_string(thread),
+        json_string(message)
+    )
+}
+
+/// Quote and escape a JSON string
+pub fn json_string(s: &str) -> String {
+    let mut quoted = String::with_capacity(s.len() + 2);
+    quoted.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(quoted, "\\u{:04x}", c as u32);
+            }
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// Current UTC time in RFC 3339 format, with millisecond precision
+fn timestamp() -> String {
+    rfc3339(SystemTime::now())
+}
+
+/// Format `time` as UTC in RFC 3339 format, with millisecond precision
+pub(crate) fn rfc3339(time: SystemTime) -> String {
+    let now = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = now.as_secs();
+    let (days, rem) = ((secs / 86400) as i64, secs % 86400);
+
+    // convert days since the epoch to a civil date
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + (month <= 2) as i64;
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        rem / 3600,
+        rem % 3600 / 60,
+        rem % 60,
+        now.subsec_millis()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_log_levels_default() {
+        let levels: LogLevels = "debug".parse().unwrap();
+        assert_eq!(levels.level("mproxy_client"), LevelFilter::Debug);
+        assert_eq!(levels.max_level(), LevelFilter::Debug);
+        assert_eq!("".parse::<LogLevels>().unwrap(), LogLevels::default());
+    }
+
+    #[test]
+    fn test_log_levels_targets() {
+        let levels: LogLevels = "mproxy_client=debug,warn,mproxy_client::downstream=trace"
+            .parse()
+            .unwrap();
+        assert_eq!(levels.level("mproxy_client"), LevelFilter::Debug);
+        assert_eq!(levels.level("mproxy_client::inputs"), LevelFilter::Debug);
+        assert_eq!(
+            levels.level("mproxy_client::downstream"),
+            LevelFilter::Trace
+        );
+        assert_eq!(levels.level("mproxy_client_extra"), LevelFilter::Warn);
+        assert_eq!(levels.level("mproxy_common::tap"), LevelFilter::Warn);
+        assert_eq!(levels.max_level(), LevelFilter::Trace);
+
+        let levels: LogLevels = "mproxy-server=off".parse().unwrap();
+        assert_eq!(levels.level("mproxy_server"), LevelFilter::Off);
+        assert_eq!(levels.level("mproxy_common"), LevelFilter::Info);
+    }
+
+    #[test]
+    fn test_log_levels_invalid() {
+        assert!("loud".parse::<LogLevels>().is_err());
+        assert!("mproxy_client=loud".parse::<LogLevels>().is_err());
+        assert!("=debug".parse::<LogLevels>().is_err());
+    }
+}
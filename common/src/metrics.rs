@@ -0,0 +1,596 @@
+//! Process-wide traffic metrics, exported in Prometheus text format.
+//!
+//! Each route (a listener forwarding to one or more outputs) registers a
+//! [`RouteMetrics`] counting its input, with a [`DownstreamMetrics`] for each
+//! of its outputs. Counters are updated with relaxed atomic operations, and are
+//! collected whether or not the metrics endpoint is served.
+//!
+//! Routes are named after their source and kind, e.g. `0.0.0.0:9920:server`.
+//! The run state of each route is tracked by a [`RunningGuard`] held by the
+//! thread handling it, and connected clients are listed with their output.
+//! Exported metrics are labelled by `route`, and by `downstream` for outputs:
+//!
+//! ```text
+//! mproxy_received_datagrams_total{route}
+//! mproxy_received_bytes_total{route}
+//! mproxy_last_receive_timestamp_seconds{route}
+//! mproxy_reconnects_total{route}
+//! mproxy_source_limit_dropped_datagrams_total{route}
+//! mproxy_source_limit_delayed_datagrams_total{route}
+//! mproxy_sent_datagrams_total{route,downstream}
+//! mproxy_sent_bytes_total{route,downstream}
+//! mproxy_send_errors_total{route,downstream}
+//! mproxy_dropped_datagrams_total{route,downstream}
+//! mproxy_limit_dropped_datagrams_total{route,downstream}
+//! mproxy_limit_delayed_datagrams_total{route,downstream}
+//! mproxy_connected_clients{route,downstream}
+//! ```
+
+use std::fmt::Write as _;
+use std::sync::atomic::{AtomicU64, AtomicU8, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{panicking, JoinHandle};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::http::{serve, Response};
+use crate::ratelimit::Shaped;
+use crate::tap::Taps;
+
+/// Routes registered in this process, in order of registration
+static ROUTES: Mutex<Vec<Arc<RouteMetrics>>> = Mutex::new(vec![]);
+
+/// Identifier of the next connected client
+static NEXT_CLIENT: AtomicU64 = AtomicU64::new(0);
+
+/// Run state of a route or downstream output
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum State {
+    Running,
+    /// Temporarily disabled after repeated send failures
+    Disabled,
+    /// The thread handling the route or output has exited
+    Stopped,
+    /// The thread handling the route or output has panicked
+    Panicked,
+}
+
+impl State {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            State::Running => "running",
+            State::Disabled => "disabled",
+            State::Stopped => "stopped",
+            State::Panicked => "panicked",
+        }
+    }
+
+    fn load(state: &AtomicU8) -> State {
+        match state.load(Ordering::Relaxed) {
+            0 => State::Running,
+            1 => State::Disabled,
+            2 => State::Stopped,
+            _ => State::Panicked,
+        }
+    }
+
+    fn store(self, state: &AtomicU8) {
+        state.store(self as u8, Ordering::Relaxed);
+    }
+}
+
+/// Marks a route or output as running until dropped, and then as stopped,
+/// or as panicked if dropped while unwinding
+#[derive(Debug)]
+pub struct RunningGuard(Arc<AtomicU8>);
+
+impl Drop for RunningGuard {
+    fn drop(&mut self) {
+        let state = if panicking() {
+            State::Panicked
+        } else {
+            State::Stopped
+        };
+        state.store(&self.0);
+    }
+}
+
+/// Counters for the input of a route, and its downstream outputs
+#[derive(Debug)]
+pub struct RouteMetrics {
+    name: String,
+    source: String,
+    kind: String,
+    state: Arc<AtomicU8>,
+    datagrams_in: AtomicU64,
+    bytes_in: AtomicU64,
+    reconnects: AtomicU64,
+    limit_drops: AtomicU64,
+    limit_delays: AtomicU64,
+    /// Milliseconds since the UNIX epoch, or zero if nothing was received
+    last_receive: AtomicU64,
+    downstreams: Mutex<Vec<Arc<DownstreamMetrics>>>,
+    taps: Taps,
+}
+
+impl RouteMetrics {
+    /// Returns the metrics for the route of `kind` (e.g. `server`) receiving
+    /// from `source`, registering a new route if needed
+    pub fn register(source: &str, kind: &str) -> Arc<RouteMetrics> {
+        let name = format!("{}:{}", source, kind);
+        let mut routes = ROUTES.lock().unwrap();
+        if let Some(route) = routes.iter().find(|r| r.name == name) {
+            return route.clone();
+        }
+        let route = Arc::new(RouteMetrics {
+            name,
+            source: source.to_string(),
+            kind: kind.to_string(),
+            state: Arc::new(AtomicU8::new(State::Running as u8)),
+            datagrams_in: AtomicU64::new(0),
+            bytes_in: AtomicU64::new(0),
+            reconnects: AtomicU64::new(0),
+            limit_drops: AtomicU64::new(0),
+            limit_delays: AtomicU64::new(0),
+            last_receive: AtomicU64::new(0),
+            downstreams: Mutex::new(vec![]),
+            taps: Taps::default(),
+        });
+        routes.push(route.clone());
+        route
+    }
+
+    /// Returns the metrics for output `name` of this route, registering a new
+    /// output if needed
+    pub fn downstream(&self, name: &str) -> Arc<DownstreamMetrics> {
+        let mut downstreams = self.downstreams.lock().unwrap();
+        if let Some(downstream) = downstreams.iter().find(|d| d.name == name) {
+            return downstream.clone();
+        }
+        let downstream = Arc::new(DownstreamMetrics::new(name));
+        downstreams.push(downstream.clone());
+        downstream
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn source(&self) -> &str {
+        &self.source
+    }
+
+    pub fn kind(&self) -> &str {
+        &self.kind
+    }
+
+    pub fn state(&self) -> State {
+        State::load(&self.state)
+    }
+
+    /// Mark this route as running, until the returned guard is dropped
+    pub fn running(&self) -> RunningGuard {
+        State::Running.store(&self.state);
+        RunningGuard(self.state.clone())
+    }
+
+    /// Count a message of `bytes` received by this route
+    pub fn received(&self, bytes: usize) {
+        self.datagrams_in.fetch_add(1, Ordering::Relaxed);
+        self.bytes_in.fetch_add(bytes as u64, Ordering::Relaxed);
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        self.last_receive
+            .store(now.as_millis() as u64, Ordering::Relaxed);
+    }
+
+    /// Count an attempt to reconnect to the upstream of this route
+    pub fn reconnected(&self) {
+        self.reconnects.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count a datagram shaped by the limit on its source address
+    pub fn source_limited(&self, shaped: Shaped) {
+        match shaped {
+            Shaped::Passed => (),
+            Shaped::Delayed => {
+                self.limit_delays.fetch_add(1, Ordering::Relaxed);
+            }
+            Shaped::Dropped => {
+                self.limit_drops.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    pub fn datagrams_in(&self) -> u64 {
+        self.datagrams_in.load(Ordering::Relaxed)
+    }
+
+    pub fn bytes_in(&self) -> u64 {
+        self.bytes_in.load(Ordering::Relaxed)
+    }
+
+    pub fn reconnects(&self) -> u64 {
+        self.reconnects.load(Ordering::Relaxed)
+    }
+
+    pub fn source_limit_drops(&self) -> u64 {
+        self.limit_drops.load(Ordering::Relaxed)
+    }
+
+    pub fn source_limit_delays(&self) -> u64 {
+        self.limit_delays.load(Ordering::Relaxed)
+    }
+
+    /// Time of the last message received by this route, if any
+    pub fn last_receive(&self) -> Option<SystemTime> {
+        match self.last_receive.load(Ordering::Relaxed) {
+            0 => None,
+            millis => Some(UNIX_EPOCH + Duration::from_millis(millis)),
+        }
+    }
+
+    /// Live taps attached to this route, see [`crate::tap`]
+    pub fn taps(&self) -> &Taps {
+        &self.taps
+    }
+
+    /// Metrics for each output of this route
+    pub fn downstreams(&self) -> Vec<Arc<DownstreamMetrics>> {
+        self.downstreams.lock().unwrap().clone()
+    }
+
+    /// Stop exporting metrics for output `name` of this route
+    pub fn remove_downstream(&self, name: &str) {
+        self.downstreams.lock().unwrap().retain(|d| d.name != name);
+    }
+
+    /// Stop exporting metrics for this route, e.g. after it is removed from
+    /// the configuration. The same route may be registered again later
+    pub fn unregister(&self) {
+        ROUTES.lock().unwrap().retain(|r| r.name != self.name);
+    }
+}
+
+/// Counters for an output of a route
+#[derive(Debug)]
+pub struct DownstreamMetrics {
+    name: String,
+    state: Arc<AtomicU8>,
+    datagrams_out: AtomicU64,
+    bytes_out: AtomicU64,
+    send_errors: AtomicU64,
+    drops: AtomicU64,
+    limit_drops: AtomicU64,
+    limit_delays: AtomicU64,
+    clients: Mutex<Vec<Client>>,
+}
+
+/// A client connected to a downstream output
+#[derive(Debug)]
+struct Client {
+    id: u64,
+    addr: String,
+    connected: SystemTime,
+    queue_depth: Option<Arc<AtomicUsize>>,
+}
+
+/// Address, connection time, and number of queued messages of a connected client
+#[derive(Clone, Debug)]
+pub struct ClientInfo {
+    pub addr: String,
+    pub connected: SystemTime,
+    pub queue_depth: Option<usize>,
+}
+
+impl DownstreamMetrics {
+    /// Create counters for output `name`, without registering them with a route.
+    /// Use [`RouteMetrics::downstream`] to create exported counters
+    pub fn new(name: &str) -> Self {
+        DownstreamMetrics {
+            name: name.to_string(),
+            state: Arc::new(AtomicU8::new(State::Running as u8)),
+            datagrams_out: AtomicU64::new(0),
+            bytes_out: AtomicU64::new(0),
+            send_errors: AtomicU64::new(0),
+            drops: AtomicU64::new(0),
+            limit_drops: AtomicU64::new(0),
+            limit_delays: AtomicU64::new(0),
+            clients: Mutex::new(vec![]),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn state(&self) -> State {
+        State::load(&self.state)
+    }
+
+    /// Set whether this output is temporarily disabled
+    pub fn set_disabled(&self, disabled: bool) {
+        let state = if disabled {
+            State::Disabled
+        } else {
+            State::Running
+        };
+        state.store(&self.state);
+    }
+
+    /// Mark this output as running, until the returned guard is dropped
+    pub fn running(&self) -> RunningGuard {
+        State::Running.store(&self.state);
+        RunningGuard(self.state.clone())
+    }
+
+    /// Count a message of `bytes` sent downstream
+    pub fn sent(&self, bytes: usize) {
+        self.datagrams_out.fetch_add(1, Ordering::Relaxed);
+        self.bytes_out.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    /// Count a failed send
+    pub fn send_error(&self) {
+        self.send_errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count a message dropped without sending, e.g. for a slow client
+    pub fn dropped(&self) {
+        self.drops.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count a message shaped by the limit on this output
+    pub fn limited(&self, shaped: Shaped) {
+        match shaped {
+            Shaped::Passed => (),
+            Shaped::Delayed => {
+                self.limit_delays.fetch_add(1, Ordering::Relaxed);
+            }
+            Shaped::Dropped => {
+                self.limit_drops.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// List a client connected from `addr`, until the returned guard is
+    /// dropped. `queue_depth` counts messages waiting to be sent to the client
+    pub fn connected(
+        self: &Arc<Self>,
+        addr: String,
+        queue_depth: Option<Arc<AtomicUsize>>,
+    ) -> ClientGuard {
+        let id = NEXT_CLIENT.fetch_add(1, Ordering::Relaxed);
+        self.clients.lock().unwrap().push(Client {
+            id,
+            addr,
+            connected: SystemTime::now(),
+            queue_depth,
+        });
+        ClientGuard {
+            metrics: self.clone(),
+            id,
+        }
+    }
+
+    pub fn datagrams_out(&self) -> u64 {
+        self.datagrams_out.load(Ordering::Relaxed)
+    }
+
+    pub fn bytes_out(&self) -> u64 {
+        self.bytes_out.load(Ordering::Relaxed)
+    }
+
+    pub fn send_errors(&self) -> u64 {
+        self.send_errors.load(Ordering::Relaxed)
+    }
+
+    pub fn drops(&self) -> u64 {
+        self.drops.load(Ordering::Relaxed)
+    }
+
+    pub fn limit_drops(&self) -> u64 {
+        self.limit_drops.load(Ordering::Relaxed)
+    }
+
+    pub fn limit_delays(&self) -> u64 {
+        self.limit_delays.load(Ordering::Relaxed)
+    }
+
+    pub fn client_count(&self) -> u64 {
+        self.clients.lock().unwrap().len() as u64
+    }
+
+    /// Clients currently connected to this output
+    pub fn clients(&self) -> Vec<ClientInfo> {
+        self.clients
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|c| ClientInfo {
+                addr: c.addr.clone(),
+                connected: c.connected,
+                queue_depth: c.queue_depth.as_ref().map(|d| d.load(Ordering::Relaxed)),
+            })
+            .collect()
+    }
+}
+
+/// A connected client, listed by [`DownstreamMetrics::connected`]
+#[derive(Debug)]
+pub struct ClientGuard {
+    metrics: Arc<DownstreamMetrics>,
+    id: u64,
+}
+
+impl Drop for ClientGuard {
+    fn drop(&mut self) {
+        self.metrics
+            .clients
+            .lock()
+            .unwrap()
+            .retain(|c| c.id != self.id);
+    }
+}
+
+/// Metrics for each route registered in this process
+pub fn routes() -> Vec<Arc<RouteMetrics>> {
+    ROUTES.lock().unwrap().clone()
+}
+
+/// Name, type, help text, and value of an exported metric family
+type Family<T> = (&'static str, &'static str, &'static str, fn(&T) -> u64);
+
+/// Escape a Prometheus label value
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Render all registered metrics in Prometheus text exposition format
+pub fn render() -> String {
+    let routes = routes();
+    let mut out = String::new();
+
+    let route_families: [Family<RouteMetrics>; 5] = [
+        (
+            "mproxy_received_datagrams_total",
+            "counter",
+            "Datagrams received by a route",
+            RouteMetrics::datagrams_in,
+        ),
+        (
+            "mproxy_received_bytes_total",
+            "counter",
+            "Bytes received by a route",
+            RouteMetrics::bytes_in,
+        ),
+        (
+            "mproxy_reconnects_total",
+            "counter",
+            "Attempts to reconnect to the upstream of a route",
+            RouteMetrics::reconnects,
+        ),
+        (
+            "mproxy_source_limit_dropped_datagrams_total",
+            "counter",
+            "Datagrams dropped by a route over the rate limit of their source",
+            RouteMetrics::source_limit_drops,
+        ),
+        (
+            "mproxy_source_limit_delayed_datagrams_total",
+            "counter",
+            "Datagrams delayed by a route over the rate limit of their source",
+            RouteMetrics::source_limit_delays,
+        ),
+    ];
+    for (name, kind, help, value) in route_families {
+        let _ = writeln!(out, "# HELP {} {}\n# TYPE {} {}", name, help, name, kind);
+        for route in &routes {
+            let _ = writeln!(
+                out,
+                "{}{{route=\"{}\"}} {}",
+                name,
+                escape(&route.name),
+                value(route)
+            );
+        }
+    }
+    let name = "mproxy_last_receive_timestamp_seconds";
+    let _ = writeln!(
+        out,
+        "# HELP {} Time of the last datagram received by a route, or 0 if none\n# TYPE {} gauge",
+        name, name
+    );
+    for route in &routes {
+        let millis = route.last_receive.load(Ordering::Relaxed);
+        let _ = writeln!(
+            out,
+            "{}{{route=\"{}\"}} {}.{:03}",
+            name,
+            escape(&route.name),
+            millis / 1000,
+            millis % 1000
+        );
+    }
+
+    let downstream_families: [Family<DownstreamMetrics>; 7] = [
+        (
+            "mproxy_sent_datagrams_total",
+            "counter",
+            "Datagrams sent to a downstream output",
+            DownstreamMetrics::datagrams_out,
+        ),
+        (
+            "mproxy_sent_bytes_total",
+            "counter",
+            "Bytes sent to a downstream output",
+            DownstreamMetrics::bytes_out,
+        ),
+        (
+            "mproxy_send_errors_total",
+            "counter",
+            "Failed sends to a downstream output",
+            DownstreamMetrics::send_errors,
+        ),
+        (
+            "mproxy_dropped_datagrams_total",
+            "counter",
+            "Datagrams dropped without sending to a downstream output",
+            DownstreamMetrics::drops,
+        ),
+        (
+            "mproxy_limit_dropped_datagrams_total",
+            "counter",
+            "Datagrams dropped over the rate limit of a downstream output",
+            DownstreamMetrics::limit_drops,
+        ),
+        (
+            "mproxy_limit_delayed_datagrams_total",
+            "counter",
+            "Datagrams delayed over the rate limit of a downstream output",
+            DownstreamMetrics::limit_delays,
+        ),
+        (
+            "mproxy_connected_clients",
+            "gauge",
+            "Clients currently connected to a downstream output",
+            DownstreamMetrics::client_count,
+        ),
+    ];
+    for (name, kind, help, value) in downstream_families {
+        let _ = writeln!(out, "# HELP {} {}\n# TYPE {} {}", name, help, name, kind);
+        for route in &routes {
+            for downstream in route.downstreams() {
+                let _ = writeln!(
+                    out,
+                    "{}{{route=\"{}\",downstream=\"{}\"}} {}",
+                    name,
+                    escape(&route.name),
+                    escape(&downstream.name),
+                    value(&downstream)
+                );
+            }
+        }
+    }
+    out
+}
+
+/// Serve registered metrics to HTTP clients requesting `GET /metrics`.
+/// Binds a TCP listener on `listen_addr`, and spawns a thread answering
+/// requests in turn
+pub fn serve_metrics(listen_addr: String) -> JoinHandle<()> {
+    serve(listen_addr, "metrics", |request, _body| {
+        match (request.method.as_str(), request.path.as_str()) {
+            ("GET", "/metrics") => Response::new(
+                "200 OK",
+                "text/plain; version=0.0.4; charset=utf-8",
+                render().into_bytes(),
+            ),
+            (_, "/metrics") => Response::method_not_allowed(),
+            _ => Response::not_found(),
+        }
+    })
+}
@@ -0,0 +1,845 @@
+//! pcap and pcapng files of received datagrams, for standard capture tools.
+//!
+//! Each datagram is written as a synthesized UDP packet from its source
+//! address to the listening address, with the receive time, so that server
+//! output opens in tools such as Wireshark and tcpdump. Packets are written
+//! without a link layer header, as raw IPv4 or IPv6 packets (link type
+//! `LINKTYPE_RAW`). If the source and listening addresses are of different
+//! families, e.g. on a dual-stack socket, both are written as IPv6.
+//!
+//! As with capture files, the file header is only written to empty files, so
+//! that output may be appended to.
+//!
+//! [`PcapReader`] reads the UDP datagrams of pcap and pcapng files written by
+//! other tools, of either byte order and timestamp resolution, with Ethernet,
+//! Linux cooked, loopback, or raw IP link layers. Other packets, including
+//! fragmented datagrams, are skipped.
+
+use std::io::{Error, ErrorKind, Read, Result as ioResult, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Link type of raw IPv4 and IPv6 packets
+pub const LINKTYPE_RAW: u16 = 101;
+
+/// Largest packet written
+const SNAPLEN: u32 = 65535;
+
+/// Largest block read, to reject corrupt lengths before allocating
+const MAX_BLOCK: usize = 1 << 24;
+
+/// Format of a packet capture file
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PcapFormat {
+    /// Classic pcap, with microsecond timestamps
+    Pcap,
+    /// pcapng, with one section and interface
+    PcapNg,
+}
+
+/// Write the file header of `format`
+pub fn write_header<W: Write>(writer: &mut W, format: PcapFormat) -> ioResult<()> {
+    match format {
+        PcapFormat::Pcap => {
+            let mut header = Vec::with_capacity(24);
+            header.extend_from_slice(&0xa1b2c3d4u32.to_le_bytes());
+            header.extend_from_slice(&2u16.to_le_bytes());
+            header.extend_from_slice(&4u16.to_le_bytes());
+            header.extend_from_slice(&0i32.to_le_bytes()); // timezone offset
+            header.extend_from_slice(&0u32.to_le_bytes()); // timestamp accuracy
+            header.extend_from_slice(&SNAPLEN.to_le_bytes());
+            header.extend_from_slice(&(LINKTYPE_RAW as u32).to_le_bytes());
+            writer.write_all(&header)
+        }
+        PcapFormat::PcapNg => {
+            // section header block, of unspecified length
+            let mut body = Vec::with_capacity(16);
+            body.extend_from_slice(&0x1a2b3c4du32.to_le_bytes());
+            body.extend_from_slice(&1u16.to_le_bytes());
+            body.extend_from_slice(&0u16.to_le_bytes());
+            body.extend_from_slice(&(-1i64).to_le_bytes());
+            writer.write_all(&block(0x0a0d0d0a, &body))?;
+            // interface description block, with microsecond timestamps
+            let mut body = Vec::with_capacity(8);
+            body.extend_from_slice(&LINKTYPE_RAW.to_le_bytes());
+            body.extend_from_slice(&0u16.to_le_bytes());
+            body.extend_from_slice(&SNAPLEN.to_le_bytes());
+            writer.write_all(&block(1, &body))
+        }
+    }
+}
+
+/// Encode a datagram received from `source` on `dest` at `time` as a packet
+/// record of `format`
+pub fn encode_packet(
+    format: PcapFormat,
+    time: SystemTime,
+    source: SocketAddr,
+    dest: SocketAddr,
+    data: &[u8],
+) -> Vec<u8> {
+    let micros = time
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_micros() as u64;
+    let packet = udp_packet(source, dest, data);
+    match format {
+        PcapFormat::Pcap => {
+            let mut record = Vec::with_capacity(packet.len() + 16);
+            record.extend_from_slice(&((micros / 1_000_000) as u32).to_le_bytes());
+            record.extend_from_slice(&((micros % 1_000_000) as u32).to_le_bytes());
+            record.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+            record.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+            record.extend_from_slice(&packet);
+            record
+        }
+        PcapFormat::PcapNg => {
+            // enhanced packet block on interface 0
+            let mut body = Vec::with_capacity(packet.len() + 24);
+            body.extend_from_slice(&0u32.to_le_bytes());
+            body.extend_from_slice(&((micros >> 32) as u32).to_le_bytes());
+            body.extend_from_slice(&(micros as u32).to_le_bytes());
+            body.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+            body.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+            body.extend_from_slice(&packet);
+            block(6, &body)
+        }
+    }
+}
+
+/// A pcapng block of `kind`, padding `body` to 32 bits
+fn block(kind: u32, body: &[u8]) -> Vec<u8> {
+    let padding = (4 - body.len() % 4) % 4;
+    let len = (12 + body.len() + padding) as u32;
+    let mut block = Vec::with_capacity(len as usize);
+    block.extend_from_slice(&kind.to_le_bytes());
+    block.extend_from_slice(&len.to_le_bytes());
+    block.extend_from_slice(body);
+    block.resize(block.len() + padding, 0);
+    block.extend_from_slice(&len.to_le_bytes());
+    block
+}
+
+/// A raw IP packet carrying `data` in a UDP datagram from `source` to `dest`
+fn udp_packet(source: SocketAddr, dest: SocketAddr, data: &[u8]) -> Vec<u8> {
+    let udp_len = (8 + data.len()) as u16;
+    let mut packet = Vec::with_capacity(48 + data.len());
+    let pseudo_header = match (source.ip(), dest.ip()) {
+        (IpAddr::V4(src), IpAddr::V4(dst)) => {
+            packet.extend_from_slice(&[0x45, 0]);
+            packet.extend_from_slice(&(20 + udp_len).to_be_bytes());
+            packet.extend_from_slice(&[0, 0, 0x40, 0]); // id, don't fragment
+            packet.extend_from_slice(&[64, 17, 0, 0]); // ttl, protocol, checksum
+            packet.extend_from_slice(&src.octets());
+            packet.extend_from_slice(&dst.octets());
+            let checksum = !fold(sum(&packet));
+            packet[10..12].copy_from_slice(&checksum.to_be_bytes());
+            [
+                &src.octets()[..],
+                &dst.octets(),
+                &[0, 17],
+                &udp_len.to_be_bytes(),
+            ]
+            .concat()
+        }
+        (src, dst) => {
+            let (src, dst) = (to_ipv6(src), to_ipv6(dst));
+            packet.extend_from_slice(&[0x60, 0, 0, 0]);
+            packet.extend_from_slice(&udp_len.to_be_bytes());
+            packet.extend_from_slice(&[17, 64]); // next header, hop limit
+            packet.extend_from_slice(&src.octets());
+            packet.extend_from_slice(&dst.octets());
+            let len = (udp_len as u32).to_be_bytes();
+            [&src.octets()[..], &dst.octets(), &len, &[0, 0, 0, 17]].concat()
+        }
+    };
+    let udp_start = packet.len();
+    packet.extend_from_slice(&source.port().to_be_bytes());
+    packet.extend_from_slice(&dest.port().to_be_bytes());
+    packet.extend_from_slice(&udp_len.to_be_bytes());
+    packet.extend_from_slice(&[0, 0]);
+    packet.extend_from_slice(data);
+    let checksum = match !fold(sum(&pseudo_header) + sum(&packet[udp_start..])) {
+        // zero means no checksum, so is sent as all ones
+        0 => 0xffff,
+        checksum => checksum,
+    };
+    packet[udp_start + 6..udp_start + 8].copy_from_slice(&checksum.to_be_bytes());
+    packet
+}
+
+/// `ip`, mapped to IPv6 if needed
+fn to_ipv6(ip: IpAddr) -> Ipv6Addr {
+    match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+        IpAddr::V6(ip) => ip,
+    }
+}
+
+/// Sum of big-endian 16 bit words, padding odd lengths with zero
+fn sum(bytes: &[u8]) -> u32 {
+    bytes
+        .chunks(2)
+        .map(|pair| u16::from_be_bytes([pair[0], *pair.get(1).unwrap_or(&0)]) as u32)
+        .sum()
+}
+
+/// Fold a sum into a 16 bit ones' complement sum
+fn fold(mut sum: u32) -> u16 {
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    sum as u16
+}
+
+/// A UDP datagram read from a packet capture
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Datagram {
+    pub time: SystemTime,
+    pub source: SocketAddr,
+    pub dest: SocketAddr,
+    pub data: Vec<u8>,
+}
+
+/// Byte order of a capture file
+#[derive(Clone, Copy, Debug)]
+struct Endian {
+    big: bool,
+}
+
+impl Endian {
+    fn u16(&self, b: &[u8]) -> u16 {
+        let b = [b[0], b[1]];
+        if self.big {
+            u16::from_be_bytes(b)
+        } else {
+            u16::from_le_bytes(b)
+        }
+    }
+
+    fn u32(&self, b: &[u8]) -> u32 {
+        let b = [b[0], b[1], b[2], b[3]];
+        if self.big {
+            u32::from_be_bytes(b)
+        } else {
+            u32::from_le_bytes(b)
+        }
+    }
+}
+
+/// A pcapng interface, giving the link type and timestamp units of packets
+#[derive(Clone, Copy, Debug)]
+struct Interface {
+    linktype: u16,
+    /// Timestamp units per second
+    units: u64,
+}
+
+#[derive(Debug)]
+enum Layout {
+    Pcap {
+        endian: Endian,
+        interface: Interface,
+    },
+    PcapNg {
+        endian: Endian,
+        interfaces: Vec<Interface>,
+    },
+}
+
+/// Reads UDP datagrams from a pcap or pcapng file.
+///
+/// A file truncated within a packet, e.g. by stopping a capture, ends at the
+/// last complete packet
+#[derive(Debug)]
+pub struct PcapReader<R: Read> {
+    reader: R,
+    layout: Layout,
+    /// Time of the last packet, for pcapng packets without a timestamp
+    last_time: SystemTime,
+}
+
+impl<R: Read> PcapReader<R> {
+    /// Read the header of a pcap or pcapng file
+    pub fn new(mut reader: R) -> ioResult<Self> {
+        let mut magic = [0u8; 4];
+        reader.read_exact(&mut magic)?;
+        let layout = match magic {
+            [0x0a, 0x0d, 0x0d, 0x0a] => {
+                let endian = read_section_header(&mut reader)?;
+                Layout::PcapNg {
+                    endian,
+                    interfaces: vec![],
+                }
+            }
+            _ => {
+                let (big, units) = match (u32::from_le_bytes(magic), u32::from_be_bytes(magic)) {
+                    (0xa1b2c3d4, _) => (false, 1_000_000),
+                    (0xa1b23c4d, _) => (false, 1_000_000_000),
+                    (_, 0xa1b2c3d4) => (true, 1_000_000),
+                    (_, 0xa1b23c4d) => (true, 1_000_000_000),
+                    _ => return Err(Error::new(ErrorKind::InvalidData, "not a pcap file")),
+                };
+                let endian = Endian { big };
+                let mut header = [0u8; 20];
+                reader.read_exact(&mut header)?;
+                Layout::Pcap {
+                    endian,
+                    interface: Interface {
+                        linktype: endian.u32(&header[16..20]) as u16,
+                        units,
+                    },
+                }
+            }
+        };
+        Ok(PcapReader {
+            reader,
+            layout,
+            last_time: UNIX_EPOCH,
+        })
+    }
+
+    /// Read the next UDP datagram, skipping other packets, or None at the
+    /// end of the file
+    pub fn next_datagram(&mut self) -> ioResult<Option<Datagram>> {
+        loop {
+            let packet = match self.next_packet() {
+                Ok(Some(packet)) => packet,
+                Ok(None) => return Ok(None),
+                Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+                Err(e) => return Err(e),
+            };
+            let (time, linktype, frame) = packet;
+            if let Some((source, dest, data)) = udp_datagram(linktype, &frame) {
+                return Ok(Some(Datagram {
+                    time,
+                    source,
+                    dest,
+                    data: data.to_vec(),
+                }));
+            }
+        }
+    }
+
+    /// Unwrap the underlying reader
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
+
+    /// Read the next packet as its time, link type and frame
+    fn next_packet(&mut self) -> ioResult<Option<(SystemTime, u16, Vec<u8>)>> {
+        match &mut self.layout {
+            Layout::Pcap { endian, interface } => {
+                let mut header = [0u8; 16];
+                if !read_or_eof(&mut self.reader, &mut header)? {
+                    return Ok(None);
+                }
+                let secs = endian.u32(&header[0..4]) as u64;
+                let fraction = endian.u32(&header[4..8]) as u64;
+                let len = endian.u32(&header[8..12]) as usize;
+                let frame = read_vec(&mut self.reader, len)?;
+                let time = UNIX_EPOCH
+                    + Duration::from_secs(secs)
+                    + Duration::from_nanos(fraction * 1_000_000_000 / interface.units);
+                Ok(Some((time, interface.linktype, frame)))
+            }
+            Layout::PcapNg { endian, interfaces } => loop {
+                let mut header = [0u8; 8];
+                if !read_or_eof(&mut self.reader, &mut header)? {
+                    return Ok(None);
+                }
+                if header[0..4] == [0x0a, 0x0d, 0x0d, 0x0a] {
+                    // a new section, with its own byte order and interfaces
+                    let mut rest = &header[4..8];
+                    let chained = (&mut rest).chain(&mut self.reader);
+                    *endian = read_section_header(chained)?;
+                    interfaces.clear();
+                    continue;
+                }
+                let kind = endian.u32(&header[0..4]);
+                let len = endian.u32(&header[4..8]) as usize;
+                if !(12..=MAX_BLOCK).contains(&len) || !len.is_multiple_of(4) {
+                    return Err(Error::new(
+                        ErrorKind::InvalidData,
+                        format!("invalid pcapng block length {}", len),
+                    ));
+                }
+                let body = read_vec(&mut self.reader, len - 8)?;
+                let body = &body[..len - 12];
+                match kind {
+                    // interface description block
+                    1 if body.len() >= 8 => {
+                        interfaces.push(Interface {
+                            linktype: endian.u16(&body[0..2]),
+                            units: timestamp_units(*endian, &body[8..]),
+                        });
+                    }
+                    // enhanced packet block
+                    6 if body.len() >= 20 => {
+                        let interface = match interfaces.get(endian.u32(&body[0..4]) as usize) {
+                            Some(interface) => *interface,
+                            None => continue,
+                        };
+                        let ts = ((endian.u32(&body[4..8]) as u64) << 32)
+                            | endian.u32(&body[8..12]) as u64;
+                        let captured = (endian.u32(&body[12..16]) as usize).min(body.len() - 20);
+                        let time = UNIX_EPOCH
+                            + Duration::from_secs(ts / interface.units)
+                            + Duration::from_nanos(
+                                // in u128, as units may be up to 10^19
+                                ((ts % interface.units) as u128 * 1_000_000_000
+                                    / interface.units as u128)
+                                    as u64,
+                            );
+                        self.last_time = time;
+                        return Ok(Some((
+                            time,
+                            interface.linktype,
+                            body[20..20 + captured].to_vec(),
+                        )));
+                    }
+                    // simple packet block, on the first interface
+                    3 if body.len() >= 4 => {
+                        if let Some(interface) = interfaces.first() {
+                            return Ok(Some((
+                                self.last_time,
+                                interface.linktype,
+                                body[4..].to_vec(),
+                            )));
+                        }
+                    }
+                    _ => (),
+                }
+            },
+        }
+    }
+}
+
+/// Read the rest of a pcapng section header block after its type, returning
+/// the byte order of the section
+fn read_section_header<R: Read>(mut reader: R) -> ioResult<Endian> {
+    let mut header = [0u8; 8];
+    reader.read_exact(&mut header)?;
+    let endian = match header[4..8] {
+        [0x4d, 0x3c, 0x2b, 0x1a] => Endian { big: false },
+        [0x1a, 0x2b, 0x3c, 0x4d] => Endian { big: true },
+        _ => return Err(Error::new(ErrorKind::InvalidData, "not a pcapng file")),
+    };
+    let len = endian.u32(&header[0..4]) as usize;
+    if !(28..=MAX_BLOCK).contains(&len) || !len.is_multiple_of(4) {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("invalid pcapng section length {}", len),
+        ));
+    }
+    read_vec(&mut reader, len - 12)?;
+    Ok(endian)
+}
+
+/// Timestamp units per second, from the `if_tsresol` option of an interface
+fn timestamp_units(endian: Endian, mut options: &[u8]) -> u64 {
+    while options.len() >= 4 {
+        let code = endian.u16(&options[0..2]);
+        let len = endian.u16(&options[2..4]) as usize;
+        let value = &options[4..options.len().min(4 + len)];
+        match code {
+            0 => break,
+            9 if len == 1 && !value.is_empty() => {
+                let exponent = (value[0] & 0x7f) as u32;
+                let base: u64 = if value[0] & 0x80 == 0 { 10 } else { 2 };
+                return base.checked_pow(exponent).unwrap_or(1_000_000).max(1);
+            }
+            _ => (),
+        }
+        options = &options[options.len().min(4 + len.div_ceil(4) * 4)..];
+    }
+    1_000_000
+}
+
+/// Fill `buf`, returning false at the end of the file
+fn read_or_eof<R: Read>(reader: &mut R, buf: &mut [u8]) -> ioResult<bool> {
+    match reader.read_exact(buf) {
+        Ok(()) => Ok(true),
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+fn read_vec<R: Read>(reader: &mut R, len: usize) -> ioResult<Vec<u8>> {
+    if len > MAX_BLOCK {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("invalid packet length {}", len),
+        ));
+    }
+    let mut buf = vec![0u8; len];
+    reader.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+/// The source, destination and payload of a UDP datagram in a frame of
+/// `linktype`, or None for other packets
+pub fn udp_datagram(linktype: u16, frame: &[u8]) -> Option<(SocketAddr, SocketAddr, &[u8])> {
+    let packet = match linktype {
+        // BSD loopback, with the address family in host or network byte order
+        0 | 108 => frame.get(4..)?,
+        // Ethernet, possibly with VLAN tags
+        1 => {
+            let mut offset = 12;
+            loop {
+                let ethertype = u16::from_be_bytes([*frame.get(offset)?, *frame.get(offset + 1)?]);
+                match ethertype {
+                    0x8100 | 0x88a8 => offset += 4,
+                    0x0800 | 0x86dd => break frame.get(offset + 2..)?,
+                    _ => return None,
+                }
+            }
+        }
+        // raw IP
+        101 | 228 | 229 => frame,
+        // Linux cooked capture
+        113 => frame.get(16..)?,
+        276 => frame.get(20..)?,
+        _ => return None,
+    };
+    let (source, dest, mut protocol, mut payload) = match packet.first()? >> 4 {
+        4 => {
+            let header_len = ((packet[0] & 0x0f) as usize) * 4;
+            let total_len = u16::from_be_bytes([*packet.get(2)?, *packet.get(3)?]) as usize;
+            let fragment = u16::from_be_bytes([*packet.get(6)?, *packet.get(7)?]);
+            if fragment & 0x3fff != 0 {
+                // more fragments, or a fragment offset
+                return None;
+            }
+            let source: [u8; 4] = packet.get(12..16)?.try_into().ok()?;
+            let dest: [u8; 4] = packet.get(16..20)?.try_into().ok()?;
+            (
+                IpAddr::V4(Ipv4Addr::from(source)),
+                IpAddr::V4(Ipv4Addr::from(dest)),
+                packet[9],
+                packet.get(header_len..total_len.min(packet.len()))?,
+            )
+        }
+        6 => {
+            let payload_len = u16::from_be_bytes([*packet.get(4)?, *packet.get(5)?]) as usize;
+            let source: [u8; 16] = packet.get(8..24)?.try_into().ok()?;
+            let dest: [u8; 16] = packet.get(24..40)?.try_into().ok()?;
+            (
+                IpAddr::V6(Ipv6Addr::from(source)),
+                IpAddr::V6(Ipv6Addr::from(dest)),
+                *packet.get(6)?,
+                packet.get(40..(40 + payload_len).min(packet.len()))?,
+            )
+        }
+        _ => return None,
+    };
+    // skip IPv6 hop-by-hop, routing and destination options headers
+    while matches!(protocol, 0 | 43 | 60) && source.is_ipv6() {
+        let len = (*payload.get(1)? as usize + 1) * 8;
+        protocol = payload[0];
+        payload = payload.get(len..)?;
+    }
+    if protocol != 17 {
+        return None;
+    }
+    let udp_len = u16::from_be_bytes([*payload.get(4)?, *payload.get(5)?]) as usize;
+    let data = payload.get(8..udp_len)?;
+    let source_port = u16::from_be_bytes([payload[0], payload[1]]);
+    let dest_port = u16::from_be_bytes([payload[2], payload[3]]);
+    Some((
+        SocketAddr::new(source, source_port),
+        SocketAddr::new(dest, dest_port),
+        data,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(s: &str) -> SocketAddr {
+        s.parse().unwrap()
+    }
+
+    fn u16s(big: bool, n: u16) -> [u8; 2] {
+        if big {
+            n.to_be_bytes()
+        } else {
+            n.to_le_bytes()
+        }
+    }
+
+    fn u32s(big: bool, n: u32) -> [u8; 4] {
+        if big {
+            n.to_be_bytes()
+        } else {
+            n.to_le_bytes()
+        }
+    }
+
+    /// A classic pcap file of `frames`, each with its seconds and fraction
+    fn pcap_file(big: bool, magic: u32, linktype: u16, frames: &[(u32, u32, &[u8])]) -> Vec<u8> {
+        let mut file = u32s(big, magic).to_vec();
+        file.extend_from_slice(&u16s(big, 2));
+        file.extend_from_slice(&u16s(big, 4));
+        file.extend_from_slice(&[0; 8]);
+        file.extend_from_slice(&u32s(big, SNAPLEN));
+        file.extend_from_slice(&u32s(big, linktype as u32));
+        for (secs, fraction, frame) in frames {
+            file.extend_from_slice(&u32s(big, *secs));
+            file.extend_from_slice(&u32s(big, *fraction));
+            file.extend_from_slice(&u32s(big, frame.len() as u32));
+            file.extend_from_slice(&u32s(big, frame.len() as u32));
+            file.extend_from_slice(frame);
+        }
+        file
+    }
+
+    /// A pcapng block of `kind`, in either byte order
+    fn ng_block(big: bool, kind: u32, body: &[u8]) -> Vec<u8> {
+        let padded = body.len().div_ceil(4) * 4;
+        let len = (12 + padded) as u32;
+        let mut block = u32s(big, kind).to_vec();
+        block.extend_from_slice(&u32s(big, len));
+        block.extend_from_slice(body);
+        block.resize(8 + padded, 0);
+        block.extend_from_slice(&u32s(big, len));
+        block
+    }
+
+    /// A pcapng section with one interface of `linktype`, with `options`
+    fn ng_section(big: bool, linktype: u16, options: &[u8]) -> Vec<u8> {
+        let mut body = u32s(big, 0x1a2b3c4d).to_vec();
+        body.extend_from_slice(&u16s(big, 1));
+        body.extend_from_slice(&u16s(big, 0));
+        body.extend_from_slice(&[0xff; 8]);
+        let mut section = ng_block(big, 0x0a0d0d0a, &body);
+        let mut body = u16s(big, linktype).to_vec();
+        body.extend_from_slice(&u16s(big, 0));
+        body.extend_from_slice(&u32s(big, SNAPLEN));
+        body.extend_from_slice(options);
+        section.extend(ng_block(big, 1, &body));
+        section
+    }
+
+    /// A pcapng enhanced packet block on interface 0, at timestamp `ts`
+    fn ng_packet(big: bool, ts: u64, frame: &[u8]) -> Vec<u8> {
+        let mut body = u32s(big, 0).to_vec();
+        body.extend_from_slice(&u32s(big, (ts >> 32) as u32));
+        body.extend_from_slice(&u32s(big, ts as u32));
+        body.extend_from_slice(&u32s(big, frame.len() as u32));
+        body.extend_from_slice(&u32s(big, frame.len() as u32));
+        body.extend_from_slice(frame);
+        ng_block(big, 6, &body)
+    }
+
+    fn read_all(file: &[u8]) -> Vec<Datagram> {
+        let mut reader = PcapReader::new(file).unwrap();
+        let mut datagrams = vec![];
+        while let Some(datagram) = reader.next_datagram().unwrap() {
+            datagrams.push(datagram);
+        }
+        datagrams
+    }
+
+    #[test]
+    fn test_encode_packet_round_trip() {
+        let time = UNIX_EPOCH + Duration::from_micros(1_700_000_000_123_456);
+        let packets = [
+            (addr("10.0.0.1:5000"), addr("10.0.0.2:9920"), &b"hello"[..]),
+            (addr("[2001:db8::1]:5001"), addr("[::1]:9921"), b"odd"),
+            (addr("10.0.0.1:5002"), addr("[::]:9922"), b""),
+        ];
+        for format in [PcapFormat::Pcap, PcapFormat::PcapNg] {
+            let mut file = vec![];
+            write_header(&mut file, format).unwrap();
+            for (source, dest, data) in packets {
+                file.extend(encode_packet(format, time, source, dest, data));
+            }
+            let datagrams = read_all(&file);
+            assert_eq!(datagrams.len(), packets.len());
+            for (datagram, (source, dest, data)) in datagrams.iter().zip(packets) {
+                assert_eq!(datagram.time, time);
+                assert_eq!(datagram.data, data);
+                if source.is_ipv4() == dest.is_ipv4() {
+                    assert_eq!((datagram.source, datagram.dest), (source, dest));
+                } else {
+                    // mixed families are written as IPv6
+                    let mapped =
+                        |a: SocketAddr| SocketAddr::new(IpAddr::V6(to_ipv6(a.ip())), a.port());
+                    assert_eq!(datagram.source, mapped(source));
+                    assert_eq!(datagram.dest, mapped(dest));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_udp_packet_checksums() {
+        // a valid checksum sums to all ones, including the checksum itself
+        let packet = udp_packet(addr("192.168.1.10:1234"), addr("192.168.1.20:80"), b"abc");
+        assert_eq!(fold(sum(&packet[..20])), 0xffff);
+        let pseudo = [&packet[12..20], &[0, 17], &packet[24..26]].concat();
+        assert_eq!(fold(sum(&pseudo) + sum(&packet[20..])), 0xffff);
+
+        let packet = udp_packet(addr("[fe80::1]:1234"), addr("[fe80::2]:80"), b"abcd");
+        let mut pseudo = packet[8..40].to_vec();
+        pseudo.extend_from_slice(&(packet.len() as u32 - 40).to_be_bytes());
+        pseudo.extend_from_slice(&[0, 0, 0, 17]);
+        assert_eq!(fold(sum(&pseudo) + sum(&packet[40..])), 0xffff);
+        assert_ne!(packet[46..48], [0, 0]);
+    }
+
+    #[test]
+    fn test_udp_datagram() {
+        let source = addr("10.1.2.3:4000");
+        let dest = addr("10.3.2.1:5000");
+        let packet = udp_packet(source, dest, b"payload");
+        assert_eq!(
+            udp_datagram(LINKTYPE_RAW, &packet),
+            Some((source, dest, &b"payload"[..]))
+        );
+        // trailing bytes, e.g. Ethernet padding, are not part of the datagram
+        let padded = [&packet[..], &[0; 6]].concat();
+        assert_eq!(
+            udp_datagram(LINKTYPE_RAW, &padded),
+            Some((source, dest, &b"payload"[..]))
+        );
+        // fragments and other protocols are skipped
+        let mut fragment = packet.clone();
+        fragment[6] |= 0x20;
+        assert_eq!(udp_datagram(LINKTYPE_RAW, &fragment), None);
+        let mut tcp = packet.clone();
+        tcp[9] = 6;
+        assert_eq!(udp_datagram(LINKTYPE_RAW, &tcp), None);
+        assert_eq!(udp_datagram(LINKTYPE_RAW, &packet[..24]), None);
+        assert_eq!(udp_datagram(147, &packet), None);
+    }
+
+    #[test]
+    fn test_udp_datagram_link_layers() {
+        let source = addr("[2001:db8::5]:4000");
+        let dest = addr("[2001:db8::6]:5000");
+        let packet = udp_packet(source, dest, b"framed");
+        let expected = Some((source, dest, &b"framed"[..]));
+        let macs = [0x02; 12];
+
+        let ethernet = [&macs[..], &[0x86, 0xdd], &packet].concat();
+        assert_eq!(udp_datagram(1, &ethernet), expected);
+        // 802.1Q and 802.1ad VLAN tags
+        let vlan = [&macs[..], &[0x81, 0x00, 0, 42, 0x86, 0xdd], &packet].concat();
+        assert_eq!(udp_datagram(1, &vlan), expected);
+        let qinq = [
+            &macs[..],
+            &[0x88, 0xa8, 0, 7, 0x81, 0x00, 0, 42, 0x86, 0xdd],
+            &packet,
+        ]
+        .concat();
+        assert_eq!(udp_datagram(1, &qinq), expected);
+        let arp = [&macs[..], &[0x08, 0x06], &packet].concat();
+        assert_eq!(udp_datagram(1, &arp), None);
+
+        // Linux cooked captures, v1 and v2
+        let mut sll = [0u8; 16];
+        sll[14..16].copy_from_slice(&[0x86, 0xdd]);
+        let sll = [&sll[..], &packet].concat();
+        assert_eq!(udp_datagram(113, &sll), expected);
+        let mut sll2 = [0u8; 20];
+        sll2[0..2].copy_from_slice(&[0x86, 0xdd]);
+        let sll2 = [&sll2[..], &packet].concat();
+        assert_eq!(udp_datagram(276, &sll2), expected);
+
+        // BSD loopback
+        let null = [&[30, 0, 0, 0][..], &packet].concat();
+        assert_eq!(udp_datagram(0, &null), expected);
+    }
+
+    #[test]
+    fn test_pcap_reader_byte_orders_and_resolutions() {
+        let source = addr("10.0.0.1:5000");
+        let dest = addr("10.0.0.2:9920");
+        let packet = udp_packet(source, dest, b"x");
+        let ethernet = [&[0x02; 12][..], &[0x08, 0x00], &packet].concat();
+        for big in [false, true] {
+            for (magic, fraction, nanos) in [
+                (0xa1b2c3d4, 250_000, 250_000_000),
+                (0xa1b23c4d, 250_000_001, 250_000_001),
+            ] {
+                let file = pcap_file(big, magic, 1, &[(1_700_000_000, fraction, &ethernet)]);
+                let datagrams = read_all(&file);
+                assert_eq!(datagrams.len(), 1, "big endian {}, magic {:x}", big, magic);
+                assert_eq!(
+                    datagrams[0].time,
+                    UNIX_EPOCH + Duration::new(1_700_000_000, nanos)
+                );
+                assert_eq!((datagrams[0].source, datagrams[0].dest), (source, dest));
+                assert_eq!(datagrams[0].data, b"x");
+            }
+        }
+        assert!(PcapReader::new(&b"not a capture"[..]).is_err());
+    }
+
+    #[test]
+    fn test_pcap_reader_truncated() {
+        let packet = udp_packet(addr("10.0.0.1:5000"), addr("10.0.0.2:9920"), b"x");
+        let file = pcap_file(false, 0xa1b2c3d4, 101, &[(1, 0, &packet), (2, 0, &packet)]);
+        let datagrams = read_all(&file[..file.len() - 3]);
+        assert_eq!(datagrams.len(), 1);
+    }
+
+    #[test]
+    fn test_pcapng_timestamp_resolution() {
+        let packet = udp_packet(addr("10.0.0.1:5000"), addr("10.0.0.2:9920"), b"ng");
+        let ts = 1_700_000_000_123_456_789u64;
+        for big in [false, true] {
+            // if_tsresol of 10^-9, then of 2^-10, then the default of 10^-6
+            let mut option = u16s(big, 9).to_vec();
+            option.extend_from_slice(&u16s(big, 1));
+            option.extend_from_slice(&[9, 0, 0, 0]);
+            let mut file = ng_section(big, 101, &option);
+            file.extend(ng_packet(big, ts, &packet));
+            option[4] = 0x80 | 10;
+            file.extend(ng_section(big, 101, &option));
+            file.extend(ng_packet(big, 1_700_000_000 * 1024 + 512, &packet));
+            file.extend(ng_section(big, 101, &[]));
+            file.extend(ng_packet(big, 1_700_000_000_250_000, &packet));
+
+            let datagrams = read_all(&file);
+            let times: Vec<_> = datagrams.iter().map(|d| d.time).collect();
+            assert_eq!(
+                times,
+                [
+                    UNIX_EPOCH + Duration::from_nanos(ts),
+                    UNIX_EPOCH + Duration::new(1_700_000_000, 500_000_000),
+                    UNIX_EPOCH + Duration::new(1_700_000_000, 250_000_000),
+                ],
+                "big endian {}",
+                big
+            );
+            assert!(datagrams.iter().all(|d| d.data == b"ng"));
+        }
+    }
+
+    #[test]
+    fn test_pcapng_fine_timestamp_resolution() {
+        let packet = udp_packet(addr("10.0.0.1:5000"), addr("10.0.0.2:9920"), b"ng");
+        // if_tsresol of 10^-12, then of 2^-63, the finest allowed
+        let mut option = u16s(false, 9).to_vec();
+        option.extend_from_slice(&u16s(false, 1));
+        option.extend_from_slice(&[12, 0, 0, 0]);
+        let mut file = ng_section(false, 101, &option);
+        file.extend(ng_packet(false, 1_000_000_123_456_789_999, &packet));
+        option[4] = 0x80 | 63;
+        file.extend(ng_section(false, 101, &option));
+        file.extend(ng_packet(false, (1 << 63) + (1 << 62), &packet));
+
+        let times: Vec<_> = read_all(&file).iter().map(|d| d.time).collect();
+        assert_eq!(
+            times,
+            [
+                UNIX_EPOCH + Duration::new(1_000_000, 123_456_789),
+                UNIX_EPOCH + Duration::new(1, 500_000_000),
+            ]
+        );
+    }
+}
@@ -0,0 +1,412 @@
+//! Token-bucket rate limits, per source address and per downstream.
+//!
+//! A [`RateLimit`] caps bytes and/or datagrams per second, each with a burst
+//! size, and either drops datagrams over the limit or delays them until the
+//! limit allows, slowing the route down. Limits are given as text, e.g.
+//!
+//! ```text
+//! bytes=1000000:2000000,packets=500,delay
+//! ```
+//!
+//! limits to 1MB/s with bursts of up to 2MB, and 500 datagrams per second
+//! with bursts of up to 500 datagrams, delaying datagrams over the limit.
+//! Bursts default to one second of traffic.
+//!
+//! Limits are set for all routes in this process with [`set_source_limit`],
+//! applied by listeners to each source address, and [`set_downstream_limit`],
+//! applied by routes to each downstream. Shaped datagrams are counted in the
+//! metrics of the route or downstream.
+//!
+//! Only downstream limits may delay datagrams: a listener receives from all
+//! sources in one loop, so delaying one source would stall every other.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+/// Limit applied to each source address
+static SOURCE_LIMIT: Mutex<Option<RateLimit>> = Mutex::new(None);
+
+/// Limit applied to each downstream
+static DOWNSTREAM_LIMIT: Mutex<Option<RateLimit>> = Mutex::new(None);
+
+/// Sources tracked before idle sources are forgotten
+const MAX_SOURCES: usize = 4096;
+
+/// Sources idle for this long are forgotten, once [`MAX_SOURCES`] is reached
+const SOURCE_IDLE: Duration = Duration::from_secs(60);
+
+/// Minimum interval between searches for idle sources
+const PRUNE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A rate with a burst size, in bytes or datagrams
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Rate {
+    pub per_sec: u64,
+    pub burst: u64,
+}
+
+impl FromStr for Rate {
+    type Err = String;
+
+    /// Parse `RATE[:BURST]`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parse = |n: &str| {
+            n.parse::<u64>()
+                .ok()
+                .filter(|n| *n > 0)
+                .ok_or_else(|| format!("invalid rate '{}'", s))
+        };
+        match s.split_once(':') {
+            Some((per_sec, burst)) => Ok(Rate {
+                per_sec: parse(per_sec)?,
+                burst: parse(burst)?,
+            }),
+            None => {
+                let per_sec = parse(s)?;
+                Ok(Rate {
+                    per_sec,
+                    burst: per_sec,
+                })
+            }
+        }
+    }
+}
+
+/// Limits on bytes and datagrams per second
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct RateLimit {
+    pub bytes: Option<Rate>,
+    pub packets: Option<Rate>,
+    /// Delay datagrams over the limit, instead of dropping them
+    pub delay: bool,
+}
+
+impl FromStr for RateLimit {
+    type Err = String;
+
+    /// Parse `bytes=RATE[:BURST],packets=RATE[:BURST][,delay|,drop]`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut limit = RateLimit::default();
+        for part in s.split(',') {
+            match part.split_once('=') {
+                Some(("bytes", rate)) => limit.bytes = Some(rate.parse()?),
+                Some(("packets", rate)) => limit.packets = Some(rate.parse()?),
+                None if part == "delay" => limit.delay = true,
+                None if part == "drop" => limit.delay = false,
+                _ => return Err(format!("unknown rate limit '{}'", part)),
+            }
+        }
+        if limit.bytes.is_none() && limit.packets.is_none() {
+            return Err(format!(
+                "rate limit '{}' limits neither bytes nor packets",
+                s
+            ));
+        }
+        Ok(limit)
+    }
+}
+
+/// Parse a limit for source addresses, which may not delay datagrams
+pub fn parse_source_limit(s: &str) -> Result<RateLimit, String> {
+    let limit: RateLimit = s.parse()?;
+    if limit.delay {
+        return Err(format!(
+            "source limit '{}': datagrams over a source limit can only be dropped",
+            s
+        ));
+    }
+    Ok(limit)
+}
+
+/// Set the limit applied by listeners to each source address, for routes
+/// started after this call. Datagrams over the limit are dropped, even if the
+/// limit delays them
+pub fn set_source_limit(limit: Option<RateLimit>) {
+    *SOURCE_LIMIT.lock().unwrap() = limit;
+}
+
+/// Set the limit applied by routes to each downstream, for downstreams
+/// added after this call
+pub fn set_downstream_limit(limit: Option<RateLimit>) {
+    *DOWNSTREAM_LIMIT.lock().unwrap() = limit;
+}
+
+/// Limit applied to each source address, if any
+pub fn source_limit() -> Option<RateLimit> {
+    *SOURCE_LIMIT.lock().unwrap()
+}
+
+/// Limit applied to each downstream, if any
+pub fn downstream_limit() -> Option<RateLimit> {
+    *DOWNSTREAM_LIMIT.lock().unwrap()
+}
+
+/// Outcome of checking a datagram against a limit
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Shaped {
+    /// Within the limit
+    Passed,
+    /// Over the limit, and delayed until within it
+    Delayed,
+    /// Over the limit, and to be dropped
+    Dropped,
+}
+
+/// Tokens refilled at a fixed rate, up to the burst size
+#[derive(Debug)]
+struct TokenBucket {
+    rate: f64,
+    burst: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: Rate) -> Self {
+        TokenBucket {
+            rate: rate.per_sec as f64,
+            burst: rate.burst as f64,
+            tokens: rate.burst as f64,
+            last: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.burst);
+        self.last = now;
+    }
+
+    /// Time until `cost` tokens are available. Costs above the burst size
+    /// are capped, so that large datagrams are not refused forever
+    fn wait(&self, cost: f64) -> Duration {
+        let missing = cost.min(self.burst) - self.tokens;
+        if missing <= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(missing / self.rate)
+        }
+    }
+
+    fn take(&mut self, cost: f64) {
+        self.tokens -= cost.min(self.burst);
+    }
+}
+
+/// Token buckets enforcing a [`RateLimit`]
+#[derive(Debug)]
+pub struct Limiter {
+    bytes: Option<TokenBucket>,
+    packets: Option<TokenBucket>,
+    delay: bool,
+    /// Set when [`Limiter::delay`] asked the caller to wait
+    waited: bool,
+}
+
+impl Limiter {
+    pub fn new(limit: RateLimit) -> Self {
+        Limiter {
+            bytes: limit.bytes.map(TokenBucket::new),
+            packets: limit.packets.map(TokenBucket::new),
+            delay: limit.delay,
+            waited: false,
+        }
+    }
+
+    /// Time until a datagram of `bytes` is within the limit, if datagrams over
+    /// the limit are delayed, or else zero. Callers sharing a limiter may wait
+    /// for this without holding a lock, before calling [`Limiter::admit`]
+    pub fn delay(&mut self, bytes: usize) -> Duration {
+        if !self.delay {
+            return Duration::ZERO;
+        }
+        let wait = self.wait(Instant::now(), bytes);
+        self.waited |= !wait.is_zero();
+        wait
+    }
+
+    /// Check a datagram of `bytes` against the limit, sleeping until it is
+    /// within the limit if datagrams over the limit are delayed
+    pub fn admit(&mut self, bytes: usize) -> Shaped {
+        self.admit_or_drop(bytes, self.delay)
+    }
+
+    /// As [`Limiter::admit`], dropping datagrams over the limit without
+    /// sleeping, even if the limit delays them
+    pub fn admit_now(&mut self, bytes: usize) -> Shaped {
+        self.admit_or_drop(bytes, false)
+    }
+
+    fn admit_or_drop(&mut self, bytes: usize, delay: bool) -> Shaped {
+        let wait = self.wait(Instant::now(), bytes);
+        let shaped = if wait.is_zero() && std::mem::take(&mut self.waited) {
+            Shaped::Delayed
+        } else if wait.is_zero() {
+            Shaped::Passed
+        } else if delay {
+            sleep(wait);
+            self.wait(Instant::now(), bytes);
+            Shaped::Delayed
+        } else {
+            return Shaped::Dropped;
+        };
+        if let Some(bucket) = &mut self.bytes {
+            bucket.take(bytes as f64);
+        }
+        if let Some(bucket) = &mut self.packets {
+            bucket.take(1.0);
+        }
+        shaped
+    }
+
+    /// Refill the buckets at `now`, returning the time until a datagram of
+    /// `bytes` is within the limit
+    fn wait(&mut self, now: Instant, bytes: usize) -> Duration {
+        let mut wait = Duration::ZERO;
+        if let Some(bucket) = &mut self.bytes {
+            bucket.refill(now);
+            wait = wait.max(bucket.wait(bytes as f64));
+        }
+        if let Some(bucket) = &mut self.packets {
+            bucket.refill(now);
+            wait = wait.max(bucket.wait(1.0));
+        }
+        wait
+    }
+}
+
+/// A [`Limiter`] for each source address, dropping datagrams over the limit
+#[derive(Debug)]
+pub struct SourceLimiter {
+    limit: RateLimit,
+    sources: HashMap<IpAddr, (Limiter, Instant)>,
+    last_prune: Instant,
+}
+
+impl SourceLimiter {
+    pub fn new(limit: RateLimit) -> Self {
+        SourceLimiter {
+            limit: RateLimit {
+                delay: false,
+                ..limit
+            },
+            sources: HashMap::new(),
+            last_prune: Instant::now(),
+        }
+    }
+
+    /// A limiter for each source address, if [`set_source_limit`] was called
+    pub fn from_settings() -> Option<Self> {
+        source_limit().map(SourceLimiter::new)
+    }
+
+    /// Check a datagram of `bytes` from `source`, see [`Limiter::admit`]
+    pub fn admit(&mut self, source: IpAddr, bytes: usize) -> Shaped {
+        let now = Instant::now();
+        if self.sources.len() >= MAX_SOURCES
+            && now.duration_since(self.last_prune) >= PRUNE_INTERVAL
+        {
+            self.last_prune = now;
+            self.sources
+                .retain(|_, (_, last_seen)| now.duration_since(*last_seen) < SOURCE_IDLE);
+        }
+        let limit = self.limit;
+        let (limiter, last_seen) = self
+            .sources
+            .entry(source)
+            .or_insert_with(|| (Limiter::new(limit), now));
+        *last_seen = now;
+        limiter.admit(bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_rate_limit() {
+        let limit: RateLimit = "bytes=1000000:2000000,packets=500,delay".parse().unwrap();
+        assert_eq!(
+            limit,
+            RateLimit {
+                bytes: Some(Rate {
+                    per_sec: 1_000_000,
+                    burst: 2_000_000
+                }),
+                packets: Some(Rate {
+                    per_sec: 500,
+                    burst: 500
+                }),
+                delay: true,
+            }
+        );
+        assert!(!"packets=10,delay,drop".parse::<RateLimit>().unwrap().delay);
+        for invalid in [
+            "",
+            "delay",
+            "bytes=0",
+            "bytes=10:x",
+            "packets=-1",
+            "rate=10",
+        ] {
+            assert!(invalid.parse::<RateLimit>().is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_parse_source_limit() {
+        assert!(parse_source_limit("packets=10").is_ok());
+        assert!(parse_source_limit("packets=10,drop").is_ok());
+        assert!(parse_source_limit("packets=10,delay").is_err());
+    }
+
+    #[test]
+    fn test_limiter_drops_over_limit() {
+        let mut limiter = Limiter::new("packets=1000:2".parse().unwrap());
+        assert_eq!(limiter.delay(100), Duration::ZERO);
+        assert_eq!(limiter.admit(100), Shaped::Passed);
+        assert_eq!(limiter.admit(100), Shaped::Passed);
+        assert_eq!(limiter.admit(100), Shaped::Dropped);
+        sleep(Duration::from_millis(5));
+        assert_eq!(limiter.admit(100), Shaped::Passed);
+
+        // datagrams larger than the burst pass once the bucket is full
+        let mut limiter = Limiter::new("bytes=1000:100".parse().unwrap());
+        assert_eq!(limiter.admit(1500), Shaped::Passed);
+        assert_eq!(limiter.admit(1), Shaped::Dropped);
+    }
+
+    #[test]
+    fn test_limiter_delays_over_limit() {
+        let mut limiter = Limiter::new("packets=20:1,delay".parse().unwrap());
+        assert_eq!(limiter.admit(10), Shaped::Passed);
+        let start = Instant::now();
+        assert_eq!(limiter.admit(10), Shaped::Delayed);
+        assert!(start.elapsed() >= Duration::from_millis(40));
+
+        // waiting for the delay outside the limiter still counts as delayed
+        let wait = limiter.delay(10);
+        assert!(wait > Duration::ZERO && wait <= Duration::from_millis(50));
+        sleep(wait);
+        assert_eq!(limiter.admit(10), Shaped::Delayed);
+
+        // unless the caller cannot wait
+        assert_eq!(limiter.admit_now(10), Shaped::Dropped);
+    }
+
+    #[test]
+    fn test_source_limiter() {
+        let mut limiter = SourceLimiter::new("packets=1000:1,delay".parse().unwrap());
+        let a: IpAddr = "10.0.0.1".parse().unwrap();
+        let b: IpAddr = "10.0.0.2".parse().unwrap();
+        assert_eq!(limiter.admit(a, 10), Shaped::Passed);
+        assert_eq!(limiter.admit(b, 10), Shaped::Passed);
+        // source limits never delay
+        assert_eq!(limiter.admit(a, 10), Shaped::Dropped);
+    }
+}
@@ -0,0 +1,104 @@
+//! Supervision of route threads.
+//!
+//! Route threads are spawned with [`supervise`]. If a route panics or returns
+//! an error, the cause is logged and the route is restarted after a delay,
+//! doubling from 100ms up to 30s while the route keeps failing. With
+//! [`OnFailure::Exit`], the process instead exits with status 1, so that an
+//! external supervisor such as systemd can restart it.
+//!
+//! A route returning `Ok(())` has stopped deliberately, e.g. when stopped with
+//! the admin API or during an upgrade, and is not restarted.
+
+use std::any::Any;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::{sleep, Builder, JoinHandle};
+use std::time::{Duration, Instant};
+
+use log::{error, info};
+
+/// Delay before the first restart of a failed route
+const INITIAL_BACKOFF: Duration = Duration::from_millis(100);
+
+/// Maximum delay between restarts of a failing route
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+/// Routes running for this long before failing are restarted without delay
+/// growth, as if failing for the first time
+const BACKOFF_RESET: Duration = Duration::from_secs(60);
+
+/// Set if the process exits when a route fails
+static EXIT_ON_FAILURE: AtomicBool = AtomicBool::new(false);
+
+/// Action taken when a route fails
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum OnFailure {
+    /// Restart the route with backoff
+    #[default]
+    Restart,
+    /// Exit the process with status 1
+    Exit,
+}
+
+impl FromStr for OnFailure {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "restart" => Ok(OnFailure::Restart),
+            "exit" => Ok(OnFailure::Exit),
+            _ => Err(format!("unknown failure action '{}'", s)),
+        }
+    }
+}
+
+/// Set the action taken when a supervised route fails, for all routes in
+/// this process. Defaults to [`OnFailure::Restart`]
+pub fn set_on_failure(on_failure: OnFailure) {
+    EXIT_ON_FAILURE.store(on_failure == OnFailure::Exit, Ordering::Relaxed);
+}
+
+/// Spawn a thread named `name` running `route`, restarting it when it panics
+/// or returns an error
+pub fn supervise<F>(name: String, mut route: F) -> JoinHandle<()>
+where
+    F: FnMut() -> Result<(), String> + Send + 'static,
+{
+    Builder::new()
+        .name(name.clone())
+        .spawn(move || {
+            let mut backoff = INITIAL_BACKOFF;
+            loop {
+                let started = Instant::now();
+                let cause = match catch_unwind(AssertUnwindSafe(&mut route)) {
+                    Ok(Ok(())) => return,
+                    Ok(Err(e)) => e,
+                    Err(payload) => format!("panicked: {}", panic_message(&*payload)),
+                };
+                if EXIT_ON_FAILURE.load(Ordering::Relaxed) {
+                    error!("{} failed: {}, exiting", name, cause);
+                    std::process::exit(1);
+                }
+                if started.elapsed() >= BACKOFF_RESET {
+                    backoff = INITIAL_BACKOFF;
+                }
+                error!("{} failed: {}, restarting in {:?}", name, cause, backoff);
+                sleep(backoff);
+                backoff = (backoff * 2).min(MAX_BACKOFF);
+                info!("{}: restarting", name);
+            }
+        })
+        .unwrap()
+}
+
+/// The message of a panic, if it has one
+fn panic_message(payload: &(dyn Any + Send)) -> &str {
+    match payload.downcast_ref::<&str>() {
+        Some(message) => message,
+        None => payload
+            .downcast_ref::<String>()
+            .map(String::as_str)
+            .unwrap_or("unknown cause"),
+    }
+}
@@ -0,0 +1,419 @@
+//! systemd integration: socket activation, and readiness and watchdog
+//! notifications.
+//!
+//! Sockets passed by systemd with `LISTEN_FDS`, e.g. from `ListenDatagram=`
+//! and `ListenStream=` in a `.socket` unit, are used in place of binding a new
+//! socket when their local address matches the address to bind. This allows
+//! services to listen on privileged ports without binding them themselves.
+//! Sockets handed over by a previous process during an upgrade (see
+//! [`handoff`](crate::handoff)) are used in the same way.
+//!
+//! When run with `NOTIFY_SOCKET` set (`Type=notify`), daemons send `READY=1`
+//! with a `STATUS=` line once their listeners are bound. If `WatchdogSec=` is
+//! set, `WATCHDOG=1` pings are sent while no route has panicked, so that
+//! systemd restarts a daemon with a route that fails for longer than the
+//! watchdog interval.
+//!
+//! Outside of systemd, and on other platforms, sockets are bound as usual and
+//! notifications are not sent.
+
+use std::io::Result as ioResult;
+#[cfg(unix)]
+use std::net::ToSocketAddrs;
+use std::net::{SocketAddr, TcpListener, UdpSocket};
+#[cfg(unix)]
+use std::os::unix::io::{AsRawFd, RawFd};
+#[cfg(unix)]
+use std::sync::{Mutex, OnceLock};
+use std::thread::{sleep, Builder, JoinHandle};
+use std::time::Duration;
+
+use log::warn;
+#[cfg(unix)]
+use log::{debug, info};
+
+use crate::handoff;
+use crate::metrics::{routes, State};
+
+/// First file descriptor passed by systemd
+#[cfg(unix)]
+const SD_LISTEN_FDS_START: i32 = 3;
+
+/// Sockets passed by systemd and not yet used
+#[cfg(unix)]
+static INHERITED: OnceLock<Mutex<Vec<Inherited>>> = OnceLock::new();
+
+#[cfg(unix)]
+#[derive(Debug)]
+pub(crate) enum Inherited {
+    Udp(UdpSocket),
+    Tcp(TcpListener),
+}
+
+#[cfg(unix)]
+impl Inherited {
+    pub(crate) fn local_addr(&self) -> Option<SocketAddr> {
+        match self {
+            Inherited::Udp(socket) => socket.local_addr().ok(),
+            Inherited::Tcp(listener) => listener.local_addr().ok(),
+        }
+    }
+
+    pub(crate) fn into_raw_fd(self) -> RawFd {
+        use std::os::unix::io::IntoRawFd;
+
+        match self {
+            Inherited::Udp(socket) => socket.into_raw_fd(),
+            Inherited::Tcp(listener) => listener.into_raw_fd(),
+        }
+    }
+}
+
+/// Take ownership of socket `fd`, passed to this process by systemd or by a
+/// process handing over its sockets. Returns None, leaving `fd` open, if it is
+/// not a bound UDP socket or listening TCP socket
+#[cfg(unix)]
+pub(crate) fn inherit(fd: RawFd) -> Option<Inherited> {
+    use std::os::unix::io::FromRawFd;
+
+    let sockopt = |option| {
+        let mut value: libc::c_int = 0;
+        let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+        // SAFETY: the option value and length point to valid memory of the given size
+        let res = unsafe {
+            libc::getsockopt(
+                fd,
+                libc::SOL_SOCKET,
+                option,
+                &mut value as *mut libc::c_int as *mut libc::c_void,
+                &mut len,
+            )
+        };
+        (res == 0).then_some(value)
+    };
+    let sock_type = sockopt(libc::SO_TYPE)?;
+    if sock_type == libc::SOCK_STREAM && sockopt(libc::SO_ACCEPTCONN) != Some(1) {
+        return None;
+    }
+    // SAFETY: `fd` is an open socket owned by this process, and is wrapped
+    // once. Sockets which are not IP sockets are released without closing
+    let socket = unsafe {
+        libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC);
+        match sock_type {
+            libc::SOCK_DGRAM => Inherited::Udp(UdpSocket::from_raw_fd(fd)),
+            libc::SOCK_STREAM => Inherited::Tcp(TcpListener::from_raw_fd(fd)),
+            _ => return None,
+        }
+    };
+    match socket.local_addr() {
+        Some(_) => Some(socket),
+        None => {
+            let _ = socket.into_raw_fd();
+            None
+        }
+    }
+}
+
+/// Read the sockets passed by systemd to this process
+#[cfg(unix)]
+fn listen_fds() -> Vec<Inherited> {
+    let pid: Option<u32> = std::env::var("LISTEN_PID")
+        .ok()
+        .and_then(|pid| pid.parse().ok());
+    if pid != Some(std::process::id()) {
+        return vec![];
+    }
+    let count: i32 = std::env::var("LISTEN_FDS")
+        .ok()
+        .and_then(|count| count.parse().ok())
+        .unwrap_or(0);
+
+    let mut sockets = vec![];
+    for fd in SD_LISTEN_FDS_START..SD_LISTEN_FDS_START + count {
+        match inherit(fd) {
+            Some(socket) => {
+                debug!(
+                    "systemd: received socket {} for {:?}",
+                    fd,
+                    socket.local_addr()
+                );
+                sockets.push(socket);
+            }
+            None => warn!(
+                "systemd: file descriptor {} is not a UDP or TCP listening socket",
+                fd
+            ),
+        }
+    }
+    sockets
+}
+
+/// Take the unused socket passed by systemd or by a previous process, if any,
+/// matching `matches`
+#[cfg(unix)]
+pub(crate) fn take_inherited(matches: impl Fn(&Inherited) -> bool) -> Option<Inherited> {
+    let mut inherited = INHERITED
+        .get_or_init(|| {
+            let mut sockets = listen_fds();
+            sockets.extend(handoff::receive().into_iter().filter_map(inherit));
+            Mutex::new(sockets)
+        })
+        .lock()
+        .unwrap();
+    let i = inherited.iter().position(matches)?;
+    Some(inherited.remove(i))
+}
+
+/// Bind a UDP socket to `addr`, or use a socket passed by systemd or by a
+/// previous process bound to `addr`
+pub fn udp_socket(addr: SocketAddr) -> ioResult<UdpSocket> {
+    #[cfg(unix)]
+    if let Some(Inherited::Udp(socket)) =
+        take_inherited(|s| matches!(s, Inherited::Udp(_)) && s.local_addr() == Some(addr))
+    {
+        info!("using inherited UDP socket {}", addr);
+        handoff::record(socket.as_raw_fd(), addr);
+        return Ok(socket);
+    }
+    let socket = UdpSocket::bind(addr)?;
+    #[cfg(unix)]
+    handoff::record(socket.as_raw_fd(), socket.local_addr()?);
+    Ok(socket)
+}
+
+/// Bind a UDP socket to `addr` to receive multicast datagrams, or use a socket
+/// passed by systemd bound to `addr`.
+///
+/// Unlike [`udp_socket`], multicast sockets are not handed over to a new
+/// process on upgrade: the previous process keeps its membership while it
+/// drains, so the new process binds the same port alongside it. On unix they
+/// are therefore bound with `SO_REUSEADDR`, without which the second bind fails
+/// with `EADDRINUSE`. Each socket bound to the port and joined to the group
+/// receives a copy of every datagram sent to it
+pub fn multicast_socket(addr: SocketAddr) -> ioResult<UdpSocket> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::io::FromRawFd;
+
+        if let Some(Inherited::Udp(socket)) =
+            take_inherited(|s| matches!(s, Inherited::Udp(_)) && s.local_addr() == Some(addr))
+        {
+            info!("using inherited UDP socket {}", addr);
+            return Ok(socket);
+        }
+        let domain = match addr {
+            SocketAddr::V4(_) => libc::AF_INET,
+            SocketAddr::V6(_) => libc::AF_INET6,
+        };
+        // SAFETY: the new socket is owned by `socket`, and closed on error
+        let socket = unsafe {
+            let fd = libc::socket(domain, libc::SOCK_DGRAM, 0);
+            if fd < 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC);
+            UdpSocket::from_raw_fd(fd)
+        };
+        let reuse: libc::c_int = 1;
+        // SAFETY: the option value points to a c_int of the given size, and
+        // the address is a zero-initialized sockaddr of the given length
+        let res = unsafe {
+            libc::setsockopt(
+                socket.as_raw_fd(),
+                libc::SOL_SOCKET,
+                libc::SO_REUSEADDR,
+                &reuse as *const libc::c_int as *const libc::c_void,
+                std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+            );
+            match addr {
+                SocketAddr::V4(v4) => {
+                    let mut sin: libc::sockaddr_in = std::mem::zeroed();
+                    sin.sin_family = libc::AF_INET as libc::sa_family_t;
+                    sin.sin_port = v4.port().to_be();
+                    sin.sin_addr.s_addr = u32::from_ne_bytes(v4.ip().octets());
+                    libc::bind(
+                        socket.as_raw_fd(),
+                        &sin as *const libc::sockaddr_in as *const libc::sockaddr,
+                        std::mem::size_of::<libc::sockaddr_in>() as libc::socklen_t,
+                    )
+                }
+                SocketAddr::V6(v6) => {
+                    let mut sin6: libc::sockaddr_in6 = std::mem::zeroed();
+                    sin6.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+                    sin6.sin6_port = v6.port().to_be();
+                    sin6.sin6_addr.s6_addr = v6.ip().octets();
+                    sin6.sin6_scope_id = v6.scope_id();
+                    libc::bind(
+                        socket.as_raw_fd(),
+                        &sin6 as *const libc::sockaddr_in6 as *const libc::sockaddr,
+                        std::mem::size_of::<libc::sockaddr_in6>() as libc::socklen_t,
+                    )
+                }
+            }
+        };
+        if res != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        Ok(socket)
+    }
+    #[cfg(not(unix))]
+    UdpSocket::bind(addr)
+}
+
+/// Bind a TCP listener to `addr`, or use a listener passed by systemd or by a
+/// previous process bound to `addr`
+pub fn tcp_listener(addr: &str) -> ioResult<TcpListener> {
+    #[cfg(unix)]
+    {
+        let addrs: Vec<SocketAddr> = addr.to_socket_addrs()?.collect();
+        if let Some(Inherited::Tcp(listener)) = take_inherited(|s| {
+            matches!(s, Inherited::Tcp(_)) && s.local_addr().is_some_and(|a| addrs.contains(&a))
+        }) {
+            info!("using inherited TCP listener {}", addr);
+            handoff::record(listener.as_raw_fd(), listener.local_addr()?);
+            return Ok(listener);
+        }
+    }
+    let listener = TcpListener::bind(addr)?;
+    #[cfg(unix)]
+    handoff::record(listener.as_raw_fd(), listener.local_addr()?);
+    Ok(listener)
+}
+
+/// Socket connected to `NOTIFY_SOCKET`, if set. Connected on first use, or
+/// by [`drop_privileges`](crate::daemon::drop_privileges) before confining
+/// the process with chroot, after which the socket path is out of reach
+#[cfg(unix)]
+pub(crate) fn notify_socket() -> Option<&'static std::os::unix::net::UnixDatagram> {
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::net::UnixDatagram;
+
+    static NOTIFY: OnceLock<Option<UnixDatagram>> = OnceLock::new();
+    NOTIFY
+        .get_or_init(|| {
+            let path = std::env::var_os("NOTIFY_SOCKET")?;
+            let socket = UnixDatagram::unbound().ok()?;
+            let connected = match path.as_bytes() {
+                #[cfg(target_os = "linux")]
+                [b'@', name @ ..] => {
+                    use std::os::linux::net::SocketAddrExt;
+                    std::os::unix::net::SocketAddr::from_abstract_name(name)
+                        .and_then(|addr| socket.connect_addr(&addr))
+                }
+                _ => socket.connect(&path),
+            };
+            match connected {
+                Ok(()) => Some(socket),
+                Err(e) => {
+                    debug!("systemd: connecting to {:?}: {}", path, e);
+                    None
+                }
+            }
+        })
+        .as_ref()
+}
+
+/// Send a notification such as `READY=1` to systemd. Returns false if not
+/// run by systemd with `NOTIFY_SOCKET`, or if the notification was not sent
+pub fn notify(state: &str) -> bool {
+    #[cfg(unix)]
+    if let Some(socket) = notify_socket() {
+        let sent = socket.send(state.as_bytes());
+        if let Err(e) = &sent {
+            debug!("systemd: notifying: {}", e);
+        }
+        return sent.is_ok();
+    }
+    false
+}
+
+/// Notify systemd that startup is complete, with a status line shown by
+/// `systemctl status`. If this process was started by a process handing over
+/// its sockets, the previous process is also notified, and begins draining
+pub fn ready(status: &str) {
+    #[cfg(unix)]
+    handoff::complete();
+    notify(&format!("READY=1\nSTATUS={}", status));
+}
+
+/// Update the status line shown by `systemctl status`
+pub fn status(status: &str) {
+    notify(&format!("STATUS={}", status));
+}
+
+/// If systemd expects watchdog pings (`WatchdogSec=`), spawn a thread sending
+/// `WATCHDOG=1` at half the watchdog interval while no route has panicked.
+/// Pings resume if the panicked route is restarted in time
+pub fn watchdog() -> Option<JoinHandle<()>> {
+    let usec: u64 = std::env::var("WATCHDOG_USEC").ok()?.parse().ok()?;
+    if let Ok(pid) = std::env::var("WATCHDOG_PID") {
+        if pid.parse() != Ok(std::process::id()) {
+            return None;
+        }
+    }
+    let interval = Duration::from_micros(usec / 2);
+    let thread = Builder::new()
+        .name("watchdog".to_string())
+        .spawn(move || {
+            let mut failed = false;
+            loop {
+                let panicked = routes().into_iter().find(|route| {
+                    route.state() == State::Panicked
+                        || route
+                            .downstreams()
+                            .iter()
+                            .any(|d| d.state() == State::Panicked)
+                });
+                match panicked {
+                    Some(route) if !failed => {
+                        warn!("{} panicked, pausing watchdog pings", route.name());
+                        status(&format!("{} panicked", route.name()));
+                        failed = true;
+                    }
+                    Some(_) => {}
+                    None => {
+                        if failed {
+                            status("recovered");
+                            failed = false;
+                        }
+                        notify("WATCHDOG=1");
+                    }
+                }
+                sleep(interval);
+            }
+        })
+        .unwrap();
+    Some(thread)
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+
+    #[test]
+    fn test_multicast_socket_shared_port() {
+        let group = Ipv4Addr::new(224, 0, 0, 1);
+        let addr = SocketAddr::from((group, 9945));
+        let first = multicast_socket(addr).unwrap();
+        // as during an upgrade, a second socket binds the same port
+        let second = multicast_socket(addr).unwrap();
+        for socket in [&first, &second] {
+            socket
+                .join_multicast_v4(&group, &Ipv4Addr::UNSPECIFIED)
+                .unwrap();
+            socket
+                .set_read_timeout(Some(Duration::from_millis(500)))
+                .unwrap();
+        }
+
+        let sender = UdpSocket::bind("0.0.0.0:0").unwrap();
+        sender.send_to(b"both", addr).unwrap();
+        let mut buf = [0u8; 16];
+        for socket in [&first, &second] {
+            let c = socket.recv(&mut buf).unwrap();
+            assert_eq!(&buf[..c], b"both");
+        }
+    }
+}
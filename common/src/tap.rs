@@ -0,0 +1,300 @@
+//! Live taps, copying the messages received by a route to a debugging socket.
+//!
+//! Connect to the tap address (a TCP socket address, or on unix, a Unix
+//! socket path) and send a line with the name of a route, as listed by the
+//! admin API, optionally followed by options:
+//!
+//! ```text
+//! 0.0.0.0:9920:forward
+//! 0.0.0.0:9920:forward hex sample=10
+//! ```
+//!
+//! Messages received by the route are then copied to the connection as
+//! received, or with `hex`, as a hexdump preceded by the time received and
+//! length of each message. With `sample=N`, only every Nth message is copied.
+//! e.g. `printf '0.0.0.0:9920:forward hex\n' | nc localhost 9930`
+//!
+//! Messages are dropped rather than slowing the route when a tap cannot keep
+//! up, and routes without a tap attached pay only for a single atomic load
+//! per message.
+
+use std::fmt::Write as _;
+use std::io::{BufRead, BufReader, ErrorKind, Read, Write};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{sync_channel, Receiver, RecvTimeoutError, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread::{spawn, Builder, JoinHandle};
+use std::time::{Duration, SystemTime};
+
+use log::{debug, info};
+
+use crate::handoff::incoming;
+use crate::logging::rfc3339;
+use crate::metrics::routes;
+use crate::systemd::tcp_listener;
+
+/// Messages queued for each tap before further messages are dropped
+const TAP_QUEUE: usize = 1024;
+
+/// Maximum length of the line naming the tapped route
+const MAX_REQUEST: u64 = 1024;
+
+/// Time allowed for a client to name the tapped route
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Interval at which an idle tap checks whether its client has disconnected
+const CLOSE_POLL: Duration = Duration::from_millis(500);
+
+/// Taps attached to a route
+#[derive(Debug, Default)]
+pub struct Taps {
+    attached: AtomicUsize,
+    taps: Mutex<Vec<Tap>>,
+}
+
+#[derive(Debug)]
+struct Tap {
+    sender: SyncSender<(SystemTime, Vec<u8>)>,
+    sample: u64,
+    seen: u64,
+}
+
+impl Taps {
+    /// Copy a received message to each attached tap.
+    /// Returns immediately if no taps are attached
+    pub fn send(&self, msg: &[u8]) {
+        if self.attached.load(Ordering::Relaxed) == 0 {
+            return;
+        }
+        let now = SystemTime::now();
+        let mut taps = self.taps.lock().unwrap();
+        taps.retain_mut(|tap| {
+            tap.seen += 1;
+            if (tap.seen - 1) % tap.sample != 0 {
+                return true;
+            }
+            match tap.sender.try_send((now, msg.to_vec())) {
+                Ok(()) | Err(TrySendError::Full(_)) => true,
+                Err(TrySendError::Disconnected(_)) => false,
+            }
+        });
+        self.attached.store(taps.len(), Ordering::Relaxed);
+    }
+
+    /// Number of attached taps
+    pub fn attached(&self) -> usize {
+        self.attached.load(Ordering::Relaxed)
+    }
+
+    /// Attach a tap receiving every `sample`th message
+    fn attach(&self, sample: u64) -> Receiver<(SystemTime, Vec<u8>)> {
+        let (sender, receiver) = sync_channel(TAP_QUEUE);
+        let mut taps = self.taps.lock().unwrap();
+        taps.push(Tap {
+            sender,
+            sample,
+            seen: 0,
+        });
+        self.attached.store(taps.len(), Ordering::Relaxed);
+        receiver
+    }
+}
+
+/// Serve taps on `listen_addr`, a TCP socket address, or on unix, a Unix
+/// socket path containing `/`. Spawns a thread accepting connections, and a
+/// thread for each attached tap
+pub fn serve_tap(listen_addr: String) -> JoinHandle<()> {
+    #[cfg(unix)]
+    if listen_addr.contains('/') {
+        use std::os::unix::fs::FileTypeExt;
+        use std::os::unix::net::UnixListener;
+
+        // replace a socket left behind by a previous process
+        if std::fs::symlink_metadata(&listen_addr).is_ok_and(|m| m.file_type().is_socket()) {
+            let _ = std::fs::remove_file(&listen_addr);
+        }
+        let listener = UnixListener::bind(&listen_addr)
+            .unwrap_or_else(|e| panic!("binding tap listener: {}", e));
+        return Builder::new()
+            .name(format!("{}:tap", listen_addr))
+            .spawn(move || {
+                for stream in listener.incoming().flatten() {
+                    let _ = stream.set_read_timeout(Some(REQUEST_TIMEOUT));
+                    if let Ok(writer) = stream.try_clone() {
+                        spawn(move || handle_tap(stream, writer));
+                    }
+                }
+            })
+            .unwrap();
+    }
+
+    let listener =
+        tcp_listener(&listen_addr).unwrap_or_else(|e| panic!("binding tap listener: {}", e));
+    Builder::new()
+        .name(format!("{}:tap", listen_addr))
+        .spawn(move || {
+            for stream in incoming(&listener).flatten() {
+                let _ = stream.set_read_timeout(Some(REQUEST_TIMEOUT));
+                if let Ok(writer) = stream.try_clone() {
+                    spawn(move || handle_tap(stream, writer));
+                }
+            }
+        })
+        .unwrap()
+}
+
+/// Read the name of the tapped route and options from `reader`, and copy
+/// messages received by the route to `writer` until the connection is closed.
+/// The reader is then watched for end of file, so that a client disconnecting
+/// from an idle route is noticed without waiting for the next message
+fn handle_tap(reader: impl Read + Send + 'static, mut writer: impl Write) {
+    let mut line = String::new();
+    let mut request = BufReader::new(reader.take(MAX_REQUEST));
+    if request.read_line(&mut line).is_err() {
+        return;
+    }
+    let reader = request.into_inner().into_inner();
+    let mut words = line.split_whitespace();
+    let name = match words.next() {
+        Some(name) => name.to_string(),
+        None => return,
+    };
+    let mut hex = false;
+    let mut sample = 1;
+    for option in words {
+        match option {
+            "hex" => hex = true,
+            _ => match option
+                .strip_prefix("sample=")
+                .and_then(|n| n.parse().ok())
+                .filter(|n| *n > 0)
+            {
+                Some(n) => sample = n,
+                None => {
+                    let _ = writeln!(writer, "error: unknown option '{}'", option);
+                    return;
+                }
+            },
+        }
+    }
+    let receiver = match routes().iter().find(|r| r.name() == name) {
+        Some(route) => route.taps().attach(sample),
+        None => {
+            let _ = writeln!(writer, "error: no such route '{}'", name);
+            return;
+        }
+    };
+
+    info!("tap attached to {}", name);
+    let closed = Arc::new(AtomicBool::new(false));
+    watch_closed(reader, closed.clone());
+    while !closed.load(Ordering::Relaxed) {
+        let (time, msg) = match receiver.recv_timeout(CLOSE_POLL) {
+            Ok(received) => received,
+            Err(RecvTimeoutError::Timeout) => continue,
+            Err(RecvTimeoutError::Disconnected) => break,
+        };
+        let written = if hex {
+            writer.write_all(hexdump(time, &msg).as_bytes())
+        } else {
+            writer.write_all(&msg)
+        };
+        if let Err(e) = written.and_then(|()| writer.flush()) {
+            debug!("tap on {}: {}", name, e);
+            break;
+        }
+    }
+    closed.store(true, Ordering::Relaxed);
+    info!("tap detached from {}", name);
+}
+
+/// Read and discard from `reader` in a thread until end of file or an error,
+/// then set `closed`. The thread also exits once `closed` is set elsewhere,
+/// on the next read timeout
+fn watch_closed(mut reader: impl Read + Send + 'static, closed: Arc<AtomicBool>) {
+    spawn(move || {
+        let mut buf = [0u8; 256];
+        while !closed.load(Ordering::Relaxed) {
+            match reader.read(&mut buf) {
+                Ok(0) => break,
+                Ok(_) => {}
+                Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {}
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(_) => break,
+            }
+        }
+        closed.store(true, Ordering::Relaxed);
+    });
+}
+
+/// Format a message received at `time` as a hexdump
+fn hexdump(time: SystemTime, msg: &[u8]) -> String {
+    let mut out = format!("{} {} bytes\n", rfc3339(time), msg.len());
+    for (i, chunk) in msg.chunks(16).enumerate() {
+        let _ = write!(out, "{:08x} ", i * 16);
+        for j in 0..16 {
+            if j == 8 {
+                out.push(' ');
+            }
+            match chunk.get(j) {
+                Some(b) => {
+                    let _ = write!(out, " {:02x}", b);
+                }
+                None => out.push_str("   "),
+            }
+        }
+        out.push_str("  |");
+        out.extend(chunk.iter().map(|&b| {
+            if b.is_ascii_graphic() || b == b' ' {
+                b as char
+            } else {
+                '.'
+            }
+        }));
+        out.push_str("|\n");
+    }
+    out
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::os::unix::net::UnixStream;
+    use std::sync::mpsc::channel;
+
+    use super::*;
+    use crate::metrics::RouteMetrics;
+
+    #[test]
+    fn test_tap_detaches_on_close() {
+        let metrics = RouteMetrics::register("tap-test", "forward");
+        let (mut client, server) = UnixStream::pair().unwrap();
+        let writer = server.try_clone().unwrap();
+        let (done, finished) = channel();
+        spawn(move || {
+            handle_tap(server, writer);
+            done.send(()).unwrap();
+        });
+
+        client.write_all(b"tap-test:forward\n").unwrap();
+        sleep_until_attached(&metrics);
+        metrics.taps().send(b"one");
+        let mut buf = [0u8; 3];
+        client.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"one");
+
+        // closing an idle tap detaches it without another message
+        drop(client);
+        assert!(finished.recv_timeout(CLOSE_POLL * 4).is_ok());
+        metrics.unregister();
+    }
+
+    fn sleep_until_attached(metrics: &RouteMetrics) {
+        for _ in 0..100 {
+            if metrics.taps().attached() > 0 {
+                return;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        panic!("tap not attached");
+    }
+}
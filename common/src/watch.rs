@@ -0,0 +1,168 @@
+//! Watching directories for new files.
+//!
+//! On Linux, a [`DirWatcher`] is notified of new files with inotify. Files
+//! are reported as created when they appear, and as written once they are
+//! closed after writing, or moved into the directory complete. If the event
+//! queue overflows, the directory is listed again and all of its files are
+//! reported as both created and written, so callers should ignore files they
+//! have already seen. Elsewhere, the directory is listed again on each wait,
+//! and new files are reported as both created and written.
+
+use std::collections::HashSet;
+use std::fs::read_dir;
+use std::io::Result as ioResult;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+#[cfg(target_os = "linux")]
+use std::fs::File;
+#[cfg(target_os = "linux")]
+use std::io::{Error, Read};
+#[cfg(target_os = "linux")]
+use std::os::unix::{ffi::OsStrExt, io::AsRawFd, io::FromRawFd, io::OwnedFd};
+
+#[cfg(target_os = "linux")]
+use log::warn;
+
+/// A change to the files of a watched directory
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Change {
+    /// A file was created, and may still be written
+    Created(PathBuf),
+    /// A file was closed after writing, or moved into the directory
+    Written(PathBuf),
+}
+
+/// Watches a directory for new files
+#[derive(Debug)]
+pub struct DirWatcher {
+    dir: PathBuf,
+    #[cfg(target_os = "linux")]
+    inotify: File,
+    #[cfg(not(target_os = "linux"))]
+    known: HashSet<PathBuf>,
+}
+
+#[cfg(target_os = "linux")]
+impl DirWatcher {
+    /// Start watching `dir`
+    pub fn new(dir: &Path) -> ioResult<Self> {
+        // SAFETY: inotify_init1 takes no pointers, and its result is checked
+        let fd = unsafe { libc::inotify_init1(libc::IN_CLOEXEC) };
+        if fd < 0 {
+            return Err(Error::last_os_error());
+        }
+        // SAFETY: `fd` is a new descriptor, owned by nothing else, and is
+        // closed when `inotify` is dropped
+        let inotify = File::from(unsafe { OwnedFd::from_raw_fd(fd) });
+        let mut c_dir = dir.as_os_str().as_bytes().to_vec();
+        c_dir.push(0);
+        let mask = libc::IN_CREATE | libc::IN_CLOSE_WRITE | libc::IN_MOVED_TO | libc::IN_ONLYDIR;
+        // SAFETY: `c_dir` is NUL-terminated, and outlives the call
+        if unsafe { libc::inotify_add_watch(fd, c_dir.as_ptr() as *const libc::c_char, mask) } < 0 {
+            return Err(Error::last_os_error());
+        }
+        Ok(DirWatcher {
+            dir: dir.to_path_buf(),
+            inotify,
+        })
+    }
+
+    /// Wait up to `timeout` for changes, returning those since the last wait
+    pub fn wait(&mut self, timeout: Duration) -> ioResult<Vec<Change>> {
+        let mut pollfd = libc::pollfd {
+            fd: self.inotify.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        // SAFETY: `pollfd` is a valid array of one entry for the call
+        let ready = unsafe { libc::poll(&mut pollfd, 1, timeout.as_millis() as libc::c_int) };
+        if ready < 0 {
+            let e = Error::last_os_error();
+            return match e.kind() {
+                std::io::ErrorKind::Interrupted => Ok(vec![]),
+                _ => Err(e),
+            };
+        } else if ready == 0 {
+            return Ok(vec![]);
+        }
+        let mut buf = [0u8; 4096];
+        let c = self.inotify.read(&mut buf)?;
+        let mut changes = vec![];
+        let mut offset = 0;
+        let mut overflowed = false;
+        // struct inotify_event: wd, mask, cookie, len, then a padded name
+        while offset + 16 <= c {
+            let field = |i: usize| {
+                let start = offset + i * 4;
+                u32::from_ne_bytes([buf[start], buf[start + 1], buf[start + 2], buf[start + 3]])
+            };
+            let (mask, len) = (field(1), field(3) as usize);
+            let name = &buf[offset + 16..(offset + 16 + len).min(c)];
+            let name = &name[..name.iter().position(|b| *b == 0).unwrap_or(name.len())];
+            offset += 16 + len;
+            if mask & libc::IN_Q_OVERFLOW != 0 {
+                overflowed = true;
+                continue;
+            }
+            if mask & libc::IN_ISDIR != 0 || name.is_empty() {
+                continue;
+            }
+            let path = self.dir.join(std::ffi::OsStr::from_bytes(name));
+            if mask & libc::IN_CREATE != 0 {
+                changes.push(Change::Created(path));
+            } else if mask & (libc::IN_CLOSE_WRITE | libc::IN_MOVED_TO) != 0 {
+                changes.push(Change::Written(path));
+            }
+        }
+        if overflowed {
+            warn!(
+                "watching {}: events were lost, listing the directory again",
+                self.dir.display()
+            );
+            for path in list_files(&self.dir)? {
+                changes.push(Change::Created(path.clone()));
+                changes.push(Change::Written(path));
+            }
+        }
+        Ok(changes)
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+impl DirWatcher {
+    /// Start watching `dir`
+    pub fn new(dir: &Path) -> ioResult<Self> {
+        let mut watcher = DirWatcher {
+            dir: dir.to_path_buf(),
+            known: HashSet::new(),
+        };
+        watcher.known = list_files(dir)?;
+        Ok(watcher)
+    }
+
+    /// Wait up to `timeout` for changes, returning those since the last wait
+    pub fn wait(&mut self, timeout: Duration) -> ioResult<Vec<Change>> {
+        std::thread::sleep(timeout);
+        let files = list_files(&self.dir)?;
+        let mut changes = vec![];
+        for path in files.difference(&self.known) {
+            changes.push(Change::Created(path.clone()));
+            changes.push(Change::Written(path.clone()));
+        }
+        self.known = files;
+        Ok(changes)
+    }
+}
+
+/// The regular files in `dir`
+fn list_files(dir: &Path) -> ioResult<HashSet<PathBuf>> {
+    let mut files = HashSet::new();
+    for entry in read_dir(dir)? {
+        let entry = entry?;
+        if entry.file_type()?.is_file() {
+            files.insert(entry.path());
+        }
+    }
+    Ok(files)
+}
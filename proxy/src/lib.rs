@@ -53,12 +53,74 @@
 //! OPTIONS:
 //!   --udp-listen-addr     [HOSTNAME:PORT]     UDP listening socket address. May be repeated
 //!   --udp-downstream-addr [HOSTNAME:PORT]     UDP downstream socket address. May be repeated
+//!   --dispatch            [POLICY]            Send to all downstreams, or to one by round-robin, hash, or failover. Defaults to all
+//!   --heartbeat-secs      [SECONDS]           Pass over downstreams which send nothing back for SECONDS
+//!   --source-limit        [LIMIT]             Rate limit for each upstream source address, see RATE LIMITS
+//!   --downstream-limit    [LIMIT]             Rate limit for each downstream, see RATE LIMITS
 //!   --tcp-connect-addr    [HOSTNAME:PORT]     Connect to TCP host, forwarding stream. May be repeated
+//!   --metrics-addr        [HOSTNAME:PORT]     Serve Prometheus metrics over HTTP at /metrics
+//!   --admin-addr          [HOSTNAME:PORT]     Serve the admin API over HTTP at /routes
+//!   --tap-addr            [ADDR|PATH]         Serve live taps of route traffic over TCP, or a Unix socket PATH
+//!   --config              [FILE]              Read options from FILE, one per line. Reloaded on SIGHUP
+//!   --on-failure          [ACTION]            Action when a route fails: restart or exit. Defaults to restart
+//!   --resolve-secs        [SECONDS]           Interval between lookups of downstream host names. Defaults to 30
+//!   --resolve-mode        [MODE]              Send to names with several addresses: failover or all. Defaults to failover
+//!   --log-level           [LEVEL]             Log level: error, warn, info, debug, or trace, or per target, e.g. mproxy_forward=debug,warn. Defaults to info
+//!   --log-format          [FORMAT]            Log format: text or json. Defaults to text
+//!   --log-output          [OUTPUT]            Write logs to stderr, syslog, or journald. Defaults to stderr
+//!   --user                [USER]              Switch to USER, a name or ID, once sockets and files are opened
+//!   --group               [GROUP]             Switch to GROUP. Defaults to the primary group of --user
+//!   --pidfile             [FILE]              Write the process ID to FILE
 //!
 //! FLAGS:
 //!   -h, --help    Prints help information
 //!   -t, --tee     Copy input to stdout
 //!
+//! CONFIG FILE:
+//!   Options are listed one per line without leading dashes, e.g. 'udp-downstream-addr [::1]:9921'.
+//!   On SIGHUP the file is reloaded: downstream addresses are added and removed, and routes are
+//!   started for new listen and connect addresses and stopped for removed listen addresses,
+//!   without interrupting other routes. Other options take effect on restart.
+//!
+//! TAPS:
+//!   Connect to --tap-addr and send a line naming a route listed by the admin API or metrics,
+//!   e.g. '0.0.0.0:9920:forward', to receive a copy of the messages it receives.
+//!   Append 'hex' for a hexdump, or 'sample=N' to copy every Nth message.
+//!
+//! SYSTEMD:
+//!   Sockets passed by systemd socket activation are used in place of binding matching
+//!   addresses. With Type=notify, readiness is reported once listeners are bound, and
+//!   watchdog pings are sent while no route has panicked.
+//!
+//! FAILURES:
+//!   Routes which panic or fail are restarted with backoff, from 100ms up to 30s. With
+//!   --on-failure exit, the process instead exits with status 1, e.g. for systemd to restart it.
+//!
+//! DISPATCH:
+//!   With --dispatch round-robin, hash, or failover, each datagram is sent to one downstream:
+//!   each in turn, chosen by the sender address, or the first listed. If sending fails, or the
+//!   downstream is disabled or missed its heartbeat, the next downstream is used instead.
+//!   Heartbeats are answers to zero-length probes, which only mproxy-server sends when run with
+//!   --heartbeat, so --heartbeat-secs requires such downstreams.
+//!
+//! RATE LIMITS:
+//!   Limits are given as 'bytes=RATE[:BURST],packets=RATE[:BURST]', either part optional, and
+//!   ',delay' to delay datagrams over a downstream limit instead of dropping them. Datagrams
+//!   over a source limit are always dropped, so that one source cannot stall the others, as are
+//!   datagrams over the limit of one of several downstreams with --dispatch all. Bursts default
+//!   to one second of traffic. Dropped and delayed datagrams are counted in the metrics.
+//!
+//! RESOLUTION:
+//!   Downstream host names are looked up again every --resolve-secs, keeping the previous
+//!   addresses if a lookup fails. Names with several addresses are sent to one at a time,
+//!   failing over to the next address on errors, or with --resolve-mode all, to every address.
+//!
+//! PRIVILEGES:
+//!   When started as root, e.g. to listen on UDP ports below 1024, use --user and --group to switch
+//!   to an unprivileged user once listeners and downstream sockets are bound. Listeners added when
+//!   the --config file is reloaded are bound as that user, so privileged ports then require a
+//!   restart. The pid file is written before.
+//!
 //! EXAMPLE:
 //!   mproxy-forward --udp-listen-addr '0.0.0.0:9920' \
 //!     --udp-downstream-addr '[::1]:9921' \
@@ -74,64 +136,107 @@
 //! - [mproxy-reverse](https://docs.rs/mproxy-reverse/)
 //!
 
-use std::io::{stdout, BufWriter, Read, Write};
-use std::net::{SocketAddr, TcpStream, UdpSocket};
-use std::thread::{spawn, Builder, JoinHandle};
+use std::io::{stdout, BufWriter, ErrorKind, Read, Result as ioResult, Write};
+use std::net::TcpStream;
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::Duration;
 
-use mproxy_client::target_socket_interface;
+use log::{debug, error, info, warn};
+use mproxy_client::{Dispatch, Downstream, Downstreams};
+use mproxy_common::metrics::RouteMetrics;
+use mproxy_common::ratelimit::{Shaped, SourceLimiter};
+use mproxy_common::supervisor::supervise;
 use mproxy_server::upstream_socket_interface;
 
 const BUFSIZE: usize = 8096;
 
+/// Interval at which a stopped route notices it has been stopped
+const STOP_POLL: Duration = Duration::from_millis(500);
+
 /// Forward UDP upstream `listen_addr` to downstream UDP socket addresses.
 /// `listen_addr` may be a multicast address.
+/// Send failures are isolated per downstream, so that an unreachable
+/// downstream does not interrupt delivery to the others.
+/// Traffic is counted in route metrics named `{listen_addr}:forward`.
+/// Rate limits per source address and per downstream are applied as set in
+/// [`mproxy_common::ratelimit`].
 pub fn forward_udp(listen_addr: String, downstream_addrs: &[String], tee: bool) -> JoinHandle<()> {
-    let (_addr, listen_socket) =
-        upstream_socket_interface(listen_addr).expect("binding server socket listener");
+    forward_udp_controlled(listen_addr, downstream_addrs, tee)
+        .expect("binding forward_udp sockets")
+        .0
+}
+
+/// As [`forward_udp`], also returning the downstreams of the route. These may
+/// be added and removed while the route is running, either with the returned
+/// [`Downstreams`] or with the admin API, and the route may be stopped.
+/// Returns an error if a socket cannot be bound
+pub fn forward_udp_controlled(
+    listen_addr: String,
+    downstream_addrs: &[String],
+    tee: bool,
+) -> ioResult<(JoinHandle<()>, Arc<Downstreams>)> {
+    forward_udp_dispatch(listen_addr, downstream_addrs, tee, Dispatch::default())
+}
+
+/// As [`forward_udp_controlled`], sending each datagram to the downstreams
+/// chosen by `dispatch`, e.g. round-robin, or to a backup downstream when the
+/// first fails. With [`Policy::Hash`](mproxy_client::Policy::Hash), each
+/// upstream sender sticks to one downstream
+pub fn forward_udp_dispatch(
+    listen_addr: String,
+    downstream_addrs: &[String],
+    tee: bool,
+    dispatch: Dispatch,
+) -> ioResult<(JoinHandle<()>, Arc<Downstreams>)> {
+    let (addr, listen_socket) = upstream_socket_interface(listen_addr)?;
     let mut output_buffer = BufWriter::new(stdout());
-    let targets: Vec<(SocketAddr, UdpSocket)> = downstream_addrs
-        .iter()
-        .map(|t| target_socket_interface(t).expect("binding client socket sender"))
-        .collect();
+    let metrics = RouteMetrics::register(&addr.to_string(), "forward");
+    let targets = Downstreams::register_dispatch(metrics.clone(), downstream_addrs, dispatch)?;
+    let route_targets = targets.clone();
+    let mut source_limits = SourceLimiter::from_settings();
     let mut buf = [0u8; BUFSIZE]; // receive buffer
-    Builder::new()
-        .name(format!("{:#?}", listen_socket))
-        .spawn(move || {
-            //listen_socket.read_timeout().unwrap();
-            listen_socket.set_broadcast(true).unwrap();
-            loop {
-                match listen_socket.recv_from(&mut buf[0..]) {
-                    Ok((c, _remote_addr)) => {
-                        for (target_addr, target_socket) in &targets {
-                            if !(target_addr.is_ipv6() && target_addr.ip().is_multicast()) {
-                                target_socket
-                                    .send_to(&buf[0..c], target_addr)
-                                    .unwrap_or_else(|e| panic!("sending to server socket: {}", e));
-                            } else {
-                                target_socket
-                                    .send(&buf[0..c])
-                                    .unwrap_or_else(|e| panic!("sending to server socket: {}", e));
-                            }
-                        }
-                        if tee {
-                            let _o = output_buffer
-                                .write(&buf[0..c])
-                                .expect("writing to output buffer");
-                            #[cfg(debug_assertions)]
-                            assert!(c == _o);
+    let thread = supervise(format!("{}:forward", addr), move || {
+        let _running = metrics.running();
+        listen_socket.set_read_timeout(Some(STOP_POLL)).unwrap();
+        listen_socket.set_broadcast(true).unwrap();
+        while !targets.is_stopped() {
+            match listen_socket.recv_from(&mut buf[0..]) {
+                Ok((c, remote_addr)) => {
+                    metrics.received(c);
+                    metrics.taps().send(&buf[0..c]);
+                    if let Some(limits) = &mut source_limits {
+                        let shaped = limits.admit(remote_addr.ip(), c);
+                        metrics.source_limited(shaped);
+                        if shaped == Shaped::Dropped {
+                            continue;
                         }
                     }
-                    Err(err) => {
-                        //output_buffer.flush().unwrap();
-                        eprintln!("forward_udp: got an error: {}", err);
+                    targets.send_from(&buf[0..c], Some(remote_addr.ip()));
+                    if tee {
+                        let _o = output_buffer
+                            .write(&buf[0..c])
+                            .expect("writing to output buffer");
                         #[cfg(debug_assertions)]
-                        panic!("forward_udp: got an error: {}", err);
+                        assert!(c == _o);
                     }
                 }
-                output_buffer.flush().unwrap();
+                Err(err) if matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {}
+                Err(err) => {
+                    //output_buffer.flush().unwrap();
+                    error!("forward_udp: got an error: {}", err);
+                    #[cfg(debug_assertions)]
+                    panic!("forward_udp: got an error: {}", err);
+                }
             }
-        })
-        .unwrap()
+            output_buffer.flush().unwrap();
+            targets.check_heartbeats();
+        }
+        info!("{}: stopped", metrics.name());
+        targets.unregister();
+        Ok(())
+    });
+    Ok((thread, route_targets))
 }
 
 /// Wrapper for forward_udp listening on multiple upstream addresses
@@ -139,15 +244,28 @@ pub fn proxy_gateway(
     downstream_addrs: &[String],
     listen_addrs: &[String],
     tee: bool,
+) -> Vec<JoinHandle<()>> {
+    proxy_gateway_dispatch(downstream_addrs, listen_addrs, tee, Dispatch::default())
+}
+
+/// As [`proxy_gateway`], sending each datagram to the downstreams chosen by
+/// `dispatch`. Each listener dispatches independently
+pub fn proxy_gateway_dispatch(
+    downstream_addrs: &[String],
+    listen_addrs: &[String],
+    tee: bool,
+    dispatch: Dispatch,
 ) -> Vec<JoinHandle<()>> {
     let mut threads: Vec<JoinHandle<()>> = vec![];
     for listen_addr in listen_addrs {
-        #[cfg(debug_assertions)]
-        println!(
-            "proxy: forwarding {:?} -> {:?}",
-            listen_addr, downstream_addrs
+        debug!(
+            "proxy: forwarding {:?} -> {:?} ({:?})",
+            listen_addr, downstream_addrs, dispatch.policy
         );
-        threads.push(forward_udp(listen_addr.to_string(), downstream_addrs, tee));
+        let (thread, _) =
+            forward_udp_dispatch(listen_addr.to_string(), downstream_addrs, tee, dispatch)
+                .expect("binding forward_udp sockets");
+        threads.push(thread);
     }
     threads
 }
@@ -155,73 +273,77 @@ pub fn proxy_gateway(
 /// Connect to TCP upstream server, and forward received bytes to a
 /// downstream UDP socket socket address.
 /// TLS can be enabled with feature `tls` (provided by crate `rustls`).
+/// Traffic and reconnect attempts are counted in route metrics named
+/// `{upstream_tcp}:tcp_proxy`.
 pub fn proxy_tcp_udp(upstream_tcp: String, downstream_udp: String) -> JoinHandle<()> {
     let mut buf = [0u8; BUFSIZE];
+    let metrics = RouteMetrics::register(&upstream_tcp, "tcp_proxy");
+    let output_metrics = metrics.downstream(&downstream_udp);
 
-    #[cfg(debug_assertions)]
-    println!(
+    debug!(
         "proxy: forwarding TCP {:?} -> UDP {:?}",
         upstream_tcp, downstream_udp
     );
 
-    spawn(move || loop {
-        let target = target_socket_interface(&downstream_udp);
+    supervise(format!("{}:tcp_proxy", upstream_tcp), move || {
+        let _running = metrics.running();
+        loop {
+            let target = Downstream::new(&downstream_udp);
 
-        let (target_addr, target_socket) = if let Ok((target_addr, target_socket)) = target {
-            (target_addr, target_socket)
-        } else {
-            println!("Retrying...");
-            std::thread::sleep(std::time::Duration::from_secs(5));
-            continue;
-        };
+            let mut target = if let Ok(target) = target {
+                target.with_metrics(output_metrics.clone())
+            } else {
+                warn!("{}: retrying in 5s", upstream_tcp);
+                metrics.reconnected();
+                std::thread::sleep(std::time::Duration::from_secs(5));
+                continue;
+            };
 
-        #[cfg(feature = "tls")]
-        let (mut conn, mut stream) =
-            if let Ok((conn, stream)) = tls_connection(upstream_tcp.clone()) {
-                (conn, stream)
+            #[cfg(feature = "tls")]
+            let (mut conn, mut stream) =
+                if let Ok((conn, stream)) = tls_connection(upstream_tcp.clone()) {
+                    (conn, stream)
+                } else {
+                    warn!("{}: retrying in 5s", upstream_tcp);
+                    metrics.reconnected();
+                    std::thread::sleep(std::time::Duration::from_secs(5));
+                    continue;
+                };
+            #[cfg(feature = "tls")]
+            let mut stream = TlsStream::new(&mut conn, &mut stream);
+            #[cfg(not(feature = "tls"))]
+            let stream = TcpStream::connect(upstream_tcp.clone());
+            #[cfg(not(feature = "tls"))]
+            let mut stream = if let Ok(s) = stream {
+                s
             } else {
-                println!("Retrying...");
+                warn!("{}: retrying in 5s", upstream_tcp);
+                metrics.reconnected();
                 std::thread::sleep(std::time::Duration::from_secs(5));
                 continue;
             };
-        #[cfg(feature = "tls")]
-        let mut stream = TlsStream::new(&mut conn, &mut stream);
-        #[cfg(not(feature = "tls"))]
-        let stream = TcpStream::connect(upstream_tcp.clone());
-        #[cfg(not(feature = "tls"))]
-        let mut stream = if let Ok(s) = stream {
-            s
-        } else {
-            println!("Retrying...");
-            std::thread::sleep(std::time::Duration::from_secs(5));
-            continue;
-        };
 
-        loop {
-            match stream.read(&mut buf[0..]) {
-                Ok(c) => {
-                    if c == 0 {
-                        eprintln!("encountered EOF, disconnecting TCP proxy thread...");
-                        break;
+            loop {
+                match stream.read(&mut buf[0..]) {
+                    Ok(c) => {
+                        if c == 0 {
+                            warn!("{}: encountered EOF, disconnecting", upstream_tcp);
+                            break;
+                        }
+                        metrics.received(c);
+                        metrics.taps().send(&buf[0..c]);
+                        target.send(&buf[0..c]);
                     }
-                    if !(target_addr.is_ipv6() && target_addr.ip().is_multicast()) {
-                        target_socket
-                            .send_to(&buf[0..c], target_addr)
-                            .expect("sending to UDP socket");
-                    } else {
-                        target_socket
-                            .send(&buf[0..c])
-                            .expect("sending to UDP socket");
+                    Err(e) => {
+                        error!("{}: {}", upstream_tcp, e);
+                        break;
                     }
                 }
-                Err(e) => {
-                    eprintln!("err: {}", e);
-                    break;
-                }
             }
+            warn!("{}: retrying in 5s", upstream_tcp);
+            metrics.reconnected();
+            std::thread::sleep(std::time::Duration::from_secs(5))
         }
-        println!("Retrying...");
-        std::thread::sleep(std::time::Duration::from_secs(5))
     })
 }
 
@@ -230,8 +352,6 @@ use rustls::client::{ClientConfig, ClientConnection, ServerName};
 #[cfg(feature = "tls")]
 use rustls::Stream as TlsStream;
 #[cfg(feature = "tls")]
-use std::sync::Arc;
-#[cfg(feature = "tls")]
 use webpki_roots::TLS_SERVER_ROOTS;
 
 #[cfg(feature = "tls")]
@@ -1,9 +1,34 @@
+use std::error::Error;
+use std::ffi::OsString;
+use std::path::PathBuf;
 use std::process::exit;
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::Duration;
 
-use mproxy_forward::{proxy_gateway, proxy_tcp_udp};
+use log::{debug, error, info, warn};
+use mproxy_client::{set_refresh_interval, set_resolve_mode, Dispatch, Downstreams, ResolveMode};
+use mproxy_common::admin::serve_admin;
+#[cfg(unix)]
+use mproxy_common::config::on_sighup;
+use mproxy_common::config::read_config;
+use mproxy_common::control::{update_downstreams, RouteControl};
+use mproxy_common::daemon::{bind_error, drop_privileges, write_pidfile};
+use mproxy_common::logging::{init_logging, LogFormat, LogLevels, LogOutput};
+use mproxy_common::metrics::serve_metrics;
+use mproxy_common::ratelimit::{
+    parse_source_limit, set_downstream_limit, set_source_limit, RateLimit,
+};
+use mproxy_common::supervisor::{set_on_failure, OnFailure};
+use mproxy_common::systemd::{notify, ready, watchdog};
+use mproxy_common::tap::serve_tap;
+use mproxy_forward::{forward_udp_dispatch, proxy_tcp_udp};
 
 use pico_args::Arguments;
 
+/// Time allowed for routes stopped on reload to release their sockets
+const STOP_WAIT: Duration = Duration::from_secs(5);
+
 const HELP: &str = r#"
 MPROXY: Forwarding Proxy
 
@@ -15,12 +40,74 @@ USAGE:
 OPTIONS:
   --udp-listen-addr     [HOSTNAME:PORT]     UDP listening socket address. May be repeated
   --udp-downstream-addr [HOSTNAME:PORT]     UDP downstream socket address. May be repeated
+  --dispatch            [POLICY]            Send to all downstreams, or to one by round-robin, hash, or failover. Defaults to all
+  --heartbeat-secs      [SECONDS]           Pass over downstreams which send nothing back for SECONDS
+  --source-limit        [LIMIT]             Rate limit for each upstream source address, see RATE LIMITS
+  --downstream-limit    [LIMIT]             Rate limit for each downstream, see RATE LIMITS
   --tcp-connect-addr    [HOSTNAME:PORT]     Connect to TCP host, forwarding stream. May be repeated
+  --metrics-addr        [HOSTNAME:PORT]     Serve Prometheus metrics over HTTP at /metrics
+  --admin-addr          [HOSTNAME:PORT]     Serve the admin API over HTTP at /routes
+  --tap-addr            [ADDR|PATH]         Serve live taps of route traffic over TCP, or a Unix socket PATH
+  --config              [FILE]              Read options from FILE, one per line. Reloaded on SIGHUP
+  --on-failure          [ACTION]            Action when a route fails: restart or exit. Defaults to restart
+  --resolve-secs        [SECONDS]           Interval between lookups of downstream host names. Defaults to 30
+  --resolve-mode        [MODE]              Send to names with several addresses: failover or all. Defaults to failover
+  --log-level           [LEVEL]             Log level: error, warn, info, debug, or trace, or per target, e.g. mproxy_forward=debug,warn. Defaults to info
+  --log-format          [FORMAT]            Log format: text or json. Defaults to text
+  --log-output          [OUTPUT]            Write logs to stderr, syslog, or journald. Defaults to stderr
+  --user                [USER]              Switch to USER, a name or ID, once sockets and files are opened
+  --group               [GROUP]             Switch to GROUP. Defaults to the primary group of --user
+  --pidfile             [FILE]              Write the process ID to FILE
 
 FLAGS:
   -h, --help    Prints help information
   -t, --tee     Copy input to stdout
 
+CONFIG FILE:
+  Options are listed one per line without leading dashes, e.g. 'udp-downstream-addr [::1]:9921'.
+  On SIGHUP the file is reloaded: downstream addresses are added and removed, and routes are
+  started for new listen and connect addresses and stopped for removed listen addresses,
+  without interrupting other routes. Other options take effect on restart.
+
+TAPS:
+  Connect to --tap-addr and send a line naming a route listed by the admin API or metrics,
+  e.g. '0.0.0.0:9920:forward', to receive a copy of the messages it receives.
+  Append 'hex' for a hexdump, or 'sample=N' to copy every Nth message.
+
+SYSTEMD:
+  Sockets passed by systemd socket activation are used in place of binding matching
+  addresses. With Type=notify, readiness is reported once listeners are bound, and
+  watchdog pings are sent while no route has panicked.
+
+FAILURES:
+  Routes which panic or fail are restarted with backoff, from 100ms up to 30s. With
+  --on-failure exit, the process instead exits with status 1, e.g. for systemd to restart it.
+
+DISPATCH:
+  With --dispatch round-robin, hash, or failover, each datagram is sent to one downstream:
+  each in turn, chosen by the sender address, or the first listed. If sending fails, or the
+  downstream is disabled or missed its heartbeat, the next downstream is used instead.
+  Heartbeats are answers to zero-length probes, which only mproxy-server sends when run with
+  --heartbeat, so --heartbeat-secs requires such downstreams.
+
+RATE LIMITS:
+  Limits are given as 'bytes=RATE[:BURST],packets=RATE[:BURST]', either part optional, and
+  ',delay' to delay datagrams over a downstream limit instead of dropping them. Datagrams
+  over a source limit are always dropped, so that one source cannot stall the others, as are
+  datagrams over the limit of one of several downstreams with --dispatch all. Bursts default
+  to one second of traffic. Dropped and delayed datagrams are counted in the metrics.
+
+RESOLUTION:
+  Downstream host names are looked up again every --resolve-secs, keeping the previous
+  addresses if a lookup fails. Names with several addresses are sent to one at a time,
+  failing over to the next address on errors, or with --resolve-mode all, to every address.
+
+PRIVILEGES:
+  When started as root, e.g. to listen on UDP ports below 1024, use --user and --group to switch
+  to an unprivileged user once listeners and downstream sockets are bound. Listeners added when
+  the --config file is reloaded are bound as that user, so privileged ports then require a
+  restart. The pid file is written before.
+
 EXAMPLE:
   mproxy-forward --udp-listen-addr '0.0.0.0:9920' \
     --udp-downstream-addr '[::1]:9921' \
@@ -31,55 +118,254 @@ EXAMPLE:
 "#;
 
 pub struct GatewayArgs {
+    config: Option<PathBuf>,
     udp_listen_addrs: Vec<String>,
     udp_downstream_addrs: Vec<String>,
+    dispatch: Dispatch,
+    source_limit: Option<RateLimit>,
+    downstream_limit: Option<RateLimit>,
     tcp_connect_addrs: Vec<String>,
+    metrics_addr: Option<String>,
+    admin_addr: Option<String>,
+    tap_addr: Option<String>,
+    on_failure: OnFailure,
+    resolve_secs: u64,
+    resolve_mode: ResolveMode,
+    user: Option<String>,
+    group: Option<String>,
+    pidfile: Option<PathBuf>,
+    log_level: LogLevels,
+    log_format: LogFormat,
+    log_output: LogOutput,
     tee: bool,
 }
 
-fn parse_args() -> Result<GatewayArgs, pico_args::Error> {
-    let mut pargs = Arguments::from_env();
+/// Parse command line arguments `cli`, followed by options read from --config
+fn parse_args(cli: &[OsString]) -> Result<GatewayArgs, Box<dyn Error>> {
+    let mut pargs = Arguments::from_vec(cli.to_vec());
     if pargs.contains(["-h", "--help"]) || pargs.clone().finish().is_empty() {
         print!("{}", HELP);
         exit(0);
     }
 
+    let config: Option<PathBuf> = pargs
+        .opt_value_from_str::<_, String>("--config")?
+        .map(PathBuf::from);
+    if let Some(path) = &config {
+        let mut args = pargs.finish();
+        args.extend(read_config(path).map_err(|e| format!("reading {}: {}", path.display(), e))?);
+        pargs = Arguments::from_vec(args);
+    }
+
     let args = GatewayArgs {
+        config,
         udp_listen_addrs: pargs.values_from_str("--udp-listen-addr")?,
         udp_downstream_addrs: pargs.values_from_str("--udp-downstream-addr")?,
+        dispatch: Dispatch {
+            policy: pargs.opt_value_from_str("--dispatch")?.unwrap_or_default(),
+            heartbeat: pargs
+                .opt_value_from_str("--heartbeat-secs")?
+                .map(Duration::from_secs),
+        },
+        source_limit: pargs.opt_value_from_fn("--source-limit", parse_source_limit)?,
+        downstream_limit: pargs.opt_value_from_str("--downstream-limit")?,
         tcp_connect_addrs: pargs.values_from_str("--tcp-connect-addr")?,
+        metrics_addr: pargs.opt_value_from_str("--metrics-addr")?,
+        admin_addr: pargs.opt_value_from_str("--admin-addr")?,
+        tap_addr: pargs.opt_value_from_str("--tap-addr")?,
+        on_failure: pargs
+            .opt_value_from_str("--on-failure")?
+            .unwrap_or_default(),
+        resolve_secs: pargs.opt_value_from_str("--resolve-secs")?.unwrap_or(30),
+        resolve_mode: pargs
+            .opt_value_from_str("--resolve-mode")?
+            .unwrap_or_default(),
+        user: pargs.opt_value_from_str("--user")?,
+        group: pargs.opt_value_from_str("--group")?,
+        pidfile: pargs
+            .opt_value_from_str::<_, String>("--pidfile")?
+            .map(PathBuf::from),
+        log_level: pargs.opt_value_from_str("--log-level")?.unwrap_or_default(),
+        log_format: pargs
+            .opt_value_from_str("--log-format")?
+            .unwrap_or_default(),
+        log_output: pargs
+            .opt_value_from_str("--log-output")?
+            .unwrap_or_default(),
         tee: pargs.contains(["-t", "--tee"]),
     };
 
     let remaining = pargs.finish();
     if !remaining.is_empty() {
-        println!("Warning: unused arguments {:?}", remaining)
+        eprintln!("Warning: unused arguments {:?}", remaining)
+    }
+
+    if args.udp_listen_addrs.is_empty() {
+        return Err("Atleast one UDP listen address is required".into());
     }
 
     Ok(args)
 }
 
+/// Routes started from the configuration, by listen or connect address
+#[derive(Default)]
+struct Gateway {
+    forward: Vec<(String, Arc<Downstreams>)>,
+    downstream_addrs: Vec<String>,
+    tcp_connect_addrs: Vec<String>,
+}
+
+impl Gateway {
+    /// Start, stop, or update routes to match `args`.
+    /// Returns the threads of new routes
+    fn apply(&mut self, args: &GatewayArgs) -> Result<Vec<JoinHandle<()>>, String> {
+        let mut threads = vec![];
+
+        // UDP listeners no longer configured are stopped, and their sockets
+        // released before new listeners are bound, which may reuse the address
+        let (keep, stopped): (Vec<_>, Vec<_>) = self
+            .forward
+            .drain(..)
+            .partition(|(listen, _)| args.udp_listen_addrs.contains(listen));
+        self.forward = keep;
+        for (listen, targets) in &stopped {
+            info!("stopping forward route {}", listen);
+            targets.stop();
+        }
+        for (listen, targets) in &stopped {
+            if !targets.wait_stopped(STOP_WAIT) {
+                warn!("forward route {} has not stopped", listen);
+            }
+        }
+        for (_, targets) in &self.forward {
+            update_downstreams(
+                targets.as_ref(),
+                &self.downstream_addrs,
+                &args.udp_downstream_addrs,
+            );
+        }
+        self.downstream_addrs = args.udp_downstream_addrs.clone();
+
+        for listen in &args.udp_listen_addrs {
+            if self.forward.iter().any(|(l, _)| l == listen) {
+                continue;
+            }
+            debug!(
+                "proxy: forwarding {:?} -> {:?}",
+                listen, args.udp_downstream_addrs
+            );
+            let (thread, targets) = forward_udp_dispatch(
+                listen.clone(),
+                &args.udp_downstream_addrs,
+                args.tee,
+                args.dispatch,
+            )
+            .map_err(|e| bind_error(listen, &e))?;
+            threads.push(thread);
+            self.forward.push((listen.clone(), targets));
+        }
+
+        for upstream in &args.tcp_connect_addrs {
+            if !self.tcp_connect_addrs.contains(upstream) {
+                threads.push(proxy_tcp_udp(
+                    upstream.clone(),
+                    args.udp_listen_addrs[0].clone(),
+                ));
+                self.tcp_connect_addrs.push(upstream.clone());
+            }
+        }
+        for upstream in &self.tcp_connect_addrs {
+            if !args.tcp_connect_addrs.contains(upstream) {
+                warn!("{}: removing a TCP connection requires a restart", upstream);
+            }
+        }
+
+        Ok(threads)
+    }
+
+    /// Status line reported to systemd
+    fn status(&self) -> String {
+        format!(
+            "forwarding {} UDP listeners and {} TCP connections to {} downstreams",
+            self.forward.len(),
+            self.tcp_connect_addrs.len(),
+            self.downstream_addrs.len()
+        )
+    }
+}
+
 pub fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let args = match parse_args() {
+    let cli: Vec<OsString> = std::env::args_os().skip(1).collect();
+    let args = match parse_args(&cli) {
         Ok(a) => a,
         Err(e) => {
             eprintln!("Error: {}.", e);
             exit(1);
         }
     };
+    if let Err(e) = init_logging(args.log_level.clone(), args.log_format, args.log_output) {
+        eprintln!("Error: initializing logging: {}.", e);
+        exit(1);
+    }
+    set_on_failure(args.on_failure);
+    set_refresh_interval(Duration::from_secs(args.resolve_secs));
+    set_resolve_mode(args.resolve_mode);
+    set_source_limit(args.source_limit);
+    set_downstream_limit(args.downstream_limit);
     let mut threads = vec![];
+    let gateway = Arc::new(Mutex::new(Gateway::default()));
 
-    if args.udp_listen_addrs.is_empty() {
-        panic!("Atleast one UDP listen address is required");
+    // reload the config file on SIGHUP, updating routes in place
+    #[cfg(unix)]
+    if let Some(path) = args.config.clone() {
+        let gateway = gateway.clone();
+        threads.push(on_sighup(move || {
+            info!("reloading {}", path.display());
+            notify("RELOADING=1");
+            let mut gateway = gateway.lock().unwrap();
+            let applied = parse_args(&cli)
+                .map_err(|e| e.to_string())
+                .and_then(|args| gateway.apply(&args));
+            if let Err(e) = applied {
+                error!("reloading {}: {}", path.display(), e);
+            }
+            ready(&gateway.status());
+        }));
     }
 
-    for upstream in args.tcp_connect_addrs {
-        threads.push(proxy_tcp_udp(upstream, args.udp_listen_addrs[0].clone()));
+    if let Some(metrics_addr) = args.metrics_addr.clone() {
+        threads.push(serve_metrics(metrics_addr));
     }
 
-    for thread in proxy_gateway(&args.udp_downstream_addrs, &args.udp_listen_addrs, args.tee) {
-        threads.push(thread);
+    if let Some(admin_addr) = args.admin_addr.clone() {
+        threads.push(serve_admin(admin_addr));
+    }
+
+    if let Some(tap_addr) = args.tap_addr.clone() {
+        threads.push(serve_tap(tap_addr));
+    }
+
+    let mut gateway = gateway.lock().unwrap();
+    match gateway.apply(&args) {
+        Ok(routes) => threads.extend(routes),
+        Err(e) => {
+            eprintln!("Error: {}.", e);
+            exit(1);
+        }
+    }
+    if let Some(path) = &args.pidfile {
+        if let Err(e) = write_pidfile(path) {
+            eprintln!("Error: writing {}: {}.", path.display(), e);
+            exit(1);
+        }
+    }
+    if let Err(e) = drop_privileges(args.user.as_deref(), args.group.as_deref(), None) {
+        eprintln!("Error: {}.", e);
+        exit(1);
     }
+    let _watchdog = watchdog();
+    ready(&gateway.status());
+    drop(gateway);
 
     for thread in threads {
         thread.join().expect("joining proxy thread");
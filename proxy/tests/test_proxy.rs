@@ -1,10 +1,17 @@
+use std::fs::write;
+use std::io::{Read, Write};
+use std::net::{TcpStream, UdpSocket};
 use std::path::PathBuf;
+use std::process::{Command, Stdio};
 use std::thread::sleep;
 use std::time::Duration;
 
-use mproxy_client::client_socket_stream;
-use mproxy_forward::forward_udp;
-use mproxy_server::listener;
+use mproxy_client::{client_socket_stream, Dispatch, Policy};
+use mproxy_common::admin::serve_admin;
+use mproxy_common::metrics::serve_metrics;
+use mproxy_common::tap::serve_tap;
+use mproxy_forward::{forward_udp, forward_udp_dispatch};
+use mproxy_server::{listener, set_answer_heartbeats};
 
 use testconfig::{truncate, TESTDATA, TESTINGDIR};
 
@@ -17,7 +24,7 @@ fn test_forward_udp_ipv4_unicast() {
     let server_listen = "0.0.0.0:8891".to_string();
 
     let data = PathBuf::from(TESTDATA);
-    let pathstr = &[TESTINGDIR, "streamoutput_forward_udp_ipv4_output.log"].join(&"");
+    let pathstr = &[TESTINGDIR, "streamoutput_forward_udp_ipv4_output.log"].join("");
     let output = PathBuf::from(pathstr);
     assert!(data.is_file());
 
@@ -43,7 +50,7 @@ fn test_forward_udp_ipv6_unicast() {
     let server_listen = "[::]:8893".to_string();
 
     let data = PathBuf::from(TESTDATA);
-    let pathstr = &[TESTINGDIR, "streamoutput_forward_udp_ipv6_output.log"].join(&"");
+    let pathstr = &[TESTINGDIR, "streamoutput_forward_udp_ipv6_output.log"].join("");
     let output = PathBuf::from(pathstr);
     assert!(data.is_file());
 
@@ -73,7 +80,7 @@ fn test_forward_udp_ipv4_multicast() {
         TESTINGDIR,
         "streamoutput_forward_udp_ipv4_multicast_output.log",
     ]
-    .join(&"");
+    .join("");
     let output = PathBuf::from(pathstr);
     assert!(data.is_file());
 
@@ -103,7 +110,7 @@ fn test_forward_udp_ipv6_multicast() {
         TESTINGDIR,
         "streamoutput_forward_udp_ipv6_multicast_output.log",
     ]
-    .join(&"");
+    .join("");
     let output = PathBuf::from(pathstr);
     assert!(data.is_file());
 
@@ -121,3 +128,384 @@ fn test_forward_udp_ipv6_multicast() {
     let bytesize = truncate(output);
     assert!(bytesize > 0);
 }
+
+#[test]
+fn test_forward_udp_failing_downstream() {
+    let client_target = "127.0.0.1:8898".to_string();
+    let proxy_listen = "0.0.0.0:8898".to_string();
+    // sending to the broadcast address fails without SO_BROADCAST
+    let failing_target = "255.255.255.255:8899".to_string();
+    let proxy_target = "127.0.0.1:8899".to_string();
+    let server_listen = "0.0.0.0:8899".to_string();
+
+    let data = PathBuf::from(TESTDATA);
+    let pathstr = &[
+        TESTINGDIR,
+        "streamoutput_forward_udp_failing_downstream.log",
+    ]
+    .join("");
+    let output = PathBuf::from(pathstr);
+
+    let _l = listener(server_listen, output, false);
+    sleep(Duration::from_millis(15));
+
+    let targets = vec![failing_target, proxy_target];
+    let p = forward_udp(proxy_listen, &targets, false);
+    sleep(Duration::from_millis(15));
+
+    let _c = client_socket_stream(&data, vec![client_target], false);
+
+    let output = PathBuf::from(pathstr);
+    let bytesize = truncate(output);
+    assert!(bytesize > 0);
+    assert!(!p.is_finished());
+}
+
+#[test]
+fn test_forward_udp_metrics() {
+    let client_target = "127.0.0.1:8900".to_string();
+    let proxy_listen = "0.0.0.0:8900".to_string();
+    let failing_target = "255.255.255.255:8901".to_string();
+    let proxy_target = "127.0.0.1:8901".to_string();
+    let server_listen = "0.0.0.0:8901".to_string();
+    let metrics_addr = "127.0.0.1:8902".to_string();
+
+    let data = PathBuf::from(TESTDATA);
+    let pathstr = &[TESTINGDIR, "streamoutput_forward_udp_metrics.log"].join("");
+    let output = PathBuf::from(pathstr);
+
+    let _m = serve_metrics(metrics_addr.clone());
+    let _l = listener(server_listen, output, false);
+    sleep(Duration::from_millis(15));
+
+    let targets = vec![failing_target, proxy_target];
+    let _p = forward_udp(proxy_listen, &targets, false);
+    sleep(Duration::from_millis(15));
+
+    let _c = client_socket_stream(&data, vec![client_target], false);
+    sleep(Duration::from_millis(50));
+
+    let mut conn = TcpStream::connect(metrics_addr).unwrap();
+    conn.write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n")
+        .unwrap();
+    let mut response = String::new();
+    conn.read_to_string(&mut response).unwrap();
+    assert!(response.starts_with("HTTP/1.1 200 OK"));
+
+    let value = |series: &str| -> u64 {
+        response
+            .lines()
+            .find_map(|line| line.strip_prefix(series))
+            .and_then(|v| v.trim().parse().ok())
+            .unwrap_or_else(|| panic!("missing series {}", series))
+    };
+    let received = value("mproxy_received_datagrams_total{route=\"0.0.0.0:8900:forward\"}");
+    assert!(received > 0);
+    assert_eq!(
+        value(
+            "mproxy_sent_datagrams_total{route=\"0.0.0.0:8900:forward\",downstream=\"127.0.0.1:8901\"}"
+        ),
+        received
+    );
+    assert!(
+        value(
+            "mproxy_send_errors_total{route=\"0.0.0.0:8900:forward\",downstream=\"255.255.255.255:8901\"}"
+        ) > 0
+    );
+    assert!(value("mproxy_received_datagrams_total{route=\"0.0.0.0:8901:server\"}") > 0);
+
+    let output = PathBuf::from(pathstr);
+    truncate(output);
+}
+
+/// Receive a datagram, or None after a short timeout
+fn recv(socket: &UdpSocket) -> Option<Vec<u8>> {
+    let mut buf = [0u8; 64];
+    socket.recv(&mut buf).ok().map(|c| buf[0..c].to_vec())
+}
+
+/// Bind a UDP socket receiving from a downstream of the proxy
+fn downstream_socket(addr: &str) -> UdpSocket {
+    let socket = UdpSocket::bind(addr).unwrap();
+    socket
+        .set_read_timeout(Some(Duration::from_millis(200)))
+        .unwrap();
+    socket
+}
+
+/// Send an HTTP request and return the response
+fn http_request(addr: &str, request: &str) -> String {
+    let mut conn = TcpStream::connect(addr).unwrap();
+    conn.write_all(request.as_bytes()).unwrap();
+    let mut response = String::new();
+    conn.read_to_string(&mut response).unwrap();
+    response
+}
+
+#[test]
+fn test_forward_udp_admin_downstreams() {
+    let proxy_listen = "127.0.0.1:8903".to_string();
+    let admin_addr = "127.0.0.1:8906";
+    let first = downstream_socket("127.0.0.1:8904");
+    let second = downstream_socket("127.0.0.1:8905");
+    let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+
+    let _p = forward_udp(proxy_listen.clone(), &["127.0.0.1:8904".to_string()], false);
+    let _a = serve_admin(admin_addr.to_string());
+    sleep(Duration::from_millis(15));
+
+    client.send_to(b"one", &proxy_listen).unwrap();
+    assert_eq!(recv(&first).unwrap(), b"one");
+
+    let response = http_request(
+        admin_addr,
+        "POST /downstreams?route=127.0.0.1:8903:forward&addr=127.0.0.1:8905 HTTP/1.1\r\n\r\n",
+    );
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+    assert!(response.ends_with("\"downstreams\":[\"127.0.0.1:8904\",\"127.0.0.1:8905\"]}"));
+
+    client.send_to(b"two", &proxy_listen).unwrap();
+    assert_eq!(recv(&first).unwrap(), b"two");
+    assert_eq!(recv(&second).unwrap(), b"two");
+
+    let response = http_request(
+        admin_addr,
+        "DELETE /downstreams?route=127.0.0.1:8903:forward&addr=127.0.0.1:8904 HTTP/1.1\r\n\r\n",
+    );
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+    assert!(response
+        .ends_with("{\"route\":\"127.0.0.1:8903:forward\",\"downstreams\":[\"127.0.0.1:8905\"]}"));
+
+    client.send_to(b"three", &proxy_listen).unwrap();
+    assert_eq!(recv(&second).unwrap(), b"three");
+    assert_eq!(recv(&first), None);
+
+    let response = http_request(
+        admin_addr,
+        "DELETE /downstreams?route=127.0.0.1:8903:forward&addr=127.0.0.1:8904 HTTP/1.1\r\n\r\n",
+    );
+    assert!(response.starts_with("HTTP/1.1 409 Conflict\r\n"));
+    let response = http_request(
+        admin_addr,
+        "GET /downstreams?route=127.0.0.1:1:forward HTTP/1.1\r\n\r\n",
+    );
+    assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"));
+}
+
+#[cfg(unix)]
+#[test]
+fn test_forward_config_reload() {
+    let config = std::env::temp_dir().join("mproxy_forward_reload.conf");
+    let first = downstream_socket("127.0.0.1:8908");
+    let second = downstream_socket("127.0.0.1:8909");
+    let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+
+    write(
+        &config,
+        "# forward to the first downstream\n\
+         udp-listen-addr 127.0.0.1:8907\n\
+         udp-downstream-addr 127.0.0.1:8908\n",
+    )
+    .unwrap();
+    let mut proxy = Command::new(env!("CARGO_BIN_EXE_mproxy-forward"))
+        .arg("--config")
+        .arg(&config)
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    sleep(Duration::from_millis(200));
+    client.send_to(b"one", "127.0.0.1:8907").unwrap();
+    let before = recv(&first);
+
+    // replace the downstream, and add a listener
+    write(
+        &config,
+        "udp-listen-addr 127.0.0.1:8907\n\
+         udp-listen-addr 127.0.0.1:8910\n\
+         udp-downstream-addr 127.0.0.1:8909\n",
+    )
+    .unwrap();
+    let hup = Command::new("kill")
+        .args(["-HUP", &proxy.id().to_string()])
+        .status()
+        .unwrap();
+    sleep(Duration::from_millis(200));
+    client.send_to(b"two", "127.0.0.1:8907").unwrap();
+    let after = (recv(&first), recv(&second));
+    client.send_to(b"three", "127.0.0.1:8910").unwrap();
+    let added = recv(&second);
+
+    // replace a listener with one on the same port, once the old one has stopped
+    write(
+        &config,
+        "udp-listen-addr 127.0.0.1:8907\n\
+         udp-listen-addr 0.0.0.0:8910\n\
+         udp-downstream-addr 127.0.0.1:8909\n",
+    )
+    .unwrap();
+    Command::new("kill")
+        .args(["-HUP", &proxy.id().to_string()])
+        .status()
+        .unwrap();
+    sleep(Duration::from_millis(800));
+    client.send_to(b"four", "127.0.0.1:8910").unwrap();
+    let rebound = recv(&second);
+
+    proxy.kill().unwrap();
+    proxy.wait().unwrap();
+    assert!(hup.success());
+    assert_eq!(before.unwrap(), b"one");
+    assert_eq!(after, (None, Some(b"two".to_vec())));
+    assert_eq!(added.unwrap(), b"three");
+    assert_eq!(rebound.unwrap(), b"four");
+}
+
+#[test]
+fn test_forward_udp_tap() {
+    let proxy_listen = "127.0.0.1:8911".to_string();
+    let tap_addr = "127.0.0.1:8913";
+    let _downstream = downstream_socket("127.0.0.1:8912");
+    let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+
+    let _p = forward_udp(proxy_listen.clone(), &["127.0.0.1:8912".to_string()], false);
+    let _t = serve_tap(tap_addr.to_string());
+    sleep(Duration::from_millis(15));
+
+    let mut raw = TcpStream::connect(tap_addr).unwrap();
+    raw.write_all(b"127.0.0.1:8911:forward\n").unwrap();
+    let mut hex = TcpStream::connect(tap_addr).unwrap();
+    hex.write_all(b"127.0.0.1:8911:forward hex sample=2\n")
+        .unwrap();
+    sleep(Duration::from_millis(50));
+
+    for msg in ["one", "two", "three"] {
+        client.send_to(msg.as_bytes(), &proxy_listen).unwrap();
+    }
+    sleep(Duration::from_millis(50));
+
+    let read_available = |stream: &mut TcpStream| {
+        stream
+            .set_read_timeout(Some(Duration::from_millis(100)))
+            .unwrap();
+        let mut output = vec![];
+        let mut buf = [0u8; 4096];
+        while let Ok(c) = stream.read(&mut buf) {
+            if c == 0 {
+                break;
+            }
+            output.extend_from_slice(&buf[0..c]);
+        }
+        String::from_utf8(output).unwrap()
+    };
+    assert_eq!(read_available(&mut raw), "onetwothree");
+    let hexdump = read_available(&mut hex);
+    assert!(hexdump.contains(" 3 bytes\n00000000  6f 6e 65 "));
+    assert!(hexdump.contains("|one|\n"));
+    assert!(hexdump.contains(" 5 bytes\n00000000  74 68 72 65 65 "));
+    assert!(!hexdump.contains("|two|"));
+
+    let mut unknown = TcpStream::connect(tap_addr).unwrap();
+    unknown.write_all(b"127.0.0.1:1:forward\n").unwrap();
+    assert_eq!(
+        read_available(&mut unknown),
+        "error: no such route '127.0.0.1:1:forward'\n"
+    );
+}
+
+#[test]
+fn test_forward_udp_round_robin() {
+    let proxy_listen = "127.0.0.1:9930".to_string();
+    let first = downstream_socket("127.0.0.1:9928");
+    let second = downstream_socket("127.0.0.1:9929");
+    let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let targets = ["127.0.0.1:9928".to_string(), "127.0.0.1:9929".to_string()];
+
+    let _p = forward_udp_dispatch(
+        proxy_listen.clone(),
+        &targets,
+        false,
+        Dispatch::new(Policy::RoundRobin),
+    )
+    .unwrap();
+    sleep(Duration::from_millis(15));
+
+    for message in [b"one", b"two", b"thr", b"fou"] {
+        client.send_to(message, &proxy_listen).unwrap();
+    }
+    assert_eq!(recv(&first).unwrap(), b"one");
+    assert_eq!(recv(&first).unwrap(), b"thr");
+    assert_eq!(recv(&second).unwrap(), b"two");
+    assert_eq!(recv(&second).unwrap(), b"fou");
+    assert_eq!(recv(&first), None);
+}
+
+#[test]
+fn test_forward_udp_failover_heartbeat() {
+    let proxy_listen = "127.0.0.1:9931".to_string();
+    let pathstr = &[TESTINGDIR, "streamoutput_forward_udp_failover.log"].join("");
+    // the primary never answers heartbeat probes, the backup server does
+    let primary = downstream_socket("127.0.0.1:9932");
+    set_answer_heartbeats(true);
+    let _backup = listener("127.0.0.1:9933".to_string(), PathBuf::from(pathstr), false);
+    let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let targets = ["127.0.0.1:9932".to_string(), "127.0.0.1:9933".to_string()];
+
+    let dispatch = Dispatch::new(Policy::Failover).with_heartbeat(Duration::from_secs(1));
+    let _p = forward_udp_dispatch(proxy_listen.clone(), &targets, false, dispatch).unwrap();
+    sleep(Duration::from_millis(15));
+
+    client.send_to(b"one", &proxy_listen).unwrap();
+    let mut buf = [0u8; 64];
+    // skip heartbeat probes
+    let message = loop {
+        let c = primary.recv(&mut buf).unwrap();
+        if c > 0 {
+            break buf[0..c].to_vec();
+        }
+    };
+    assert_eq!(message, b"one");
+
+    // the primary misses its heartbeat, so the backup is used
+    sleep(Duration::from_millis(1600));
+    client.send_to(b"two\n", &proxy_listen).unwrap();
+    sleep(Duration::from_millis(100));
+    let output = std::fs::read_to_string(pathstr).unwrap();
+    assert_eq!(output, "two\n");
+    truncate(PathBuf::from(pathstr));
+}
+
+#[test]
+fn test_forward_rate_limits() {
+    let downstream = downstream_socket("127.0.0.1:9934");
+    let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+
+    // each source may send bursts of 3 datagrams, and the downstream 2
+    let mut proxy = Command::new(env!("CARGO_BIN_EXE_mproxy-forward"))
+        .args(["--udp-listen-addr", "127.0.0.1:9935"])
+        .args(["--udp-downstream-addr", "127.0.0.1:9934"])
+        .args(["--source-limit", "packets=1:3"])
+        .args(["--downstream-limit", "packets=1:2"])
+        .args(["--metrics-addr", "127.0.0.1:9936"])
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    sleep(Duration::from_millis(200));
+    for _ in 0..6 {
+        client.send_to(b"blast", "127.0.0.1:9935").unwrap();
+    }
+    let mut received = 0;
+    while recv(&downstream).is_some() {
+        received += 1;
+    }
+    let metrics = http_request("127.0.0.1:9936", "GET /metrics HTTP/1.1\r\n\r\n");
+    proxy.kill().unwrap();
+    proxy.wait().unwrap();
+
+    assert_eq!(received, 2);
+    assert!(metrics.contains(
+        "mproxy_source_limit_dropped_datagrams_total{route=\"127.0.0.1:9935:forward\"} 3\n"
+    ));
+    assert!(metrics.contains(
+        "mproxy_limit_dropped_datagrams_total{route=\"127.0.0.1:9935:forward\",downstream=\"127.0.0.1:9934\"} 1\n"
+    ));
+}
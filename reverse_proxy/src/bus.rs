@@ -0,0 +1,346 @@
+//! Fan-out of messages received from the multicast bus to downstream subscribers.
+//!
+//! A single thread joins the multicast channel and copies each message into a
+//! bounded queue for every subscriber whose subscription matches the message
+//! topic. Subscribers that fall behind have messages dropped from their own
+//! queue, without slowing down the bus or other subscribers.
+//!
+//! Recent messages, or the latest message for each key, may optionally be
+//! kept in memory and replayed to new subscribers before live messages.
+//! Whatever the configured count or age, at most [`MAX_HISTORY`] recent
+//! messages of up to [`MAX_HISTORY_BYTES`] in total are kept. New subscribers
+//! have room queued for their replay in addition to live messages, so that
+//! live messages are not dropped while the replay is written.
+//!
+//! Outputs forwarding the same multicast address share a single bus.
+//! Received messages are counted in route metrics named `{addr}:bus`, and
+//! messages dropped for slow subscribers in the metrics of their output.
+
+use std::collections::VecDeque;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{sync_channel, Receiver, RecvTimeoutError, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use mproxy_common::metrics::{DownstreamMetrics, RouteMetrics};
+use mproxy_common::supervisor::supervise;
+use mproxy_server::upstream_socket_interface;
+
+use crate::cache::LastValueCache;
+use crate::topic::{topic_of, Subscription};
+use crate::{SubscriberConfig, BUFSIZE};
+
+/// Maximum number of messages queued for a single subscriber
+const SUBSCRIBER_QUEUE: usize = 1024;
+
+/// Maximum number of recent messages kept for replay
+const MAX_HISTORY: usize = 1024;
+
+/// Maximum total size of recent messages kept for replay
+const MAX_HISTORY_BYTES: usize = 4 << 20;
+
+/// A message payload shared between subscribers
+pub(crate) type Message = Arc<[u8]>;
+
+/// A message queued for a subscriber, along with its topic
+type Queued = (Arc<str>, Message);
+
+/// Buses listening in this process, by multicast address
+static BUSES: Mutex<Vec<(SocketAddr, Arc<Bus>)>> = Mutex::new(vec![]);
+
+struct Subscriber {
+    subscription: Arc<Mutex<Subscription>>,
+    sender: SyncSender<Queued>,
+    queue_depth: Arc<AtomicUsize>,
+    metrics: Arc<DownstreamMetrics>,
+}
+
+/// Cached and recent messages replayed to a subscriber, followed by the
+/// live messages queued for it
+pub(crate) struct Messages {
+    replay: VecDeque<Queued>,
+    receiver: Receiver<Queued>,
+    queue_depth: Arc<AtomicUsize>,
+    subscription: Arc<Mutex<Subscription>>,
+}
+
+impl Messages {
+    /// Number of messages waiting in the queue, shared with the bus
+    pub(crate) fn queue_depth(&self) -> Arc<AtomicUsize> {
+        self.queue_depth.clone()
+    }
+
+    /// Change the subscription, also filtering the messages already queued,
+    /// e.g. once a client has sent its subscription line
+    pub(crate) fn resubscribe(&self, subscription: Subscription) {
+        *self.subscription.lock().unwrap() = subscription;
+    }
+
+    /// The next replayed message matching the subscription, if any
+    fn next_replayed(&mut self) -> Option<Message> {
+        while let Some((topic, msg)) = self.replay.pop_front() {
+            if self.subscription.lock().unwrap().matches(&topic) {
+                return Some(msg);
+            }
+        }
+        None
+    }
+
+    /// Returns `msg` taken from the queue if it matches the subscription
+    fn dequeued(&self, (topic, msg): Queued) -> Option<Message> {
+        self.queue_depth.fetch_sub(1, Ordering::Relaxed);
+        self.subscription
+            .lock()
+            .unwrap()
+            .matches(&topic)
+            .then_some(msg)
+    }
+
+    pub(crate) fn recv_timeout(&mut self, timeout: Duration) -> Result<Message, RecvTimeoutError> {
+        if let Some(msg) = self.next_replayed() {
+            return Ok(msg);
+        }
+        let deadline = Instant::now() + timeout;
+        loop {
+            let timeout = deadline.saturating_duration_since(Instant::now());
+            if let Some(msg) = self.dequeued(self.receiver.recv_timeout(timeout)?) {
+                return Ok(msg);
+            }
+        }
+    }
+}
+
+impl Iterator for Messages {
+    type Item = Message;
+
+    fn next(&mut self) -> Option<Message> {
+        if let Some(msg) = self.next_replayed() {
+            return Some(msg);
+        }
+        loop {
+            if let Some(msg) = self.dequeued(self.receiver.recv().ok()?) {
+                return Some(msg);
+            }
+        }
+    }
+}
+
+/// A message kept in the replay history
+struct Recent {
+    received: Instant,
+    topic: Arc<str>,
+    payload: Message,
+}
+
+struct BusState {
+    subscribers: Vec<Subscriber>,
+    history: VecDeque<Recent>,
+    /// Total size of the payloads in `history`
+    history_bytes: usize,
+    cache: Option<LastValueCache>,
+}
+
+/// Messages received from a multicast channel, shared by downstream subscribers
+pub(crate) struct Bus {
+    config: SubscriberConfig,
+    metrics: Arc<RouteMetrics>,
+    state: Mutex<BusState>,
+}
+
+impl Bus {
+    /// Returns the bus listening on `multicast_addr`, or starts a new one with
+    /// the given config. Outputs sharing a bus also share its config, so an
+    /// output with a different config is refused
+    pub(crate) fn shared(
+        multicast_addr: String,
+        config: SubscriberConfig,
+    ) -> Result<Arc<Bus>, String> {
+        let addr = multicast_addr
+            .to_socket_addrs()
+            .map_err(|e| format!("resolving {}: {}", multicast_addr, e))?
+            .next()
+            .ok_or_else(|| format!("no address for {}", multicast_addr))?;
+        let mut buses = BUSES.lock().unwrap();
+        if let Some((_, bus)) = buses.iter().find(|(a, _)| *a == addr) {
+            if bus.config != config {
+                return Err(format!(
+                    "the bus on {} is already shared by outputs with different subscriber options",
+                    addr
+                ));
+            }
+            return Ok(bus.clone());
+        }
+        let bus = Bus::listen(multicast_addr, config);
+        buses.push((addr, bus.clone()));
+        Ok(bus)
+    }
+
+    /// Join the multicast channel at `multicast_addr`, and spawn a thread
+    /// forwarding received messages to subscribers
+    fn listen(multicast_addr: String, config: SubscriberConfig) -> Arc<Bus> {
+        let (addr, multicast_socket) =
+            upstream_socket_interface(multicast_addr).expect("binding multicast socket listener");
+        if !addr.ip().is_multicast() {
+            panic!("not a multicast address {}", addr);
+        }
+        let state = BusState {
+            subscribers: vec![],
+            history: VecDeque::new(),
+            history_bytes: 0,
+            cache: config
+                .cache_key
+                .clone()
+                .map(|rule| LastValueCache::new(rule, config.cache_ttl)),
+        };
+        let bus = Arc::new(Bus {
+            config,
+            metrics: RouteMetrics::register(&addr.to_string(), "bus"),
+            state: Mutex::new(state),
+        });
+        let bus_thread = bus.clone();
+        supervise(format!("{}:bus", addr), move || {
+            let _running = bus_thread.metrics.running();
+            let mut buf = [0u8; BUFSIZE];
+            loop {
+                match multicast_socket.recv_from(&mut buf[0..]) {
+                    Ok((c, remote_addr)) => bus_thread.publish(&buf[0..c], &remote_addr),
+                    Err(err) => return Err(err.to_string()),
+                }
+            }
+        });
+        bus
+    }
+
+    /// Metrics counting messages received by the bus
+    pub(crate) fn metrics(&self) -> &RouteMetrics {
+        &self.metrics
+    }
+
+    /// Returns true if messages are tagged with topics, and subscribers may
+    /// select a subset of the bus
+    pub(crate) fn has_topics(&self) -> bool {
+        !self.config.topic_rules.is_empty()
+    }
+
+    /// Returns true if recent messages are kept for replay to new subscribers
+    fn keeps_history(&self) -> bool {
+        self.config.replay_count.is_some() || self.config.replay_age.is_some()
+    }
+
+    /// Drop messages from the replay history exceeding the configured
+    /// limits, or [`MAX_HISTORY`] and [`MAX_HISTORY_BYTES`]
+    fn trim_history(&self, state: &mut BusState) {
+        let count = self
+            .config
+            .replay_count
+            .unwrap_or(MAX_HISTORY)
+            .min(MAX_HISTORY);
+        let age = self.config.replay_age;
+        while state.history.front().is_some_and(|m| {
+            state.history.len() > count
+                || state.history_bytes > MAX_HISTORY_BYTES
+                || age.is_some_and(|age| m.received.elapsed() > age)
+        }) {
+            let oldest = state.history.pop_front().unwrap();
+            state.history_bytes -= oldest.payload.len();
+        }
+    }
+
+    /// Register a new subscriber. Returns the cached and recent messages
+    /// matching the subscription, followed by live messages. The subscriber
+    /// is removed once the messages are dropped. Messages dropped from the
+    /// subscriber queue are counted in `metrics`
+    pub(crate) fn subscribe(
+        &self,
+        subscription: Subscription,
+        metrics: Arc<DownstreamMetrics>,
+    ) -> Messages {
+        let queue_depth = Arc::new(AtomicUsize::new(0));
+        let mut state = self.state.lock().unwrap();
+        let mut replay: VecDeque<Queued> = match state.cache.as_mut() {
+            Some(cache) => cache.snapshot(|topic| subscription.matches(topic)).into(),
+            None => VecDeque::new(),
+        };
+        self.trim_history(&mut state);
+        replay.extend(
+            state
+                .history
+                .iter()
+                .filter(|m| subscription.matches(&m.topic))
+                .map(|m| (m.topic.clone(), m.payload.clone())),
+        );
+        // live messages received while the replay is written are queued
+        // in addition to the usual queue
+        let (sender, receiver) = sync_channel(SUBSCRIBER_QUEUE + replay.len());
+        let subscription = Arc::new(Mutex::new(subscription));
+        state.subscribers.push(Subscriber {
+            subscription: subscription.clone(),
+            sender,
+            queue_depth: queue_depth.clone(),
+            metrics,
+        });
+        Messages {
+            replay,
+            receiver,
+            queue_depth,
+            subscription,
+        }
+    }
+
+    fn publish(&self, msg: &[u8], remote_addr: &SocketAddr) {
+        self.metrics.received(msg.len());
+        self.metrics.taps().send(msg);
+        let (topic, payload) = topic_of(&self.config.topic_rules, msg, remote_addr);
+        let payload: Message = payload.into();
+        let topic: Arc<str> = topic.into();
+        let mut state = self.state.lock().unwrap();
+        state.subscribers.retain(|subscriber| {
+            if !subscriber.subscription.lock().unwrap().matches(&topic) {
+                return true;
+            }
+            // counted before sending, so that the receiver never sees a negative depth
+            subscriber.queue_depth.fetch_add(1, Ordering::Relaxed);
+            match subscriber.sender.try_send((topic.clone(), payload.clone())) {
+                Ok(()) => true,
+                Err(TrySendError::Full(_)) => {
+                    subscriber.queue_depth.fetch_sub(1, Ordering::Relaxed);
+                    subscriber.metrics.dropped();
+                    true
+                }
+                Err(TrySendError::Disconnected(_)) => false,
+            }
+        });
+        if let Some(cache) = state.cache.as_mut() {
+            cache.insert(&topic, &payload);
+        }
+        if self.keeps_history() {
+            state.history_bytes += payload.len();
+            state.history.push_back(Recent {
+                received: Instant::now(),
+                topic,
+                payload,
+            });
+            self.trim_history(&mut state);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_shared_bus_config() {
+        let addr = "224.0.0.1:9955".to_string();
+        let bus = Bus::shared(addr.clone(), SubscriberConfig::default()).unwrap();
+        let same = Bus::shared(addr.clone(), SubscriberConfig::default()).unwrap();
+        assert!(Arc::ptr_eq(&bus, &same));
+        let replaying = SubscriberConfig {
+            replay_count: Some(10),
+            ..Default::default()
+        };
+        assert!(Bus::shared(addr, replaying).is_err());
+        assert!(Bus::shared("unresolvable.invalid:9955".to_string(), Default::default()).is_err());
+    }
+}
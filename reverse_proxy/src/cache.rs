@@ -0,0 +1,164 @@
+//! Keyed last-value cache of bus messages.
+//!
+//! Keeps the most recent message for each key (e.g. a vessel identifier),
+//! so that new subscribers receive a snapshot of the latest state before
+//! live messages. Keys are extracted from each message payload by a [`KeyRule`].
+//! Regex key rules require feature `regex`.
+
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::bus::Message;
+
+/// Default delimiter separating fields of a message
+pub const FIELD_DELIMITER: u8 = b',';
+
+/// Rule used to extract a cache key from a message
+#[derive(Clone, Debug)]
+pub enum KeyRule {
+    /// Key is the zero-indexed field of the message split by a delimiter,
+    /// excluding trailing whitespace
+    Field { index: usize, delimiter: u8 },
+    /// Key is the first capture group of a regular expression, or the whole
+    /// match if the expression has no groups
+    #[cfg(feature = "regex")]
+    Regex(regex::bytes::Regex),
+}
+
+/// Rules are equal if they extract the same key, comparing regular
+/// expressions by their source
+impl PartialEq for KeyRule {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (
+                KeyRule::Field { index, delimiter },
+                KeyRule::Field {
+                    index: other_index,
+                    delimiter: other_delimiter,
+                },
+            ) => index == other_index && delimiter == other_delimiter,
+            #[cfg(feature = "regex")]
+            (KeyRule::Regex(re), KeyRule::Regex(other)) => re.as_str() == other.as_str(),
+            #[cfg(feature = "regex")]
+            _ => false,
+        }
+    }
+}
+
+impl KeyRule {
+    /// Extract the key from a message payload
+    pub fn key<'a>(&self, msg: &'a [u8]) -> Option<&'a [u8]> {
+        match self {
+            KeyRule::Field { index, delimiter } => msg
+                .split(|b| b == delimiter)
+                .nth(*index)
+                .map(|field| field.trim_ascii_end())
+                .filter(|field| !field.is_empty()),
+            #[cfg(feature = "regex")]
+            KeyRule::Regex(re) => {
+                let captures = re.captures(msg)?;
+                captures
+                    .get(1)
+                    .or_else(|| captures.get(0))
+                    .map(|m| m.as_bytes())
+            }
+        }
+    }
+}
+
+/// Parse a key rule from the command line.
+///
+/// Accepted forms are `field:INDEX`, `field:INDEX:DELIM`, and `regex:PATTERN`
+impl FromStr for KeyRule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (kind, rule) = s.split_once(':').unwrap_or((s, ""));
+        match kind {
+            "field" => {
+                let (index, delimiter) = match rule.split_once(':') {
+                    Some((index, delim)) if delim.len() == 1 => (index, delim.as_bytes()[0]),
+                    Some(_) => return Err(format!("invalid delimiter in key rule '{}'", s)),
+                    None => (rule, FIELD_DELIMITER),
+                };
+                let index = index
+                    .parse()
+                    .map_err(|e| format!("invalid field index in key rule '{}': {}", s, e))?;
+                Ok(KeyRule::Field { index, delimiter })
+            }
+            #[cfg(feature = "regex")]
+            "regex" => regex::bytes::Regex::new(rule)
+                .map(KeyRule::Regex)
+                .map_err(|e| format!("invalid pattern in key rule '{}': {}", s, e)),
+            #[cfg(not(feature = "regex"))]
+            "regex" => Err("regex key rules require feature 'regex'".to_string()),
+            _ => Err(format!("unknown key rule '{}'", s)),
+        }
+    }
+}
+
+/// A cached message, along with its topic
+struct Latest {
+    received: Instant,
+    topic: Arc<str>,
+    payload: Message,
+}
+
+/// Most recent message for each key, with optional expiry
+pub(crate) struct LastValueCache {
+    rule: KeyRule,
+    ttl: Option<Duration>,
+    latest: HashMap<Vec<u8>, Latest>,
+    last_purge: Instant,
+}
+
+impl LastValueCache {
+    pub(crate) fn new(rule: KeyRule, ttl: Option<Duration>) -> Self {
+        LastValueCache {
+            rule,
+            ttl,
+            latest: HashMap::new(),
+            last_purge: Instant::now(),
+        }
+    }
+
+    /// Store `payload` as the latest message for its key.
+    /// Messages without a key are not cached
+    pub(crate) fn insert(&mut self, topic: &Arc<str>, payload: &Message) {
+        if let Some(key) = self.rule.key(payload) {
+            let latest = Latest {
+                received: Instant::now(),
+                topic: topic.clone(),
+                payload: payload.clone(),
+            };
+            self.latest.insert(key.to_vec(), latest);
+        }
+        if let Some(ttl) = self.ttl {
+            if self.last_purge.elapsed() > ttl {
+                self.purge();
+            }
+        }
+    }
+
+    /// Remove expired messages
+    fn purge(&mut self) {
+        if let Some(ttl) = self.ttl {
+            self.latest.retain(|_, m| m.received.elapsed() <= ttl);
+        }
+        self.last_purge = Instant::now();
+    }
+
+    /// Latest unexpired message for each key with a topic matching `filter`,
+    /// along with its topic, in the order they were received
+    pub(crate) fn snapshot(&mut self, filter: impl Fn(&str) -> bool) -> Vec<(Arc<str>, Message)> {
+        self.purge();
+        let mut latest: Vec<&Latest> = self.latest.values().filter(|m| filter(&m.topic)).collect();
+        latest.sort_by_key(|m| m.received);
+        latest
+            .into_iter()
+            .map(|m| (m.topic.clone(), m.payload.clone()))
+            .collect()
+    }
+}
@@ -0,0 +1,298 @@
+//! HTTP endpoints of the reverse proxy.
+//!
+//! `GET /stream` serves messages from the multicast bus to HTTP clients,
+//! either as Server-Sent Events (with `?format=sse`, or when the client accepts
+//! `text/event-stream`), or as chunked newline-delimited output. Messages may
+//! be filtered with query string parameters `topics` (when topic rules are
+//! set) and `contains`, e.g. `/stream?topics=ais.*&contains=AIVDM`.
+//!
+//! `POST /publish` sends the request body to the multicast bus as a single
+//! message, or as one message per line when the request has query parameter
+//! `batch`, or content type `application/x-ndjson`.
+//! If a token is set, requests must include header `Authorization: Bearer TOKEN`.
+
+use std::io::{BufReader, BufWriter, Read, Result as ioResult, Write};
+use std::net::TcpStream;
+use std::sync::mpsc::RecvTimeoutError;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use log::{debug, error};
+use mproxy_client::Downstream;
+use mproxy_common::http::{read_request, respond};
+use mproxy_common::metrics::{DownstreamMetrics, RouteMetrics};
+
+use crate::bus::Bus;
+use crate::topic::Subscription;
+use crate::{peer_name, BUFSIZE};
+
+/// Interval between keepalive comments sent to Server-Sent Events clients
+const SSE_KEEPALIVE: Duration = Duration::from_secs(30);
+
+/// Maximum size of a request body sent to `POST /publish`
+const MAX_BODY: usize = 1 << 20;
+
+/// Time allowed for a publishing client to send a complete request
+const PUBLISH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Returns true if `needle` occurs in `haystack`
+fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+    needle.is_empty() || haystack.windows(needle.len()).any(|w| w == needle)
+}
+
+/// Write a message as a Server-Sent Event, with one data field per line
+fn write_event(writer: &mut impl Write, msg: &[u8]) -> ioResult<()> {
+    let text = String::from_utf8_lossy(msg);
+    for line in text.trim_end_matches(['\r', '\n']).split('\n') {
+        writeln!(writer, "data: {}", line.trim_end_matches('\r'))?;
+    }
+    writer.write_all(b"\n")
+}
+
+/// Write a message as a chunk, terminated by a newline
+fn write_chunk(writer: &mut impl Write, msg: &[u8]) -> ioResult<()> {
+    let newline = !msg.ends_with(b"\n");
+    write!(writer, "{:x}\r\n", msg.len() + newline as usize)?;
+    writer.write_all(msg)?;
+    if newline {
+        writer.write_all(b"\n")?;
+    }
+    writer.write_all(b"\r\n")
+}
+
+/// Serve `GET /stream` requests, forwarding bus messages matching the
+/// request filters until the connection is closed
+pub(crate) fn handle_client_http(
+    downstream: TcpStream,
+    bus: Arc<Bus>,
+    metrics: Arc<DownstreamMetrics>,
+) {
+    debug!("handling downstream client: {:?} HTTP", downstream);
+
+    let peer = peer_name(&downstream);
+    let mut writer = match downstream.try_clone() {
+        Ok(w) => BufWriter::new(w),
+        Err(_) => return,
+    };
+    let request = match read_request(&mut BufReader::new(downstream)) {
+        Ok(r) => r,
+        Err(_) => {
+            let _ = respond(
+                &mut writer,
+                "400 Bad Request",
+                "text/plain",
+                b"bad request\n",
+            );
+            return;
+        }
+    };
+    if request.path != "/stream" {
+        let _ = respond(&mut writer, "404 Not Found", "text/plain", b"not found\n");
+        return;
+    } else if request.method != "GET" {
+        let body = b"method not allowed\n";
+        let _ = respond(&mut writer, "405 Method Not Allowed", "text/plain", body);
+        return;
+    }
+
+    let sse = match request.param("format") {
+        Some(format) => format == "sse",
+        None => request
+            .header("Accept")
+            .is_some_and(|accept| accept.contains("text/event-stream")),
+    };
+    let subscription = match request.param("topics") {
+        Some(topics) if bus.has_topics() => topics.parse().unwrap_or_else(|_| Subscription::all()),
+        _ => Subscription::all(),
+    };
+    let filter = request
+        .param("contains")
+        .unwrap_or_default()
+        .as_bytes()
+        .to_vec();
+
+    let head = if sse {
+        "HTTP/1.1 200 OK\r\n\
+         Content-Type: text/event-stream\r\n\
+         Cache-Control: no-cache\r\n\r\n"
+    } else {
+        "HTTP/1.1 200 OK\r\n\
+         Content-Type: text/plain; charset=utf-8\r\n\
+         Cache-Control: no-cache\r\n\
+         Transfer-Encoding: chunked\r\n\r\n"
+    };
+    if writer
+        .write_all(head.as_bytes())
+        .and_then(|()| writer.flush())
+        .is_err()
+    {
+        return;
+    }
+
+    let mut messages = bus.subscribe(subscription, metrics.clone());
+    let _client = metrics.connected(peer, Some(messages.queue_depth()));
+    let send = |writer: &mut BufWriter<TcpStream>, msg: &[u8]| {
+        if !contains(msg, &filter) {
+            return Ok(());
+        }
+        if sse {
+            write_event(writer, msg)?;
+        } else {
+            write_chunk(writer, msg)?;
+        }
+        writer.flush()?;
+        metrics.sent(msg.len());
+        Ok(())
+    };
+    loop {
+        let sent = match messages.recv_timeout(SSE_KEEPALIVE) {
+            Ok(msg) => send(&mut writer, &msg),
+            Err(RecvTimeoutError::Timeout) if sse => writer
+                .write_all(b": keepalive\n\n")
+                .and_then(|()| writer.flush()),
+            Err(RecvTimeoutError::Timeout) => Ok(()),
+            Err(RecvTimeoutError::Disconnected) => break,
+        };
+        if let Err(e) = sent {
+            debug!("http: closing {:?} {}", writer.get_ref(), e);
+            break;
+        }
+    }
+}
+
+/// Compare tokens in constant time
+fn token_matches(expected: &str, given: &str) -> bool {
+    expected.len() == given.len()
+        && expected
+            .bytes()
+            .zip(given.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+/// Serve `POST /publish` requests, sending the request body to `target`
+pub(crate) fn handle_client_publish(
+    upstream: TcpStream,
+    target: &Mutex<Downstream>,
+    token: Option<&str>,
+    metrics: &RouteMetrics,
+) {
+    debug!("handling upstream client: {:?} HTTP", upstream);
+
+    let _ = upstream.set_read_timeout(Some(PUBLISH_TIMEOUT));
+    let mut writer = match upstream.try_clone() {
+        Ok(w) => BufWriter::new(w),
+        Err(_) => return,
+    };
+    let mut reader = BufReader::new(upstream);
+    let request = match read_request(&mut reader) {
+        Ok(r) => r,
+        Err(_) => {
+            let _ = respond(
+                &mut writer,
+                "400 Bad Request",
+                "text/plain",
+                b"bad request\n",
+            );
+            return;
+        }
+    };
+    if request.path != "/publish" {
+        let _ = respond(&mut writer, "404 Not Found", "text/plain", b"not found\n");
+        return;
+    } else if request.method != "POST" {
+        let body = b"method not allowed\n";
+        let _ = respond(&mut writer, "405 Method Not Allowed", "text/plain", body);
+        return;
+    }
+    if let Some(token) = token {
+        let given = request
+            .header("Authorization")
+            .and_then(|auth| auth.strip_prefix("Bearer "))
+            .unwrap_or_default();
+        if !token_matches(token, given.trim()) {
+            let _ = write!(
+                writer,
+                "HTTP/1.1 401 Unauthorized\r\n\
+                 WWW-Authenticate: Bearer\r\n\
+                 Content-Length: 0\r\n\
+                 Connection: close\r\n\r\n"
+            )
+            .and_then(|()| writer.flush());
+            return;
+        }
+    }
+
+    let length: usize = match request.header("Content-Length").map(|l| l.parse()) {
+        Some(Ok(length)) => length,
+        Some(Err(_)) => {
+            let body = b"invalid content length\n";
+            let _ = respond(&mut writer, "400 Bad Request", "text/plain", body);
+            return;
+        }
+        None => {
+            let body = b"content length is required\n";
+            let _ = respond(&mut writer, "411 Length Required", "text/plain", body);
+            return;
+        }
+    };
+    if length > MAX_BODY {
+        let body = format!("request body exceeds {} bytes\n", MAX_BODY);
+        let _ = respond(
+            &mut writer,
+            "413 Payload Too Large",
+            "text/plain",
+            body.as_bytes(),
+        );
+        return;
+    }
+    let mut body = Vec::with_capacity(length);
+    match reader.take(length as u64).read_to_end(&mut body) {
+        Ok(c) if c == length => {}
+        _ => {
+            let _ = respond(
+                &mut writer,
+                "400 Bad Request",
+                "text/plain",
+                b"incomplete body\n",
+            );
+            return;
+        }
+    }
+
+    let batch = request.param("batch").is_some()
+        || request
+            .header("Content-Type")
+            .is_some_and(|t| t.starts_with("application/x-ndjson"));
+    let messages: Vec<&[u8]> = if batch {
+        body.split_inclusive(|b| *b == b'\n')
+            .filter(|line| !line.trim_ascii().is_empty())
+            .collect()
+    } else {
+        vec![&body[..]]
+    };
+    if messages.iter().any(|msg| msg.len() > BUFSIZE) {
+        let body = format!("message exceeds {} bytes\n", BUFSIZE);
+        let _ = respond(
+            &mut writer,
+            "413 Payload Too Large",
+            "text/plain",
+            body.as_bytes(),
+        );
+        return;
+    }
+
+    for msg in &messages {
+        metrics.received(msg.len());
+        metrics.taps().send(msg);
+        let mut target = target.lock().unwrap();
+        if !target.send(msg) {
+            error!("http: sending to {} failed", target.name());
+            let body = b"failed to publish\n";
+            let _ = respond(&mut writer, "502 Bad Gateway", "text/plain", body);
+            return;
+        }
+    }
+    let body = format!("{}\n", messages.len());
+    let _ = respond(&mut writer, "202 Accepted", "text/plain", body.as_bytes());
+}
@@ -66,17 +66,102 @@
 //!
 //! OPTIONS:
 //!   --udp-listen-addr [HOSTNAME:PORT]     Spawn a UDP socket listener, and forward to --multicast-addr
-//!   --tcp_listen_addr [HOSTNAME:PORT]     Reverse-proxy accepting TCP connections and forwarding to --multicast-addr
+//!   --tcp-listen-addr [HOSTNAME:PORT]     Reverse-proxy accepting TCP connections and forwarding to --multicast-addr
 //!   --multicast-addr  [MULTICAST_IP:PORT] Defaults to '[ff02::1]:9918'
 //!   --tcp-output-addr [HOSTNAME:PORT]     Forward packets from --multicast-addr to TCP downstream
-//!   --udp_output_addr [HOSTNAME:PORT]     Forward packets from --multicast-addr to UDP downstream
+//!   --udp-output-addr [HOSTNAME:PORT]     Forward packets from --multicast-addr to UDP downstream. May be repeated
+//!   --ws-output-addr  [HOSTNAME:PORT]     Forward packets from --multicast-addr to WebSocket clients
+//!   --http-output-addr [HOSTNAME:PORT]    Stream packets from --multicast-addr to HTTP clients at /stream
+//!   --http-listen-addr [HOSTNAME:PORT]    Accept HTTP POST requests at /publish, forwarding to --multicast-addr
+//!   --http-token      [TOKEN]             Require 'Authorization: Bearer TOKEN' for /publish
+//!   --topic-rule      [RULE]              Tag messages with a topic for subscribers. May be repeated
+//!   --replay-count    [N]                 Replay the last N messages to new subscribers, up to 1024
+//!   --replay-secs     [SECONDS]           Replay messages from the last SECONDS to new subscribers, up to 1024 or 4 MiB
+//!   --cache-key       [RULE]              Send the latest message for each key to new subscribers
+//!   --cache-ttl       [SECONDS]           Expire cached messages after SECONDS
+//!   --metrics-addr    [HOSTNAME:PORT]     Serve Prometheus metrics over HTTP at /metrics
+//!   --admin-addr      [HOSTNAME:PORT]     Serve the admin API over HTTP at /routes
+//!   --tap-addr        [ADDR|PATH]         Serve live taps of route traffic over TCP, or a Unix socket PATH
+//!   --config          [FILE]              Read options from FILE, one per line. Reloaded on SIGHUP
+//!   --drain-secs      [SECONDS]           Time allowed for clients to disconnect after an upgrade. Defaults to 30
+//!   --on-failure      [ACTION]            Action when a route fails: restart or exit. Defaults to restart
+//!   --resolve-secs    [SECONDS]           Interval between lookups of downstream host names. Defaults to 30
+//!   --resolve-mode    [MODE]              Send to names with several addresses: failover or all. Defaults to failover
+//!   --log-level       [LEVEL]             Log level: error, warn, info, debug, or trace, or per target, e.g. mproxy_reverse=debug,warn. Defaults to info
+//!   --log-format      [FORMAT]            Log format: text or json. Defaults to text
+//!   --log-output      [OUTPUT]            Write logs to stderr, syslog, or journald. Defaults to stderr
+//!   --user            [USER]              Switch to USER, a name or ID, once sockets and files are opened
+//!   --group           [GROUP]             Switch to GROUP. Defaults to the primary group of --user
+//!   --pidfile         [FILE]              Write the process ID to FILE
+//!
+//! TOPIC RULES:
+//!   header                Topic is the message header before the first '|'. The header is stripped
+//!   header:DELIM          Topic is the message header before the first DELIM
+//!   port:PORT=TOPIC       Messages sent to the bus from source port PORT are tagged TOPIC
+//!                         by producers sending to --multicast-addr directly. Not allowed with
+//!                         --udp-listen-addr, which sends to the bus from its own port
+//!   prefix:PREFIX=TOPIC   Messages starting with PREFIX are tagged TOPIC
+//!   When topic rules are set, TCP clients may send an initial line listing topic patterns
+//!   separated by commas or spaces, e.g. 'ais.*,weather'. Otherwise, all messages are sent.
+//!   WebSocket clients may subscribe with a query string, e.g. '/?topics=ais.*,weather'.
+//!
+//! HTTP STREAMING:
+//!   GET /stream                     Chunked newline-delimited messages
+//!   GET /stream?format=sse          Server-Sent Events
+//!   GET /stream?topics=ais.*        Filter by topic, when topic rules are set
+//!   GET /stream?contains=AIVDM      Filter messages containing a substring
+//!   POST /publish                   Publish the request body as a single message
+//!   POST /publish?batch             Publish each line of the request body as a message
+//!
+//! CACHE KEY RULES:
+//!   field:INDEX           Key is the zero-indexed comma-separated field INDEX of each message
+//!   field:INDEX:DELIM     Key is the zero-indexed field INDEX, separated by DELIM
+//!   regex:PATTERN         Key is the first capture group of PATTERN (requires feature 'regex')
+//!
+//! CONFIG FILE:
+//!   Options are listed one per line without leading dashes, e.g. 'udp-output-addr [::1]:9921'.
+//!   On SIGHUP the file is reloaded: UDP output addresses are added and removed, and the UDP
+//!   listener is restarted if its address changed, without interrupting other routes.
+//!   Other options take effect on restart.
+//!
+//! TAPS:
+//!   Connect to --tap-addr and send a line naming a route listed by the admin API or metrics,
+//!   e.g. '0.0.0.0:9920:forward', to receive a copy of the messages it receives.
+//!   Append 'hex' for a hexdump, or 'sample=N' to copy every Nth message.
+//!
+//! SYSTEMD:
+//!   Sockets passed by systemd socket activation are used in place of binding matching
+//!   addresses. With Type=notify, readiness is reported once listeners are bound, and
+//!   watchdog pings are sent while no route has panicked.
+//!
+//! UPGRADES:
+//!   On SIGUSR2, a new process is started with the same command line, and listening sockets
+//!   are handed over to it. Once it is ready, this process stops accepting connections, and
+//!   exits when its clients disconnect, or after --drain-secs.
+//!
+//! FAILURES:
+//!   Routes which panic or fail are restarted with backoff, from 100ms up to 30s. With
+//!   --on-failure exit, the process instead exits with status 1, e.g. for systemd to restart it.
+//!
+//! RESOLUTION:
+//!   Downstream host names are looked up again every --resolve-secs, keeping the previous
+//!   addresses if a lookup fails. Names with several addresses are sent to one at a time,
+//!   failing over to the next address on errors, or with --resolve-mode all, to every address.
+//!
+//! PRIVILEGES:
+//!   When started as root, e.g. to serve TCP, HTTP, or WebSocket clients on ports below 1024, use
+//!   --user and --group to switch to an unprivileged user once listeners are bound. A UDP listener
+//!   changed when the --config file is reloaded is bound as that user, so privileged ports then
+//!   require a restart. The pid file is written before.
 //!
 //! FLAGS:
 //!   -h, --help    Prints help information
 //!   -t, --tee     Print UDP input to stdout
+//!   --ws-binary   Send WebSocket messages as binary frames. Defaults to text for UTF-8 messages
 //!
 //! EXAMPLE:
 //!   mproxy-reverse --udp-listen-addr '0.0.0.0:9920' --tcp-output-addr '[::1]:9921' --multicast-addr '224.0.0.1:9922'
+//!   mproxy-reverse --udp-listen-addr '0.0.0.0:9920' --tcp-output-addr '[::1]:9921' --topic-rule header
 //! ```
 //!
 //! ### See Also
@@ -86,139 +171,344 @@
 //! - [mproxy-reverse](https://docs.rs/mproxy-reverse/)
 //!
 
-use std::io::{BufWriter, Read, Write};
-use std::net::{TcpListener, TcpStream};
+use std::io::{BufRead, BufReader, BufWriter, ErrorKind, Read, Result as ioResult, Write};
+use std::net::TcpStream;
+use std::sync::{Arc, Mutex};
 use std::thread::{spawn, JoinHandle};
+use std::time::Duration;
 
-use mproxy_client::target_socket_interface;
+use log::{debug, error, warn};
+use mproxy_client::{Downstream, Downstreams};
+use mproxy_common::handoff::incoming;
+use mproxy_common::metrics::{DownstreamMetrics, RouteMetrics};
+use mproxy_common::supervisor::supervise;
+use mproxy_common::systemd::tcp_listener;
 use mproxy_server::upstream_socket_interface;
 
+mod bus;
+pub mod cache;
+mod http;
+pub mod topic;
+mod websocket;
+use bus::Bus;
+use cache::KeyRule;
+use topic::{Subscription, TopicRule};
+
 const BUFSIZE: usize = 8096;
 
-fn handle_client_tcp(downstream: TcpStream, multicast_addr: String) {
-    #[cfg(debug_assertions)]
-    println!(
-        "handling downstream client: {} UDP -> {:?} TCP",
-        multicast_addr, downstream
-    );
-    let (_multicast_addr, multicast_socket) =
-        if let Ok((addr, socket)) = upstream_socket_interface(multicast_addr) {
-            if !addr.ip().is_multicast() {
-                panic!("not a multicast address {}", addr);
-            }
-            (addr, socket)
-        } else {
-            panic!()
-        };
+/// How long to wait for a newly connected TCP client to send a subscription line
+const SUBSCRIBE_TIMEOUT: Duration = Duration::from_millis(250);
+
+/// Interval at which a stopped route notices it has been stopped
+const STOP_POLL: Duration = Duration::from_millis(500);
 
-    let mut buf = [0u8; BUFSIZE];
+/// Options for forwarding the multicast bus to downstream subscribers
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SubscriberConfig {
+    /// Rules used to tag bus messages with a topic. If empty, subscription
+    /// lines are not read and every client receives the whole bus
+    pub topic_rules: Vec<TopicRule>,
+    /// Replay up to this many recent messages to newly connected clients.
+    /// At most 1024 messages of up to 4 MiB in total are kept for replay
+    pub replay_count: Option<usize>,
+    /// Replay messages received within this duration to newly connected
+    /// clients, within the same limits
+    pub replay_age: Option<Duration>,
+    /// Keep the latest message for each key, and send them to newly connected
+    /// clients before recent and live messages
+    pub cache_key: Option<KeyRule>,
+    /// Expire cached messages after this duration
+    pub cache_ttl: Option<Duration>,
+}
+
+/// Remote address of a connected client, for listing in the admin API
+pub(crate) fn peer_name(stream: &TcpStream) -> String {
+    stream
+        .peer_addr()
+        .map_or_else(|_| "unknown".to_string(), |addr| addr.to_string())
+}
+
+/// Read the subscription line optionally sent by a TCP client after connecting
+fn read_subscription(downstream: &TcpStream) -> Subscription {
+    let mut line = String::new();
+    let _ = downstream.set_read_timeout(Some(SUBSCRIBE_TIMEOUT));
+    let subscription = match BufReader::new(downstream).read_line(&mut line) {
+        Ok(_) => line.parse().unwrap_or_else(|_| Subscription::all()),
+        Err(_) => Subscription::all(),
+    };
+    let _ = downstream.set_read_timeout(None);
+    subscription
+}
+
+fn handle_client_tcp(downstream: TcpStream, bus: Arc<Bus>, metrics: Arc<DownstreamMetrics>) {
+    debug!("handling downstream client: {:?} TCP", downstream);
+
+    // subscribe before reading the subscription line, so that messages
+    // received meanwhile are queued, and filtered once the line is read
+    let messages = bus.subscribe(Subscription::all(), metrics.clone());
+    if bus.has_topics() {
+        let subscription = read_subscription(&downstream);
+        debug!("{:?} subscribed to {:?}", downstream, subscription);
+        messages.resubscribe(subscription);
+    }
+    let _client = metrics.connected(peer_name(&downstream), Some(messages.queue_depth()));
     let mut tcp_writer = BufWriter::new(downstream);
 
-    loop {
-        match multicast_socket.recv_from(&mut buf[0..]) {
-            Ok((count_input, _remote_addr)) => {
-                //println!("{}", String::from_utf8_lossy(&buf[0..count_input]));
-                let _count_output = tcp_writer.write(&buf[0..count_input]);
-            }
-            Err(err) => {
-                eprintln!("reverse_proxy: got an error: {}", err);
-                break;
-            }
-        }
-        if let Err(_e) = tcp_writer.flush() {
-            #[cfg(debug_assertions)]
-            eprintln!("reverse_proxy: closing {:?} {}", multicast_socket, _e);
+    for msg in messages {
+        //println!("{}", String::from_utf8_lossy(&msg));
+        if let Err(e) = tcp_writer.write_all(&msg).and_then(|()| tcp_writer.flush()) {
+            debug!("reverse_proxy: closing {:?} {}", tcp_writer.get_ref(), e);
             break;
         }
+        metrics.sent(msg.len());
     }
 }
 
 /// Forward a UDP socket stream (e.g. from a multicast channel) to connected TCP clients.
-/// Spawns a listener thread, plus one thread for each incoming TCP connection.
+/// Spawns a listener thread, a multicast receiver thread, plus one thread for
+/// each incoming TCP connection.
 pub fn reverse_proxy_udp_tcp(multicast_addr: String, tcp_listen_addr: String) -> JoinHandle<()> {
-    #[cfg(debug_assertions)]
-    println!(
+    reverse_proxy_udp_tcp_with(multicast_addr, tcp_listen_addr, SubscriberConfig::default())
+}
+
+/// Forward a UDP socket stream to connected TCP clients, with subscriber options.
+/// See [`topic`] for the subscription line format used when topic rules are set.
+/// If a cache key rule is set, the latest message for each key is sent to
+/// each new client, followed by recent messages if a replay limit is set,
+/// before live messages.
+/// Messages sent to clients are counted in the metrics of the bus route,
+/// as output `{tcp_listen_addr}:tcp`.
+pub fn reverse_proxy_udp_tcp_with(
+    multicast_addr: String,
+    tcp_listen_addr: String,
+    config: SubscriberConfig,
+) -> JoinHandle<()> {
+    debug!(
         "forwarding: {} UDP -> {} TCP",
         multicast_addr, tcp_listen_addr
     );
-    spawn(move || {
-        let listener = TcpListener::bind(tcp_listen_addr).expect("binding downstream TCP Listener");
-        for stream in listener.incoming() {
-            #[cfg(debug_assertions)]
-            println!("new client {:?}", stream);
-            let multicast_addr = multicast_addr.clone();
+    let listener = tcp_listener(&tcp_listen_addr).expect("binding downstream TCP Listener");
+    supervise(format!("{}:tcp", tcp_listen_addr), move || {
+        let bus = Bus::shared(multicast_addr.clone(), config.clone())?;
+        let metrics = bus
+            .metrics()
+            .downstream(&format!("{}:tcp", tcp_listen_addr));
+        let _running = metrics.running();
+        for stream in incoming(&listener) {
+            debug!("new client {:?}", stream);
+            let bus = bus.clone();
+            let metrics = metrics.clone();
             let _tcp_client = spawn(move || {
-                handle_client_tcp(stream.unwrap(), multicast_addr);
+                handle_client_tcp(stream.unwrap(), bus, metrics);
             });
         }
+        Ok(())
     })
 }
 
-/// Forward bytes from UDP upstream socket address to UDP downstream socket address
+/// Forward a UDP socket stream (e.g. from a multicast channel) to WebSocket clients.
+/// Each datagram is sent as a single message, using binary frames if `binary`
+/// is true, or text frames for UTF-8 payloads otherwise.
+/// Spawns a listener thread, plus two threads for each incoming connection.
+/// See [`reverse_proxy_udp_tcp_with`] for subscriber options.
+pub fn reverse_proxy_udp_ws(
+    multicast_addr: String,
+    ws_listen_addr: String,
+    config: SubscriberConfig,
+    binary: bool,
+) -> JoinHandle<()> {
+    debug!(
+        "forwarding: {} UDP -> {} WebSocket",
+        multicast_addr, ws_listen_addr
+    );
+    let listener = tcp_listener(&ws_listen_addr).expect("binding downstream WebSocket Listener");
+    supervise(format!("{}:ws", ws_listen_addr), move || {
+        let bus = Bus::shared(multicast_addr.clone(), config.clone())?;
+        let metrics = bus.metrics().downstream(&format!("{}:ws", ws_listen_addr));
+        let _running = metrics.running();
+        for stream in incoming(&listener) {
+            debug!("new client {:?}", stream);
+            let bus = bus.clone();
+            let metrics = metrics.clone();
+            let _ws_client = spawn(move || {
+                websocket::handle_client_ws(stream.unwrap(), bus, metrics, binary);
+            });
+        }
+        Ok(())
+    })
+}
+
+/// Forward a UDP socket stream (e.g. from a multicast channel) to HTTP clients
+/// requesting `GET /stream`, as Server-Sent Events or chunked newline-delimited
+/// output. See [`reverse_proxy_udp_tcp_with`] for subscriber options.
+/// Spawns a listener thread, plus one thread for each incoming connection.
+pub fn reverse_proxy_udp_http(
+    multicast_addr: String,
+    http_listen_addr: String,
+    config: SubscriberConfig,
+) -> JoinHandle<()> {
+    debug!(
+        "forwarding: {} UDP -> {} HTTP",
+        multicast_addr, http_listen_addr
+    );
+    let listener = tcp_listener(&http_listen_addr).expect("binding downstream HTTP Listener");
+    supervise(format!("{}:http", http_listen_addr), move || {
+        let bus = Bus::shared(multicast_addr.clone(), config.clone())?;
+        let metrics = bus
+            .metrics()
+            .downstream(&format!("{}:http", http_listen_addr));
+        let _running = metrics.running();
+        for stream in incoming(&listener) {
+            debug!("new client {:?}", stream);
+            let bus = bus.clone();
+            let metrics = metrics.clone();
+            let _http_client = spawn(move || {
+                http::handle_client_http(stream.unwrap(), bus, metrics);
+            });
+        }
+        Ok(())
+    })
+}
+
+/// Forward bytes from UDP upstream socket address to UDP downstream socket address.
+/// Traffic is counted in route metrics named `{udp_input_addr}:reverse_proxy`.
 pub fn reverse_proxy_udp(udp_input_addr: String, udp_output_addr: String) -> JoinHandle<()> {
-    #[cfg(debug_assertions)]
-    println!(
-        "forwarding: {} UDP -> {} UDP",
-        udp_input_addr, udp_output_addr
+    reverse_proxy_udp_controlled(udp_input_addr, &[udp_output_addr])
+        .unwrap()
+        .0
+}
+
+/// Forward bytes from UDP upstream socket address to UDP downstream socket
+/// addresses, also returning the downstreams of the route. These may be added
+/// and removed while the route is running, either with the returned
+/// [`Downstreams`] or with the admin API, and the route may be stopped.
+/// Returns an error if a socket cannot be bound
+pub fn reverse_proxy_udp_controlled(
+    udp_input_addr: String,
+    udp_output_addrs: &[String],
+) -> ioResult<(JoinHandle<()>, Arc<Downstreams>)> {
+    debug!(
+        "forwarding: {} UDP -> {:?} UDP",
+        udp_input_addr, udp_output_addrs
     );
-    spawn(move || {
-        let (addr, listen_socket) = upstream_socket_interface(udp_input_addr).unwrap();
-        let (outaddr, output_socket) = target_socket_interface(&udp_output_addr).unwrap();
+    let (addr, listen_socket) = upstream_socket_interface(udp_input_addr)?;
+    let metrics = RouteMetrics::register(&addr.to_string(), "reverse_proxy");
+    let targets = Downstreams::register(metrics.clone(), udp_output_addrs)?;
+    let route_targets = targets.clone();
+    let thread = supervise(format!("{}:reverse_proxy", addr), move || {
+        let _running = metrics.running();
+        listen_socket.set_read_timeout(Some(STOP_POLL)).unwrap();
 
         let mut buf = [0u8; BUFSIZE];
-        loop {
+        while !targets.is_stopped() {
             match listen_socket.recv_from(&mut buf[0..]) {
                 Ok((c, remote_addr)) => {
                     if c == 0 {
-                        eprintln!("got message with size 0 from upstream: {}", remote_addr);
+                        warn!("got message with size 0 from upstream: {}", remote_addr);
                     } else {
-                        let c_out = output_socket
-                            .send_to(&buf[0..c], outaddr)
-                            .expect("forwarding UDP downstream");
-                        assert!(c == c_out);
+                        metrics.received(c);
+                        metrics.taps().send(&buf[0..c]);
+                        targets.send(&buf[0..c]);
                         //println!("{}", String::from_utf8_lossy(&buf[0..c]));
                     }
                 }
-                Err(err) => {
-                    eprintln!("{}:reverse_proxy: error {}", addr, err);
-                    break;
-                }
+                Err(err) if matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {}
+                Err(err) => return Err(err.to_string()),
             }
         }
-    })
+        targets.unregister();
+        Ok(())
+    });
+    Ok((thread, route_targets))
 }
 
-/// Listen for incoming TCP connections and forward received bytes to a UDP socket address
+/// Listen for incoming TCP connections and forward received bytes to a UDP socket address.
+/// Traffic is counted in route metrics named `{upstream_tcp}:tcp_listener`.
 pub fn reverse_proxy_tcp_udp(upstream_tcp: String, downstream_udp: String) -> JoinHandle<()> {
     //pub fn reverse_proxy_tcp_udp(upstream_tcp: String, downstream_udp: String) {
-    spawn(move || {
-        let listener = TcpListener::bind(upstream_tcp).expect("binding TCP socket");
+    let listener = tcp_listener(&upstream_tcp).expect("binding TCP socket");
+    let metrics = RouteMetrics::register(&upstream_tcp, "tcp_listener");
+    supervise(format!("{}:tcp_listener", upstream_tcp), move || {
+        let _running = metrics.running();
+        let output_metrics = metrics.downstream(&downstream_udp);
 
-        for upstream in listener.incoming() {
-            let (target_addr, target_socket) = target_socket_interface(&downstream_udp).unwrap();
+        for upstream in incoming(&listener) {
+            let mut target = match Downstream::new(&downstream_udp) {
+                Ok(target) => target.with_metrics(output_metrics.clone()),
+                Err(e) => {
+                    warn!("dropping client: downstream {}: {}", downstream_udp, e);
+                    continue;
+                }
+            };
             let mut buf = [0u8; BUFSIZE];
             //let mut stream = stream.as_ref().expect("connecting to stream");
 
             match upstream {
                 Ok(mut input) => {
+                    let metrics = metrics.clone();
                     spawn(move || loop {
                         match input.read(&mut buf[0..]) {
+                            Ok(0) => break,
                             Ok(c) => {
-                                target_socket
-                                    .send_to(&buf[0..c], target_addr)
-                                    .expect("sending to UDP socket");
+                                metrics.received(c);
+                                metrics.taps().send(&buf[0..c]);
+                                target.send(&buf[0..c]);
                             }
                             Err(e) => {
-                                eprintln!("err: {}", e);
+                                error!("reading from TCP client: {}", e);
                                 break;
                             }
                         }
                     });
                 }
                 Err(e) => {
-                    eprintln!("dropping client: {}", e);
+                    warn!("dropping client: {}", e);
+                }
+            }
+        }
+        Ok(())
+    })
+}
+
+/// Listen for HTTP `POST /publish` requests, and forward request bodies to a
+/// UDP socket address. If `token` is set, requests must include header
+/// `Authorization: Bearer TOKEN`.
+/// Spawns a listener thread, plus one thread for each incoming connection.
+/// Traffic is counted in route metrics named `{upstream_http}:http_listener`.
+pub fn reverse_proxy_http_udp(
+    upstream_http: String,
+    downstream_udp: String,
+    token: Option<String>,
+) -> JoinHandle<()> {
+    debug!(
+        "forwarding: {} HTTP -> {} UDP",
+        upstream_http, downstream_udp
+    );
+    let listener = tcp_listener(&upstream_http).expect("binding HTTP socket");
+    let metrics = RouteMetrics::register(&upstream_http, "http_listener");
+    supervise(format!("{}:http_listener", upstream_http), move || {
+        let _running = metrics.running();
+        let target = Downstream::new(&downstream_udp)
+            .map_err(|e| format!("downstream {}: {}", downstream_udp, e))?
+            .with_metrics(metrics.downstream(&downstream_udp));
+        let target = Arc::new(Mutex::new(target));
+        let token: Option<Arc<str>> = token.clone().map(Arc::from);
+
+        for upstream in incoming(&listener) {
+            match upstream {
+                Ok(input) => {
+                    let target = target.clone();
+                    let token = token.clone();
+                    let metrics = metrics.clone();
+                    spawn(move || {
+                        http::handle_client_publish(input, &target, token.as_deref(), &metrics);
+                    });
+                }
+                Err(e) => {
+                    warn!("dropping client: {}", e);
                 }
             }
         }
+        Ok(())
     })
 }
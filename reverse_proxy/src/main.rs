@@ -1,10 +1,40 @@
+use std::error::Error;
+use std::ffi::OsString;
+use std::path::PathBuf;
 use std::process::exit;
+use std::slice::from_ref;
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::Duration;
 
-use mproxy_forward::forward_udp;
-use mproxy_reverse::{reverse_proxy_tcp_udp, reverse_proxy_udp, reverse_proxy_udp_tcp};
+use log::{error, info, warn};
+use mproxy_client::{set_refresh_interval, set_resolve_mode, Downstreams, ResolveMode};
+use mproxy_common::admin::serve_admin;
+#[cfg(unix)]
+use mproxy_common::config::on_sighup;
+use mproxy_common::config::read_config;
+use mproxy_common::control::{update_downstreams, RouteControl};
+use mproxy_common::daemon::{bind_error, drop_privileges, write_pidfile};
+#[cfg(unix)]
+use mproxy_common::handoff::on_upgrade;
+use mproxy_common::logging::{init_logging, LogFormat, LogLevels, LogOutput};
+use mproxy_common::metrics::serve_metrics;
+use mproxy_common::supervisor::{set_on_failure, OnFailure};
+use mproxy_common::systemd::{notify, ready, watchdog};
+use mproxy_common::tap::serve_tap;
+use mproxy_forward::forward_udp_controlled;
+use mproxy_reverse::cache::KeyRule;
+use mproxy_reverse::topic::TopicRule;
+use mproxy_reverse::{
+    reverse_proxy_http_udp, reverse_proxy_tcp_udp, reverse_proxy_udp_controlled,
+    reverse_proxy_udp_http, reverse_proxy_udp_tcp_with, reverse_proxy_udp_ws, SubscriberConfig,
+};
 
 use pico_args::Arguments;
 
+/// Time allowed for routes stopped on reload to release their sockets
+const STOP_WAIT: Duration = Duration::from_secs(5);
+
 const HELP: &str = r#"
 MPROXY: Reverse Proxy
 
@@ -20,89 +50,413 @@ OPTIONS:
   --tcp-listen-addr [HOSTNAME:PORT]     Reverse-proxy accepting TCP connections and forwarding to --multicast-addr
   --multicast-addr  [MULTICAST_IP:PORT] Defaults to '[ff02::1]:9918'
   --tcp-output-addr [HOSTNAME:PORT]     Forward packets from --multicast-addr to TCP downstream
-  --udp-output-addr [HOSTNAME:PORT]     Forward packets from --multicast-addr to UDP downstream
+  --udp-output-addr [HOSTNAME:PORT]     Forward packets from --multicast-addr to UDP downstream. May be repeated
+  --ws-output-addr  [HOSTNAME:PORT]     Forward packets from --multicast-addr to WebSocket clients
+  --http-output-addr [HOSTNAME:PORT]    Stream packets from --multicast-addr to HTTP clients at /stream
+  --http-listen-addr [HOSTNAME:PORT]    Accept HTTP POST requests at /publish, forwarding to --multicast-addr
+  --http-token      [TOKEN]             Require 'Authorization: Bearer TOKEN' for /publish
+  --topic-rule      [RULE]              Tag messages with a topic for subscribers. May be repeated
+  --replay-count    [N]                 Replay the last N messages to new subscribers, up to 1024
+  --replay-secs     [SECONDS]           Replay messages from the last SECONDS to new subscribers, up to 1024 or 4 MiB
+  --cache-key       [RULE]              Send the latest message for each key to new subscribers
+  --cache-ttl       [SECONDS]           Expire cached messages after SECONDS
+  --metrics-addr    [HOSTNAME:PORT]     Serve Prometheus metrics over HTTP at /metrics
+  --admin-addr      [HOSTNAME:PORT]     Serve the admin API over HTTP at /routes
+  --tap-addr        [ADDR|PATH]         Serve live taps of route traffic over TCP, or a Unix socket PATH
+  --config          [FILE]              Read options from FILE, one per line. Reloaded on SIGHUP
+  --drain-secs      [SECONDS]           Time allowed for clients to disconnect after an upgrade. Defaults to 30
+  --on-failure      [ACTION]            Action when a route fails: restart or exit. Defaults to restart
+  --resolve-secs    [SECONDS]           Interval between lookups of downstream host names. Defaults to 30
+  --resolve-mode    [MODE]              Send to names with several addresses: failover or all. Defaults to failover
+  --log-level       [LEVEL]             Log level: error, warn, info, debug, or trace, or per target, e.g. mproxy_reverse=debug,warn. Defaults to info
+  --log-format      [FORMAT]            Log format: text or json. Defaults to text
+  --log-output      [OUTPUT]            Write logs to stderr, syslog, or journald. Defaults to stderr
+  --user            [USER]              Switch to USER, a name or ID, once sockets and files are opened
+  --group           [GROUP]             Switch to GROUP. Defaults to the primary group of --user
+  --pidfile         [FILE]              Write the process ID to FILE
+
+TOPIC RULES:
+  header                Topic is the message header before the first '|'. The header is stripped
+  header:DELIM          Topic is the message header before the first DELIM
+  port:PORT=TOPIC       Messages sent to the bus from source port PORT are tagged TOPIC
+                        by producers sending to --multicast-addr directly. Not allowed with
+                        --udp-listen-addr, which sends to the bus from its own port
+  prefix:PREFIX=TOPIC   Messages starting with PREFIX are tagged TOPIC
+  When topic rules are set, TCP clients may send an initial line listing topic patterns
+  separated by commas or spaces, e.g. 'ais.*,weather'. Otherwise, all messages are sent.
+  WebSocket clients may subscribe with a query string, e.g. '/?topics=ais.*,weather'.
+
+HTTP STREAMING:
+  GET /stream                     Chunked newline-delimited messages
+  GET /stream?format=sse          Server-Sent Events
+  GET /stream?topics=ais.*        Filter by topic, when topic rules are set
+  GET /stream?contains=AIVDM      Filter messages containing a substring
+  POST /publish                   Publish the request body as a single message
+  POST /publish?batch             Publish each line of the request body as a message
+
+CACHE KEY RULES:
+  field:INDEX           Key is the zero-indexed comma-separated field INDEX of each message
+  field:INDEX:DELIM     Key is the zero-indexed field INDEX, separated by DELIM
+  regex:PATTERN         Key is the first capture group of PATTERN (requires feature 'regex')
+
+CONFIG FILE:
+  Options are listed one per line without leading dashes, e.g. 'udp-output-addr [::1]:9921'.
+  On SIGHUP the file is reloaded: UDP output addresses are added and removed, and the UDP
+  listener is restarted if its address changed, without interrupting other routes.
+  Other options take effect on restart.
+
+TAPS:
+  Connect to --tap-addr and send a line naming a route listed by the admin API or metrics,
+  e.g. '0.0.0.0:9920:forward', to receive a copy of the messages it receives.
+  Append 'hex' for a hexdump, or 'sample=N' to copy every Nth message.
+
+SYSTEMD:
+  Sockets passed by systemd socket activation are used in place of binding matching
+  addresses. With Type=notify, readiness is reported once listeners are bound, and
+  watchdog pings are sent while no route has panicked.
+
+UPGRADES:
+  On SIGUSR2, a new process is started with the same command line, and listening sockets
+  are handed over to it. Once it is ready, this process stops accepting connections, and
+  exits when its clients disconnect, or after --drain-secs.
+
+FAILURES:
+  Routes which panic or fail are restarted with backoff, from 100ms up to 30s. With
+  --on-failure exit, the process instead exits with status 1, e.g. for systemd to restart it.
+
+RESOLUTION:
+  Downstream host names are looked up again every --resolve-secs, keeping the previous
+  addresses if a lookup fails. Names with several addresses are sent to one at a time,
+  failing over to the next address on errors, or with --resolve-mode all, to every address.
+
+PRIVILEGES:
+  When started as root, e.g. to serve TCP, HTTP, or WebSocket clients on ports below 1024, use
+  --user and --group to switch to an unprivileged user once listeners are bound. A UDP listener
+  changed when the --config file is reloaded is bound as that user, so privileged ports then
+  require a restart. The pid file is written before.
 
 FLAGS:
   -h, --help    Prints help information
   -t, --tee     Print UDP input to stdout
+  --ws-binary   Send WebSocket messages as binary frames. Defaults to text for UTF-8 messages
 
 EXAMPLE:
   mproxy-reverse --udp-listen-addr '0.0.0.0:9920' --tcp-output-addr '[::1]:9921' --multicast-addr '224.0.0.1:9922'
+  mproxy-reverse --udp-listen-addr '0.0.0.0:9920' --tcp-output-addr '[::1]:9921' --topic-rule header
 
 "#;
 
 pub struct ReverseProxyArgs {
+    pub config: Option<PathBuf>,
     pub udp_listen_addr: Option<String>,
     pub tcp_listen_addr: Option<String>,
     pub multicast_addr: Option<String>,
     pub tcp_output_addr: Option<String>,
-    pub udp_output_addr: Option<String>,
+    pub udp_output_addrs: Vec<String>,
+    pub ws_output_addr: Option<String>,
+    pub http_output_addr: Option<String>,
+    pub http_listen_addr: Option<String>,
+    pub http_token: Option<String>,
+    pub topic_rules: Vec<TopicRule>,
+    pub replay_count: Option<usize>,
+    pub replay_age: Option<Duration>,
+    pub cache_key: Option<KeyRule>,
+    pub cache_ttl: Option<Duration>,
+    pub metrics_addr: Option<String>,
+    pub admin_addr: Option<String>,
+    pub tap_addr: Option<String>,
+    pub on_failure: OnFailure,
+    pub resolve_secs: u64,
+    pub resolve_mode: ResolveMode,
+    pub user: Option<String>,
+    pub group: Option<String>,
+    pub pidfile: Option<PathBuf>,
+    pub drain: Duration,
+    pub log_level: LogLevels,
+    pub log_format: LogFormat,
+    pub log_output: LogOutput,
     pub tee: bool,
+    pub ws_binary: bool,
+}
+
+fn parse_secs(s: &str) -> Result<Duration, String> {
+    s.parse::<f64>()
+        .ok()
+        .filter(|secs| secs.is_finite() && *secs >= 0.0)
+        .map(Duration::from_secs_f64)
+        .ok_or_else(|| format!("invalid number of seconds '{}'", s))
 }
 
-fn parse_args() -> Result<ReverseProxyArgs, pico_args::Error> {
-    let mut pargs = Arguments::from_env();
+/// Parse command line arguments `cli`, followed by options read from --config
+fn parse_args(cli: &[OsString]) -> Result<ReverseProxyArgs, Box<dyn Error>> {
+    let mut pargs = Arguments::from_vec(cli.to_vec());
     if pargs.contains(["-h", "--help"]) || pargs.clone().finish().is_empty() {
         print!("{}", HELP);
         exit(0);
     }
+    let config: Option<PathBuf> = pargs
+        .opt_value_from_str::<_, String>("--config")?
+        .map(PathBuf::from);
+    if let Some(path) = &config {
+        let mut args = pargs.finish();
+        args.extend(read_config(path).map_err(|e| format!("reading {}: {}", path.display(), e))?);
+        pargs = Arguments::from_vec(args);
+    }
     let tee = pargs.contains(["-t", "--tee"]);
+    let ws_binary = pargs.contains("--ws-binary");
     let args = ReverseProxyArgs {
+        config,
         udp_listen_addr: pargs.opt_value_from_str("--udp-listen-addr")?,
         tcp_listen_addr: pargs.opt_value_from_str("--tcp-listen-addr")?,
         multicast_addr: pargs.opt_value_from_str("--multicast-addr")?,
         tcp_output_addr: pargs.opt_value_from_str("--tcp-output-addr")?,
-        udp_output_addr: pargs.opt_value_from_str("--udp-output-addr")?,
+        udp_output_addrs: pargs.values_from_str("--udp-output-addr")?,
+        ws_output_addr: pargs.opt_value_from_str("--ws-output-addr")?,
+        http_output_addr: pargs.opt_value_from_str("--http-output-addr")?,
+        http_listen_addr: pargs.opt_value_from_str("--http-listen-addr")?,
+        http_token: pargs.opt_value_from_str("--http-token")?,
+        topic_rules: pargs.values_from_str("--topic-rule")?,
+        replay_count: pargs.opt_value_from_str("--replay-count")?,
+        replay_age: pargs.opt_value_from_fn("--replay-secs", parse_secs)?,
+        cache_key: pargs.opt_value_from_str("--cache-key")?,
+        cache_ttl: pargs.opt_value_from_fn("--cache-ttl", parse_secs)?,
+        metrics_addr: pargs.opt_value_from_str("--metrics-addr")?,
+        admin_addr: pargs.opt_value_from_str("--admin-addr")?,
+        tap_addr: pargs.opt_value_from_str("--tap-addr")?,
+        on_failure: pargs
+            .opt_value_from_str("--on-failure")?
+            .unwrap_or_default(),
+        resolve_secs: pargs.opt_value_from_str("--resolve-secs")?.unwrap_or(30),
+        resolve_mode: pargs
+            .opt_value_from_str("--resolve-mode")?
+            .unwrap_or_default(),
+        drain: pargs
+            .opt_value_from_fn("--drain-secs", parse_secs)?
+            .unwrap_or(Duration::from_secs(30)),
+        user: pargs.opt_value_from_str("--user")?,
+        group: pargs.opt_value_from_str("--group")?,
+        pidfile: pargs
+            .opt_value_from_str::<_, String>("--pidfile")?
+            .map(PathBuf::from),
+        log_level: pargs.opt_value_from_str("--log-level")?.unwrap_or_default(),
+        log_format: pargs
+            .opt_value_from_str("--log-format")?
+            .unwrap_or_default(),
+        log_output: pargs
+            .opt_value_from_str("--log-output")?
+            .unwrap_or_default(),
         tee,
+        ws_binary,
     };
     let remaining = pargs.finish();
     if !remaining.is_empty() {
-        println!("Warning: unused arguments {:?}", remaining)
+        eprintln!("Warning: unused arguments {:?}", remaining)
+    }
+    if args.udp_listen_addr.is_some()
+        && args
+            .topic_rules
+            .iter()
+            .any(|rule| matches!(rule, TopicRule::SourcePort(..)))
+    {
+        return Err(
+            "port topic rules cannot match datagrams received on --udp-listen-addr, \
+            which are sent to the bus from its own port"
+                .into(),
+        );
     }
 
     Ok(args)
 }
 
+/// UDP routes started from the configuration, which are updated in place
+/// when the configuration is reloaded
+struct UdpRoutes {
+    multicast: String,
+    listener: Option<(String, Arc<Downstreams>)>,
+    output: Option<Arc<Downstreams>>,
+    output_addrs: Vec<String>,
+}
+
+impl UdpRoutes {
+    /// Start, stop, or update routes to match `args`.
+    /// Returns the threads of new routes
+    fn apply(&mut self, args: &ReverseProxyArgs) -> Result<Vec<JoinHandle<()>>, String> {
+        let mut threads = vec![];
+
+        // UDP listener thread -> UPD multicast sender
+        // rebroadcast upstream UDP via multicast to client threads
+        if let Some((listen, targets)) = &self.listener {
+            if args.udp_listen_addr.as_ref() != Some(listen) {
+                info!("stopping UDP listener {}", listen);
+                targets.stop();
+                // release the socket first, as the new listener may reuse the address
+                if !targets.wait_stopped(STOP_WAIT) {
+                    warn!("UDP listener {} has not stopped", listen);
+                }
+                self.listener = None;
+            }
+        }
+        if let (None, Some(listen)) = (&self.listener, &args.udp_listen_addr) {
+            let (thread, targets) =
+                forward_udp_controlled(listen.clone(), from_ref(&self.multicast), args.tee)
+                    .map_err(|e| bind_error(listen, &e))?;
+            threads.push(thread);
+            self.listener = Some((listen.clone(), targets));
+        }
+
+        // UDP multicast listener -> UDP sender
+        match &self.output {
+            Some(targets) => {
+                update_downstreams(targets.as_ref(), &self.output_addrs, &args.udp_output_addrs)
+            }
+            None if !args.udp_output_addrs.is_empty() => {
+                let (thread, targets) =
+                    reverse_proxy_udp_controlled(self.multicast.clone(), &args.udp_output_addrs)
+                        .map_err(|e| bind_error(&self.multicast, &e))?;
+                threads.push(thread);
+                self.output = Some(targets);
+            }
+            None => {}
+        }
+        self.output_addrs = args.udp_output_addrs.clone();
+
+        Ok(threads)
+    }
+}
+
 pub fn main() {
-    let args = match parse_args() {
+    let cli: Vec<OsString> = std::env::args_os().skip(1).collect();
+    let args = match parse_args(&cli) {
         Ok(a) => a,
         Err(e) => {
             eprintln!("Error: {}.", e);
             exit(1);
         }
     };
+    if let Err(e) = init_logging(args.log_level.clone(), args.log_format, args.log_output) {
+        eprintln!("Error: initializing logging: {}.", e);
+        exit(1);
+    }
+    set_on_failure(args.on_failure);
+    set_refresh_interval(Duration::from_secs(args.resolve_secs));
+    set_resolve_mode(args.resolve_mode);
 
-    let multicast: String = match args.multicast_addr {
-        Some(addr) => addr,
+    let multicast: String = match &args.multicast_addr {
+        Some(addr) => addr.clone(),
         _ => "[ff02::1]:9918".to_string(),
     };
 
     let mut threads = vec![];
+    let udp_routes = Arc::new(Mutex::new(UdpRoutes {
+        multicast: multicast.clone(),
+        listener: None,
+        output: None,
+        output_addrs: vec![],
+    }));
+
+    // reload the config file on SIGHUP, updating UDP routes in place
+    #[cfg(unix)]
+    if let Some(path) = args.config.clone() {
+        let udp_routes = udp_routes.clone();
+        threads.push(on_sighup(move || {
+            info!("reloading {}", path.display());
+            notify("RELOADING=1");
+            let applied = parse_args(&cli)
+                .map_err(|e| e.to_string())
+                .and_then(|args| udp_routes.lock().unwrap().apply(&args));
+            if let Err(e) = applied {
+                error!("reloading {}: {}", path.display(), e);
+            }
+            ready(&format!("reloaded {}", path.display()));
+        }));
+    }
+
+    // hand listening sockets over to a new process on SIGUSR2
+    #[cfg(unix)]
+    threads.push(on_upgrade(args.drain));
 
-    // UDP listener thread -> UPD multicast sender
-    // rebroadcast upstream UDP via multicast to client threads
-    if let Some(udp_listen) = args.udp_listen_addr {
-        let multicast = forward_udp(udp_listen, &[multicast.to_string()], args.tee);
-        threads.push(multicast);
+    if let Some(metrics_addr) = args.metrics_addr.clone() {
+        threads.push(serve_metrics(metrics_addr));
     }
 
+    if let Some(admin_addr) = args.admin_addr.clone() {
+        threads.push(serve_admin(admin_addr));
+    }
+
+    if let Some(tap_addr) = args.tap_addr.clone() {
+        threads.push(serve_tap(tap_addr));
+    }
+
+    // options shared by multicast subscribers
+    let config = SubscriberConfig {
+        topic_rules: args.topic_rules.clone(),
+        replay_count: args.replay_count,
+        replay_age: args.replay_age,
+        cache_key: args.cache_key.clone(),
+        cache_ttl: args.cache_ttl,
+    };
+
     // UDP multicast listener -> TCP sender
     if let Some(tcpout) = &args.tcp_output_addr {
-        let tcp_proxy = reverse_proxy_udp_tcp(multicast.to_string(), tcpout.to_string());
+        let tcp_proxy =
+            reverse_proxy_udp_tcp_with(multicast.to_string(), tcpout.to_string(), config.clone());
         threads.push(tcp_proxy);
     }
 
+    // UDP multicast listener -> WebSocket sender
+    if let Some(wsout) = &args.ws_output_addr {
+        let ws_proxy = reverse_proxy_udp_ws(
+            multicast.to_string(),
+            wsout.to_string(),
+            config.clone(),
+            args.ws_binary,
+        );
+        threads.push(ws_proxy);
+    }
+
+    // UDP multicast listener -> HTTP stream sender
+    if let Some(httpout) = &args.http_output_addr {
+        let http_proxy = reverse_proxy_udp_http(multicast.to_string(), httpout.to_string(), config);
+        threads.push(http_proxy);
+    }
+
     // TCP connection listener -> UDP multicast
-    if let Some(tcpin) = args.tcp_listen_addr {
-        let tcp_rproxy = reverse_proxy_tcp_udp(tcpin, multicast.to_string());
+    if let Some(tcpin) = &args.tcp_listen_addr {
+        let tcp_rproxy = reverse_proxy_tcp_udp(tcpin.to_string(), multicast.to_string());
         threads.push(tcp_rproxy);
     }
 
-    // UDP listener -> UDP sender
-    if let Some(udpout) = args.udp_output_addr {
-        let udp_proxy = reverse_proxy_udp(multicast, udpout);
-        threads.push(udp_proxy);
+    // HTTP publish listener -> UDP multicast
+    if let Some(httpin) = &args.http_listen_addr {
+        let http_rproxy = reverse_proxy_http_udp(
+            httpin.to_string(),
+            multicast.to_string(),
+            args.http_token.clone(),
+        );
+        threads.push(http_rproxy);
+    }
+
+    // UDP listener and UDP sender routes
+    match udp_routes.lock().unwrap().apply(&args) {
+        Ok(routes) => threads.extend(routes),
+        Err(e) => {
+            eprintln!("Error: {}.", e);
+            exit(1);
+        }
+    }
+
+    if let Some(path) = &args.pidfile {
+        if let Err(e) = write_pidfile(path) {
+            eprintln!("Error: writing {}: {}.", path.display(), e);
+            exit(1);
+        }
+    }
+    if let Err(e) = drop_privileges(args.user.as_deref(), args.group.as_deref(), None) {
+        eprintln!("Error: {}.", e);
+        exit(1);
     }
+    let _watchdog = watchdog();
+    ready(&format!("serving multicast group {}", multicast));
 
     for thread in threads {
         thread.join().unwrap();
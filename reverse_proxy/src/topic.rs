@@ -0,0 +1,157 @@
+//! Topic-based subscriptions for downstream TCP clients.
+//!
+//! Each datagram on the multicast bus is tagged with a topic by the first
+//! matching [`TopicRule`]. TCP clients may send a single subscription line
+//! after connecting, listing topic patterns separated by commas or spaces.
+//! Patterns may use `*` and `?` wildcards. Clients that don't send a
+//! subscription line receive every message. Messages received before the
+//! subscription line are queued, and filtered once it is read.
+
+use std::borrow::Cow;
+use std::net::SocketAddr;
+use std::str::FromStr;
+
+/// Default delimiter separating a topic header from the message payload
+pub const TOPIC_DELIMITER: u8 = b'|';
+
+/// Rule used to tag messages on the bus with a topic
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum TopicRule {
+    /// Topic is the message header up to the first delimiter.
+    /// The header is stripped before forwarding the message to subscribers
+    Header(u8),
+    /// Messages sent to the bus from the given source port are tagged with a
+    /// topic. This is the port of a producer sending to the bus directly;
+    /// datagrams received by a listener, e.g. with
+    /// [`forward_udp_controlled`](mproxy_forward::forward_udp_controlled), are
+    /// sent to the bus from the port of the listener
+    SourcePort(u16, String),
+    /// Messages starting with the given bytes are tagged with a topic
+    Prefix(Vec<u8>, String),
+}
+
+impl TopicRule {
+    /// Returns the topic and payload of `msg` if this rule matches
+    fn apply<'a>(&'a self, msg: &'a [u8], src: &SocketAddr) -> Option<(Cow<'a, str>, &'a [u8])> {
+        match self {
+            TopicRule::Header(delim) => {
+                let pos = msg.iter().position(|b| b == delim)?;
+                Some((String::from_utf8_lossy(&msg[..pos]), &msg[pos + 1..]))
+            }
+            TopicRule::SourcePort(port, topic) if src.port() == *port => {
+                Some((Cow::Borrowed(topic), msg))
+            }
+            TopicRule::Prefix(prefix, topic) if msg.starts_with(prefix) => {
+                Some((Cow::Borrowed(topic), msg))
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Parse a topic rule from the command line.
+///
+/// Accepted forms are `header`, `header:DELIM`, `port:PORT=TOPIC`, and
+/// `prefix:PREFIX=TOPIC`
+impl FromStr for TopicRule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (kind, rule) = s.split_once(':').unwrap_or((s, ""));
+        match (kind, rule.as_bytes()) {
+            ("header", []) => Ok(TopicRule::Header(TOPIC_DELIMITER)),
+            ("header", [delim]) => Ok(TopicRule::Header(*delim)),
+            ("port", _) | ("prefix", _) => {
+                let (matching, topic) = rule
+                    .rsplit_once('=')
+                    .ok_or_else(|| format!("missing '=TOPIC' in topic rule '{}'", s))?;
+                if kind == "port" {
+                    let port = matching
+                        .parse()
+                        .map_err(|e| format!("invalid port in topic rule '{}': {}", s, e))?;
+                    Ok(TopicRule::SourcePort(port, topic.to_string()))
+                } else {
+                    Ok(TopicRule::Prefix(matching.into(), topic.to_string()))
+                }
+            }
+            _ => Err(format!("unknown topic rule '{}'", s)),
+        }
+    }
+}
+
+/// Tag a message with a topic using the first matching rule.
+/// Returns the topic and the payload to forward to subscribers.
+/// Messages matching no rule have an empty topic.
+pub fn topic_of<'a>(
+    rules: &'a [TopicRule],
+    msg: &'a [u8],
+    src: &SocketAddr,
+) -> (Cow<'a, str>, &'a [u8]) {
+    rules
+        .iter()
+        .find_map(|rule| rule.apply(msg, src))
+        .unwrap_or((Cow::Borrowed(""), msg))
+}
+
+/// Topic patterns requested by a downstream client
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Subscription {
+    patterns: Vec<String>,
+}
+
+impl Subscription {
+    /// Subscribe to every message on the bus
+    pub fn all() -> Self {
+        Subscription {
+            patterns: vec!["*".to_string()],
+        }
+    }
+
+    /// Returns true if `topic` matches any of the subscribed patterns
+    pub fn matches(&self, topic: &str) -> bool {
+        self.patterns
+            .iter()
+            .any(|p| wildcard_match(p.as_bytes(), topic.as_bytes()))
+    }
+}
+
+/// Parse a subscription line. An empty line subscribes to all topics
+impl FromStr for Subscription {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let patterns: Vec<String> = s
+            .split(|c: char| c == ',' || c.is_whitespace())
+            .filter(|p| !p.is_empty())
+            .map(String::from)
+            .collect();
+        if patterns.is_empty() {
+            Ok(Subscription::all())
+        } else {
+            Ok(Subscription { patterns })
+        }
+    }
+}
+
+/// Match `text` against a glob `pattern`, where `*` matches any sequence
+/// and `?` matches a single byte
+fn wildcard_match(pattern: &[u8], text: &[u8]) -> bool {
+    let (mut p, mut t) = (0, 0);
+    let mut backtrack: Option<(usize, usize)> = None;
+    while t < text.len() {
+        if p < pattern.len() && (pattern[p] == b'?' || pattern[p] == text[t]) {
+            p += 1;
+            t += 1;
+        } else if p < pattern.len() && pattern[p] == b'*' {
+            backtrack = Some((p, t));
+            p += 1;
+        } else if let Some((star_p, star_t)) = backtrack {
+            p = star_p + 1;
+            t = star_t + 1;
+            backtrack = Some((star_p, star_t + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|&c| c == b'*')
+}
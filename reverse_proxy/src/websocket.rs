@@ -0,0 +1,392 @@
+//! WebSocket output for subscribers of the multicast bus.
+//!
+//! Performs the HTTP upgrade handshake described in RFC 6455, then sends each
+//! bus message as a single WebSocket message. Clients are pinged periodically,
+//! and disconnected if no pong is received. Requests without the upgrade
+//! headers of version 13 are refused, and clients sending unmasked frames are
+//! disconnected with a protocol error. When topic rules are set, clients
+//! may subscribe to topics with a query string, e.g. `/?topics=ais.*,weather`.
+
+use std::io::{BufReader, Error, ErrorKind, Read, Result as ioResult, Write};
+use std::net::{Shutdown, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::RecvTimeoutError;
+use std::sync::{Arc, Mutex};
+use std::thread::spawn;
+use std::time::{Duration, Instant};
+
+use log::debug;
+use mproxy_common::http::{read_request, respond};
+use mproxy_common::metrics::DownstreamMetrics;
+
+use crate::bus::Bus;
+use crate::peer_name;
+use crate::topic::Subscription;
+
+/// Interval between keepalive pings sent to each client
+const PING_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Clients are disconnected if no pong is received within this duration
+const PONG_TIMEOUT: Duration = Duration::from_secs(75);
+
+/// Maximum payload size of frames accepted from clients
+const MAX_CLIENT_FRAME: u64 = 65536;
+
+/// Appended to the client key to compute the handshake response
+const HANDSHAKE_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+const OPCODE_TEXT: u8 = 0x1;
+const OPCODE_BINARY: u8 = 0x2;
+const OPCODE_CLOSE: u8 = 0x8;
+const OPCODE_PING: u8 = 0x9;
+const OPCODE_PONG: u8 = 0xA;
+
+/// Close status sent to clients violating the protocol
+const CLOSE_PROTOCOL_ERROR: u16 = 1002;
+
+/// Connection state shared by the reader and writer threads of a client
+struct Connection {
+    stream: Mutex<TcpStream>,
+    last_pong: Mutex<Instant>,
+    closed: AtomicBool,
+}
+
+impl Connection {
+    /// Send a single unfragmented frame
+    fn send(&self, opcode: u8, payload: &[u8]) -> ioResult<()> {
+        let mut frame = Vec::with_capacity(payload.len() + 10);
+        frame.push(0x80 | opcode);
+        match payload.len() {
+            len if len < 126 => frame.push(len as u8),
+            len if len <= u16::MAX as usize => {
+                frame.push(126);
+                frame.extend_from_slice(&(len as u16).to_be_bytes());
+            }
+            len => {
+                frame.push(127);
+                frame.extend_from_slice(&(len as u64).to_be_bytes());
+            }
+        }
+        frame.extend_from_slice(payload);
+        self.stream.lock().unwrap().write_all(&frame)
+    }
+
+    fn close(&self) {
+        self.closed.store(true, Ordering::Relaxed);
+        let _ = self.stream.lock().unwrap().shutdown(Shutdown::Both);
+    }
+}
+
+/// Read a single frame sent by the client, returning the opcode and unmasked payload
+fn read_frame(reader: &mut impl Read) -> ioResult<(u8, Vec<u8>)> {
+    let mut header = [0u8; 2];
+    reader.read_exact(&mut header)?;
+    let opcode = header[0] & 0x0f;
+    let masked = header[1] & 0x80 != 0;
+    let len = match header[1] & 0x7f {
+        126 => {
+            let mut ext = [0u8; 2];
+            reader.read_exact(&mut ext)?;
+            u16::from_be_bytes(ext) as u64
+        }
+        127 => {
+            let mut ext = [0u8; 8];
+            reader.read_exact(&mut ext)?;
+            u64::from_be_bytes(ext)
+        }
+        len => len as u64,
+    };
+    if len > MAX_CLIENT_FRAME {
+        return Err(Error::new(ErrorKind::InvalidData, "client frame too large"));
+    }
+    // clients must mask every frame, see RFC 6455 section 5.1
+    if !masked {
+        return Err(Error::new(ErrorKind::InvalidData, "unmasked client frame"));
+    }
+    let mut mask = [0u8; 4];
+    reader.read_exact(&mut mask)?;
+    let mut payload = vec![0u8; len as usize];
+    reader.read_exact(&mut payload)?;
+    for (i, b) in payload.iter_mut().enumerate() {
+        *b ^= mask[i % 4];
+    }
+    Ok((opcode, payload))
+}
+
+/// Handle control frames sent by the client until the connection is closed
+fn read_client_frames(mut reader: impl Read, conn: Arc<Connection>) {
+    loop {
+        match read_frame(&mut reader) {
+            Ok((OPCODE_CLOSE, payload)) => {
+                let _ = conn.send(OPCODE_CLOSE, &payload[..payload.len().min(2)]);
+                break;
+            }
+            Ok((OPCODE_PING, payload)) => {
+                if conn.send(OPCODE_PONG, &payload).is_err() {
+                    break;
+                }
+            }
+            Ok((OPCODE_PONG, _)) => *conn.last_pong.lock().unwrap() = Instant::now(),
+            Ok(_) => {}
+            Err(e) if e.kind() == ErrorKind::InvalidData => {
+                debug!("websocket: closing {:?} {}", conn.stream, e);
+                let _ = conn.send(OPCODE_CLOSE, &CLOSE_PROTOCOL_ERROR.to_be_bytes());
+                break;
+            }
+            Err(e) => {
+                if !conn.closed.load(Ordering::Relaxed) {
+                    debug!("websocket: closing {:?} {}", conn.stream, e);
+                }
+                break;
+            }
+        }
+    }
+    conn.close();
+}
+
+/// Perform the upgrade handshake with a new client, then forward bus messages
+/// matching the client's subscription until the connection is closed
+pub(crate) fn handle_client_ws(
+    downstream: TcpStream,
+    bus: Arc<Bus>,
+    metrics: Arc<DownstreamMetrics>,
+    binary: bool,
+) {
+    debug!("handling downstream client: {:?} WebSocket", downstream);
+
+    let peer = peer_name(&downstream);
+    let mut writer = match downstream.try_clone() {
+        Ok(w) => w,
+        Err(_) => return,
+    };
+    let mut reader = BufReader::new(downstream);
+    let request = match read_request(&mut reader) {
+        Ok(r) => r,
+        Err(_) => return,
+    };
+    let has_token = |header: &str, token: &str| {
+        request
+            .header(header)
+            .is_some_and(|v| v.split(',').any(|t| t.trim().eq_ignore_ascii_case(token)))
+    };
+    let key = match (request.method.as_str(), request.header("Sec-WebSocket-Key")) {
+        ("GET", Some(key))
+            if has_token("Upgrade", "websocket") && has_token("Connection", "upgrade") =>
+        {
+            key
+        }
+        _ => {
+            let body = b"expected a WebSocket upgrade request\n";
+            let _ = respond(&mut writer, "400 Bad Request", "text/plain", body);
+            return;
+        }
+    };
+    if request.header("Sec-WebSocket-Version") != Some("13") {
+        let _ = writer.write_all(
+            b"HTTP/1.1 426 Upgrade Required\r\n\
+              Sec-WebSocket-Version: 13\r\n\
+              Content-Length: 0\r\n\
+              Connection: close\r\n\r\n",
+        );
+        return;
+    }
+    let accept = base64(&sha1(format!("{}{}", key, HANDSHAKE_GUID).as_bytes()));
+    let response = format!(
+        "HTTP/1.1 101 Switching Protocols\r\n\
+         Upgrade: websocket\r\n\
+         Connection: Upgrade\r\n\
+         Sec-WebSocket-Accept: {}\r\n\r\n",
+        accept
+    );
+    if writer.write_all(response.as_bytes()).is_err() {
+        return;
+    }
+
+    let subscription = match request.param("topics") {
+        Some(topics) if bus.has_topics() => topics.parse().unwrap_or_else(|_| Subscription::all()),
+        _ => Subscription::all(),
+    };
+    let conn = Arc::new(Connection {
+        stream: Mutex::new(writer),
+        last_pong: Mutex::new(Instant::now()),
+        closed: AtomicBool::new(false),
+    });
+    let reader_conn = conn.clone();
+    spawn(move || read_client_frames(reader, reader_conn));
+
+    let mut messages = bus.subscribe(subscription, metrics.clone());
+    let _client = metrics.connected(peer, Some(messages.queue_depth()));
+    let send_message = |msg: &[u8]| -> ioResult<()> {
+        let opcode = if binary || std::str::from_utf8(msg).is_err() {
+            OPCODE_BINARY
+        } else {
+            OPCODE_TEXT
+        };
+        conn.send(opcode, msg)?;
+        metrics.sent(msg.len());
+        Ok(())
+    };
+    let mut last_ping = Instant::now();
+    loop {
+        let sent = match messages.recv_timeout(PING_INTERVAL) {
+            Ok(msg) => send_message(&msg),
+            Err(RecvTimeoutError::Timeout) => Ok(()),
+            Err(RecvTimeoutError::Disconnected) => break,
+        };
+        if sent.is_err() || conn.closed.load(Ordering::Relaxed) {
+            break;
+        }
+        if last_ping.elapsed() >= PING_INTERVAL {
+            if conn.last_pong.lock().unwrap().elapsed() > PONG_TIMEOUT {
+                debug!("websocket: no pong received, closing {:?}", conn.stream);
+                let _ = conn.send(OPCODE_CLOSE, &1001u16.to_be_bytes());
+                break;
+            }
+            if conn.send(OPCODE_PING, b"").is_err() {
+                break;
+            }
+            last_ping = Instant::now();
+        }
+    }
+    conn.close();
+}
+
+/// SHA-1 digest, as required by the WebSocket handshake
+fn sha1(data: &[u8]) -> [u8; 20] {
+    let mut h: [u32; 5] = [0x67452301, 0xEFCDAB89, 0x98BADCFE, 0x10325476, 0xC3D2E1F0];
+    let mut msg = data.to_vec();
+    msg.push(0x80);
+    while msg.len() % 64 != 56 {
+        msg.push(0);
+    }
+    msg.extend_from_slice(&(data.len() as u64 * 8).to_be_bytes());
+
+    for block in msg.chunks(64) {
+        let mut w = [0u32; 80];
+        for (i, word) in block.chunks(4).enumerate() {
+            w[i] = u32::from_be_bytes([word[0], word[1], word[2], word[3]]);
+        }
+        for i in 16..80 {
+            w[i] = (w[i - 3] ^ w[i - 8] ^ w[i - 14] ^ w[i - 16]).rotate_left(1);
+        }
+        let [mut a, mut b, mut c, mut d, mut e] = h;
+        for (i, word) in w.iter().enumerate() {
+            let (f, k) = match i {
+                0..=19 => ((b & c) | (!b & d), 0x5A827999),
+                20..=39 => (b ^ c ^ d, 0x6ED9EBA1),
+                40..=59 => ((b & c) | (b & d) | (c & d), 0x8F1BBCDC),
+                _ => (b ^ c ^ d, 0xCA62C1D6),
+            };
+            let temp = a
+                .rotate_left(5)
+                .wrapping_add(f)
+                .wrapping_add(e)
+                .wrapping_add(k)
+                .wrapping_add(*word);
+            e = d;
+            d = c;
+            c = b.rotate_left(30);
+            b = a;
+            a = temp;
+        }
+        for (state, value) in h.iter_mut().zip([a, b, c, d, e]) {
+            *state = state.wrapping_add(value);
+        }
+    }
+
+    let mut digest = [0u8; 20];
+    for (chunk, state) in digest.chunks_mut(4).zip(h) {
+        chunk.copy_from_slice(&state.to_be_bytes());
+    }
+    digest
+}
+
+/// Standard base64 encoding with padding
+fn base64(data: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut encoded = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let n = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |n, (i, &b)| n | (b as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(ALPHABET[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    #[test]
+    fn sha1_test_vectors() {
+        // FIPS 180 examples
+        assert_eq!(hex(&sha1(b"")), "da39a3ee5e6b4b0d3255bfef95601890afd80709");
+        assert_eq!(
+            hex(&sha1(b"abc")),
+            "a9993e364706816aba3e25717850c26c9cd0d89d"
+        );
+        assert_eq!(
+            hex(&sha1(
+                b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq"
+            )),
+            "84983e441c3bd26ebaae4aa1f95129e5e54670f1"
+        );
+        assert_eq!(
+            hex(&sha1(&[b'a'; 1_000_000])),
+            "34aa973cd4c4daa4f61eeb2bdbad27316534016f"
+        );
+    }
+
+    #[test]
+    fn base64_test_vectors() {
+        // RFC 4648 section 10
+        for (data, encoded) in [
+            ("", ""),
+            ("f", "Zg=="),
+            ("fo", "Zm8="),
+            ("foo", "Zm9v"),
+            ("foob", "Zm9vYg=="),
+            ("fooba", "Zm9vYmE="),
+            ("foobar", "Zm9vYmFy"),
+        ] {
+            assert_eq!(base64(data.as_bytes()), encoded);
+        }
+    }
+
+    #[test]
+    fn handshake_accept_key() {
+        // RFC 6455 section 1.3
+        let key = format!("{}{}", "dGhlIHNhbXBsZSBub25jZQ==", HANDSHAKE_GUID);
+        assert_eq!(
+            base64(&sha1(key.as_bytes())),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+    }
+
+    #[test]
+    fn read_masked_and_unmasked_frames() {
+        // RFC 6455 section 5.7
+        let masked = [
+            0x81, 0x85, 0x37, 0xfa, 0x21, 0x3d, 0x7f, 0x9f, 0x4d, 0x51, 0x58,
+        ];
+        let (opcode, payload) = read_frame(&mut &masked[..]).unwrap();
+        assert_eq!(opcode, OPCODE_TEXT);
+        assert_eq!(payload, b"Hello");
+
+        let unmasked = [0x81, 0x05, 0x48, 0x65, 0x6c, 0x6c, 0x6f];
+        let e = read_frame(&mut &unmasked[..]).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::InvalidData);
+    }
+}
@@ -1,9 +1,17 @@
+use std::io::{Read, Write};
+use std::net::TcpStream;
 use std::path::PathBuf;
 use std::thread::sleep;
 use std::time::Duration;
 
-use mproxy_client::client_socket_stream;
-use mproxy_reverse::reverse_proxy_udp_tcp;
+use mproxy_client::{client_socket_stream, target_socket_interface};
+use mproxy_common::admin::serve_admin;
+use mproxy_reverse::cache::KeyRule;
+use mproxy_reverse::topic::TopicRule;
+use mproxy_reverse::{
+    reverse_proxy_http_udp, reverse_proxy_udp_http, reverse_proxy_udp_tcp,
+    reverse_proxy_udp_tcp_with, reverse_proxy_udp_ws, SubscriberConfig,
+};
 
 use testconfig::TESTDATA;
 
@@ -40,3 +48,420 @@ fn test_reverse_proxy_tcp_ipv6() {
     let _c = client_socket_stream(&data, vec![client_target_addr], false);
     sleep(Duration::from_millis(15));
 }
+
+/// Read from a TCP stream until no more data arrives
+fn read_available_bytes(stream: &mut TcpStream) -> Vec<u8> {
+    stream
+        .set_read_timeout(Some(Duration::from_millis(100)))
+        .unwrap();
+    let mut output = vec![];
+    let mut buf = [0u8; 1024];
+    while let Ok(c) = stream.read(&mut buf) {
+        if c == 0 {
+            break;
+        }
+        output.extend_from_slice(&buf[0..c]);
+    }
+    output
+}
+
+/// Read UTF-8 text from a TCP stream until no more data arrives
+fn read_available(stream: &mut TcpStream) -> String {
+    String::from_utf8(read_available_bytes(stream)).unwrap()
+}
+
+#[test]
+fn test_reverse_proxy_tcp_topics() {
+    let multicast_addr = "224.0.0.1:8996".to_string();
+    let proxy_tcp_output_addr = "127.0.0.1:8997".to_string();
+    let config = SubscriberConfig {
+        topic_rules: vec![
+            "prefix:!AIVDM=ais.vdm".parse::<TopicRule>().unwrap(),
+            "header".parse::<TopicRule>().unwrap(),
+        ],
+        ..Default::default()
+    };
+
+    let _r = reverse_proxy_udp_tcp_with(
+        multicast_addr.clone(),
+        proxy_tcp_output_addr.clone(),
+        config,
+    );
+    sleep(Duration::from_millis(30));
+
+    // each client subscribes to a different topic, and a third sends no subscription
+    let mut ais = TcpStream::connect(&proxy_tcp_output_addr).unwrap();
+    ais.write_all(b"ais.*\n").unwrap();
+    let mut weather = TcpStream::connect(&proxy_tcp_output_addr).unwrap();
+    weather.write_all(b"weather\n").unwrap();
+    let mut everything = TcpStream::connect(&proxy_tcp_output_addr).unwrap();
+    sleep(Duration::from_millis(300));
+
+    let (target_addr, target_socket) = target_socket_interface(&multicast_addr).unwrap();
+    for msg in [
+        &b"!AIVDM,1,1,,A,example\n"[..],
+        b"weather|rain\n",
+        b"traffic|jam\n",
+    ] {
+        target_socket.send_to(msg, target_addr).unwrap();
+    }
+
+    let ais_output = read_available(&mut ais);
+    assert_eq!(ais_output, "!AIVDM,1,1,,A,example\n");
+    let weather_output = read_available(&mut weather);
+    assert_eq!(weather_output, "rain\n");
+    let everything_output = read_available(&mut everything);
+    assert_eq!(everything_output, "!AIVDM,1,1,,A,example\nrain\njam\n");
+
+    // messages sent before the subscription line are kept, then filtered
+    let mut late = TcpStream::connect(&proxy_tcp_output_addr).unwrap();
+    sleep(Duration::from_millis(50));
+    for msg in [&b"!AIVDM,1,1,,B,early\n"[..], b"weather|sun\n"] {
+        target_socket.send_to(msg, target_addr).unwrap();
+    }
+    sleep(Duration::from_millis(20));
+    late.write_all(b"weather\n").unwrap();
+    assert_eq!(read_available(&mut late), "sun\n");
+}
+
+#[test]
+fn test_reverse_proxy_tcp_replay() {
+    let multicast_addr = "224.0.0.1:8998".to_string();
+    let proxy_tcp_output_addr = "127.0.0.1:8999".to_string();
+    let config = SubscriberConfig {
+        replay_count: Some(2),
+        ..Default::default()
+    };
+
+    let _r = reverse_proxy_udp_tcp_with(
+        multicast_addr.clone(),
+        proxy_tcp_output_addr.clone(),
+        config,
+    );
+    sleep(Duration::from_millis(30));
+
+    // messages sent before the client connects are kept for replay
+    let (target_addr, target_socket) = target_socket_interface(&multicast_addr).unwrap();
+    for msg in ["first\n", "second\n", "third\n"] {
+        target_socket.send_to(msg.as_bytes(), target_addr).unwrap();
+    }
+    sleep(Duration::from_millis(30));
+
+    let mut late_joiner = TcpStream::connect(&proxy_tcp_output_addr).unwrap();
+    sleep(Duration::from_millis(30));
+    target_socket.send_to(b"fourth\n", target_addr).unwrap();
+
+    let output = read_available(&mut late_joiner);
+    assert_eq!(output, "second\nthird\nfourth\n");
+}
+
+#[test]
+fn test_reverse_proxy_tcp_last_value_cache() {
+    let multicast_addr = "224.0.0.1:9000".to_string();
+    let proxy_tcp_output_addr = "127.0.0.1:9001".to_string();
+    let config = SubscriberConfig {
+        cache_key: Some("field:1".parse::<KeyRule>().unwrap()),
+        ..Default::default()
+    };
+
+    let _r = reverse_proxy_udp_tcp_with(
+        multicast_addr.clone(),
+        proxy_tcp_output_addr.clone(),
+        config,
+    );
+    sleep(Duration::from_millis(30));
+
+    // only the latest message for each key is sent to new clients
+    let (target_addr, target_socket) = target_socket_interface(&multicast_addr).unwrap();
+    for msg in [
+        "1,vessel_a,0.0\n",
+        "2,vessel_b,1.0\n",
+        "3,vessel_a,2.0\n",
+        "no key\n",
+    ] {
+        target_socket.send_to(msg.as_bytes(), target_addr).unwrap();
+    }
+    sleep(Duration::from_millis(30));
+
+    let mut late_joiner = TcpStream::connect(&proxy_tcp_output_addr).unwrap();
+    let output = read_available(&mut late_joiner);
+    assert_eq!(output, "2,vessel_b,1.0\n3,vessel_a,2.0\n");
+}
+
+#[test]
+fn test_reverse_proxy_websocket() {
+    let multicast_addr = "224.0.0.1:9002".to_string();
+    let proxy_tcp_output_addr = "127.0.0.1:9003".to_string();
+    let proxy_ws_output_addr = "127.0.0.1:9004".to_string();
+
+    // TCP and WebSocket outputs share the multicast bus
+    let config = SubscriberConfig::default();
+    let _t = reverse_proxy_udp_tcp_with(
+        multicast_addr.clone(),
+        proxy_tcp_output_addr.clone(),
+        config.clone(),
+    );
+    let _w = reverse_proxy_udp_ws(
+        multicast_addr.clone(),
+        proxy_ws_output_addr.clone(),
+        config,
+        false,
+    );
+    sleep(Duration::from_millis(30));
+
+    // handshake example from RFC 6455
+    let mut ws = TcpStream::connect(&proxy_ws_output_addr).unwrap();
+    ws.write_all(
+        b"GET /chat HTTP/1.1\r\n\
+          Host: server.example.com\r\n\
+          Upgrade: websocket\r\n\
+          Connection: Upgrade\r\n\
+          Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+          Sec-WebSocket-Version: 13\r\n\r\n",
+    )
+    .unwrap();
+    let handshake = read_available(&mut ws);
+    assert!(handshake.starts_with("HTTP/1.1 101 Switching Protocols\r\n"));
+    assert!(handshake.contains("Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n"));
+    let mut tcp = TcpStream::connect(&proxy_tcp_output_addr).unwrap();
+    sleep(Duration::from_millis(30));
+
+    let (target_addr, target_socket) = target_socket_interface(&multicast_addr).unwrap();
+    target_socket.send_to(b"hello", target_addr).unwrap();
+
+    // unmasked text frame with a 5 byte payload
+    let frame = read_available_bytes(&mut ws);
+    assert_eq!(frame, b"\x81\x05hello");
+    assert_eq!(read_available(&mut tcp), "hello");
+
+    // other protocol versions are refused
+    let response = http_request(
+        &proxy_ws_output_addr,
+        b"GET / HTTP/1.1\r\n\
+          Upgrade: websocket\r\n\
+          Connection: Upgrade\r\n\
+          Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+          Sec-WebSocket-Version: 8\r\n\r\n",
+    );
+    assert!(response.starts_with("HTTP/1.1 426 Upgrade Required\r\n"));
+    assert!(response.contains("Sec-WebSocket-Version: 13\r\n"));
+}
+
+#[test]
+fn test_reverse_proxy_http_stream() {
+    let multicast_addr = "224.0.0.1:9005".to_string();
+    let proxy_http_output_addr = "127.0.0.1:9006".to_string();
+    let config = SubscriberConfig::default();
+    let _h = reverse_proxy_udp_http(
+        multicast_addr.clone(),
+        proxy_http_output_addr.clone(),
+        config,
+    );
+    sleep(Duration::from_millis(30));
+
+    let mut chunked = TcpStream::connect(&proxy_http_output_addr).unwrap();
+    chunked
+        .write_all(b"GET /stream?contains=hello HTTP/1.1\r\nHost: localhost\r\n\r\n")
+        .unwrap();
+    let mut sse = TcpStream::connect(&proxy_http_output_addr).unwrap();
+    sse.write_all(b"GET /stream?format=sse HTTP/1.1\r\nHost: localhost\r\n\r\n")
+        .unwrap();
+    let mut not_found = TcpStream::connect(&proxy_http_output_addr).unwrap();
+    not_found
+        .write_all(b"GET /other HTTP/1.1\r\nHost: localhost\r\n\r\n")
+        .unwrap();
+    sleep(Duration::from_millis(30));
+
+    let (target_addr, target_socket) = target_socket_interface(&multicast_addr).unwrap();
+    target_socket.send_to(b"hello\nworld", target_addr).unwrap();
+    target_socket.send_to(b"goodbye\n", target_addr).unwrap();
+
+    let output = read_available(&mut chunked);
+    assert!(output.starts_with("HTTP/1.1 200 OK\r\n"));
+    assert!(output.contains("Transfer-Encoding: chunked\r\n"));
+    assert!(output.ends_with("\r\n\r\nc\r\nhello\nworld\n\r\n"));
+
+    let output = read_available(&mut sse);
+    assert!(output.contains("Content-Type: text/event-stream\r\n"));
+    assert!(output.ends_with("\r\n\r\ndata: hello\ndata: world\n\ndata: goodbye\n\n"));
+
+    let output = read_available(&mut not_found);
+    assert!(output.starts_with("HTTP/1.1 404 Not Found\r\n"));
+}
+
+/// Send an HTTP request and return the response
+fn http_request(addr: &str, request: &[u8]) -> String {
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream.write_all(request).unwrap();
+    read_available(&mut stream)
+}
+
+#[test]
+fn test_reverse_proxy_http_publish() {
+    let multicast_addr = "224.0.0.1:9007".to_string();
+    let proxy_tcp_output_addr = "127.0.0.1:9008".to_string();
+    let proxy_http_listen_addr = "127.0.0.1:9009".to_string();
+    let token = Some("secret".to_string());
+
+    let _t = reverse_proxy_udp_tcp(multicast_addr.clone(), proxy_tcp_output_addr.clone());
+    let _h = reverse_proxy_http_udp(proxy_http_listen_addr.clone(), multicast_addr, token);
+    sleep(Duration::from_millis(30));
+    let mut tcp = TcpStream::connect(&proxy_tcp_output_addr).unwrap();
+    sleep(Duration::from_millis(30));
+
+    let response = http_request(
+        &proxy_http_listen_addr,
+        b"POST /publish HTTP/1.1\r\nContent-Length: 6\r\n\r\nhello\n",
+    );
+    assert!(response.starts_with("HTTP/1.1 401 Unauthorized\r\n"));
+
+    let response = http_request(
+        &proxy_http_listen_addr,
+        b"POST /publish HTTP/1.1\r\n\
+          Authorization: Bearer secret\r\n\
+          Content-Length: 6\r\n\r\n\
+          hello\n",
+    );
+    assert!(response.starts_with("HTTP/1.1 202 Accepted\r\n"));
+
+    let response = http_request(
+        &proxy_http_listen_addr,
+        b"POST /publish?batch HTTP/1.1\r\n\
+          Authorization: Bearer secret\r\n\
+          Content-Length: 12\r\n\r\n\
+          first\nsecond",
+    );
+    assert!(response.starts_with("HTTP/1.1 202 Accepted\r\n"));
+    assert!(response.ends_with("\r\n\r\n2\n"));
+
+    let response = http_request(
+        &proxy_http_listen_addr,
+        b"POST /publish HTTP/1.1\r\n\
+          Authorization: Bearer secret\r\n\
+          Content-Length: 100000000\r\n\r\n",
+    );
+    assert!(response.starts_with("HTTP/1.1 413 Payload Too Large\r\n"));
+
+    assert_eq!(read_available(&mut tcp), "hello\nfirst\nsecond");
+}
+
+#[test]
+fn test_reverse_proxy_admin_routes() {
+    let multicast_addr = "224.0.0.1:9010".to_string();
+    let proxy_tcp_output_addr = "127.0.0.1:9011".to_string();
+    let admin_addr = "127.0.0.1:9012".to_string();
+
+    let _t = reverse_proxy_udp_tcp(multicast_addr.clone(), proxy_tcp_output_addr.clone());
+    let _a = serve_admin(admin_addr.clone());
+    sleep(Duration::from_millis(30));
+    let tcp = TcpStream::connect(&proxy_tcp_output_addr).unwrap();
+    sleep(Duration::from_millis(30));
+
+    let response = http_request(&admin_addr, b"GET /routes HTTP/1.1\r\n\r\n");
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+    assert!(response.contains("Content-Type: application/json"));
+    assert!(response.contains(&format!(
+        "\"name\":\"{}:bus\",\"source\":\"{}\",\"kind\":\"bus\",\"state\":\"running\"",
+        multicast_addr, multicast_addr
+    )));
+    assert!(response.contains(&format!(
+        "\"name\":\"{}:tcp\",\"state\":\"running\"",
+        proxy_tcp_output_addr
+    )));
+    assert!(response.contains(&format!("\"addr\":\"{}\"", tcp.local_addr().unwrap())));
+    assert!(response.contains("\"queue_depth\":0"));
+
+    let response = http_request(&admin_addr, b"POST /routes HTTP/1.1\r\n\r\n");
+    assert!(response.starts_with("HTTP/1.1 405 Method Not Allowed\r\n"));
+}
+
+#[cfg(unix)]
+#[test]
+fn test_reverse_proxy_upgrade_handoff() {
+    use std::net::UdpSocket;
+    use std::os::unix::net::UnixDatagram;
+    use std::process::{Command, Stdio};
+
+    let udp_listen_addr = "127.0.0.1:9013";
+    let udp_output_addr = "127.0.0.1:9014";
+    let tcp_output_addr = "127.0.0.1:9016";
+    let notify_path = std::env::temp_dir().join("mproxy_reverse_notify.sock");
+    let _ = std::fs::remove_file(&notify_path);
+    let notify = UnixDatagram::bind(&notify_path).unwrap();
+    notify
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    let next_notification = || {
+        let mut buf = [0u8; 256];
+        let c = notify.recv(&mut buf).unwrap();
+        String::from_utf8(buf[0..c].to_vec()).unwrap()
+    };
+    let downstream = UdpSocket::bind(udp_output_addr).unwrap();
+    downstream
+        .set_read_timeout(Some(Duration::from_secs(2)))
+        .unwrap();
+    let recv = || {
+        let mut buf = [0u8; 64];
+        let c = downstream.recv(&mut buf).unwrap();
+        String::from_utf8(buf[0..c].to_vec()).unwrap()
+    };
+    let upstream = UdpSocket::bind("127.0.0.1:0").unwrap();
+
+    let mut previous = Command::new(env!("CARGO_BIN_EXE_mproxy-reverse"))
+        .args(["--udp-listen-addr", udp_listen_addr])
+        .args(["--udp-output-addr", udp_output_addr])
+        .args(["--tcp-output-addr", tcp_output_addr])
+        .args(["--multicast-addr", "224.0.0.1:9015"])
+        .args(["--drain-secs", "2"])
+        .env("NOTIFY_SOCKET", &notify_path)
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    assert!(next_notification().starts_with("READY=1\n"));
+    upstream.send_to(b"one", udp_listen_addr).unwrap();
+    assert_eq!(recv(), "one");
+    // let the bus deliver "one" before the subscriber connects
+    sleep(Duration::from_millis(30));
+    let mut subscriber = TcpStream::connect(tcp_output_addr).unwrap();
+    sleep(Duration::from_millis(30));
+
+    // the new process reports readiness, and takes over as main process
+    Command::new("kill")
+        .args(["-USR2", &previous.id().to_string()])
+        .status()
+        .unwrap();
+    let notifications = [next_notification(), next_notification()];
+    assert!(notifications.iter().any(|n| n.starts_with("READY=1\n")));
+    let pid = notifications
+        .iter()
+        .find_map(|n| n.strip_prefix("MAINPID="))
+        .expect("new main process")
+        .to_string();
+
+    // clients of the previous process are served while it drains
+    sleep(Duration::from_millis(600));
+    upstream.send_to(b"two", udp_listen_addr).unwrap();
+    assert_eq!(recv(), "two");
+    assert_eq!(read_available(&mut subscriber), "two");
+    assert_eq!(previous.try_wait().unwrap(), None);
+    assert!(previous.wait().unwrap().success());
+
+    upstream.send_to(b"three", udp_listen_addr).unwrap();
+    assert_eq!(recv(), "three");
+    Command::new("kill").arg(&pid).status().unwrap();
+    let _ = std::fs::remove_file(&notify_path);
+}
+
+#[test]
+fn test_reverse_proxy_refuses_port_rules_with_udp_listener() {
+    use std::process::Command;
+
+    let output = Command::new(env!("CARGO_BIN_EXE_mproxy-reverse"))
+        .args(["--udp-listen-addr", "127.0.0.1:9953"])
+        .args(["--tcp-output-addr", "127.0.0.1:9954"])
+        .args(["--topic-rule", "port:5000=ais"])
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("port topic rules"));
+}
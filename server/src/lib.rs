@@ -47,11 +47,55 @@
 //!
 //! OPTIONS:
 //!   --path        [FILE_DESCRIPTOR]   Filepath, descriptor, or handle.
+//!   --format      [FORMAT]            File format: raw, capture, pcap, or pcapng. Defaults to raw
 //!   --listen-addr [SOCKET_ADDR]       Upstream UDP listening address. May be repeated
+//!   --metrics-addr [SOCKET_ADDR]      Serve Prometheus metrics over HTTP at /metrics
+//!   --tap-addr    [ADDR|PATH]         Serve live taps of route traffic over TCP, or a Unix socket PATH
+//!   --source-limit [LIMIT]            Rate limit for each upstream source address, see RATE LIMITS
+//!   --on-failure  [ACTION]            Action when a route fails: restart or exit. Defaults to restart
+//!   --log-level   [LEVEL]             Log level: error, warn, info, debug, or trace, or per target, e.g. mproxy_server=debug,warn. Defaults to info
+//!   --log-format  [FORMAT]            Log format: text or json. Defaults to text
+//!   --log-output  [OUTPUT]            Write logs to stderr, syslog, or journald. Defaults to stderr
+//!   --user        [USER]              Switch to USER, a name or ID, once sockets and files are opened
+//!   --group       [GROUP]             Switch to GROUP. Defaults to the primary group of --user
+//!   --pidfile     [FILE]              Write the process ID to FILE
 //!
 //! FLAGS:
 //!   -h, --help    Prints help information
 //!   -t, --tee     Copy input to stdout
+//!   --chroot      Confine the server to the directory of --path, once opened
+//!   --heartbeat   Answer zero-length datagrams, for mproxy-forward --heartbeat-secs. Off by default
+//!
+//! TAPS:
+//!   Connect to --tap-addr and send a line naming a route listed by the admin API or metrics,
+//!   e.g. '0.0.0.0:9920:server', to receive a copy of the messages it receives.
+//!   Append 'hex' for a hexdump, or 'sample=N' to copy every Nth message.
+//!
+//! SYSTEMD:
+//!   Sockets passed by systemd socket activation are used in place of binding matching
+//!   addresses. With Type=notify, readiness is reported once listeners are bound, and
+//!   watchdog pings are sent while no route has panicked.
+//!
+//! FAILURES:
+//!   Routes which panic or fail are restarted with backoff, from 100ms up to 30s. With
+//!   --on-failure exit, the process instead exits with status 1, e.g. for systemd to restart it.
+//!
+//! RATE LIMITS:
+//!   Limits are given as 'bytes=RATE[:BURST],packets=RATE[:BURST]', either part optional.
+//!   Datagrams over the limit are dropped, and counted in the metrics. Bursts default to one
+//!   second of traffic.
+//!
+//! CAPTURE:
+//!   With --format capture, each datagram is written with its arrival time and source address,
+//!   so that the feed can be replayed with its original timing by mproxy-client --replay.
+//!   With --format pcap or pcapng, each datagram is written as a UDP packet from its source
+//!   address to the listening address, to open in tools such as Wireshark.
+//!
+//! PRIVILEGES:
+//!   When started as root, e.g. to listen on ports below 1024 or write to a directory owned by
+//!   root, use --user and --group to switch to an unprivileged user once listeners are bound and
+//!   output files are opened, and --chroot to confine the server to the directory of --path.
+//!   The pid file is written before.
 //!
 //! EXAMPLE:
 //!   mproxy-server --path logfile.log --listen-addr '127.0.0.1:9920' --listen-addr '[::1]:9921'
@@ -65,13 +109,35 @@
 //!
 
 use std::fs::OpenOptions;
-use std::io::{stdout, BufWriter, Result as ioResult, Write};
+use std::io::{stdout, BufWriter, Error, ErrorKind, Read, Result as ioResult, Write};
 use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs, UdpSocket};
 use std::path::PathBuf;
-use std::thread::{Builder, JoinHandle};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::JoinHandle;
+use std::time::SystemTime;
+
+use log::error;
+use mproxy_common::capture::{self, encode_record};
+use mproxy_common::metrics::RouteMetrics;
+use mproxy_common::pcap::{self, encode_packet, PcapFormat};
+use mproxy_common::ratelimit::{Shaped, SourceLimiter};
+use mproxy_common::supervisor::supervise;
+use mproxy_common::systemd::{multicast_socket, udp_socket};
 
 const BUFSIZE: usize = 8096;
 
+/// Set if zero-length datagrams are answered as heartbeats
+static ANSWER_HEARTBEATS: AtomicBool = AtomicBool::new(false);
+
+/// Answer zero-length datagrams with a zero-length datagram, as heartbeats for
+/// downstream failover (see `mproxy_client::Dispatch`), for all listeners in
+/// this process. Off by default, as any sender could then have replies sent to
+/// a spoofed source address
+pub fn set_answer_heartbeats(answer: bool) {
+    ANSWER_HEARTBEATS.store(answer, Ordering::Relaxed);
+}
+
 pub fn upstream_socket_interface(listen_addr: String) -> ioResult<(SocketAddr, UdpSocket)> {
     let addr = listen_addr
         .to_socket_addrs()
@@ -81,22 +147,22 @@ pub fn upstream_socket_interface(listen_addr: String) -> ioResult<(SocketAddr, U
     let listen_socket;
     match (addr.ip().is_multicast(), addr.ip()) {
         (false, std::net::IpAddr::V4(_)) => {
-            listen_socket = UdpSocket::bind(addr).expect("binding server socket");
+            listen_socket = udp_socket(addr).expect("binding server socket");
         }
         (false, std::net::IpAddr::V6(_)) => {
-            listen_socket = UdpSocket::bind(addr).expect("binding server socket");
+            listen_socket = udp_socket(addr).expect("binding server socket");
         }
         (true, std::net::IpAddr::V4(ip)) => {
             #[cfg(not(target_os = "windows"))]
             {
-                listen_socket = UdpSocket::bind(addr).expect("binding server socket");
+                listen_socket = multicast_socket(addr).expect("binding server socket");
                 listen_socket
                     .join_multicast_v4(&ip, &Ipv4Addr::UNSPECIFIED)
                     .unwrap_or_else(|e| panic!("{}", e));
             }
             #[cfg(target_os = "windows")]
             {
-                listen_socket = UdpSocket::bind(SocketAddr::new(
+                listen_socket = udp_socket(SocketAddr::new(
                     IpAddr::V4(Ipv4Addr::UNSPECIFIED),
                     addr.port(),
                 ))
@@ -108,7 +174,7 @@ pub fn upstream_socket_interface(listen_addr: String) -> ioResult<(SocketAddr, U
             }
         }
         (true, std::net::IpAddr::V6(ip)) => {
-            listen_socket = UdpSocket::bind(SocketAddr::new(
+            listen_socket = multicast_socket(SocketAddr::new(
                 IpAddr::V6(Ipv6Addr::UNSPECIFIED),
                 addr.port(),
             ))
@@ -133,52 +199,188 @@ pub fn upstream_socket_interface(listen_addr: String) -> ioResult<(SocketAddr, U
     Ok((addr, listen_socket))
 }
 
+/// Format of the file written by a listener
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum OutputFormat {
+    /// Received bytes, concatenated
+    #[default]
+    Raw,
+    /// Timestamped datagrams with their source address, see
+    /// [`mproxy_common::capture`]
+    Capture,
+    /// Synthesized UDP packets in a pcap file, see [`mproxy_common::pcap`]
+    Pcap,
+    /// Synthesized UDP packets in a pcapng file
+    PcapNg,
+}
+
+impl OutputFormat {
+    /// Write the file header, if the format has one
+    fn write_header<W: Write>(&self, writer: &mut W) -> ioResult<()> {
+        match self {
+            OutputFormat::Raw => Ok(()),
+            OutputFormat::Capture => capture::write_header(writer),
+            OutputFormat::Pcap => pcap::write_header(writer, PcapFormat::Pcap),
+            OutputFormat::PcapNg => pcap::write_header(writer, PcapFormat::PcapNg),
+        }
+    }
+
+    /// Check that a file being appended to starts with the file header,
+    /// so that datagrams are not appended to a file of another format
+    fn check_header<R: Read>(&self, reader: &mut R) -> ioResult<()> {
+        let mut header = vec![];
+        self.write_header(&mut header)?;
+        let mut existing = vec![0u8; header.len()];
+        match reader.read_exact(&mut existing) {
+            Ok(()) if existing == header => Ok(()),
+            Ok(()) | Err(_) => {
+                let name = match self {
+                    OutputFormat::Raw => "raw",
+                    OutputFormat::Capture => "capture",
+                    OutputFormat::Pcap => "pcap",
+                    OutputFormat::PcapNg => "pcapng",
+                };
+                Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("not a {} file written by mproxy-server", name),
+                ))
+            }
+        }
+    }
+
+    /// Write a datagram received from `source` on `dest`
+    fn write<W: Write>(
+        &self,
+        writer: &mut W,
+        source: SocketAddr,
+        dest: SocketAddr,
+        data: &[u8],
+    ) -> ioResult<()> {
+        let now = SystemTime::now();
+        match self {
+            OutputFormat::Raw => writer.write_all(data),
+            OutputFormat::Capture => writer.write_all(&encode_record(now, source, data)),
+            OutputFormat::Pcap => {
+                writer.write_all(&encode_packet(PcapFormat::Pcap, now, source, dest, data))
+            }
+            OutputFormat::PcapNg => {
+                writer.write_all(&encode_packet(PcapFormat::PcapNg, now, source, dest, data))
+            }
+        }
+    }
+}
+
+impl FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "raw" => Ok(OutputFormat::Raw),
+            "capture" => Ok(OutputFormat::Capture),
+            "pcap" => Ok(OutputFormat::Pcap),
+            "pcapng" => Ok(OutputFormat::PcapNg),
+            _ => Err(format!("unknown output format '{}'", s)),
+        }
+    }
+}
+
 /// Server UDP socket listener.
 /// Binds to UDP socket address `addr`, and logs input to `logfile`.
 /// Can optionally copy input to stdout if `tee` is true.
 /// `logfile` may be a filepath, file descriptor/handle, etc.
+/// Traffic is counted in route metrics named `{addr}:server`.
+/// Datagrams over the limit set with
+/// [`set_source_limit`](mproxy_common::ratelimit::set_source_limit) are
+/// dropped, per source address.
+/// Zero-length datagrams are not written, and are answered as heartbeats if
+/// enabled with [`set_answer_heartbeats`].
 pub fn listener(addr: String, logfile: PathBuf, tee: bool) -> JoinHandle<()> {
-    let file = OpenOptions::new()
+    listener_format(addr, logfile, tee, OutputFormat::Raw)
+}
+
+/// As [`listener`], writing `logfile` in `format`, e.g. as a capture file
+/// that can be replayed with the original timing by the client, or as a
+/// pcap file of UDP packets to `addr`. An existing `logfile` is appended to,
+/// and must start with the file header of `format`
+pub fn listener_format(
+    addr: String,
+    logfile: PathBuf,
+    tee: bool,
+    format: OutputFormat,
+) -> JoinHandle<()> {
+    let mut file = OpenOptions::new()
         .create(true)
-        .write(true)
+        .read(true)
         .append(true)
-        .open(&logfile);
-    let mut writer = BufWriter::new(file.unwrap());
+        .open(&logfile)
+        .unwrap();
+    let empty = file.metadata().map(|m| m.len() == 0).unwrap_or(false);
+    if !empty {
+        format
+            .check_header(&mut file)
+            .unwrap_or_else(|e| panic!("appending to {:?}: {}", &logfile, e));
+    }
+    let mut writer = BufWriter::new(file);
+    if empty {
+        format
+            .write_header(&mut writer)
+            .and_then(|()| writer.flush())
+            .unwrap_or_else(|e| panic!("writing to {:?}: {}", &logfile, e));
+    }
     let mut output_buffer = BufWriter::new(stdout());
 
     let (addr, listen_socket) = upstream_socket_interface(addr).unwrap();
+    let metrics = RouteMetrics::register(&addr.to_string(), "server");
+    let output_metrics = metrics.downstream(&logfile.display().to_string());
+
+    let mut source_limits = SourceLimiter::from_settings();
 
-    Builder::new()
-        .name(format!("{}:server", addr))
-        .spawn(move || {
-            let mut buf = [0u8; BUFSIZE]; // receive buffer
-            loop {
-                match listen_socket.recv_from(&mut buf[0..]) {
-                    Ok((c, _remote_addr)) => {
-                        if tee {
-                            let _o = output_buffer
-                                .write(&buf[0..c])
-                                .expect("writing to output buffer");
-                            #[cfg(debug_assertions)]
-                            assert!(c == _o);
+    supervise(format!("{}:server", addr), move || {
+        let _running = metrics.running();
+        let mut buf = [0u8; BUFSIZE]; // receive buffer
+        loop {
+            match listen_socket.recv_from(&mut buf[0..]) {
+                Ok((0, remote_addr)) => {
+                    // heartbeat probe from a downstream dispatcher, never
+                    // written, so that probes leave no empty records
+                    if ANSWER_HEARTBEATS.load(Ordering::Relaxed) {
+                        let _ = listen_socket.send_to(&[], remote_addr);
+                    }
+                }
+                Ok((c, remote_addr)) => {
+                    metrics.received(c);
+                    metrics.taps().send(&buf[0..c]);
+                    if let Some(limits) = &mut source_limits {
+                        let shaped = limits.admit(remote_addr.ip(), c);
+                        metrics.source_limited(shaped);
+                        if shaped == Shaped::Dropped {
+                            continue;
                         }
-                        let _ = writer
-                            .write(&buf[0..c])
-                            .unwrap_or_else(|_| panic!("writing to {:?}", &logfile));
                     }
-                    Err(err) => {
-                        writer.flush().unwrap();
-                        eprintln!("{}:server: got an error: {}", addr, err);
+                    if tee {
+                        let _o = output_buffer
+                            .write(&buf[0..c])
+                            .expect("writing to output buffer");
                         #[cfg(debug_assertions)]
-                        panic!("{}:server: got an error: {}", addr, err);
+                        assert!(c == _o);
                     }
+                    format
+                        .write(&mut writer, remote_addr, addr, &buf[0..c])
+                        .unwrap_or_else(|_| panic!("writing to {:?}", &logfile));
+                    output_metrics.sent(c);
                 }
-
-                writer.flush().unwrap();
-                if tee {
-                    output_buffer.flush().unwrap();
+                Err(err) => {
+                    writer.flush().unwrap();
+                    error!("{}:server: got an error: {}", addr, err);
+                    #[cfg(debug_assertions)]
+                    panic!("{}:server: got an error: {}", addr, err);
                 }
             }
-        })
-        .unwrap()
+
+            writer.flush().unwrap();
+            if tee {
+                output_buffer.flush().unwrap();
+            }
+        }
+    })
 }
@@ -1,9 +1,17 @@
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::process::exit;
 use std::str::FromStr;
 
-use mproxy_server::listener;
+use mproxy_common::daemon::{drop_privileges, write_pidfile};
+use mproxy_common::logging::{init_logging, LogFormat, LogLevels, LogOutput};
+use mproxy_common::metrics::serve_metrics;
+use mproxy_common::ratelimit::{parse_source_limit, set_source_limit, RateLimit};
+use mproxy_common::supervisor::{set_on_failure, OnFailure};
+use mproxy_common::systemd::{ready, watchdog};
+use mproxy_common::tap::serve_tap;
+use mproxy_server::{listener_format, set_answer_heartbeats, OutputFormat};
 
+use log::info;
 use pico_args::Arguments;
 
 const HELP: &str = r#"
@@ -16,11 +24,55 @@ USAGE:
 
 OPTIONS: 
   --path        [FILE_DESCRIPTOR]   Filepath, descriptor, or handle.
+  --format      [FORMAT]            File format: raw, capture, pcap, or pcapng. Defaults to raw
   --listen-addr [SOCKET_ADDR]       Upstream UDP listening address. May be repeated 
+  --metrics-addr [SOCKET_ADDR]      Serve Prometheus metrics over HTTP at /metrics
+  --tap-addr    [ADDR|PATH]         Serve live taps of route traffic over TCP, or a Unix socket PATH
+  --source-limit [LIMIT]            Rate limit for each upstream source address, see RATE LIMITS
+  --on-failure  [ACTION]            Action when a route fails: restart or exit. Defaults to restart
+  --log-level   [LEVEL]             Log level: error, warn, info, debug, or trace, or per target, e.g. mproxy_server=debug,warn. Defaults to info
+  --log-format  [FORMAT]            Log format: text or json. Defaults to text
+  --log-output  [OUTPUT]            Write logs to stderr, syslog, or journald. Defaults to stderr
+  --user        [USER]              Switch to USER, a name or ID, once sockets and files are opened
+  --group       [GROUP]             Switch to GROUP. Defaults to the primary group of --user
+  --pidfile     [FILE]              Write the process ID to FILE
 
 FLAGS:
   -h, --help    Prints help information
   -t, --tee     Copy input to stdout
+  --chroot      Confine the server to the directory of --path, once opened
+  --heartbeat   Answer zero-length datagrams, for mproxy-forward --heartbeat-secs. Off by default
+
+TAPS:
+  Connect to --tap-addr and send a line naming a route listed by the admin API or metrics,
+  e.g. '0.0.0.0:9920:server', to receive a copy of the messages it receives.
+  Append 'hex' for a hexdump, or 'sample=N' to copy every Nth message.
+
+SYSTEMD:
+  Sockets passed by systemd socket activation are used in place of binding matching
+  addresses. With Type=notify, readiness is reported once listeners are bound, and
+  watchdog pings are sent while no route has panicked.
+
+FAILURES:
+  Routes which panic or fail are restarted with backoff, from 100ms up to 30s. With
+  --on-failure exit, the process instead exits with status 1, e.g. for systemd to restart it.
+
+RATE LIMITS:
+  Limits are given as 'bytes=RATE[:BURST],packets=RATE[:BURST]', either part optional.
+  Datagrams over the limit are dropped, and counted in the metrics. Bursts default to one
+  second of traffic.
+
+CAPTURE:
+  With --format capture, each datagram is written with its arrival time and source address,
+  so that the feed can be replayed with its original timing by mproxy-client --replay.
+  With --format pcap or pcapng, each datagram is written as a UDP packet from its source
+  address to the listening address, to open in tools such as Wireshark.
+
+PRIVILEGES:
+  When started as root, e.g. to listen on ports below 1024 or write to a directory owned by
+  root, use --user and --group to switch to an unprivileged user once listeners are bound and
+  output files are opened, and --chroot to confine the server to the directory of --path.
+  The pid file is written before.
 
 EXAMPLE:
   mproxy-server --path logfile.log --listen-addr '127.0.0.1:9920' --listen-addr '[::1]:9921'
@@ -29,7 +81,20 @@ EXAMPLE:
 
 struct ServerArgs {
     listen_addr: Vec<String>,
+    metrics_addr: Option<String>,
+    tap_addr: Option<String>,
+    source_limit: Option<RateLimit>,
+    on_failure: OnFailure,
+    user: Option<String>,
+    group: Option<String>,
+    pidfile: Option<PathBuf>,
+    log_level: LogLevels,
+    log_format: LogFormat,
+    log_output: LogOutput,
     path: String,
+    format: OutputFormat,
+    chroot: bool,
+    heartbeat: bool,
     tee: bool,
 }
 
@@ -40,14 +105,37 @@ fn parse_args() -> Result<ServerArgs, pico_args::Error> {
         exit(0);
     }
     let tee = pargs.contains(["-t", "--tee"]);
+    let chroot = pargs.contains("--chroot");
+    let heartbeat = pargs.contains("--heartbeat");
     let args = ServerArgs {
         path: pargs.value_from_str("--path")?,
+        format: pargs.opt_value_from_str("--format")?.unwrap_or_default(),
         listen_addr: pargs.values_from_str("--listen-addr")?,
+        metrics_addr: pargs.opt_value_from_str("--metrics-addr")?,
+        tap_addr: pargs.opt_value_from_str("--tap-addr")?,
+        source_limit: pargs.opt_value_from_fn("--source-limit", parse_source_limit)?,
+        on_failure: pargs
+            .opt_value_from_str("--on-failure")?
+            .unwrap_or_default(),
+        user: pargs.opt_value_from_str("--user")?,
+        group: pargs.opt_value_from_str("--group")?,
+        pidfile: pargs
+            .opt_value_from_str::<_, String>("--pidfile")?
+            .map(PathBuf::from),
+        log_level: pargs.opt_value_from_str("--log-level")?.unwrap_or_default(),
+        log_format: pargs
+            .opt_value_from_str("--log-format")?
+            .unwrap_or_default(),
+        log_output: pargs
+            .opt_value_from_str("--log-output")?
+            .unwrap_or_default(),
+        chroot,
+        heartbeat,
         tee,
     };
     let remaining = pargs.finish();
     if !remaining.is_empty() {
-        println!("Warning: unused arguments {:?}", remaining)
+        eprintln!("Warning: unused arguments {:?}", remaining)
     }
     if args.listen_addr.is_empty() {
         eprintln!("Error: the --listen-addr option must be set. Must provide atleast one client IP address");
@@ -64,10 +152,26 @@ pub fn main() {
             exit(1);
         }
     };
+    if let Err(e) = init_logging(args.log_level, args.log_format, args.log_output) {
+        eprintln!("Error: initializing logging: {}.", e);
+        exit(1);
+    }
+    set_on_failure(args.on_failure);
+    set_source_limit(args.source_limit);
+    set_answer_heartbeats(args.heartbeat);
 
     let mut threads = vec![];
 
+    if let Some(metrics_addr) = args.metrics_addr {
+        threads.push(serve_metrics(metrics_addr));
+    }
+
+    if let Some(tap_addr) = args.tap_addr {
+        threads.push(serve_tap(tap_addr));
+    }
+
     let append_listen_addr = args.listen_addr.len() > 1;
+    let listen_count = args.listen_addr.len();
 
     for hostname in args.listen_addr {
         // if listening to multiple clients at once, log each client to a
@@ -80,13 +184,39 @@ pub fn main() {
             }
         }
 
-        println!("logging transmissions from {} to {}", hostname, logpath);
-        threads.push(listener(
+        info!("logging transmissions from {} to {}", hostname, logpath);
+        threads.push(listener_format(
             hostname,
             PathBuf::from_str(&logpath).unwrap(),
             args.tee,
+            args.format,
         ));
     }
+    // log files are open, so the server may be confined to their directory
+    let chroot = args.chroot.then(|| match Path::new(&args.path).parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+        _ => PathBuf::from("."),
+    });
+    if let Some(path) = &args.pidfile {
+        if let Err(e) = write_pidfile(path) {
+            eprintln!("Error: writing {}: {}.", path.display(), e);
+            exit(1);
+        }
+    }
+    if let Err(e) = drop_privileges(
+        args.user.as_deref(),
+        args.group.as_deref(),
+        chroot.as_deref(),
+    ) {
+        eprintln!("Error: {}.", e);
+        exit(1);
+    }
+    let _watchdog = watchdog();
+    ready(&format!(
+        "logging {} listeners to {}",
+        listen_count, args.path
+    ));
+
     for thread in threads {
         thread.join().unwrap();
     }
@@ -1,10 +1,14 @@
 use std::fs::File;
+use std::net::UdpSocket;
 use std::path::PathBuf;
 use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
 use std::thread::sleep;
 use std::time::Duration;
 
 use mproxy_client::{client_socket_stream, target_socket_interface};
+use mproxy_common::supervisor::supervise;
 use mproxy_server::listener;
 
 use testconfig::{truncate, TESTINGDIR};
@@ -34,7 +38,7 @@ fn demo_client(addr: String, logfile: PathBuf) {
 #[test]
 fn test_server_ipv4_unicast() {
     let ipv4 = "127.0.0.1:9900".to_string();
-    let pathstr = &[TESTINGDIR, "streamoutput_ipv4_unicast.log"].join(&"");
+    let pathstr = &[TESTINGDIR, "streamoutput_ipv4_unicast.log"].join("");
     let logfile: PathBuf = PathBuf::from_str(pathstr).unwrap();
     demo_client(ipv4, logfile);
 }
@@ -42,7 +46,7 @@ fn test_server_ipv4_unicast() {
 #[test]
 fn test_server_ipv4_multicast() {
     let ipv4 = "224.0.0.2:9901".to_string();
-    let pathstr = &[TESTINGDIR, "streamoutput_ipv4_multicast.log"].join(&"");
+    let pathstr = &[TESTINGDIR, "streamoutput_ipv4_multicast.log"].join("");
     let logfile: PathBuf = PathBuf::from_str(pathstr).unwrap();
     demo_client(ipv4, logfile);
 }
@@ -50,7 +54,7 @@ fn test_server_ipv4_multicast() {
 #[test]
 fn test_server_ipv6_unicast() {
     let listen = "[::1]:9902".to_string();
-    let pathstr = &[TESTINGDIR, "streamoutput_ipv6_unicast.log"].join(&"");
+    let pathstr = &[TESTINGDIR, "streamoutput_ipv6_unicast.log"].join("");
     let logfile: PathBuf = PathBuf::from_str(pathstr).unwrap();
     demo_client(listen, logfile);
 }
@@ -58,7 +62,7 @@ fn test_server_ipv6_unicast() {
 #[test]
 fn test_server_ipv6_multicast() {
     let listen = "[ff02::1]:9903".to_string();
-    let pathstr = &[TESTINGDIR, "streamoutput_ipv6_multicast.log"].join(&"");
+    let pathstr = &[TESTINGDIR, "streamoutput_ipv6_multicast.log"].join("");
     let logfile: PathBuf = PathBuf::from_str(pathstr).unwrap();
     demo_client(listen, logfile);
 }
@@ -70,8 +74,8 @@ fn test_server_multiple_clients_single_channel() {
         TESTINGDIR,
         "streamoutput_client_ipv6_multiclient_samefile.log",
     ]
-    .join(&"");
-    File::create(&pathstr_1).expect("truncating file");
+    .join("");
+    File::create(pathstr_1).expect("truncating file");
     sleep(Duration::from_millis(15));
     let listen_addr_1 = "[::]:9904".to_string();
     let target_addr_1 = "[::1]:9904".to_string();
@@ -88,8 +92,8 @@ fn test_server_multiple_clients_dual_channel() {
         TESTINGDIR,
         "streamoutput_client_ipv6_multiclient_different_channels.log",
     ]
-    .join(&"");
-    File::create(&pathstr_1).expect("truncating file");
+    .join("");
+    File::create(pathstr_1).expect("truncating file");
     sleep(Duration::from_millis(15));
     let listen_addr_1 = "[::]:9905".to_string();
     let listen_addr_2 = "[::]:9906".to_string();
@@ -100,3 +104,267 @@ fn test_server_multiple_clients_dual_channel() {
     let _c1 = client_socket_stream(&PathBuf::from("./Cargo.toml"), vec![target_addr_1], false);
     let _c2 = client_socket_stream(&PathBuf::from("../Cargo.toml"), vec![target_addr_2], false);
 }
+
+#[test]
+fn test_supervisor_restarts_failed_route() {
+    let attempts = Arc::new(AtomicUsize::new(0));
+    let route_attempts = attempts.clone();
+
+    // the route is restarted after panicking and after returning an error,
+    // and exits once it returns Ok
+    let route = supervise("test:supervised".to_string(), move || match route_attempts
+        .fetch_add(1, Ordering::SeqCst)
+    {
+        0 => panic!("first attempt"),
+        1 => Err("second attempt".to_string()),
+        _ => Ok(()),
+    });
+    route.join().unwrap();
+    assert_eq!(attempts.load(Ordering::SeqCst), 3);
+}
+
+#[cfg(unix)]
+#[test]
+fn test_server_systemd_socket_activation() {
+    use std::os::fd::OwnedFd;
+    use std::os::unix::net::UnixDatagram;
+    use std::process::{Command, Stdio};
+
+    let listen_addr = "127.0.0.1:9925";
+    let logfile = PathBuf::from_str(&[TESTINGDIR, "streamoutput_systemd.log"].join("")).unwrap();
+    let notify_path = std::env::temp_dir().join("mproxy_server_notify.sock");
+    let _ = std::fs::remove_file(&notify_path);
+    let notify = UnixDatagram::bind(&notify_path).unwrap();
+    notify
+        .set_read_timeout(Some(Duration::from_secs(2)))
+        .unwrap();
+
+    // pass a bound socket as file descriptor 3, as systemd would. The server
+    // fails to bind the address itself while the socket is open
+    let socket = UdpSocket::bind(listen_addr).unwrap();
+    let mut server = Command::new("sh")
+        .arg("-c")
+        .arg("exec 3<&0 0</dev/null; export LISTEN_PID=$$ LISTEN_FDS=1; exec \"$0\" \"$@\"")
+        .arg(env!("CARGO_BIN_EXE_mproxy-server"))
+        .args(["--listen-addr", listen_addr, "--path"])
+        .arg(&logfile)
+        .env("NOTIFY_SOCKET", &notify_path)
+        .stdin(Stdio::from(OwnedFd::from(socket)))
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+
+    let mut buf = [0u8; 256];
+    let ready = notify.recv(&mut buf).map(|c| buf[0..c].to_vec());
+
+    let (target_addr, target_socket) = target_socket_interface(&listen_addr.to_string()).unwrap();
+    target_socket
+        .send_to(b"Hello from systemd!\n", target_addr)
+        .unwrap();
+    sleep(Duration::from_millis(50));
+    server.kill().unwrap();
+    server.wait().unwrap();
+
+    let expected = format!(
+        "READY=1\nSTATUS=logging 1 listeners to {}",
+        logfile.display()
+    );
+    assert_eq!(String::from_utf8(ready.unwrap()).unwrap(), expected);
+    assert!(truncate(logfile) > 0);
+    let _ = std::fs::remove_file(&notify_path);
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn test_server_pidfile_and_user() {
+    use std::os::unix::fs::PermissionsExt;
+    use std::os::unix::net::UnixDatagram;
+    use std::process::{Command, Stdio};
+
+    /// Real user ID of process `pid`, from /proc
+    fn uid_of(pid: &str) -> String {
+        let status = std::fs::read_to_string(format!("/proc/{}/status", pid)).unwrap();
+        let uid = status.lines().find_map(|l| l.strip_prefix("Uid:")).unwrap();
+        uid.split_whitespace().next().unwrap().to_string()
+    }
+
+    // switch to nobody when run as root, or else to the current user
+    let uid = match uid_of("self").as_str() {
+        "0" => "65534".to_string(),
+        uid => uid.to_string(),
+    };
+    let logfile = PathBuf::from_str(&[TESTINGDIR, "streamoutput_pidfile.log"].join("")).unwrap();
+    let pidfile = std::env::temp_dir().join("mproxy_server_test.pid");
+    let notify_path = std::env::temp_dir().join("mproxy_server_pidfile_notify.sock");
+    let _ = std::fs::remove_file(&notify_path);
+    let notify = UnixDatagram::bind(&notify_path).unwrap();
+    notify
+        .set_read_timeout(Some(Duration::from_secs(2)))
+        .unwrap();
+    // readiness is notified after switching user
+    std::fs::set_permissions(&notify_path, std::fs::Permissions::from_mode(0o777)).unwrap();
+
+    let mut server = Command::new(env!("CARGO_BIN_EXE_mproxy-server"))
+        .args(["--listen-addr", "127.0.0.1:9926", "--path"])
+        .arg(&logfile)
+        .arg("--pidfile")
+        .arg(&pidfile)
+        .args(["--user", &uid])
+        .env("NOTIFY_SOCKET", &notify_path)
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    let mut buf = [0u8; 256];
+    let ready = notify.recv(&mut buf);
+    let pid = std::fs::read_to_string(&pidfile);
+    let running_as = uid_of(&server.id().to_string());
+    server.kill().unwrap();
+    server.wait().unwrap();
+
+    assert!(ready.is_ok());
+    assert_eq!(pid.unwrap(), format!("{}\n", server.id()));
+    assert_eq!(running_as, uid);
+    let _ = std::fs::remove_file(&pidfile);
+    let _ = std::fs::remove_file(&notify_path);
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn test_server_chroot_notify() {
+    use std::os::unix::net::UnixDatagram;
+    use std::process::{Command, Stdio};
+
+    let logfile = PathBuf::from_str(&[TESTINGDIR, "streamoutput_chroot.log"].join("")).unwrap();
+    let _ = std::fs::remove_file(&logfile);
+    let notify_path = std::env::temp_dir().join("mproxy_server_chroot_notify.sock");
+    let _ = std::fs::remove_file(&notify_path);
+    let notify = UnixDatagram::bind(&notify_path).unwrap();
+    notify
+        .set_read_timeout(Some(Duration::from_secs(2)))
+        .unwrap();
+
+    // readiness and watchdog pings are sent from within the chroot
+    let mut server = Command::new(env!("CARGO_BIN_EXE_mproxy-server"))
+        .args(["--listen-addr", "127.0.0.1:9946", "--path"])
+        .arg(&logfile)
+        .arg("--chroot")
+        .env("NOTIFY_SOCKET", &notify_path)
+        .env("WATCHDOG_USEC", "200000")
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    let mut notifications = vec![];
+    let mut buf = [0u8; 256];
+    for _ in 0..2 {
+        if let Ok(c) = notify.recv(&mut buf) {
+            notifications.push(String::from_utf8_lossy(&buf[0..c]).to_string());
+        }
+    }
+    let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+    client.send_to(b"confined\n", "127.0.0.1:9946").unwrap();
+    sleep(Duration::from_millis(50));
+    server.kill().unwrap();
+    server.wait().unwrap();
+
+    assert_eq!(notifications.len(), 2);
+    assert!(notifications.iter().any(|n| n.starts_with("READY=1\n")));
+    assert!(notifications.iter().any(|n| n == "WATCHDOG=1"));
+    assert_eq!(std::fs::read(&logfile).unwrap(), b"confined\n");
+    let _ = std::fs::remove_file(&logfile);
+    let _ = std::fs::remove_file(&notify_path);
+}
+
+#[test]
+fn test_server_ignores_heartbeat_probes_by_default() {
+    let logfile = PathBuf::from_str(&[TESTINGDIR, "streamoutput_probes.log"].join("")).unwrap();
+    listener("127.0.0.1:9947".to_string(), logfile.clone(), false);
+    sleep(Duration::from_millis(15));
+
+    let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+    client
+        .set_read_timeout(Some(Duration::from_millis(200)))
+        .unwrap();
+    client.send_to(&[], "127.0.0.1:9947").unwrap();
+    let mut buf = [0u8; 16];
+    assert!(client.recv(&mut buf).is_err());
+    truncate(logfile);
+}
+
+#[test]
+fn test_server_pcap_output() {
+    use mproxy_server::{listener_format, OutputFormat};
+
+    let pathstr = &[TESTINGDIR, "streamoutput_server.pcap"].join("");
+    let _ = std::fs::remove_file(pathstr);
+    let _l = listener_format(
+        "127.0.0.1:9940".to_string(),
+        PathBuf::from(pathstr),
+        false,
+        OutputFormat::Pcap,
+    );
+    let sender = UdpSocket::bind("127.0.0.1:0").unwrap();
+    sender.send_to(b"hello", "127.0.0.1:9940").unwrap();
+    sleep(Duration::from_millis(100));
+
+    let pcap = std::fs::read(pathstr).unwrap();
+    std::fs::remove_file(pathstr).unwrap();
+    // global header, with raw IP link type
+    assert_eq!(pcap[0..4], [0xd4, 0xc3, 0xb2, 0xa1]);
+    assert_eq!(pcap[20..24], 101u32.to_le_bytes());
+    // one record of an IPv4 packet carrying the UDP datagram
+    let packet = &pcap[40..];
+    assert_eq!(pcap[32..36], (packet.len() as u32).to_le_bytes());
+    assert_eq!(packet.len(), 20 + 8 + 5);
+    assert_eq!(packet[0], 0x45);
+    assert_eq!(packet[9], 17);
+    assert_eq!(packet[12..16], [127, 0, 0, 1]);
+    let sport = sender.local_addr().unwrap().port();
+    assert_eq!(packet[20..22], sport.to_be_bytes());
+    assert_eq!(packet[22..24], 9940u16.to_be_bytes());
+    assert_eq!(&packet[28..], b"hello");
+}
+
+#[test]
+fn test_server_refuses_to_append_to_other_format() {
+    use mproxy_server::{listener_format, OutputFormat};
+
+    let pathstr = &[TESTINGDIR, "streamoutput_server_mismatch.pcap"].join("");
+    std::fs::write(pathstr, b"raw output\n").unwrap();
+    let result = std::panic::catch_unwind(|| {
+        listener_format(
+            "127.0.0.1:9948".to_string(),
+            PathBuf::from(pathstr),
+            false,
+            OutputFormat::Pcap,
+        )
+    });
+    let contents = std::fs::read(pathstr).unwrap();
+    std::fs::remove_file(pathstr).unwrap();
+    assert!(result.is_err());
+    assert_eq!(contents, b"raw output\n");
+}
+
+#[test]
+fn test_server_skips_zero_length_datagrams() {
+    use mproxy_common::capture::CaptureReader;
+    use mproxy_server::{listener_format, OutputFormat};
+
+    let pathstr = &[TESTINGDIR, "streamoutput_server_probes.cap"].join("");
+    let _ = std::fs::remove_file(pathstr);
+    let _l = listener_format(
+        "127.0.0.1:9956".to_string(),
+        PathBuf::from(pathstr),
+        false,
+        OutputFormat::Capture,
+    );
+    let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+    client.send_to(&[], "127.0.0.1:9956").unwrap();
+    client.send_to(b"data", "127.0.0.1:9956").unwrap();
+    sleep(Duration::from_millis(100));
+
+    let mut reader = CaptureReader::new(File::open(pathstr).unwrap()).unwrap();
+    let record = reader.next_record().unwrap().unwrap();
+    assert_eq!(record.data, b"data");
+    assert_eq!(reader.next_record().unwrap(), None);
+    std::fs::remove_file(pathstr).unwrap();
+}